@@ -0,0 +1,32 @@
+//! Derive wallets from a mnemonic and restore them from the phrase
+//!
+//! Run with `cargo run --example hd_wallet`. Nothing is written to disk.
+
+use polytorus::hdwallet::{derive_wallet, generate_mnemonic, mnemonic_to_seed, DerivationPath};
+use polytorus::signer::Signer;
+use polytorus::Result;
+
+fn main() -> Result<()> {
+    let phrase = generate_mnemonic()?.to_string();
+    println!("mnemonic: {}", phrase);
+
+    let seed = mnemonic_to_seed(&phrase, "")?;
+    let mut addresses = Vec::new();
+    for index in 0..3 {
+        let path = DerivationPath::default_for(index);
+        let wallet = derive_wallet(&seed, &path);
+        println!("{}: {}", path, wallet.get_address());
+        addresses.push(wallet.get_address());
+    }
+
+    // the same phrase always gives back the same wallets
+    let restored = derive_wallet(
+        &mnemonic_to_seed(&phrase, "")?,
+        &"m/44'/7391'/0'/0'".parse()?,
+    );
+    assert_eq!(restored.get_address(), addresses[0]);
+
+    let signature = restored.sign(b"hello")?;
+    println!("signature over \"hello\": {} bytes", signature.len());
+    Ok(())
+}
@@ -0,0 +1,41 @@
+//! Create two wallets, start a chain, send coins and query the balances
+//!
+//! Run with `cargo run --example transfer`. The chain is written to
+//! `data/blocks` and `data/utxos` of the working directory, replacing any
+//! chain already there.
+
+use bitcoincash_addr::Address;
+use polytorus::blockchain::Blockchain;
+use polytorus::transaction::Transaction;
+use polytorus::utxoset::UTXOSet;
+use polytorus::wallets::Wallet;
+use polytorus::Result;
+use rand_core::OsRng;
+
+fn balance(utxo_set: &UTXOSet, address: &str) -> Result<i32> {
+    let pub_key_hash = Address::decode(address).unwrap().body;
+    let utxos = utxo_set.find_UTXO(&pub_key_hash)?;
+    Ok(utxos.outputs.iter().map(|out| out.value).sum())
+}
+
+fn main() -> Result<()> {
+    let alice = Wallet::from_rng(&mut OsRng);
+    let bob = Wallet::from_rng(&mut OsRng);
+
+    // the genesis coinbase pays alice
+    let bc = Blockchain::create_blockchain(alice.get_address())?;
+    let mut utxo_set = UTXOSet { blockchain: bc };
+    utxo_set.reindex()?;
+    println!("alice: {}", balance(&utxo_set, &alice.get_address())?);
+
+    // alice signs a transfer and mines it herself
+    let tx = Transaction::new_UTXO(&alice, &bob.get_address(), 3, &utxo_set)?;
+    let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("reward!"))?;
+    let block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
+    utxo_set.update(&block)?;
+
+    println!("height: {}", utxo_set.blockchain.get_best_height()?);
+    println!("alice: {}", balance(&utxo_set, &alice.get_address())?);
+    println!("bob: {}", balance(&utxo_set, &bob.get_address())?);
+    Ok(())
+}
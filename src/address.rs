@@ -0,0 +1,299 @@
+//! Address format v2
+//!
+//! A v2 address is the bech32m (BIP 350) encoding of a public key hash.
+//! The human readable part names the network, `pt` on mainnet and `tpt`
+//! on testnets. The first data symbol is the key type, like the witness
+//! version of a segwit address: 1 is FN-DSA, 0 is kept for ECDSA, which
+//! this node cannot verify. Decoding is strict: a wrong checksum, mixed
+//! case, a wrong length or non-zero padding is an error.
+//!
+//! Legacy addresses are the Base58 encoding of bitcoincash_addr. Both
+//! forms lock to the same public key hash, so they are interchangeable.
+
+use super::*;
+use bitcoincash_addr::{Address, HashType, Scheme};
+use failure::format_err;
+
+pub const MAINNET_HRP: &str = "pt";
+pub const TESTNET_HRP: &str = "tpt";
+/// Length of a public key hash, RIPEMD-160 of SHA-256
+pub const PUB_KEY_HASH_LEN: usize = 20;
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32M_CONST: u32 = 0x2bc8_30a3;
+const MAX_LEN: usize = 90;
+const CHECKSUM_LEN: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+}
+
+impl Network {
+    pub fn hrp(self) -> &'static str {
+        match self {
+            Network::Mainnet => MAINNET_HRP,
+            Network::Testnet => TESTNET_HRP,
+        }
+    }
+}
+
+/// KeyType is the signature scheme of the key behind an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    FnDsa,
+}
+
+impl KeyType {
+    fn version(self) -> u8 {
+        match self {
+            KeyType::FnDsa => 1,
+        }
+    }
+
+    fn from_version(version: u8) -> Result<KeyType> {
+        match version {
+            1 => Ok(KeyType::FnDsa),
+            0 => Err(format_err!("ECDSA addresses are not supported")),
+            v => Err(format_err!("unknown key type {}", v)),
+        }
+    }
+}
+
+/// AddressV2 is a decoded v2 address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressV2 {
+    pub network: Network,
+    pub key_type: KeyType,
+    pub pub_key_hash: Vec<u8>,
+}
+
+impl AddressV2 {
+    pub fn new(network: Network, pub_key_hash: &[u8]) -> AddressV2 {
+        AddressV2 {
+            network,
+            key_type: KeyType::FnDsa,
+            pub_key_hash: pub_key_hash.to_vec(),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut data = vec![self.key_type.version()];
+        data.extend(convert_bits(&self.pub_key_hash, 8, 5, true).unwrap_or_default());
+        bech32m_encode(self.network.hrp(), &data)
+    }
+
+    pub fn decode(address: &str) -> Result<AddressV2> {
+        let (hrp, data) = bech32m_decode(address)?;
+        let network = match hrp.as_str() {
+            MAINNET_HRP => Network::Mainnet,
+            TESTNET_HRP => Network::Testnet,
+            _ => return Err(format_err!("unknown address prefix {}", hrp)),
+        };
+        let (version, program) = match data.split_first() {
+            Some(split) => split,
+            None => return Err(format_err!("address has no key type")),
+        };
+        let key_type = KeyType::from_version(*version)?;
+        let pub_key_hash = match convert_bits(program, 5, 8, false) {
+            Some(h) if h.len() == PUB_KEY_HASH_LEN => h,
+            _ => {
+                return Err(format_err!(
+                    "address does not hold a {} byte key hash",
+                    PUB_KEY_HASH_LEN
+                ))
+            }
+        };
+        Ok(AddressV2 {
+            network,
+            key_type,
+            pub_key_hash,
+        })
+    }
+}
+
+/// PubKeyHash returns the public key hash of a v2 or legacy address
+pub fn pub_key_hash(address: &str) -> Result<Vec<u8>> {
+    if is_v2(address) {
+        return Ok(AddressV2::decode(address)?.pub_key_hash);
+    }
+    match Address::decode(address) {
+        Ok(a) if a.body.len() == PUB_KEY_HASH_LEN => Ok(a.body),
+        _ => Err(format_err!("invalid address {}", address)),
+    }
+}
+
+/// LegacyAddress encodes a public key hash in the legacy format
+pub fn legacy_address(pub_key_hash: &[u8]) -> String {
+    let address = Address {
+        body: pub_key_hash.to_vec(),
+        scheme: Scheme::Base58,
+        hash_type: HashType::Script,
+        ..Default::default()
+    };
+    address.encode().unwrap()
+}
+
+/// ToV2 converts an address of either format to a v2 address on `network`
+pub fn to_v2(address: &str, network: Network) -> Result<String> {
+    Ok(AddressV2::new(network, &pub_key_hash(address)?).encode())
+}
+
+/// ToLegacy converts an address of either format to the legacy format
+pub fn to_legacy(address: &str) -> Result<String> {
+    Ok(legacy_address(&pub_key_hash(address)?))
+}
+
+fn is_v2(address: &str) -> bool {
+    let lower = address.to_ascii_lowercase();
+    lower.starts_with(&format!("{}1", MAINNET_HRP))
+        || lower.starts_with(&format!("{}1", TESTNET_HRP))
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    const GEN: [u32; 5] = [
+        0x3b6a_57b2,
+        0x2650_8e6d,
+        0x1ea1_19fa,
+        0x3d42_33dd,
+        0x2a14_62b3,
+    ];
+    let mut chk: u32 = 1;
+    for v in values {
+        let top = chk >> 25;
+        chk = ((chk & 0x1ff_ffff) << 5) ^ *v as u32;
+        for (i, g) in GEN.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut values: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|c| c & 31));
+    values
+}
+
+fn bech32m_encode(hrp: &str, data: &[u8]) -> String {
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0; CHECKSUM_LEN]);
+    let pm = polymod(&values) ^ BECH32M_CONST;
+    let mut encoded = format!("{}1", hrp);
+    for d in data {
+        encoded.push(CHARSET[*d as usize] as char);
+    }
+    for i in 0..CHECKSUM_LEN {
+        encoded.push(CHARSET[((pm >> (5 * (5 - i))) & 31) as usize] as char);
+    }
+    encoded
+}
+
+fn bech32m_decode(address: &str) -> Result<(String, Vec<u8>)> {
+    if address.len() > MAX_LEN {
+        return Err(format_err!("address longer than {} characters", MAX_LEN));
+    }
+    if address.bytes().any(|c| !(33..=126).contains(&c)) {
+        return Err(format_err!("address has invalid characters"));
+    }
+    let lower = address.to_ascii_lowercase();
+    if lower != address && address.to_ascii_uppercase() != address {
+        return Err(format_err!("address mixes upper and lower case"));
+    }
+    let sep = match lower.rfind('1') {
+        Some(p) if p >= 1 && p + 1 + CHECKSUM_LEN <= lower.len() => p,
+        _ => return Err(format_err!("address has no separator or checksum")),
+    };
+    let hrp = &lower[..sep];
+    let mut data = Vec::new();
+    for c in lower[sep + 1..].bytes() {
+        match CHARSET.iter().position(|x| *x == c) {
+            Some(d) => data.push(d as u8),
+            None => return Err(format_err!("invalid address character {}", c as char)),
+        }
+    }
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(&data);
+    if polymod(&values) != BECH32M_CONST {
+        return Err(format_err!("invalid address checksum"));
+    }
+    data.truncate(data.len() - CHECKSUM_LEN);
+    Ok((hrp.to_string(), data))
+}
+
+/// convert_bits regroups bits, None on leftover bits when not padding
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let maxv = (1 << to) - 1;
+    let mut out = Vec::new();
+    for v in data {
+        if (*v as u32) >> from != 0 {
+            return None;
+        }
+        acc = (acc << from) | *v as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            out.push(((acc >> bits) & maxv) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            out.push(((acc << (to - bits)) & maxv) as u8);
+        }
+    } else if bits >= from || (acc << (to - bits)) & maxv != 0 {
+        return None;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_address_v2() {
+        // BIP 350 test vectors
+        for valid in [
+            "A1LQFN3A",
+            "a1lqfn3a",
+            "abcdef1l7aum6echk45nj3s0wdvt2fg8x9yrzpqzd3ryx",
+        ] {
+            assert!(bech32m_decode(valid).is_ok(), "{}", valid);
+        }
+        let (_, data) = bech32m_decode("abcdef1l7aum6echk45nj3s0wdvt2fg8x9yrzpqzd3ryx").unwrap();
+        assert_eq!(data, (0..32).rev().collect::<Vec<u8>>());
+        // bech32 (not m) checksum and mixed case
+        assert!(bech32m_decode("a12uel5l").is_err());
+        assert!(bech32m_decode("A1lqfn3a").is_err());
+
+        let hash: Vec<u8> = (0..20).collect();
+        let main = AddressV2::new(Network::Mainnet, &hash).encode();
+        let test = AddressV2::new(Network::Testnet, &hash).encode();
+        assert!(main.starts_with("pt1p"));
+        assert!(test.starts_with("tpt1p"));
+        assert_eq!(
+            AddressV2::decode(&main.to_uppercase()).unwrap(),
+            AddressV2::new(Network::Mainnet, &hash)
+        );
+        assert_eq!(pub_key_hash(&test).unwrap(), hash);
+
+        let mut typo = main.clone().into_bytes();
+        let last = typo.len() - 1;
+        typo[last] = if typo[last] == b'q' { b'p' } else { b'q' };
+        assert!(AddressV2::decode(&String::from_utf8(typo).unwrap()).is_err());
+        assert!(AddressV2::decode(&bech32m_encode(MAINNET_HRP, &[0; 33])).is_err());
+        assert!(AddressV2::decode(&bech32m_encode("bc", &[1; 33])).is_err());
+
+        let legacy = legacy_address(&hash);
+        assert_eq!(to_v2(&legacy, Network::Mainnet).unwrap(), main);
+        assert_eq!(to_legacy(&main).unwrap(), legacy);
+        assert!(pub_key_hash("not an address").is_err());
+    }
+}
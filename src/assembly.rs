@@ -0,0 +1,191 @@
+//! Block assembly ordering
+//!
+//! The miner picks transactions from the mempool in the order of an
+//! `OrderingStrategy`, optionally capped to a number of transactions per
+//! block, and always within the block weight limit. Transactions left out
+//! stay in the mempool for the next block.
+
+use super::*;
+use crate::systemtx::MAX_SYSTEM_TXS_PER_BLOCK;
+use crate::transaction::Transaction;
+use failure::format_err;
+use std::fmt;
+use std::str::FromStr;
+
+/// OrderingStrategy decides which mempool transactions go first
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OrderingStrategy {
+    /// Transactions in the order the node received them
+    #[default]
+    OldestFirst,
+    /// Highest fee per weight unit first, ties broken by arrival
+    FeePriority,
+}
+
+impl FromStr for OrderingStrategy {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<OrderingStrategy> {
+        match s {
+            "oldest" => Ok(OrderingStrategy::OldestFirst),
+            "fee" => Ok(OrderingStrategy::FeePriority),
+            _ => Err(format_err!(
+                "unknown tx ordering {}, expected oldest or fee",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OrderingStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OrderingStrategy::OldestFirst => write!(f, "oldest"),
+            OrderingStrategy::FeePriority => write!(f, "fee"),
+        }
+    }
+}
+
+/// Candidate is a verified mempool transaction
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub tx: Transaction,
+    /// Position in the order the node received its transactions
+    pub arrival: u64,
+    pub fee: i32,
+    pub weight: usize,
+}
+
+/// Select orders the candidates and keeps at most `max_txs` of them, 0 for no limit
+///
+/// Candidates that do not fit into `max_weight` any more are skipped, as are
+/// system transactions beyond `MAX_SYSTEM_TXS_PER_BLOCK`.
+pub fn select(
+    strategy: OrderingStrategy,
+    mut candidates: Vec<Candidate>,
+    max_txs: usize,
+    max_weight: usize,
+) -> Vec<Transaction> {
+    match strategy {
+        OrderingStrategy::OldestFirst => candidates.sort_by_key(|c| c.arrival),
+        OrderingStrategy::FeePriority => candidates.sort_by(|a, b| {
+            let rate_a = a.fee as i64 * b.weight.max(1) as i64;
+            let rate_b = b.fee as i64 * a.weight.max(1) as i64;
+            rate_b.cmp(&rate_a).then(a.arrival.cmp(&b.arrival))
+        }),
+    }
+    let mut weight = 0;
+    let mut system_txs = 0;
+    let mut selected = Vec::new();
+    for c in candidates {
+        if max_txs > 0 && selected.len() == max_txs {
+            break;
+        }
+        if weight + c.weight > max_weight {
+            continue;
+        }
+        if c.tx.is_system() {
+            if system_txs == MAX_SYSTEM_TXS_PER_BLOCK {
+                continue;
+            }
+            system_txs += 1;
+        }
+        weight += c.weight;
+        selected.push(c.tx);
+    }
+    selected
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::systemtx::{SystemTxEnvelope, KIND_CHECKPOINT};
+
+    fn candidate(arrival: u64, fee: i32) -> Candidate {
+        let mut tx = Transaction::new_coinbase(
+            String::from("3K2B53BeZoaSPBGxihwPkbjNxrLkapfYrn"),
+            format!("tx {}", arrival),
+        )
+        .unwrap();
+        tx.id = format!("tx{}", arrival);
+        Candidate {
+            tx,
+            arrival,
+            fee,
+            weight: 100,
+        }
+    }
+
+    fn ids(txs: Vec<Transaction>) -> Vec<String> {
+        txs.into_iter().map(|tx| tx.id).collect()
+    }
+
+    #[test]
+    fn test_select() {
+        let candidates = vec![candidate(2, 5), candidate(0, 1), candidate(1, 5)];
+        assert_eq!(
+            ids(select(
+                OrderingStrategy::OldestFirst,
+                candidates.clone(),
+                0,
+                1000
+            )),
+            vec!["tx0", "tx1", "tx2"]
+        );
+        assert_eq!(
+            ids(select(
+                OrderingStrategy::FeePriority,
+                candidates.clone(),
+                0,
+                1000
+            )),
+            vec!["tx1", "tx2", "tx0"]
+        );
+        assert_eq!(
+            ids(select(
+                OrderingStrategy::FeePriority,
+                candidates.clone(),
+                1,
+                1000
+            )),
+            vec!["tx1"]
+        );
+
+        // fees are compared per weight unit and heavy txs make room for light ones
+        let mut heavy = candidates.clone();
+        heavy[2].weight = 250;
+        assert_eq!(
+            ids(select(
+                OrderingStrategy::FeePriority,
+                heavy.clone(),
+                0,
+                1000
+            )),
+            vec!["tx2", "tx1", "tx0"]
+        );
+        assert_eq!(
+            ids(select(OrderingStrategy::FeePriority, heavy, 0, 200)),
+            vec!["tx2", "tx0"]
+        );
+
+        // system transactions beyond the block limit wait for the next block
+        let mut system = candidates.clone();
+        for (i, c) in system.iter_mut().enumerate().skip(1) {
+            c.tx = SystemTxEnvelope::new(KIND_CHECKPOINT, &i)
+                .unwrap()
+                .into_transaction()
+                .unwrap();
+        }
+        assert_eq!(
+            select(OrderingStrategy::OldestFirst, system, 0, 1000).len(),
+            1 + MAX_SYSTEM_TXS_PER_BLOCK
+        );
+
+        assert_eq!(
+            "fee".parse::<OrderingStrategy>().unwrap(),
+            OrderingStrategy::FeePriority
+        );
+        assert_eq!(OrderingStrategy::default().to_string(), "oldest");
+        assert!("random".parse::<OrderingStrategy>().is_err());
+    }
+}
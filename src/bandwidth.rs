@@ -0,0 +1,215 @@
+//! Bandwidth and flood limits of the node server
+//!
+//! Message bytes are metered with token buckets, one per peer and
+//! direction plus a global one per direction. A bucket holds at most one
+//! second worth of bytes. A message larger than the tokens left is not
+//! dropped but delayed until the bucket refills, so a peer sending or
+//! requesting too much is slowed down instead of losing blocks.
+//!
+//! Independently, every peer may send each message type only at the rate
+//! of the `message_rate` schedule. Messages beyond it are floods and are
+//! dropped.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// TokenBucket meters bytes at `rate` bytes per second
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    /// Bytes that may pass now, negative while messages are delayed
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            updated: now,
+        }
+    }
+
+    /// Take takes `bytes` and returns how long to wait before sending them
+    pub fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.updated = now.max(self.updated);
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+/// MessageRate returns how many messages of a command a peer may send per second
+pub fn message_rate(command: &str) -> u64 {
+    match command {
+        "version" | "getstate" | "getheaders" | "getblocks" => 5,
+        "addr" | "ping" | "pong" => 10,
+        // every chunk is cut out of the whole UTXO set
+        "getchunk" => 20,
+        "inv" | "block" | "headers" => 100,
+        "tx" | "getdata" | "statechunk" => 200,
+        _ => 50,
+    }
+}
+
+/// FloodLimiter counts the messages of every peer by command
+#[derive(Debug, Default)]
+pub struct FloodLimiter {
+    buckets: HashMap<(String, String), TokenBucket>,
+}
+
+impl FloodLimiter {
+    pub fn new() -> FloodLimiter {
+        FloodLimiter::default()
+    }
+
+    /// Allow counts a message and reports whether it is within the rate of its command
+    pub fn allow(&mut self, peer: &str, command: &str, now: Instant) -> bool {
+        let bucket = self
+            .buckets
+            .entry((peer.to_string(), command.to_string()))
+            .or_insert_with(|| TokenBucket::new(message_rate(command), now));
+        bucket.take(1, now) == Duration::ZERO
+    }
+
+    /// RemovePeer forgets the counts of a peer
+    pub fn remove_peer(&mut self, peer: &str) {
+        self.buckets.retain(|(p, _), _| p != peer);
+    }
+}
+
+/// Direction of a metered message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Ingress,
+    Egress,
+}
+
+/// BandwidthLimiter holds the buckets of every peer, 0 disables a limit
+#[derive(Debug, Default)]
+pub struct BandwidthLimiter {
+    peer_rate: u64,
+    global_rate: u64,
+    peers: HashMap<(String, Direction), TokenBucket>,
+    global: HashMap<Direction, TokenBucket>,
+}
+
+impl BandwidthLimiter {
+    /// NewBandwidthLimiter limits every peer to `peer_rate` and all peers to `global_rate` bytes per second
+    pub fn new(peer_rate: u64, global_rate: u64) -> BandwidthLimiter {
+        BandwidthLimiter {
+            peer_rate,
+            global_rate,
+            ..Default::default()
+        }
+    }
+
+    pub fn peer_rate(&self) -> u64 {
+        self.peer_rate
+    }
+
+    pub fn global_rate(&self) -> u64 {
+        self.global_rate
+    }
+
+    /// Delay meters a message and returns how long to hold it back
+    pub fn delay(
+        &mut self,
+        peer: &str,
+        direction: Direction,
+        bytes: usize,
+        now: Instant,
+    ) -> Duration {
+        let mut delay = Duration::ZERO;
+        if self.peer_rate > 0 {
+            let rate = self.peer_rate;
+            let bucket = self
+                .peers
+                .entry((peer.to_string(), direction))
+                .or_insert_with(|| TokenBucket::new(rate, now));
+            delay = delay.max(bucket.take(bytes, now));
+        }
+        if self.global_rate > 0 {
+            let rate = self.global_rate;
+            let bucket = self
+                .global
+                .entry(direction)
+                .or_insert_with(|| TokenBucket::new(rate, now));
+            delay = delay.max(bucket.take(bytes, now));
+        }
+        delay
+    }
+
+    /// RemovePeer forgets the buckets of a peer
+    pub fn remove_peer(&mut self, peer: &str) {
+        self.peers.retain(|(p, _), _| p != peer);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bandwidth_limiter() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        assert_eq!(bucket.take(600, start), Duration::ZERO);
+        assert_eq!(bucket.take(900, start), Duration::from_millis(500));
+        // refilled by 500 bytes after half a second, the burst stays at one second
+        assert_eq!(
+            bucket.take(0, start + Duration::from_millis(500)),
+            Duration::ZERO
+        );
+        assert_eq!(
+            bucket.take(1500, start + Duration::from_secs(10)),
+            Duration::from_millis(500)
+        );
+
+        let mut limiter = BandwidthLimiter::new(1000, 1500);
+        assert_eq!(
+            limiter.delay("a:1", Direction::Ingress, 1000, start),
+            Duration::ZERO
+        );
+        assert_eq!(
+            limiter.delay("a:1", Direction::Egress, 1000, start),
+            Duration::ZERO
+        );
+        // b is within its own limit but the global limit is used up
+        assert_eq!(
+            limiter
+                .delay("b:1", Direction::Ingress, 1000, start)
+                .as_millis(),
+            333
+        );
+        assert_eq!(
+            limiter
+                .delay("a:1", Direction::Ingress, 500, start)
+                .as_millis(),
+            666
+        );
+        limiter.remove_peer("a:1");
+        assert!(limiter.peers.keys().all(|(p, _)| p != "a:1"));
+
+        let mut unlimited = BandwidthLimiter::default();
+        assert_eq!(
+            unlimited.delay("a:1", Direction::Egress, usize::MAX, start),
+            Duration::ZERO
+        );
+
+        let mut floods = FloodLimiter::new();
+        for _ in 0..message_rate("version") {
+            assert!(floods.allow("a:1", "version", start));
+        }
+        assert!(!floods.allow("a:1", "version", start));
+        assert!(floods.allow("a:1", "inv", start));
+        assert!(floods.allow("b:1", "version", start));
+        assert!(floods.allow("a:1", "version", start + Duration::from_secs(2)));
+    }
+}
@@ -1,16 +1,29 @@
 //! Block implement of blockchain
 
 use super::*;
+use crate::systemtx::{system_tx_count, MAX_SYSTEM_TXS_PER_BLOCK};
 use crate::transaction::Transaction;
 use bincode::serialize;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use failure::format_err;
 use merkle_cbt::merkle_tree::Merge;
-use merkle_cbt::merkle_tree::CBMT;
+use merkle_cbt::merkle_tree::{MerkleProof, CBMT};
 use serde::{Deserialize, Serialize};
 use std::time::SystemTime;
 
-const TARGET_HEXS: usize = 4;
+/// Number of leading zero hex digits of the genesis block hash
+pub const TARGET_HEXS: usize = 4;
+/// Fewest leading zero bits of a block hash, the target of the genesis block
+pub const MIN_TARGET_BITS: u32 = TARGET_HEXS as u32 * 4;
+/// Blocks between two difficulty retargets
+pub const RETARGET_INTERVAL: i32 = 144;
+/// Time between two blocks the retarget aims for, in milliseconds
+pub const TARGET_BLOCK_TIME_MS: u128 = 60_000;
+/// Bits of a block hash, the unreachable upper bound of a target
+const HASH_BITS: u32 = 256;
+/// Most weight of the transactions of a block, see `Transaction::weight`
+pub const MAX_BLOCK_WEIGHT: usize = 4_000_000;
 
 /// Block keeps block headers
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -21,6 +34,142 @@ pub struct Block {
     hash: String,
     nonce: i32,
     height: i32,
+    /// leading zero bits the hash must have, see `retarget`
+    target: u32,
+    /// chain the block extends, see `Blockchain::chain_id`, empty for a
+    /// genesis block which defines the chain
+    chain_id: String,
+}
+
+/// BlockHeader is a block without its transactions, used by headers-first sync
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockHeader {
+    pub timestamp: u128,
+    pub prev_block_hash: String,
+    pub merkle_root: Vec<u8>,
+    pub hash: String,
+    pub nonce: i32,
+    pub height: i32,
+    pub target: u32,
+    pub chain_id: String,
+}
+
+/// BlockTemplate is a block waiting for its proof of work
+///
+/// External miners search a nonce such that the SHA-256 of `pow_prefix`
+/// followed by the little endian nonce starts with `target` zero bits.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockTemplate {
+    pub transactions: Vec<Transaction>,
+    pub prev_block_hash: String,
+    pub height: i32,
+    pub timestamp: u128,
+    pub target: u32,
+    pub chain_id: String,
+}
+
+impl BlockTemplate {
+    /// NewBlockTemplate creates a template timestamped now
+    pub fn new(
+        transactions: Vec<Transaction>,
+        prev_block_hash: String,
+        chain_id: &str,
+        height: i32,
+        target: u32,
+    ) -> Result<BlockTemplate> {
+        Ok(BlockTemplate {
+            transactions,
+            prev_block_hash,
+            height,
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_millis(),
+            target,
+            chain_id: chain_id.to_string(),
+        })
+    }
+
+    /// PowPrefix returns the hashed header bytes before the nonce
+    pub fn pow_prefix(&self) -> Result<Vec<u8>> {
+        let block = self.to_block(0);
+        let mut data = block.prepare_hash_data()?;
+        data.truncate(data.len() - std::mem::size_of::<i32>());
+        Ok(data)
+    }
+
+    pub fn merkle_root(&self) -> Result<Vec<u8>> {
+        self.to_block(0).hash_transactions()
+    }
+
+    /// Solve returns the block for a nonce meeting the target
+    pub fn solve(&self, nonce: i32) -> Result<Block> {
+        let mut block = self.to_block(nonce);
+        if !block.validate()? {
+            return Err(format_err!("nonce {} does not meet the target", nonce));
+        }
+        let mut hasher = Sha256::new();
+        hasher.input(&block.prepare_hash_data()?);
+        block.hash = hasher.result_str();
+        Ok(block)
+    }
+
+    fn to_block(&self, nonce: i32) -> Block {
+        Block {
+            timestamp: self.timestamp,
+            transactions: self.transactions.clone(),
+            prev_block_hash: self.prev_block_hash.clone(),
+            hash: String::new(),
+            nonce,
+            height: self.height,
+            target: self.target,
+            chain_id: self.chain_id.clone(),
+        }
+    }
+}
+
+/// OrderingProof proves the position of a transaction within a block
+///
+/// Every merkle leaf commits to the position of its transaction, so a proof against the merkle root of a header
+/// fixes the position too.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OrderingProof {
+    pub txid: String,
+    pub position: u32,
+    /// Index of the leaf in the merkle tree
+    pub index: u32,
+    pub lemmas: Vec<Vec<u8>>,
+}
+
+impl OrderingProof {
+    /// Verify checks the proof against the merkle root of a header
+    pub fn verify(&self, header: &BlockHeader) -> bool {
+        let proof = MerkleProof::<Vec<u8>, MergeVu8>::new(vec![self.index], self.lemmas.clone());
+        proof.verify(
+            &header.merkle_root,
+            &[merkle_leaf(self.position, &self.txid)],
+        )
+    }
+}
+
+impl BlockHeader {
+    /// Validate checks that the header hash is correct and meets the PoW target
+    ///
+    /// Only the floor of the target is checked, whether it is the target
+    /// the chain asks for at this height is up to `Blockchain::check_header`.
+    pub fn validate(&self) -> Result<bool> {
+        let data = hash_data(
+            &self.prev_block_hash,
+            &self.merkle_root,
+            self.timestamp,
+            self.target,
+            &self.chain_id,
+            self.nonce,
+        )?;
+        let mut hasher = Sha256::new();
+        hasher.input(&data[..]);
+        let hash = hasher.result_str();
+        Ok(hash == self.hash && self.target >= MIN_TARGET_BITS && meets_target(&hash, self.target))
+    }
 }
 
 impl Block {
@@ -40,15 +189,108 @@ impl Block {
         self.height
     }
 
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    pub fn get_target(&self) -> u32 {
+        self.target
+    }
+
+    pub fn get_chain_id(&self) -> &str {
+        &self.chain_id
+    }
+
+    /// Verify checks that the hash commits to the transactions in their order
+    /// and meets the PoW target, and that every transaction id is its hash
+    pub fn verify(&self) -> Result<bool> {
+        for tx in &self.transactions {
+            if tx.hash()? != tx.id {
+                return Ok(false);
+            }
+        }
+        if self.weight()? > MAX_BLOCK_WEIGHT
+            || system_tx_count(&self.transactions) > MAX_SYSTEM_TXS_PER_BLOCK
+        {
+            return Ok(false);
+        }
+        self.header()?.validate()
+    }
+
+    /// Weight returns the total weight of the transactions
+    pub fn weight(&self) -> Result<usize> {
+        let mut weight = 0;
+        for tx in &self.transactions {
+            weight += tx.weight()?;
+        }
+        Ok(weight)
+    }
+
+    /// OrderingProof returns the proof of the position of a transaction in the block
+    pub fn ordering_proof(&self, txid: &str) -> Result<Option<OrderingProof>> {
+        let position = match self.transactions.iter().position(|tx| tx.id == txid) {
+            Some(p) => p as u32,
+            None => return Ok(None),
+        };
+        let proof = match CBMT::<Vec<u8>, MergeVu8>::build_merkle_proof(
+            &self.merkle_leaves()?,
+            &[position],
+        ) {
+            Some(p) => p,
+            None => return Err(format_err!("cannot build the merkle proof of {}", txid)),
+        };
+        Ok(Some(OrderingProof {
+            txid: txid.to_string(),
+            position,
+            index: proof.indices()[0],
+            lemmas: proof.lemmas().to_vec(),
+        }))
+    }
+
+    /// Header returns the header of the block
+    pub fn header(&self) -> Result<BlockHeader> {
+        Ok(BlockHeader {
+            timestamp: self.timestamp,
+            prev_block_hash: self.prev_block_hash.clone(),
+            merkle_root: self.hash_transactions()?,
+            hash: self.hash.clone(),
+            nonce: self.nonce,
+            height: self.height,
+            target: self.target,
+            chain_id: self.chain_id.clone(),
+        })
+    }
+
     /// NewBlock creates and returns Block
     pub fn new_block(
         transactions: Vec<Transaction>,
         prev_block_hash: String,
+        chain_id: &str,
         height: i32,
+        target: u32,
     ) -> Result<Block> {
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_millis();
+        Block::new_block_at(
+            transactions,
+            prev_block_hash,
+            chain_id,
+            height,
+            timestamp,
+            target,
+        )
+    }
+
+    /// NewBlockAt creates and returns a Block with a given timestamp
+    pub fn new_block_at(
+        transactions: Vec<Transaction>,
+        prev_block_hash: String,
+        chain_id: &str,
+        height: i32,
+        timestamp: u128,
+        target: u32,
+    ) -> Result<Block> {
         let mut block = Block {
             timestamp,
             transactions,
@@ -56,6 +298,8 @@ impl Block {
             hash: String::new(),
             nonce: 0,
             height,
+            target,
+            chain_id: chain_id.to_string(),
         };
         block.run_proof_of_work()?;
         Ok(block)
@@ -63,43 +307,54 @@ impl Block {
 
     /// NewGenesisBlock creates and returns genesis Block
     pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new_block(vec![coinbase], String::new(), 0).unwrap()
+        Block::new_block(vec![coinbase], String::new(), "", 0, MIN_TARGET_BITS).unwrap()
     }
 
     /// Run performs a proof-of-work
+    ///
+    /// The nonce is encoded last, so only its bytes change between attempts.
     fn run_proof_of_work(&mut self) -> Result<()> {
         info!("Mining the block");
-        while !self.validate()? {
+        let mut data = self.prepare_hash_data()?;
+        let prefix = data.len() - std::mem::size_of::<i32>();
+        loop {
+            data.truncate(prefix);
+            data.extend_from_slice(&self.nonce.to_le_bytes());
+            let mut hasher = Sha256::new();
+            hasher.input(&data[..]);
+            let hash = hasher.result_str();
+            if meets_target(&hash, self.target) {
+                self.hash = hash;
+                return Ok(());
+            }
             self.nonce += 1;
         }
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
-        Ok(())
     }
 
-    /// HashTransactions returns a hash of the transactions in the block
+    /// HashTransactions returns the merkle root of the transactions in their order
     fn hash_transactions(&self) -> Result<Vec<u8>> {
-        let mut transactions = Vec::new();
-        for tx in &self.transactions {
-            transactions.push(tx.hash()?.as_bytes().to_owned());
-        }
-        let tree = CBMT::<Vec<u8>, MergeVu8>::build_merkle_tree(transactions);
+        let tree = CBMT::<Vec<u8>, MergeVu8>::build_merkle_tree(self.merkle_leaves()?);
 
         Ok(tree.root())
     }
 
+    fn merkle_leaves(&self) -> Result<Vec<Vec<u8>>> {
+        let mut leaves = Vec::new();
+        for (position, tx) in self.transactions.iter().enumerate() {
+            leaves.push(merkle_leaf(position as u32, &tx.hash()?));
+        }
+        Ok(leaves)
+    }
+
     fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        let content = (
-            self.prev_block_hash.clone(),
-            self.hash_transactions()?,
+        hash_data(
+            &self.prev_block_hash,
+            &self.hash_transactions()?,
             self.timestamp,
-            TARGET_HEXS,
+            self.target,
+            &self.chain_id,
             self.nonce,
-        );
-        let bytes = serialize(&content)?;
-        Ok(bytes)
+        )
     }
 
     /// Validate validates block's PoW
@@ -107,13 +362,73 @@ impl Block {
         let data = self.prepare_hash_data()?;
         let mut hasher = Sha256::new();
         hasher.input(&data[..]);
-        let mut vec1: Vec<u8> = Vec::new();
-        vec1.resize(TARGET_HEXS, '0' as u8);
-        Ok(&hasher.result_str()[0..TARGET_HEXS] == String::from_utf8(vec1)?)
+        Ok(meets_target(&hasher.result_str(), self.target))
+    }
+}
+
+/// hash_data encodes the hashed header fields, the nonce last
+fn hash_data(
+    prev_block_hash: &str,
+    merkle_root: &[u8],
+    timestamp: u128,
+    target: u32,
+    chain_id: &str,
+    nonce: i32,
+) -> Result<Vec<u8>> {
+    let content = (
+        prev_block_hash.to_string(),
+        merkle_root.to_vec(),
+        timestamp,
+        target,
+        chain_id.to_string(),
+        nonce,
+    );
+    let bytes = serialize(&content)?;
+    Ok(bytes)
+}
+
+/// MeetsTarget reports whether a hex hash starts with `target` zero bits
+pub fn meets_target(hash: &str, target: u32) -> bool {
+    let mut zeros = 0;
+    for c in hash.chars() {
+        match c.to_digit(16) {
+            Some(0) => zeros += 4,
+            Some(d) => {
+                zeros += d.leading_zeros() - 28;
+                break;
+            }
+            None => return false,
+        }
+    }
+    zeros >= target
+}
+
+/// Retarget returns the target following an epoch of `RETARGET_INTERVAL`
+/// blocks that took `timespan_ms` from its first to its last block
+///
+/// The target gains a bit, halving the chance of a hash, when the epoch was
+/// more than twice as fast as `TARGET_BLOCK_TIME_MS` asks, and loses one
+/// when it was more than twice as slow. It never drops below `MIN_TARGET_BITS`.
+pub fn retarget(target: u32, timespan_ms: u128) -> u32 {
+    let expected = TARGET_BLOCK_TIME_MS * (RETARGET_INTERVAL - 1) as u128;
+    if timespan_ms < expected / 2 {
+        (target + 1).min(HASH_BITS)
+    } else if timespan_ms > expected * 2 {
+        target.saturating_sub(1).max(MIN_TARGET_BITS)
+    } else {
+        target
     }
 }
 
-struct MergeVu8 {}
+/// merkle_leaf commits to a transaction hash and its position in the block
+fn merkle_leaf(position: u32, tx_hash: &str) -> Vec<u8> {
+    let mut leaf = position.to_be_bytes().to_vec();
+    leaf.extend_from_slice(tx_hash.as_bytes());
+    leaf
+}
+
+/// MergeVu8 merges two SHA-256 merkle nodes
+pub struct MergeVu8 {}
 
 impl Merge for MergeVu8 {
     type Item = Vec<u8>;
@@ -127,3 +442,100 @@ impl Merge for MergeVu8 {
         re.to_vec()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ordering_proof() {
+        let txs: Vec<Transaction> = (0..3)
+            .map(|i| {
+                Transaction::new_coinbase(
+                    String::from("3K2B53BeZoaSPBGxihwPkbjNxrLkapfYrn"),
+                    format!("tx{}", i),
+                )
+                .unwrap()
+            })
+            .collect();
+        let block = Block::new_block(txs.clone(), String::new(), "", 1, MIN_TARGET_BITS).unwrap();
+        assert!(block.verify().unwrap());
+        let header = block.header().unwrap();
+
+        let proof = block.ordering_proof(&txs[2].id).unwrap().unwrap();
+        assert_eq!(proof.position, 2);
+        assert!(proof.verify(&header));
+        let moved = OrderingProof {
+            position: 1,
+            ..proof.clone()
+        };
+        assert!(!moved.verify(&header));
+        assert!(block.ordering_proof("unknown").unwrap().is_none());
+
+        // the same transactions in another order have another root
+        let mut reordered = block.clone();
+        reordered.transactions.swap(0, 2);
+        assert!(!reordered.verify().unwrap());
+        assert_ne!(reordered.header().unwrap().merkle_root, header.merkle_root);
+
+        // the bare transaction hashes are no valid leaves
+        let hashes = txs
+            .iter()
+            .map(|tx| tx.hash().unwrap().into_bytes())
+            .collect();
+        assert_ne!(
+            header.merkle_root,
+            CBMT::<Vec<u8>, MergeVu8>::build_merkle_tree(hashes).root()
+        );
+    }
+
+    #[test]
+    fn test_block_template() {
+        let txs = vec![Transaction::new_coinbase(
+            String::from("3K2B53BeZoaSPBGxihwPkbjNxrLkapfYrn"),
+            String::from("tx"),
+        )
+        .unwrap()];
+        let template = BlockTemplate::new(txs, String::new(), "", 0, MIN_TARGET_BITS + 1).unwrap();
+        let prefix = template.pow_prefix().unwrap();
+        // mine like an external miner that only knows the prefix
+        let meets = |nonce: i32| {
+            let mut hasher = Sha256::new();
+            hasher.input(&prefix);
+            hasher.input(&nonce.to_le_bytes());
+            meets_target(&hasher.result_str(), template.target)
+        };
+        let nonce = (0..).find(|n| meets(*n)).unwrap();
+        let block = template.solve(nonce).unwrap();
+        assert!(block.verify().unwrap());
+        assert_eq!(
+            block.header().unwrap().merkle_root,
+            template.merkle_root().unwrap()
+        );
+        let miss = (0..).find(|n| !meets(*n)).unwrap();
+        assert!(template.solve(miss).is_err());
+
+        // the target is part of the header and has a floor
+        let mut header = block.header().unwrap();
+        header.target = MIN_TARGET_BITS;
+        assert!(!header.validate().unwrap());
+        let easy = BlockTemplate::new(vec![], String::new(), "", 0, MIN_TARGET_BITS - 4).unwrap();
+        let nonce = (0..).find(|n| easy.solve(*n).is_ok()).unwrap();
+        assert!(!easy.solve(nonce).unwrap().verify().unwrap());
+    }
+
+    #[test]
+    fn test_retarget() {
+        assert!(meets_target("0000ffff", 16));
+        assert!(meets_target("00001fff", 19));
+        assert!(!meets_target("00001fff", 20));
+        assert!(!meets_target("zz", 0));
+
+        let expected = TARGET_BLOCK_TIME_MS * (RETARGET_INTERVAL - 1) as u128;
+        assert_eq!(retarget(20, expected), 20);
+        assert_eq!(retarget(20, expected / 3), 21);
+        assert_eq!(retarget(20, expected * 3), 19);
+        assert_eq!(retarget(MIN_TARGET_BITS, expected * 3), MIN_TARGET_BITS);
+        assert_eq!(retarget(HASH_BITS, 0), HASH_BITS);
+    }
+}
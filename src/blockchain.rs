@@ -2,20 +2,32 @@
 
 use super::*;
 use crate::block::*;
+use crate::fees::*;
+use crate::genesis::{chain_id_of, GenesisConfig};
+use crate::signer::*;
+use crate::systemtx::*;
 use crate::transaction::*;
 use bincode::{deserialize, serialize};
 use failure::format_err;
-use sled;
 use std::collections::HashMap;
+use std::time::SystemTime;
 
 const GENESIS_COINBASE_DATA: &str =
     "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+/// Blocks whose median timestamp a new block must be later than
+pub const MEDIAN_TIME_BLOCKS: usize = 11;
+/// Most a block timestamp may be ahead of the local clock, in milliseconds
+pub const MAX_FUTURE_BLOCK_TIME_MS: u128 = 2 * 60 * 60 * 1000;
+/// Format of the block database, databases of another format are refused
+const DB_FORMAT: &str = "2";
 
 /// Blockchain implements interactions with a DB
 #[derive(Debug)]
 pub struct Blockchain {
     pub tip: String,
     pub db: sled::Db,
+    /// validators of the system transaction kinds this node accepts
+    pub system_txs: SystemTxRegistry,
 }
 
 /// BlockchainIterator is used to iterate over blockchain blocks
@@ -29,7 +41,11 @@ impl Blockchain {
     pub fn new() -> Result<Blockchain> {
         info!("open blockchain");
 
-        let db = sled::open("data/blocks")?;
+        Blockchain::open(sled::open("data/blocks")?)
+    }
+
+    /// open loads the blockchain of `db`, refusing databases of another format
+    fn open(db: sled::Db) -> Result<Blockchain> {
         let hash = match db.get("LAST")? {
             Some(l) => l.to_vec(),
             None => Vec::new(),
@@ -40,7 +56,17 @@ impl Blockchain {
         } else {
             String::from_utf8(hash.to_vec())?
         };
-        Ok(Blockchain { tip: lasthash, db })
+        let format = db.get("FORMAT")?;
+        if !lasthash.is_empty() && format.as_deref() != Some(DB_FORMAT.as_bytes()) {
+            return Err(format_err!(
+                "the block database predates the current block format, create the blockchain again"
+            ));
+        }
+        Ok(Blockchain {
+            tip: lasthash,
+            db,
+            system_txs: SystemTxRegistry::new(),
+        })
     }
 
     /// CreateBlockchain creates a new blockchain DB
@@ -52,12 +78,91 @@ impl Blockchain {
         debug!("Creating new block database");
         let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
         let genesis: Block = Block::new_genesis_block(cbtx);
+        Blockchain::store_genesis(db, genesis)
+    }
+
+    /// CreateBlockchainFromGenesis creates a new blockchain DB for a network
+    ///
+    /// The genesis block is the same on every node using the same config.
+    pub fn create_from_genesis(config: &GenesisConfig) -> Result<Blockchain> {
+        config.validate()?;
+        info!(
+            "Creating new blockchain {} from genesis {}",
+            config.chain_id,
+            config.hash()?
+        );
+
+        std::fs::remove_dir_all("data/blocks").ok();
+        let db = sled::open("data/blocks")?;
+        let genesis = Block::new_block_at(
+            vec![config.coinbase()?],
+            String::new(),
+            "",
+            0,
+            config.timestamp,
+            MIN_TARGET_BITS,
+        )?;
+        db.insert("GENESIS", serialize(config)?)?;
+        Blockchain::store_genesis(db, genesis)
+    }
+
+    /// GenesisConfig returns the config the chain was created from, if any
+    pub fn genesis_config(&self) -> Result<Option<GenesisConfig>> {
+        match self.db.get("GENESIS")? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// ChainId returns the chain id committed by the genesis block
+    ///
+    /// Chains created without a genesis file are identified by the hash of
+    /// their genesis block. The id is unknown, an error, while the genesis
+    /// block is not stored.
+    pub fn chain_id(&self) -> Result<String> {
+        if let Some(id) = self.db.get("CHAIN_ID")? {
+            return Ok(String::from_utf8(id.to_vec())?);
+        }
+        let genesis = match self.iter().last() {
+            Some(b) if b.get_prev_hash().is_empty() => b,
+            _ => {
+                return Err(format_err!(
+                    "the chain id is unknown without the genesis block"
+                ))
+            }
+        };
+        let id = genesis_chain_id(&genesis);
+        self.db.insert("CHAIN_ID", id.as_bytes())?;
+        Ok(id)
+    }
+
+    /// Temporary creates a blockchain of `genesis` kept in memory, for tests
+    #[cfg(test)]
+    pub fn temporary(genesis: &Block) -> Result<Blockchain> {
+        let db = sled::Config::new().temporary(true).open()?;
+        db.insert("FORMAT", DB_FORMAT.as_bytes())?;
+        db.insert(genesis.get_hash(), serialize(genesis)?)?;
+        db.insert("LAST", genesis.get_hash().as_bytes())?;
+        let bc = Blockchain {
+            tip: genesis.get_hash(),
+            db,
+            system_txs: SystemTxRegistry::new(),
+        };
+        bc.index_heights()?;
+        Ok(bc)
+    }
+
+    fn store_genesis(db: sled::Db, genesis: Block) -> Result<Blockchain> {
+        db.insert("FORMAT", DB_FORMAT.as_bytes())?;
+        db.insert("CHAIN_ID", genesis_chain_id(&genesis).as_bytes())?;
         db.insert(genesis.get_hash(), serialize(&genesis)?)?;
         db.insert("LAST", genesis.get_hash().as_bytes())?;
         let bc = Blockchain {
             tip: genesis.get_hash(),
             db,
+            system_txs: SystemTxRegistry::new(),
         };
+        bc.index_heights()?;
         bc.db.flush()?;
         Ok(bc)
     }
@@ -66,32 +171,52 @@ impl Blockchain {
     pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
         info!("mine a new block");
 
+        let mut weight = 0;
         for tx in &transactions {
             if !self.verify_transacton(tx)? {
                 return Err(format_err!("ERROR: Invalid transaction"));
             }
+            weight += tx.weight()?;
+        }
+        if system_tx_count(&transactions) > MAX_SYSTEM_TXS_PER_BLOCK {
+            return Err(format_err!(
+                "a block carries at most {} system transactions",
+                MAX_SYSTEM_TXS_PER_BLOCK
+            ));
         }
+        if weight > MAX_BLOCK_WEIGHT {
+            return Err(format_err!(
+                "block weight {} exceeds {}",
+                weight,
+                MAX_BLOCK_WEIGHT
+            ));
+        }
+        self.check_coinbase(&transactions)?;
 
-        let lasthash = self.db.get("LAST")?.unwrap();
+        let lasthash = String::from_utf8(self.db.get("LAST")?.unwrap().to_vec())?;
+        let target = self.next_target(&lasthash)?;
 
         let newblock = Block::new_block(
             transactions,
-            String::from_utf8(lasthash.to_vec())?,
+            lasthash,
+            &self.chain_id()?,
             self.get_best_height()? + 1,
+            target,
         )?;
         self.db.insert(newblock.get_hash(), serialize(&newblock)?)?;
         self.db.insert("LAST", newblock.get_hash().as_bytes())?;
+        self.tip = newblock.get_hash();
+        self.index_heights()?;
         self.db.flush()?;
 
-        self.tip = newblock.get_hash();
         Ok(newblock)
     }
 
     /// Iterator returns a BlockchainIterat
-    pub fn iter(&self) -> BlockchainIterator {
+    pub fn iter(&self) -> BlockchainIterator<'_> {
         BlockchainIterator {
             current_hash: self.tip.clone(),
-            bc: &self,
+            bc: self,
         }
     }
 
@@ -124,7 +249,7 @@ impl Blockchain {
                     }
                 }
 
-                if !tx.is_coinbase() {
+                if !tx.is_coinbase() && !tx.is_system() {
                     for i in &tx.vin {
                         match spend_txos.get_mut(&i.txid) {
                             Some(v) => {
@@ -163,10 +288,77 @@ impl Blockchain {
         Ok(prev_TXs)
     }
 
+    /// GetFee returns the inputs of a transaction minus its outputs
+    pub fn get_fee(&self, tx: &Transaction) -> Result<i32> {
+        if tx.is_coinbase() || tx.is_system() {
+            return Ok(0);
+        }
+        let prev_TXs = self.get_prev_TXs(tx)?;
+        let mut fee = 0;
+        for vin in &tx.vin {
+            match prev_TXs[&vin.txid].vout.get(vin.vout as usize) {
+                Some(out) => fee += out.value,
+                None => return Err(format_err!("tx {} spends a missing output", tx.id)),
+            }
+        }
+        Ok(fee - tx.vout.iter().map(|out| out.value).sum::<i32>())
+    }
+
+    /// TotalFees returns the fees of `txs`, what their coinbase may claim on top of `SUBSIDY`
+    pub fn total_fees(&self, txs: &[Transaction]) -> Result<i32> {
+        let mut fees: i32 = 0;
+        for tx in txs {
+            fees = fees
+                .checked_add(self.get_fee(tx)?)
+                .ok_or_else(|| format_err!("the fees of the block overflow"))?;
+        }
+        Ok(fees)
+    }
+
+    /// CheckCoinbase checks that a block of `txs` has one coinbase paying
+    /// at most the subsidy and the fees of the other transactions
+    fn check_coinbase(&self, txs: &[Transaction]) -> Result<()> {
+        let coinbases: Vec<&Transaction> = txs.iter().filter(|tx| tx.is_coinbase()).collect();
+        if coinbases.len() != 1 {
+            return Err(format_err!(
+                "a block has one coinbase, not {}",
+                coinbases.len()
+            ));
+        }
+        let reward: i64 = coinbases[0].vout.iter().map(|out| out.value as i64).sum();
+        let allowed = SUBSIDY as i64 + self.total_fees(txs)? as i64;
+        if reward > allowed {
+            return Err(format_err!(
+                "coinbase {} pays {}, more than the {} of subsidy and fees",
+                coinbases[0].id,
+                reward,
+                allowed
+            ));
+        }
+        Ok(())
+    }
+
+    /// EstimateFee suggests fees from the last `FEE_ESTIMATE_BLOCKS` blocks
+    pub fn estimate_fee(&self, mempool_size: usize) -> Result<FeeEstimate> {
+        let mut fees = Vec::new();
+        let mut block_txs = Vec::new();
+        for block in self.iter().take(FEE_ESTIMATE_BLOCKS) {
+            let mut count = 0;
+            for tx in block.get_transaction() {
+                if !tx.is_coinbase() && !tx.is_system() {
+                    fees.push(self.get_fee(tx)?);
+                    count += 1;
+                }
+            }
+            block_txs.push(count);
+        }
+        Ok(FeeEstimate::estimate(&fees, &block_txs, mempool_size))
+    }
+
     /// SignTransaction signs inputs of a Transaction
-    pub fn sign_transacton(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
+    pub fn sign_transacton(&self, tx: &mut Transaction, signer: &dyn Signer) -> Result<()> {
         let prev_TXs = self.get_prev_TXs(tx)?;
-        tx.sign(private_key, prev_TXs)?;
+        tx.sign(signer, prev_TXs, &self.chain_id()?)?;
         Ok(())
     }
 
@@ -175,31 +367,231 @@ impl Blockchain {
         if tx.is_coinbase() {
             return Ok(true);
         }
+        if tx.is_system() {
+            if let Err(e) = self.system_txs.validate(tx, self) {
+                warn!("invalid system transaction {}: {}", tx.id, e);
+                return Ok(false);
+            }
+            return Ok(true);
+        }
         let prev_TXs = self.get_prev_TXs(tx)?;
-        tx.verify(prev_TXs)
+        tx.verify(prev_TXs, &self.chain_id()?)
     }
 
     /// AddBlock saves the block into the blockchain
     pub fn add_block(&mut self, block: Block) -> Result<()> {
         let data = serialize(&block)?;
-        if let Some(_) = self.db.get(block.get_hash())? {
+        if self.db.get(block.get_hash())?.is_some() {
             return Ok(());
         }
+        self.check_header(&block)?;
+        if !block.get_prev_hash().is_empty() {
+            self.check_coinbase(block.get_transaction())?;
+        }
         self.db.insert(block.get_hash(), data)?;
 
         let lastheight = self.get_best_height()?;
         if block.get_height() > lastheight {
             self.db.insert("LAST", block.get_hash().as_bytes())?;
             self.tip = block.get_hash();
+            self.index_heights()?;
             self.db.flush()?;
         }
         Ok(())
     }
 
+    /// NextTarget returns the target of the block following `prev_hash`
+    ///
+    /// The genesis block has `MIN_TARGET_BITS`. The target changes every
+    /// `RETARGET_INTERVAL` blocks by the time the last epoch took, see
+    /// `retarget`, and stays the same in between.
+    pub fn next_target(&self, prev_hash: &str) -> Result<u32> {
+        if prev_hash.is_empty() {
+            return Ok(MIN_TARGET_BITS);
+        }
+        let load = |hash: &str| -> Result<Block> {
+            if !self.has_block(hash)? {
+                return Err(format_err!("block {} is not stored", hash));
+            }
+            self.get_block(hash)
+        };
+        let last = load(prev_hash)?;
+        if (last.get_height() + 1) % RETARGET_INTERVAL != 0 {
+            return Ok(last.get_target());
+        }
+        let mut first = last.clone();
+        for _ in 1..RETARGET_INTERVAL {
+            first = load(&first.get_prev_hash())?;
+        }
+        let timespan = last.get_timestamp().saturating_sub(first.get_timestamp());
+        Ok(retarget(last.get_target(), timespan))
+    }
+
+    /// CheckHeader fails if a block does not fit onto its parent
+    ///
+    /// The parent must be stored and the block one higher, on the same chain
+    /// id and with the target the parent asks for. Its timestamp must be later than the median of
+    /// the last `MEDIAN_TIME_BLOCKS` blocks and at most
+    /// `MAX_FUTURE_BLOCK_TIME_MS` ahead of the local clock.
+    pub fn check_header(&self, block: &Block) -> Result<()> {
+        let prev = block.get_prev_hash();
+        if prev.is_empty() {
+            if block.get_height() != 0
+                || block.get_target() != MIN_TARGET_BITS
+                || !block.get_chain_id().is_empty()
+            {
+                return Err(format_err!(
+                    "genesis block {} has a wrong height, target or chain id",
+                    block.get_hash()
+                ));
+            }
+            return Ok(());
+        }
+        if !self.has_block(&prev)? {
+            return Err(format_err!(
+                "parent {} of block {} is not stored",
+                prev,
+                block.get_hash()
+            ));
+        }
+        let parent = self.get_block(&prev)?;
+        if block.get_height() != parent.get_height() + 1 {
+            return Err(format_err!(
+                "block {} has height {}, expected {}",
+                block.get_hash(),
+                block.get_height(),
+                parent.get_height() + 1
+            ));
+        }
+        let chain_id = self.chain_id()?;
+        if block.get_chain_id() != chain_id {
+            return Err(format_err!(
+                "block {} is on chain '{}', not '{}'",
+                block.get_hash(),
+                block.get_chain_id(),
+                chain_id
+            ));
+        }
+        let target = self.next_target(&prev)?;
+        if block.get_target() != target {
+            return Err(format_err!(
+                "block {} has target {}, expected {}",
+                block.get_hash(),
+                block.get_target(),
+                target
+            ));
+        }
+        if block.get_timestamp() <= self.median_time_past(&parent)? {
+            return Err(format_err!(
+                "block {} is not later than the median time past",
+                block.get_hash()
+            ));
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis();
+        if block.get_timestamp() > now + MAX_FUTURE_BLOCK_TIME_MS {
+            return Err(format_err!(
+                "block {} is too far in the future",
+                block.get_hash()
+            ));
+        }
+        Ok(())
+    }
+
+    /// MedianTimePast returns the median timestamp of `block` and the
+    /// `MEDIAN_TIME_BLOCKS - 1` blocks before it
+    pub fn median_time_past(&self, block: &Block) -> Result<u128> {
+        let mut times = vec![block.get_timestamp()];
+        let mut prev = block.get_prev_hash();
+        while times.len() < MEDIAN_TIME_BLOCKS && !prev.is_empty() && self.has_block(&prev)? {
+            let b = self.get_block(&prev)?;
+            times.push(b.get_timestamp());
+            prev = b.get_prev_hash();
+        }
+        times.sort_unstable();
+        Ok(times[times.len() / 2])
+    }
+
+    /// HasBlock reports whether the block is stored locally
+    pub fn has_block(&self, block_hash: &str) -> Result<bool> {
+        Ok(self.db.contains_key(block_hash)?)
+    }
+
+    /// ReorgDepth returns how many blocks of the chain ending at `old_tip` are
+    /// not ancestors of `new_tip`, None if an ancestor is not stored
+    pub fn reorg_depth(&self, old_tip: &str, new_tip: &str) -> Result<Option<usize>> {
+        let load = |hash: &str| -> Result<Option<Block>> {
+            if hash.is_empty() || !self.has_block(hash)? {
+                return Ok(None);
+            }
+            Ok(Some(self.get_block(hash)?))
+        };
+        let (mut old, mut new) = match (load(old_tip)?, load(new_tip)?) {
+            (Some(old), Some(new)) => (old, new),
+            _ => return Ok(None),
+        };
+        let mut depth = 0;
+        while old.get_hash() != new.get_hash() {
+            if old.get_height() >= new.get_height() {
+                old = match load(&old.get_prev_hash())? {
+                    Some(b) => b,
+                    None => return Ok(None),
+                };
+                depth += 1;
+            } else {
+                new = match load(&new.get_prev_hash())? {
+                    Some(b) => b,
+                    None => return Ok(None),
+                };
+            }
+        }
+        Ok(Some(depth))
+    }
+
+    /// BlockHashAt returns the hash of the best chain block at `height`
+    ///
+    /// It only reads the height index, kept up to date whenever the tip moves.
+    pub fn block_hash_at(&self, height: i32) -> Result<Option<String>> {
+        if height < 0 {
+            return Ok(None);
+        }
+        if height > self.get_best_height()? {
+            return Ok(None);
+        }
+        match self
+            .db
+            .open_tree("heights")?
+            .get((height as u32).to_be_bytes())?
+        {
+            Some(h) => Ok(Some(String::from_utf8(h.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// index_heights points the heights index at the blocks of the best chain
+    ///
+    /// It walks back from the tip until the index agrees, so a reorg
+    /// rewrites the heights of the new branch only.
+    fn index_heights(&self) -> Result<()> {
+        let heights = self.db.open_tree("heights")?;
+        let mut hash = self.tip.clone();
+        while !hash.is_empty() {
+            let block = self.get_block(&hash)?;
+            let key = (block.get_height() as u32).to_be_bytes();
+            if heights.get(key)?.as_deref() == Some(hash.as_bytes()) {
+                break;
+            }
+            heights.insert(key, hash.as_bytes())?;
+            hash = block.get_prev_hash();
+        }
+        Ok(())
+    }
+
     // GetBlock finds a block by its hash and returns it
     pub fn get_block(&self, block_hash: &str) -> Result<Block> {
         let data = self.db.get(block_hash)?.unwrap();
-        let block = deserialize(&data.to_vec())?;
+        let block = deserialize(&data)?;
         Ok(block)
     }
 
@@ -211,10 +603,49 @@ impl Blockchain {
             return Ok(-1);
         };
         let last_data = self.db.get(lasthash)?.unwrap();
-        let last_block: Block = deserialize(&last_data.to_vec())?;
+        let last_block: Block = deserialize(&last_data)?;
         Ok(last_block.get_height())
     }
 
+    /// Locator returns block hashes from the tip back to genesis, spaced
+    /// exponentially, so a peer can find the last block both chains share
+    pub fn locator(&self) -> Vec<String> {
+        let hashes = self.get_block_hashs();
+        let mut locator = Vec::new();
+        let mut step = 1;
+        let mut i = 0;
+        while i < hashes.len() {
+            locator.push(hashes[i].clone());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            i += step;
+        }
+        if let Some(genesis) = hashes.last() {
+            if locator.last() != Some(genesis) {
+                locator.push(genesis.clone());
+            }
+        }
+        locator
+    }
+
+    /// HeadersAfter returns up to `max` headers following the newest block
+    /// of the locator that is in this chain, oldest first
+    pub fn headers_after(&self, locator: &[String], max: usize) -> Result<Vec<BlockHeader>> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for b in self.iter() {
+            if locator.contains(&b.get_hash()) {
+                break;
+            }
+            blocks.push(b);
+        }
+        let mut headers = Vec::new();
+        for b in blocks.iter().rev().take(max) {
+            headers.push(b.header()?);
+        }
+        Ok(headers)
+    }
+
     /// GetBlockHashes returns a list of hashes of all the blocks in the chain
     pub fn get_block_hashs(&self) -> Vec<String> {
         let mut list = Vec::new();
@@ -225,6 +656,12 @@ impl Blockchain {
     }
 }
 
+/// genesis_chain_id returns the chain id of the genesis file a genesis block
+/// was created from, or its hash if it was created without one
+fn genesis_chain_id(genesis: &Block) -> String {
+    chain_id_of(genesis).unwrap_or_else(|| genesis.get_hash())
+}
+
 impl<'a> Iterator for BlockchainIterator<'a> {
     type Item = Block;
 
@@ -245,3 +682,224 @@ impl<'a> Iterator for BlockchainIterator<'a> {
         None
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ADDRESS: &str = "3K2B53BeZoaSPBGxihwPkbjNxrLkapfYrn";
+
+    fn now() -> u128 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    }
+
+    fn block_at(bc: &Blockchain, prev: &Block, height: i32, timestamp: u128, target: u32) -> Block {
+        let cbtx = Transaction::new_coinbase(String::from(ADDRESS), format!("block {}", timestamp))
+            .unwrap();
+        Block::new_block_at(
+            vec![cbtx],
+            prev.get_hash(),
+            &bc.chain_id().unwrap(),
+            height,
+            timestamp,
+            target,
+        )
+        .unwrap()
+    }
+
+    fn genesis_at(timestamp: u128) -> Block {
+        let cbtx =
+            Transaction::new_coinbase(String::from(ADDRESS), String::from("genesis")).unwrap();
+        Block::new_block_at(vec![cbtx], String::new(), "", 0, timestamp, MIN_TARGET_BITS).unwrap()
+    }
+
+    #[test]
+    fn test_retarget_chain() {
+        // an epoch mined three times faster than the chain aims for
+        let spacing = TARGET_BLOCK_TIME_MS / 3;
+        let start = now() - (RETARGET_INTERVAL as u128 + 1) * spacing;
+        let genesis = genesis_at(start);
+        let mut bc = Blockchain::temporary(&genesis).unwrap();
+        let mut tip = genesis;
+        for height in 1..RETARGET_INTERVAL {
+            let target = bc.next_target(&tip.get_hash()).unwrap();
+            let block = block_at(&bc, &tip, height, start + height as u128 * spacing, target);
+            bc.add_block(block.clone()).unwrap();
+            tip = block;
+        }
+        assert_eq!(bc.tip, tip.get_hash());
+        assert_eq!(tip.get_target(), MIN_TARGET_BITS);
+
+        // the first block of the next epoch needs one more bit
+        let time = start + RETARGET_INTERVAL as u128 * spacing;
+        assert_eq!(
+            bc.next_target(&tip.get_hash()).unwrap(),
+            MIN_TARGET_BITS + 1
+        );
+        let easy = block_at(&bc, &tip, RETARGET_INTERVAL, time, MIN_TARGET_BITS);
+        assert!(bc.add_block(easy).is_err());
+        let hard = block_at(&bc, &tip, RETARGET_INTERVAL, time, MIN_TARGET_BITS + 1);
+        bc.add_block(hard.clone()).unwrap();
+        assert_eq!(bc.tip, hard.get_hash());
+        assert_eq!(
+            bc.next_target(&hard.get_hash()).unwrap(),
+            MIN_TARGET_BITS + 1
+        );
+    }
+
+    #[test]
+    fn test_coinbase_fees() {
+        let wallet = crate::wallets::Wallet::from_rng(&mut rand_core::OsRng);
+        let cbtx =
+            Transaction::new_coinbase(wallet.get_address(), String::from("genesis")).unwrap();
+        let genesis = Block::new_block_at(
+            vec![cbtx.clone()],
+            String::new(),
+            "",
+            0,
+            now() - 1000,
+            MIN_TARGET_BITS,
+        )
+        .unwrap();
+        let mut bc = Blockchain::temporary(&genesis).unwrap();
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: cbtx.id.clone(),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: wallet.public_key.clone(),
+            }],
+            vout: vec![TXOutput::new(SUBSIDY - 2, String::from(ADDRESS)).unwrap()],
+        };
+        tx.id = tx.hash().unwrap();
+        bc.sign_transacton(&mut tx, &wallet).unwrap();
+        assert_eq!(bc.total_fees(&[tx.clone()]).unwrap(), 2);
+
+        let chain_id = bc.chain_id().unwrap();
+        let block = |fees: i32, coinbases: usize| {
+            let mut txs = vec![tx.clone()];
+            for i in 0..coinbases {
+                txs.push(
+                    Transaction::new_coinbase_with_fees(
+                        String::from(ADDRESS),
+                        format!("cb {}", i),
+                        fees,
+                    )
+                    .unwrap(),
+                );
+            }
+            Block::new_block_at(
+                txs,
+                genesis.get_hash(),
+                &chain_id,
+                1,
+                now(),
+                MIN_TARGET_BITS,
+            )
+            .unwrap()
+        };
+        // the coinbase may not claim more than the subsidy and the fees
+        assert!(bc.add_block(block(3, 1)).is_err());
+        assert!(bc.add_block(block(2, 0)).is_err());
+        assert!(bc.add_block(block(2, 2)).is_err());
+        assert_eq!(bc.get_best_height().unwrap(), 0);
+        bc.add_block(block(2, 1)).unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_heights_index() {
+        let start = now() - 10_000;
+        let genesis = genesis_at(start);
+        let mut bc = Blockchain::temporary(&genesis).unwrap();
+        assert_eq!(bc.block_hash_at(0).unwrap(), Some(genesis.get_hash()));
+        let b1 = block_at(&bc, &genesis, 1, start + 1, MIN_TARGET_BITS);
+        bc.add_block(b1.clone()).unwrap();
+        assert_eq!(bc.block_hash_at(1).unwrap(), Some(b1.get_hash()));
+
+        // a longer branch takes over the heights of the old one
+        let c1 = block_at(&bc, &genesis, 1, start + 2, MIN_TARGET_BITS);
+        bc.add_block(c1.clone()).unwrap();
+        assert_eq!(bc.block_hash_at(1).unwrap(), Some(b1.get_hash()));
+        let c2 = block_at(&bc, &c1, 2, start + 3, MIN_TARGET_BITS);
+        bc.add_block(c2.clone()).unwrap();
+        assert_eq!(bc.block_hash_at(1).unwrap(), Some(c1.get_hash()));
+        assert_eq!(bc.block_hash_at(2).unwrap(), Some(c2.get_hash()));
+        assert_eq!(bc.block_hash_at(3).unwrap(), None);
+    }
+
+    #[test]
+    fn test_open_format() {
+        let cbtx = Transaction::new_coinbase(ADDRESS.to_string(), String::new()).unwrap();
+        let genesis = Block::new_genesis_block(cbtx);
+        let bc = Blockchain::temporary(&genesis).unwrap();
+        assert_eq!(
+            Blockchain::open(bc.db.clone()).unwrap().tip,
+            genesis.get_hash()
+        );
+
+        // a database of the legacy block format has no format marker
+        bc.db.remove("FORMAT").unwrap();
+        assert!(Blockchain::open(bc.db.clone()).is_err());
+        let empty = sled::Config::new().temporary(true).open().unwrap();
+        assert!(Blockchain::open(empty).unwrap().tip.is_empty());
+    }
+
+    #[test]
+    fn test_check_header() {
+        let start = now() - 600_000;
+        let genesis = genesis_at(start);
+        let mut bc = Blockchain::temporary(&genesis).unwrap();
+        let mut tip = genesis.clone();
+        for height in 1..4 {
+            let block = block_at(
+                &bc,
+                &tip,
+                height,
+                start + height as u128 * 60_000,
+                MIN_TARGET_BITS,
+            );
+            bc.add_block(block.clone()).unwrap();
+            tip = block;
+        }
+        // the median of the four stored timestamps is the one of height 2
+        let median = start + 2 * 60_000;
+        assert_eq!(bc.median_time_past(&tip).unwrap(), median);
+
+        let unstored = block_at(&bc, &genesis, 1, start + 1, MIN_TARGET_BITS);
+        let orphan = block_at(&bc, &unstored, 2, median + 1, MIN_TARGET_BITS);
+        assert!(bc.add_block(orphan).is_err());
+        assert!(bc
+            .add_block(block_at(&bc, &tip, 5, median + 1, MIN_TARGET_BITS))
+            .is_err());
+        let cbtx =
+            Transaction::new_coinbase(String::from(ADDRESS), String::from("testnet")).unwrap();
+        let testnet = Block::new_block_at(
+            vec![cbtx],
+            tip.get_hash(),
+            "testnet",
+            4,
+            median + 1,
+            MIN_TARGET_BITS,
+        )
+        .unwrap();
+        assert!(bc.add_block(testnet).is_err());
+        assert!(bc
+            .add_block(block_at(&bc, &tip, 4, median, MIN_TARGET_BITS))
+            .is_err());
+        let future = now() + MAX_FUTURE_BLOCK_TIME_MS + 60_000;
+        assert!(bc
+            .add_block(block_at(&bc, &tip, 4, future, MIN_TARGET_BITS))
+            .is_err());
+        assert_eq!(bc.tip, tip.get_hash());
+
+        // later than the median is enough, even if earlier than the tip
+        let late = block_at(&bc, &tip, 4, median + 1, MIN_TARGET_BITS);
+        bc.add_block(late.clone()).unwrap();
+        assert_eq!(bc.tip, late.get_hash());
+    }
+}
@@ -0,0 +1,154 @@
+//! Network census from version handshakes
+//!
+//! The node remembers what each connected peer announced in its last
+//! version message and reports aggregates only: how many peers run each
+//! protocol version and user agent, which services they offer and how far
+//! their clocks are from ours. Peer addresses never leave the census, and
+//! the share of upgraded peers helps to time protocol changes.
+
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Serves UTXO state snapshots
+pub const SERVICE_STATE: u64 = 1;
+/// Serves headers-first sync
+pub const SERVICE_HEADERS: u64 = 1 << 1;
+/// Accepts Noise encrypted connections
+pub const SERVICE_NOISE: u64 = 1 << 2;
+/// Services a node can announce in its version message, with their names
+pub const SERVICES: [(u64, &str); 3] = [
+    (SERVICE_STATE, "state"),
+    (SERVICE_HEADERS, "headers"),
+    (SERVICE_NOISE, "noise"),
+];
+
+/// Observation is what one peer announced
+#[derive(Debug, Clone, PartialEq)]
+pub struct Observation {
+    pub version: i32,
+    pub services: u64,
+    pub user_agent: String,
+    /// Peer clock minus ours, None if the peer does not send its time
+    pub clock_offset_ms: Option<i64>,
+}
+
+/// ClockOffsets summarizes the clock offsets of the peers
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ClockOffsets {
+    pub median_ms: i64,
+    pub max_abs_ms: i64,
+}
+
+/// CensusReport is the anonymized view of the peers
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CensusReport {
+    pub peers: usize,
+    /// Peers running `version` or later
+    pub upgraded: usize,
+    pub versions: BTreeMap<i32, usize>,
+    pub user_agents: BTreeMap<String, usize>,
+    pub services: BTreeMap<String, usize>,
+    pub clock_offset: Option<ClockOffsets>,
+}
+
+/// Census keeps the last observation of each peer
+#[derive(Debug, Default)]
+pub struct Census {
+    peers: HashMap<String, Observation>,
+}
+
+impl Census {
+    pub fn new() -> Census {
+        Census::default()
+    }
+
+    pub fn observe(&mut self, addr: &str, observation: Observation) {
+        self.peers.insert(addr.to_string(), observation);
+    }
+
+    pub fn remove(&mut self, addr: &str) {
+        self.peers.remove(addr);
+    }
+
+    /// Report aggregates the observations, counting peers at `version` or later as upgraded
+    pub fn report(&self, version: i32) -> CensusReport {
+        let mut versions = BTreeMap::new();
+        let mut user_agents = BTreeMap::new();
+        let mut services = BTreeMap::new();
+        let mut offsets = Vec::new();
+        for peer in self.peers.values() {
+            *versions.entry(peer.version).or_insert(0) += 1;
+            *user_agents.entry(peer.user_agent.clone()).or_insert(0) += 1;
+            for (bit, name) in SERVICES {
+                if peer.services & bit != 0 {
+                    *services.entry(name.to_string()).or_insert(0) += 1;
+                }
+            }
+            offsets.extend(peer.clock_offset_ms);
+        }
+        offsets.sort_unstable();
+        let clock_offset = if offsets.is_empty() {
+            None
+        } else {
+            Some(ClockOffsets {
+                median_ms: offsets[offsets.len() / 2],
+                max_abs_ms: offsets.iter().map(|o| o.abs()).max().unwrap_or(0),
+            })
+        };
+        CensusReport {
+            peers: self.peers.len(),
+            upgraded: self.peers.values().filter(|p| p.version >= version).count(),
+            versions,
+            user_agents,
+            services,
+            clock_offset,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_census() {
+        let mut census = Census::new();
+        assert_eq!(census.report(2).peers, 0);
+        assert!(census.report(2).clock_offset.is_none());
+
+        let observation = |version, services, offset| Observation {
+            version,
+            services,
+            user_agent: format!("polytorus/{}", version),
+            clock_offset_ms: offset,
+        };
+        census.observe("a:1", observation(1, 0, None));
+        census.observe(
+            "b:1",
+            observation(2, SERVICE_STATE | SERVICE_HEADERS, Some(-300)),
+        );
+        census.observe(
+            "c:1",
+            observation(2, SERVICE_HEADERS | SERVICE_NOISE, Some(100)),
+        );
+        census.observe("c:1", observation(2, SERVICE_HEADERS, Some(50)));
+
+        let report = census.report(2);
+        assert_eq!(report.peers, 3);
+        assert_eq!(report.upgraded, 2);
+        assert_eq!(report.versions[&1], 1);
+        assert_eq!(report.user_agents["polytorus/2"], 2);
+        assert_eq!(report.services["headers"], 2);
+        assert!(!report.services.contains_key("noise"));
+        assert_eq!(
+            report.clock_offset,
+            Some(ClockOffsets {
+                median_ms: 50,
+                max_abs_ms: 300
+            })
+        );
+
+        census.remove("b:1");
+        assert_eq!(census.report(2).upgraded, 1);
+    }
+}
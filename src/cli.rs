@@ -1,178 +1,1279 @@
 //! cli process
 
 use super::*;
+use crate::address;
+use crate::block::Block;
 use crate::blockchain::*;
+use crate::coinlocks::*;
+use crate::fees::*;
+use crate::genesis::*;
+use crate::keypolicy::*;
+use crate::policy::*;
+use crate::rpc;
 use crate::server::*;
+use crate::signer::*;
+use crate::systemtx::*;
+use crate::telemetry::*;
 use crate::transaction::*;
+use crate::txbuilder::*;
+use crate::txindex::*;
 use crate::utxoset::*;
 use crate::wallets::*;
-use bitcoincash_addr::Address;
-use clap::{App, Arg};
+use crate::watchdog::DEFAULT_MIN_PEERS;
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind};
+use failure::format_err;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::net::TcpListener;
 use std::process::exit;
+use std::time::Duration;
 
-pub struct Cli {}
+#[derive(Default)]
+pub struct Cli {
+    /// subcommand path of the last command, e.g. "node start"
+    command: Option<String>,
+}
+
+/// Subcommands that run a server until they fail
+const LONG_RUNNING_COMMANDS: [&str; 2] = ["node start", "signer start"];
+/// Exit code of a command that failed
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code of a command line that does not parse
+pub const EXIT_USAGE: i32 = 2;
+/// Commands of the former flat command line and what replaced them
+const RENAMED_COMMANDS: [(&str, &str); 21] = [
+    ("createwallet", "wallet create"),
+    ("listaddresses", "wallet list"),
+    ("getbalance", "wallet balance"),
+    ("history", "wallet history"),
+    ("convertaddress", "wallet convert-address"),
+    ("encryptwallet", "wallet encrypt"),
+    ("rekey", "wallet rekey"),
+    ("keypolicy", "wallet policy"),
+    ("send", "tx send"),
+    ("checkpoint", "tx checkpoint"),
+    ("createblockchain", "chain create"),
+    ("printchain", "chain print"),
+    ("reindex", "chain reindex"),
+    ("startnode", "node start"),
+    ("startminer", "node start --mine <address>"),
+    ("mempool", "node mempool"),
+    ("features", "node features"),
+    ("peers", "node peers"),
+    ("startsigner", "signer start"),
+    ("signpolicy", "policy sign"),
+    ("exporttelemetry", "telemetry export"),
+];
+
+/// Output prints the result of a command as text, or as JSON with --json
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    fn new(matches: &ArgMatches) -> Output {
+        Output {
+            json: matches.is_present("json"),
+        }
+    }
+
+    /// print writes `value` in JSON mode and `text` otherwise
+    fn print(&self, value: Value, text: &str) -> Result<()> {
+        let mut stdout = std::io::stdout();
+        if self.json {
+            writeln!(stdout, "{}", serde_json::to_string_pretty(&value)?)?;
+        } else {
+            writeln!(stdout, "{}", text)?;
+        }
+        Ok(())
+    }
+}
 
 impl Cli {
     pub fn new() -> Cli {
-        Cli {}
+        Cli { command: None }
+    }
+
+    /// IsLongRunning reports whether the last command ran a server
+    pub fn is_long_running(&self) -> bool {
+        match &self.command {
+            Some(c) => LONG_RUNNING_COMMANDS.contains(&c.as_str()),
+            None => false,
+        }
     }
 
+    /// Run parses the command line and runs its command
+    ///
+    /// Help and version exit with 0 and a command line that does not parse
+    /// with `EXIT_USAGE`, the errors of the command itself are returned.
     pub fn run(&mut self) -> Result<()> {
         info!("run app");
-        let matches = App::new("polytorus")
-            .version("0.1")
-            .author("quantumshiro")
-            .about("post quantum blockchain")
-            .subcommand(App::new("printchain").about("print all the chain blocks"))
-            .subcommand(App::new("createwallet").about("create a wallet"))
-            .subcommand(App::new("listaddresses").about("list all addresses"))
-            .subcommand(App::new("reindex").about("reindex UTXO"))
-            .subcommand(
-                App::new("startnode")
-                    .about("start the node server")
-                    .arg(Arg::from_usage("<port> 'the port server bind to locally'"))
-                    .arg(
-                        Arg::with_name("host")
-                            .long("host")
-                            .takes_value(true)
-                            .default_value("0.0.0.0")
-                            .help("the host IP to bind for inbound connections"),
-                    )
-                    .arg(
-                        Arg::with_name("bootstrap")
-                            .long("bootstrap")
-                            .takes_value(true)
-                            .help("the address of an existing node (host:port) to connect first"),
-                    ),
-            )
-            .subcommand(
-                App::new("startminer")
-                    .about("start the minner server")
-                    .arg(Arg::from_usage("<port> 'the port server bind to locally'"))
-                    .arg(Arg::from_usage("<address> 'wallet address'")),
-            )
-            .subcommand(
-                App::new("getbalance")
-                    .about("get balance in the blockchain")
-                    .arg(Arg::from_usage(
-                        "<address> 'The address to get balance for'",
-                    )),
-            )
-            .subcommand(App::new("createblockchain").about("create blockchain").arg(
-                Arg::from_usage("<address> 'The address to send genesis block reward to'"),
-            ))
-            .subcommand(
-                App::new("send")
-                    .about("send in the blockchain")
-                    .arg(Arg::from_usage("<from> 'Source wallet address'"))
-                    .arg(Arg::from_usage("<to> 'Destination wallet address'"))
-                    .arg(Arg::from_usage("<amount> 'Amount to send'"))
-                    .arg(Arg::from_usage(
-                        "-m --mine 'the from address mine immediately'",
-                    )),
-            )
-            .get_matches();
-
-        if let Some(ref matches) = matches.subcommand_matches("getbalance") {
-            if let Some(address) = matches.value_of("address") {
-                let balance = cmd_get_balance(address)?;
-                println!("Balance: {}\n", balance);
+        let command = std::env::args().skip(1).find(|arg| !arg.starts_with('-'));
+        if let Some(new) = command.as_deref().and_then(renamed_command) {
+            eprintln!(
+                "this command is now `polytorus {}`, see `polytorus help`",
+                new
+            );
+            exit(EXIT_USAGE);
+        }
+        let matches = match app().get_matches_safe() {
+            Ok(matches) => matches,
+            Err(e)
+                if e.kind == ErrorKind::HelpDisplayed || e.kind == ErrorKind::VersionDisplayed =>
+            {
+                e.exit()
             }
-        } else if let Some(_) = matches.subcommand_matches("createwallet") {
-            println!("address: {}", cmd_create_wallet()?);
-        } else if let Some(_) = matches.subcommand_matches("printchain") {
-            cmd_print_chain()?;
-        } else if let Some(_) = matches.subcommand_matches("reindex") {
-            let count = cmd_reindex()?;
-            println!("Done! There are {} transactions in the UTXO set.", count);
-        } else if let Some(_) = matches.subcommand_matches("listaddresses") {
-            cmd_list_address()?;
-        } else if let Some(ref matches) = matches.subcommand_matches("createblockchain") {
-            if let Some(address) = matches.value_of("address") {
-                cmd_create_blockchain(address)?;
+            Err(e) => {
+                eprintln!("{}", e.message);
+                exit(EXIT_USAGE)
             }
-        } else if let Some(ref matches) = matches.subcommand_matches("send") {
-            let from = if let Some(address) = matches.value_of("from") {
-                address
+        };
+        let (group, matches) = match matches.subcommand() {
+            (group, Some(matches)) => (group, matches),
+            _ => unreachable!("a subcommand is required"),
+        };
+        let (command, leaf) = match matches.subcommand() {
+            (name, Some(leaf)) => (format!("{} {}", group, name), leaf),
+            _ => (group.to_string(), matches),
+        };
+        self.command = Some(command);
+        let out = Output::new(leaf);
+
+        match group {
+            "wallet" => run_wallet(matches, &out),
+            "tx" => run_tx(matches, &out),
+            "chain" => run_chain(matches, &out),
+            "genesis" => run_genesis(matches, &out),
+            "node" => run_node(matches, &out),
+            "signer" => run_signer(matches),
+            "policy" => run_policy(matches),
+            "telemetry" => run_telemetry(matches),
+            "protocol" => {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&protocol_description()?)?
+                );
+                Ok(())
+            }
+            _ => unreachable!("unknown command {}", group),
+        }
+    }
+}
+
+/// renamed_command returns what replaced a command of the former flat command line
+fn renamed_command(command: &str) -> Option<&'static str> {
+    RENAMED_COMMANDS
+        .iter()
+        .find(|(old, _)| *old == command)
+        .map(|(_, new)| *new)
+}
+
+/// app defines the command line: a subcommand per area, each with its own commands
+fn app() -> App<'static, 'static> {
+    App::new("polytorus")
+        .version("0.1")
+        .author("quantumshiro")
+        .about("post quantum blockchain")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .after_help("Commands exit with 0 on success, 1 when they fail and 2 when the command line is invalid.")
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .global(true)
+                .help("print the result as JSON, for scripts"),
+        )
+        .subcommand(
+            App::new("wallet")
+                .about("create and manage the wallets")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("create")
+                        .about("create a wallet")
+                        .arg(Arg::from_usage("--hd 'derive the next wallet from the mnemonic seed'"))
+                        .arg(
+                            Arg::with_name("restore")
+                                .long("wallet-restore")
+                                .alias("restore")
+                                .takes_value(true)
+                                .value_name("mnemonic")
+                                .help("restore the HD seed from a mnemonic phrase"),
+                        )
+                        .arg(
+                            Arg::with_name("derive")
+                                .long("wallet-derive")
+                                .alias("derive")
+                                .takes_value(true)
+                                .value_name("path")
+                                .help("derive the wallet at a path like m/44'/7391'/0'/0'"),
+                        )
+                        .arg(Arg::from_usage(
+                            "--passphrase [passphrase] 'BIP-39 passphrase of a new or restored mnemonic'",
+                        ))
+                        .arg(Arg::from_usage("--force 'restore over an existing HD seed'")),
+                )
+                .subcommand(App::new("list").about("list all addresses"))
+                .subcommand(
+                    App::new("balance")
+                        .about("get the balance of an address")
+                        .arg(Arg::from_usage("<address> 'The address to get balance for'")),
+                )
+                .subcommand(
+                    App::new("history")
+                        .about("list the transactions paying or spending an address")
+                        .arg(Arg::from_usage("<address> 'The address to list transactions for'")),
+                )
+                .subcommand(
+                    App::new("convert-address")
+                        .about("print an address in the legacy and the v2 (bech32m) format")
+                        .arg(Arg::from_usage("<address> 'address in either format'"))
+                        .arg(Arg::from_usage("--testnet 'encode the v2 address for testnets'")),
+                )
+                .subcommand(App::new("encrypt").about("encrypt the wallets with a password, or change the password"))
+                .subcommand(
+                    App::new("rekey")
+                        .about("replace the key of a wallet and sweep its outputs to the new key")
+                        .arg(Arg::from_usage("<address> 'wallet address to deprecate'"))
+                        .arg(Arg::from_usage("--fee [amount] 'fee of the sweep transaction'"))
+                        .arg(Arg::from_usage("-m --mine 'mine the sweep immediately'")),
+                )
+                .subcommand(
+                    App::new("policy")
+                        .about("show or set the usage policy of a wallet key")
+                        .arg(Arg::from_usage("<address> 'wallet address'"))
+                        .arg(Arg::from_usage(
+                            "--daily-limit [amount] 'most the key pays to other addresses per UTC day'",
+                        ))
+                        .arg(Arg::from_usage(
+                            "--allow [address]... 'only destination the key may pay, repeat for more'",
+                        ))
+                        .arg(Arg::from_usage(
+                            "--confirm-above [amount] 'spends above this amount need tx send --confirm'",
+                        ))
+                        .arg(Arg::from_usage("--clear 'remove the policy'")),
+                )
+                .subcommand(
+                    App::new("lock-output")
+                        .about("keep an output out of coin selection")
+                        .arg(Arg::from_usage("<txid> 'transaction of the output'"))
+                        .arg(Arg::from_usage("<vout> 'index of the output'")),
+                )
+                .subcommand(
+                    App::new("unlock-output")
+                        .about("let coin selection spend a locked output again")
+                        .arg(Arg::from_usage("<txid> 'transaction of the output'"))
+                        .arg(Arg::from_usage("<vout> 'index of the output'")),
+                )
+                .subcommand(App::new("locked-outputs").about("list the locked outputs")),
+        )
+        .subcommand(
+            App::new("tx")
+                .about("send transactions")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("send")
+                        .about("send in the blockchain")
+                        .arg(Arg::from_usage("<from> 'Source wallet address'"))
+                        .arg(Arg::from_usage("<to> 'Destination wallet address'"))
+                        .arg(Arg::from_usage("<amount> 'Amount to send'"))
+                        .arg(Arg::from_usage("-m --mine 'the from address mine immediately'"))
+                        .arg(Arg::from_usage("--signer [endpoint] 'sign with the remote signer at host:port'"))
+                        .arg(Arg::from_usage(
+                            "--coin-selection [strategy] 'coins to spend: largest or bnb (exact match, no change)'",
+                        ))
+                        .arg(Arg::from_usage("--fee [amount] 'amount the inputs exceed the outputs by'"))
+                        .arg(
+                            Arg::from_usage(
+                                "--estimate-fee [priority] 'pay the fee recent blocks suggest: low, medium or high'",
+                            )
+                            .conflicts_with("fee"),
+                        )
+                        .arg(Arg::from_usage(
+                            "--confirm 'approve a spend above the confirmation threshold of the key policy'",
+                        )),
+                )
+                .subcommand(
+                    App::new("checkpoint")
+                        .about("submit a checkpoint system transaction for a local block")
+                        .arg(Arg::from_usage("<height> 'height of the block to checkpoint'"))
+                        .arg(Arg::from_usage(
+                            "-m --mine [address] 'mine the checkpoint immediately, rewarding address'",
+                        )),
+                ),
+        )
+        .subcommand(
+            App::new("chain")
+                .about("create and inspect the local blockchain")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("create")
+                        .about("create blockchain")
+                        .arg(
+                            Arg::from_usage("[address] 'The address to send genesis block reward to'")
+                                .required_unless("genesis"),
+                        )
+                        .arg(
+                            Arg::from_usage(
+                                "--genesis [file] 'create the chain of the network defined by a genesis file'",
+                            )
+                            .conflicts_with("address"),
+                        ),
+                )
+                .subcommand(App::new("print").about("print all the chain blocks"))
+                .subcommand(App::new("reindex").about("reindex UTXO")),
+        )
+        .subcommand(
+            App::new("genesis")
+                .about("define a network by its genesis file")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("generate")
+                        .about("write a genesis file")
+                        .arg(Arg::from_usage("--chain-id <id> 'name of the network'"))
+                        .arg(Arg::from_usage(
+                            "--alloc <allocation>... 'initial balance as address:amount, repeat for more'",
+                        ))
+                        .arg(Arg::from_usage("--out [file] 'write to a file instead of stdout'")),
+                )
+                .subcommand(
+                    App::new("validate")
+                        .about("check a genesis file and print its hash")
+                        .arg(Arg::from_usage("<file> 'genesis file'")),
+                ),
+        )
+        .subcommand(
+            App::new("node")
+                .about("run a node and inspect a running one")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(node_start_app())
+                .subcommand(
+                    App::new("mempool")
+                        .about("show the mempool, fee rates and block times of a running node")
+                        .arg(Arg::from_usage("--rpc-port <port> 'JSON-RPC port of the node'"))
+                        .arg(Arg::from_usage("--watch [secs] 'refresh every secs seconds until interrupted'")),
+                )
+                .subcommand(
+                    App::new("features")
+                        .about("list the protocol and the subsystems enabled on a running node")
+                        .arg(Arg::from_usage("--rpc-port <port> 'JSON-RPC port of the node'")),
+                )
+                .subcommand(
+                    App::new("peers")
+                        .about("export or import the peers of a running node")
+                        .setting(AppSettings::SubcommandRequiredElseHelp)
+                        .arg(Arg::from_usage("--rpc-port <port> 'JSON-RPC port of the node'"))
+                        .subcommand(
+                            App::new("export")
+                                .about("write the known and pinned peers as JSON")
+                                .arg(Arg::from_usage("[file] 'file to write, stdout if omitted'")),
+                        )
+                        .subcommand(
+                            App::new("import")
+                                .about("add the peers of an exported JSON file")
+                                .arg(Arg::from_usage("<file> 'file written by node peers export'")),
+                        )
+                        .subcommand(
+                            App::new("stats")
+                                .about("print the messages, misbehavior and latency of a peer")
+                                .arg(Arg::from_usage("<addr> 'address of the peer (host:port)'")),
+                        ),
+                ),
+        )
+        .subcommand(
+            App::new("signer")
+                .about("serve the local wallets to remote signer clients")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("start")
+                        .about("start a remote signer serving the local wallets to clients holding POLYTORUS_SIGNER_SECRET")
+                        .arg(Arg::from_usage("<port> 'the port signer bind to locally'"))
+                        .arg(
+                            Arg::with_name("host")
+                                .long("host")
+                                .takes_value(true)
+                                .default_value("127.0.0.1")
+                                .help("the host IP to bind for signing requests"),
+                        ),
+                ),
+        )
+        .subcommand(
+            App::new("policy")
+                .about("manage compliance policy lists")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("sign")
+                        .about("sign a compliance policy list, printing it as JSON")
+                        .arg(Arg::from_usage("<authority> 'wallet address signing the list'"))
+                        .arg(Arg::from_usage("<version> 'version of the list'"))
+                        .arg(Arg::from_usage("<file> 'file with one blocked address per line'")),
+                ),
+        )
+        .subcommand(
+            App::new("telemetry")
+                .about("work with the recorded arrival telemetry")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("export")
+                        .about("export the recorded arrival telemetry as CSV")
+                        .arg(Arg::from_usage("[out] 'file to write, stdout if omitted'")),
+                ),
+        )
+        .subcommand(App::new("protocol").about("print the P2P wire protocol description as JSON"))
+}
+
+/// node_start_app defines `node start`, which mines too when given --mine
+fn node_start_app() -> App<'static, 'static> {
+    App::new("start")
+        .about("start the node server, mining with --mine")
+        .arg(Arg::from_usage("<port> 'the port server bind to locally'"))
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .takes_value(true)
+                .default_value("0.0.0.0")
+                .help("the host IP to bind for inbound connections"),
+        )
+        .arg(
+            Arg::with_name("bootstrap")
+                .long("bootstrap")
+                .takes_value(true)
+                .help("the address of an existing node (host:port) to connect first"),
+        )
+        .arg(Arg::from_usage("--mine [address] 'mine blocks of the mempool, rewarding address'"))
+        .arg(Arg::from_usage("--statesync 'fetch the UTXO set from peers before syncing blocks'"))
+        .arg(Arg::from_usage("--statesync-root [root] 'hex state root the fetched UTXO set has to match'"))
+        .arg(Arg::from_usage(
+            "--statesync-unverified 'accept the UTXO set of any peer without a trusted root'",
+        ))
+        .arg(Arg::from_usage("--fast-relay [count] 'number of low latency peers announced to first'"))
+        .arg(Arg::from_usage(
+            "--max-peer-bandwidth [bytes] 'most message bytes per second to and from each peer'",
+        ))
+        .arg(Arg::from_usage("--max-bandwidth [bytes] 'most message bytes per second to and from all peers'"))
+        .arg(Arg::from_usage("--encrypt 'send messages to other nodes over encrypted connections'"))
+        .arg(Arg::from_usage("--require-encryption 'encrypt and refuse plaintext messages from other nodes'"))
+        .arg(Arg::from_usage("--rpc-port [port] 'serve JSON-RPC 2.0 on 127.0.0.1:<port>'"))
+        .arg(Arg::from_usage("--metrics-port [port] 'serve Prometheus metrics on 127.0.0.1:<port>/metrics'"))
+        .arg(Arg::from_usage(
+            "--idempotency-window [secs] 'how long sendrawtransaction remembers a request id (default 600)'",
+        ))
+        .arg(Arg::from_usage(
+            "--policy [file] 'refuse transactions touching the addresses of a signed policy list'",
+        ))
+        .arg(Arg::from_usage("--policy-authority [address] 'address whose key must sign the policy list'"))
+        .arg(Arg::from_usage(
+            "--telemetry 'record tx and block arrival times per peer (stores peer addresses)'",
+        ))
+        .arg(Arg::from_usage("--pin [address]... 'peer (host:port) that is never evicted or banned'"))
+        .arg(Arg::from_usage(
+            "--system-tx-from [identity]... 'peer IP or Noise key allowed to submit system transactions, besides RPC and the local host'",
+        ))
+        .arg(Arg::from_usage(
+            "--watchdog 'check the node health and heal it, actions go to data/watchdog-audit.log'",
+        ))
+        .arg(Arg::from_usage(
+            "--watchdog-min-peers [count] 'reconnect when fewer peers are known (default 1)'",
+        ))
+        .arg(Arg::from_usage(
+            "--watchdog-page [program] 'program run with the problem when healing does not help'",
+        ))
+        .arg(
+            Arg::from_usage("--tx-order [ordering] 'order of mempool transactions in mined blocks: oldest or fee'")
+                .requires("mine"),
+        )
+        .arg(
+            Arg::from_usage("--max-block-txs [count] 'most mempool transactions per mined block, 0 for all'")
+                .requires("mine"),
+        )
+}
+
+fn run_wallet(matches: &ArgMatches, out: &Output) -> Result<()> {
+    match matches.subcommand() {
+        ("create", Some(matches)) => {
+            let passphrase = matches.value_of("passphrase").unwrap_or("");
+            let (address, mnemonic) = if let Some(mnemonic) = matches.value_of("restore") {
+                (
+                    cmd_restore_wallet(mnemonic, passphrase, matches.is_present("force"))?,
+                    None,
+                )
+            } else if let Some(path) = matches.value_of("derive") {
+                (cmd_derive_wallet(path)?, None)
+            } else if matches.is_present("hd") {
+                cmd_create_hd_wallet(passphrase)?
             } else {
-                println!("from not supply!: usage\n{}", matches.usage());
-                exit(1)
+                (cmd_create_wallet()?, None)
             };
-            let to = if let Some(address) = matches.value_of("to") {
-                address
-            } else {
-                println!("to not supply!: usage\n{}", matches.usage());
-                exit(1)
+            let text = match &mnemonic {
+                Some(mnemonic) => format!(
+                    "mnemonic: {}\nwrite down the mnemonic, it is the only backup of your HD wallets\naddress: {}",
+                    mnemonic, address
+                ),
+                None => format!("address: {}", address),
             };
-            let amount: i32 = if let Some(amount) = matches.value_of("amount") {
-                amount.parse()?
+            out.print(json!({"address": address, "mnemonic": mnemonic}), &text)
+        }
+        ("list", Some(_)) => {
+            let wallets = cmd_list_address()?;
+            let mut text = String::from("addresses: ");
+            for (address, rotated_to) in &wallets {
+                match rotated_to {
+                    Some(to) => text += &format!("\n{} (deprecated, rotated to {})", address, to),
+                    None => text += &format!("\n{}", address),
+                }
+            }
+            let value: Vec<Value> = wallets
+                .iter()
+                .map(|(address, to)| json!({"address": address, "rotated_to": to}))
+                .collect();
+            out.print(json!(value), &text)
+        }
+        ("balance", Some(matches)) => {
+            let address = matches.value_of("address").unwrap();
+            let balance = cmd_get_balance(address)?;
+            out.print(
+                json!({"address": address, "balance": balance}),
+                &format!("Balance: {}", balance),
+            )
+        }
+        ("history", Some(matches)) => {
+            let txs = cmd_history(matches.value_of("address").unwrap())?;
+            let text: Vec<String> = txs
+                .iter()
+                .map(|tx| format!("{} {} {}", tx.height, tx.txid, tx.block))
+                .collect();
+            out.print(json!(txs), &text.join("\n"))
+        }
+        ("convert-address", Some(matches)) => {
+            let network = if matches.is_present("testnet") {
+                address::Network::Testnet
             } else {
-                println!("amount in send not supply!: usage\n{}", matches.usage());
-                exit(1)
+                address::Network::Mainnet
             };
-            if matches.is_present("mine") {
-                cmd_send(from, to, amount, true)?;
-            } else {
-                cmd_send(from, to, amount, false)?;
-            }
-        } else if let Some(ref matches) = matches.subcommand_matches("startnode") {
-            if let Some(port) = matches.value_of("port") {
-                println!("Start node...");
-                let bc = Blockchain::new()?;
-                let utxo_set = UTXOSet { blockchain: bc };
-                let server = Server::new(matches.value_of("host").unwrap_or("0.0.0.0"), port, "", matches.value_of("bootstrap"), utxo_set)?;
-                server.start_server()?;
+            let from = matches.value_of("address").unwrap();
+            let (legacy, v2) = (address::to_legacy(from)?, address::to_v2(from, network)?);
+            out.print(
+                json!({"legacy": legacy, "v2": v2}),
+                &format!("legacy: {}\nv2: {}", legacy, v2),
+            )
+        }
+        ("encrypt", Some(_)) => {
+            cmd_encrypt_wallet()?;
+            out.print(json!({"encrypted": true}), "wallets encrypted")
+        }
+        ("rekey", Some(matches)) => {
+            let address = matches.value_of("address").unwrap();
+            let fee = matches.value_of("fee").unwrap_or("0").parse()?;
+            let rekey = cmd_rekey(address, fee, matches.is_present("mine"))?;
+            let mut text = format!(
+                "{} is deprecated, new address: {}",
+                address, rekey.new_address
+            );
+            text += &match (&rekey.sweep, rekey.mined) {
+                (Some(_), true) => format!("\nswept to {}", rekey.new_address),
+                (Some(txid), false) => format!("\nsweep transaction {} sent", txid),
+                (None, _) => String::from("\nno unlocked outputs to sweep"),
+            };
+            out.print(
+                json!({"address": address, "new_address": rekey.new_address, "sweep": rekey.sweep, "mined": rekey.mined}),
+                &text,
+            )
+        }
+        ("policy", Some(matches)) => {
+            let address = matches.value_of("address").unwrap();
+            if matches.is_present("clear") {
+                cmd_set_key_policy(address, None)?;
+            } else if matches.is_present("daily-limit")
+                || matches.is_present("allow")
+                || matches.is_present("confirm-above")
+            {
+                let policy = KeyPolicy {
+                    daily_limit: matches
+                        .value_of("daily-limit")
+                        .map(str::parse)
+                        .transpose()?,
+                    allowed_destinations: matches
+                        .values_of("allow")
+                        .map(|a| a.map(String::from).collect())
+                        .unwrap_or_default(),
+                    confirm_above: matches
+                        .value_of("confirm-above")
+                        .map(str::parse)
+                        .transpose()?,
+                };
+                cmd_set_key_policy(address, Some(&policy))?;
             }
-        } else if let Some(ref matches) = matches.subcommand_matches("startminer") {
-            let _address = if let Some(address) = matches.value_of("address") {
-                address
-            } else {
-                println!("address not supply!: usage\n{}", matches.usage());
-                exit(1)
+            let policy = KeyPolicies::open()?.get(address)?;
+            let text = match &policy {
+                Some(policy) => serde_json::to_string_pretty(policy)?,
+                None => format!("no key policy for {}", address),
             };
-            let port = if let Some(port) = matches.value_of("port") {
-                port
-            } else {
-                println!("port not supply!: usage\n{}", matches.usage());
-                exit(1)
+            out.print(json!({"address": address, "policy": policy}), &text)
+        }
+        (command @ "lock-output", Some(matches)) | (command @ "unlock-output", Some(matches)) => {
+            let (txid, vout) = (
+                matches.value_of("txid").unwrap(),
+                matches.value_of("vout").unwrap().parse()?,
+            );
+            let lock = command == "lock-output";
+            let changed = cmd_lock_output(txid, vout, lock)?;
+            let text = match (lock, changed) {
+                (true, true) => format!("{}:{} locked", txid, vout),
+                (true, false) => format!("{}:{} was already locked", txid, vout),
+                (false, true) => format!("{}:{} unlocked", txid, vout),
+                (false, false) => format!("{}:{} was not locked", txid, vout),
             };
-            println!("Start miner node...");
-            let bc = Blockchain::new()?;
-            let utxo_set = UTXOSet { blockchain: bc };
-            let server = Server::new(matches.value_of("host").unwrap_or("0.0.0.0"), port, "", matches.value_of("bootstrap"), utxo_set)?;
-            server.start_server()?;
+            out.print(
+                json!({"txid": txid, "vout": vout, "locked": lock, "changed": changed}),
+                &text,
+            )
         }
+        ("locked-outputs", Some(_)) => {
+            let mut locked: Vec<(String, i32)> = CoinLocks::open()?.locked()?.into_iter().collect();
+            locked.sort();
+            let text: Vec<String> = locked
+                .iter()
+                .map(|(txid, vout)| format!("{}:{}", txid, vout))
+                .collect();
+            let value: Vec<Value> = locked
+                .iter()
+                .map(|(txid, vout)| json!({"txid": txid, "vout": vout}))
+                .collect();
+            out.print(json!(value), &text.join("\n"))
+        }
+        _ => unreachable!("a wallet command is required"),
+    }
+}
 
-        Ok(())
+fn run_tx(matches: &ArgMatches, out: &Output) -> Result<()> {
+    match matches.subcommand() {
+        ("send", Some(matches)) => {
+            let from = matches.value_of("from").unwrap();
+            let to = matches.value_of("to").unwrap();
+            let amount: i32 = matches.value_of("amount").unwrap().parse()?;
+            let options = SendOptions {
+                signer: matches.value_of("signer"),
+                coin_selection: matches
+                    .value_of("coin-selection")
+                    .unwrap_or("largest")
+                    .parse()?,
+                fee: match matches.value_of("estimate-fee") {
+                    Some(priority) => cmd_estimate_fee(priority.parse()?)?,
+                    None => matches.value_of("fee").unwrap_or("0").parse()?,
+                },
+                confirmed: matches.is_present("confirm"),
+            };
+            let mined = matches.is_present("mine");
+            let txid = cmd_send(from, to, amount, mined, &options)?;
+            out.print(
+                json!({"txid": txid, "fee": options.fee, "mined": mined}),
+                &format!("success! transaction {} with fee {}", txid, options.fee),
+            )
+        }
+        ("checkpoint", Some(matches)) => {
+            let height: i32 = matches.value_of("height").unwrap().parse()?;
+            let txid = cmd_checkpoint(height, matches.value_of("mine"))?;
+            out.print(
+                json!({"txid": txid, "height": height, "mined": matches.is_present("mine")}),
+                &format!("success! checkpoint transaction {}", txid),
+            )
+        }
+        _ => unreachable!("a tx command is required"),
+    }
+}
+
+fn run_chain(matches: &ArgMatches, out: &Output) -> Result<()> {
+    match matches.subcommand() {
+        ("create", Some(matches)) => {
+            let (chain_id, genesis) = match matches.value_of("genesis") {
+                Some(file) => cmd_create_blockchain_from_genesis(file)?,
+                None => cmd_create_blockchain(matches.value_of("address").unwrap())?,
+            };
+            out.print(
+                json!({"chain_id": chain_id, "genesis": genesis}),
+                &format!(
+                    "create blockchain {} with genesis block {}",
+                    chain_id, genesis
+                ),
+            )
+        }
+        ("print", Some(_)) => {
+            let blocks = cmd_print_chain()?;
+            let text: Vec<String> = blocks.iter().map(|b| format!("{:#?}", b)).collect();
+            out.print(json!(blocks), &text.join("\n"))
+        }
+        ("reindex", Some(_)) => {
+            let count = cmd_reindex()?;
+            out.print(
+                json!({"transactions": count}),
+                &format!("Done! There are {} transactions in the UTXO set.", count),
+            )
+        }
+        _ => unreachable!("a chain command is required"),
+    }
+}
+
+fn run_genesis(matches: &ArgMatches, out: &Output) -> Result<()> {
+    match matches.subcommand() {
+        ("generate", Some(matches)) => {
+            let mut allocations = Vec::new();
+            for alloc in matches.values_of("alloc").unwrap() {
+                allocations.push(parse_allocation(alloc)?);
+            }
+            let config = GenesisConfig::new(matches.value_of("chain-id").unwrap(), allocations)?;
+            config.validate()?;
+            match matches.value_of("out") {
+                Some(file) => config.save(file)?,
+                None => println!("{}", serde_json::to_string_pretty(&config)?),
+            }
+            Ok(())
+        }
+        ("validate", Some(matches)) => {
+            let config = GenesisConfig::load(matches.value_of("file").unwrap())?;
+            let hash = config.hash()?;
+            out.print(
+                json!({"chain_id": config.chain_id, "genesis": hash}),
+                &format!("chain {}: genesis {}", config.chain_id, hash),
+            )
+        }
+        _ => unreachable!("a genesis command is required"),
+    }
+}
+
+fn run_node(matches: &ArgMatches, out: &Output) -> Result<()> {
+    match matches.subcommand() {
+        ("start", Some(matches)) => cmd_start_node(matches),
+        ("mempool", Some(matches)) => {
+            let rpc = format!("127.0.0.1:{}", matches.value_of("rpc-port").unwrap());
+            let watch = match matches.value_of("watch") {
+                Some(secs) => Some(Duration::from_secs(secs.parse()?)),
+                None => None,
+            };
+            cmd_mempool(&rpc, watch, out)
+        }
+        ("features", Some(matches)) => cmd_features(
+            &format!("127.0.0.1:{}", matches.value_of("rpc-port").unwrap()),
+            out,
+        ),
+        ("peers", Some(matches)) => {
+            let rpc = format!("127.0.0.1:{}", matches.value_of("rpc-port").unwrap());
+            match matches.subcommand() {
+                ("export", Some(export)) => cmd_export_peers(&rpc, export.value_of("file")),
+                ("import", Some(import)) => {
+                    let count = cmd_import_peers(&rpc, import.value_of("file").unwrap())?;
+                    out.print(
+                        json!({"imported": count}),
+                        &format!("imported {} peers", count),
+                    )
+                }
+                ("stats", Some(stats)) => {
+                    let stats = rpc::call(
+                        &rpc,
+                        "getpeerstats",
+                        json!([stats.value_of("addr").unwrap()]),
+                    )?;
+                    println!("{}", serde_json::to_string_pretty(&stats)?);
+                    Ok(())
+                }
+                _ => unreachable!("a peers command is required"),
+            }
+        }
+        _ => unreachable!("a node command is required"),
+    }
+}
+
+fn run_signer(matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("start", Some(matches)) => {
+            println!("Start signer...");
+            let port = matches.value_of("port").unwrap();
+            let host = matches.value_of("host").unwrap_or("127.0.0.1");
+            let listener = TcpListener::bind(format!("{}:{}", host, port))?;
+            let signer =
+                SignerServer::new(open_wallets()?, KeyPolicies::open()?, &signer_secret()?)?;
+            signer.serve(listener)
+        }
+        _ => unreachable!("a signer command is required"),
+    }
+}
+
+fn run_policy(matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("sign", Some(matches)) => {
+            let authority = matches.value_of("authority").unwrap();
+            let version: u64 = matches.value_of("version").unwrap().parse()?;
+            cmd_sign_policy(authority, version, matches.value_of("file").unwrap())
+        }
+        _ => unreachable!("a policy command is required"),
+    }
+}
+
+fn run_telemetry(matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("export", Some(matches)) => cmd_export_telemetry(matches.value_of("out")),
+        _ => unreachable!("a telemetry command is required"),
+    }
+}
+
+/// cmd_start_node runs the node server, mining blocks when given --mine
+fn cmd_start_node(matches: &ArgMatches) -> Result<()> {
+    let port = matches.value_of("port").unwrap();
+    let mining_address = matches.value_of("mine").unwrap_or("");
+    if mining_address.is_empty() {
+        println!("Start node...");
+    } else {
+        println!("Start miner node...");
+    }
+    let bc = Blockchain::new()?;
+    let utxo_set = UTXOSet { blockchain: bc };
+    let server = Server::new(
+        matches.value_of("host").unwrap_or("0.0.0.0"),
+        port,
+        mining_address,
+        matches.value_of("bootstrap"),
+        utxo_set,
+    )?;
+    if let Some(count) = matches.value_of("fast-relay") {
+        server.set_fast_relay_count(count.parse()?);
+    }
+    server.set_bandwidth_limits(
+        matches
+            .value_of("max-peer-bandwidth")
+            .unwrap_or("0")
+            .parse()?,
+        matches.value_of("max-bandwidth").unwrap_or("0").parse()?,
+    );
+    if !mining_address.is_empty() {
+        server.set_block_assembly(
+            matches.value_of("tx-order").unwrap_or("oldest").parse()?,
+            matches.value_of("max-block-txs").unwrap_or("0").parse()?,
+        );
+    }
+    if matches.is_present("statesync") {
+        let trusted_root = match matches.value_of("statesync-root") {
+            Some(root) => Some(hex::decode(root)?),
+            None if matches.is_present("statesync-unverified") => None,
+            None => {
+                return Err(format_err!(
+                    "state sync needs --statesync-root, or --statesync-unverified to trust any peer"
+                ))
+            }
+        };
+        server.enable_state_sync(trusted_root)?;
+    }
+    if matches.is_present("encrypt") || matches.is_present("require-encryption") {
+        server.enable_encryption(matches.is_present("require-encryption"))?;
+    }
+    if let Some(port) = matches.value_of("rpc-port") {
+        server.start_rpc(&format!("127.0.0.1:{}", port))?;
+    }
+    if let Some(port) = matches.value_of("metrics-port") {
+        server.start_metrics(&format!("127.0.0.1:{}", port))?;
+    }
+    if let Some(secs) = matches.value_of("idempotency-window") {
+        server.set_idempotency_window(Duration::from_secs(secs.parse()?));
+    }
+    if let Some(file) = matches.value_of("policy") {
+        server.set_policy(load_policy(file, matches.value_of("policy-authority"))?);
+    }
+    if matches.is_present("telemetry") {
+        server.enable_telemetry()?;
+    }
+    if let Some(pins) = matches.values_of("pin") {
+        server.pin_peers(&pins.map(String::from).collect::<Vec<String>>());
     }
+    if let Some(identities) = matches.values_of("system-tx-from") {
+        server.authorize_system_txs(&identities.map(String::from).collect::<Vec<String>>());
+    }
+    if matches.is_present("watchdog") {
+        server.enable_watchdog(
+            matches
+                .value_of("watchdog-min-peers")
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or(DEFAULT_MIN_PEERS),
+            matches.value_of("watchdog-page"),
+        );
+    }
+    server.start_server()
+}
+
+/// SendOptions are the optional settings of the send command
+#[derive(Default)]
+struct SendOptions<'a> {
+    /// endpoint of a remote signer, the local wallet if None
+    signer: Option<&'a str>,
+    coin_selection: CoinSelection,
+    fee: i32,
+    /// approves a spend above the confirmation threshold of the key policy
+    confirmed: bool,
 }
 
-fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
+/// cmd_send pays `amount` from `from` to `to` and returns the id of the transaction
+fn cmd_send(
+    from: &str,
+    to: &str,
+    amount: i32,
+    mine_now: bool,
+    options: &SendOptions,
+) -> Result<String> {
     let bc = Blockchain::new()?;
     let mut utxo_set = UTXOSet { blockchain: bc };
-    let wallets = Wallets::new()?;
-    let wallet = wallets.get_wallet(from).unwrap();
-    let tx = Transaction::new_UTXO(wallet, to, amount, &utxo_set)?;
+    let locked = CoinLocks::open()?.locked()?;
+    let build = |signer: &dyn Signer| {
+        TransactionBuilder::new(signer)
+            .pay_to(to, amount)
+            .fee(options.fee)
+            .coin_selection(options.coin_selection)
+            .confirmed(options.confirmed)
+            .skip_locked(locked.clone())
+            .build(&utxo_set)
+    };
+    let tx = match options.signer {
+        Some(endpoint) => build(&RemoteSigner::new(endpoint, from, &signer_secret()?))?,
+        None => {
+            let wallets = open_wallets()?;
+            if let Some(rotation) = wallets.rotation(from) {
+                eprintln!(
+                    "warning: {} is deprecated, its key was rotated to {}",
+                    from, rotation.to
+                );
+            }
+            let wallet = wallets.get_wallet(from).unwrap();
+            build(&PolicySigner::new(wallet, from)?)?
+        }
+    };
+    let txid = tx.id.clone();
     if mine_now {
-        let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward!"))?;
+        let fee = utxo_set.blockchain.get_fee(&tx)?;
+        let cbtx =
+            Transaction::new_coinbase_with_fees(from.to_string(), String::from("reward!"), fee)?;
         let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
 
         utxo_set.update(&new_block)?;
     } else {
         Server::send_transaction(&tx, utxo_set)?;
     }
+    Ok(txid)
+}
+
+/// Rekey is the outcome of rotating the key of a wallet
+struct Rekey {
+    new_address: String,
+    /// id of the sweep transaction, None when nothing was left to sweep
+    sweep: Option<String>,
+    /// whether the sweep was mined locally instead of sent to the node
+    mined: bool,
+}
+
+/// cmd_rekey rotates the key of `address` and sweeps its unlocked outputs to the new key
+fn cmd_rekey(address: &str, fee: i32, mine_now: bool) -> Result<Rekey> {
+    let mut ws = open_wallets()?;
+    let new_address = ws.rekey(address)?;
+    let bc = Blockchain::new()?;
+    let mut utxo_set = UTXOSet { blockchain: bc };
+    let locked = CoinLocks::open()?.locked()?;
+    let wallet = ws.get_wallet(address).unwrap();
+    let mut pub_key_hash = wallet.public_key.clone();
+    hash_pub_key(&mut pub_key_hash);
+    let unlocked = utxo_set
+        .find_coins(&pub_key_hash)?
+        .iter()
+        .any(|c| !locked.contains(&(c.txid.clone(), c.vout)));
+    let sweep = if unlocked {
+        let signer = PolicySigner::new(wallet, address)?;
+        Some(
+            TransactionBuilder::new(&signer)
+                .sweep_to(&new_address)
+                .fee(fee)
+                .confirmed(true)
+                .skip_locked(locked)
+                .build(&utxo_set)?,
+        )
+    } else {
+        None
+    };
+    // the new key is saved before its first output exists
+    ws.save_all()?;
+
+    let txid = sweep.as_ref().map(|tx| tx.id.clone());
+    match sweep {
+        Some(tx) if mine_now => {
+            let fee = utxo_set.blockchain.get_fee(&tx)?;
+            let cbtx = Transaction::new_coinbase_with_fees(
+                new_address.clone(),
+                String::from("reward!"),
+                fee,
+            )?;
+            let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
+            utxo_set.update(&new_block)?;
+        }
+        Some(tx) => Server::send_transaction(&tx, utxo_set)?,
+        None => {}
+    }
+    Ok(Rekey {
+        new_address,
+        mined: mine_now && txid.is_some(),
+        sweep: txid,
+    })
+}
+
+/// cmd_lock_output locks or unlocks an output, returning false when it already was
+fn cmd_lock_output(txid: &str, vout: i32, lock: bool) -> Result<bool> {
+    let locks = CoinLocks::open()?;
+    if lock {
+        locks.lock(txid, vout)
+    } else {
+        locks.unlock(txid, vout)
+    }
+}
 
-    println!("success!");
+fn cmd_set_key_policy(address: &str, policy: Option<&KeyPolicy>) -> Result<()> {
+    if open_wallets()?.get_wallet(address).is_none() {
+        return Err(format_err!("no wallet for {}", address));
+    }
+    KeyPolicies::open()?.set(address, policy)
+}
+
+fn cmd_estimate_fee(priority: Priority) -> Result<i32> {
+    let bc = Blockchain::new()?;
+    Ok(bc.estimate_fee(0)?.get(priority))
+}
+
+fn cmd_sign_policy(authority: &str, version: u64, file: &str) -> Result<()> {
+    let addresses: Vec<String> = std::fs::read_to_string(file)?
+        .lines()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect();
+    let wallets = open_wallets()?;
+    let wallet = match wallets.get_wallet(authority) {
+        Some(w) => w,
+        None => return Err(format_err!("no wallet for {}", authority)),
+    };
+    let list = PolicyList::sign(version, addresses, wallet)?;
+    println!("{}", serde_json::to_string_pretty(&list)?);
     Ok(())
 }
 
-fn cmd_create_wallet() -> Result<String> {
+fn cmd_export_telemetry(out: Option<&str>) -> Result<()> {
+    let arrivals = read_arrivals(ARRIVAL_LOG_PATH)?;
+    match out {
+        Some(path) => export_csv(&arrivals, &mut std::fs::File::create(path)?)?,
+        None => export_csv(&arrivals, &mut std::io::stdout())?,
+    }
+    Ok(())
+}
+
+fn cmd_features(rpc: &str, out: &Output) -> Result<()> {
+    let capabilities = rpc::call(rpc, "getcapabilities", json!([]))?;
+    if out.json {
+        println!("{}", serde_json::to_string_pretty(&capabilities)?);
+        return Ok(());
+    }
+    let services: Vec<&str> = capabilities["services"]
+        .as_array()
+        .map(|s| s.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    println!(
+        "{} (protocol {}), services: {}",
+        capabilities["user_agent"].as_str().unwrap_or_default(),
+        capabilities["protocol_version"],
+        services.join(", ")
+    );
+    for feature in capabilities["features"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+    {
+        let state = if feature["enabled"] == json!(true) {
+            "on "
+        } else {
+            "off"
+        };
+        let mut line = format!("{} {}", state, feature["name"].as_str().unwrap_or_default());
+        if let Some(version) = feature["version"].as_u64() {
+            line += &format!(" version {}", version);
+        }
+        if let Some(digest) = feature["config_digest"].as_str() {
+            line += &format!(" config {}", &digest[..16.min(digest.len())]);
+        }
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn cmd_mempool(rpc: &str, watch: Option<Duration>, out: &Output) -> Result<()> {
+    loop {
+        let info = rpc::call(rpc, "getmempoolinfo", json!([]))?;
+        if out.json {
+            println!("{}", serde_json::to_string_pretty(&info)?);
+            match watch {
+                Some(interval) => std::thread::sleep(interval),
+                None => return Ok(()),
+            }
+            continue;
+        }
+        let mining = if info["mining"] == json!(true) {
+            "on"
+        } else {
+            "off"
+        };
+        println!("pending: {} mining: {}", info["size"], mining);
+        let buckets = info["fee_histogram"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let most = buckets
+            .iter()
+            .filter_map(|b| b["count"].as_u64())
+            .max()
+            .unwrap_or(0);
+        for bucket in &buckets {
+            let count = bucket["count"].as_u64().unwrap_or(0);
+            let bar = "#".repeat((count * 40).div_ceil(most.max(1)) as usize);
+            println!("  fee >= {:>6}: {:>5} {}", bucket["min_fee"], count, bar);
+        }
+        let times: Vec<String> = info["recent_block_times_ms"]
+            .as_array()
+            .map(|t| {
+                t.iter()
+                    .filter_map(Value::as_u64)
+                    .map(|ms| format!("{}s", ms / 1000))
+                    .collect()
+            })
+            .unwrap_or_default();
+        println!("recent block times: {}", times.join(" "));
+        match watch {
+            Some(interval) => {
+                std::thread::sleep(interval);
+                println!();
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+fn cmd_export_peers(rpc: &str, file: Option<&str>) -> Result<()> {
+    let peers = serde_json::to_string_pretty(&rpc::call(rpc, "exportpeers", json!([]))?)?;
+    match file {
+        Some(path) => std::fs::write(path, peers)?,
+        None => println!("{}", peers),
+    }
+    Ok(())
+}
+
+fn cmd_import_peers(rpc: &str, file: &str) -> Result<Value> {
+    let peers: Value = serde_json::from_slice(&std::fs::read(file)?)?;
+    rpc::call(rpc, "importpeers", json!([peers]))
+}
+
+fn load_policy(file: &str, authority: Option<&str>) -> Result<CompliancePolicy> {
+    match authority {
+        Some(authority) => CompliancePolicy::load(file, authority),
+        None => Err(format_err!("--policy needs --policy-authority")),
+    }
+}
+
+fn cmd_checkpoint(height: i32, mine_to: Option<&str>) -> Result<String> {
+    let bc = Blockchain::new()?;
+    let block_hash = match bc.block_hash_at(height)? {
+        Some(hash) => hash,
+        None => return Err(format_err!("no block at height {}", height)),
+    };
+    let checkpoint = Checkpoint { height, block_hash };
+    let tx = SystemTxEnvelope::new(KIND_CHECKPOINT, &checkpoint)?.into_transaction()?;
+    let txid = tx.id.clone();
+
+    let mut utxo_set = UTXOSet { blockchain: bc };
+    if let Some(address) = mine_to {
+        let cbtx = Transaction::new_coinbase(address.to_string(), String::from("reward!"))?;
+        let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
+        utxo_set.update(&new_block)?;
+    } else {
+        Server::send_transaction(&tx, utxo_set)?;
+    }
+    Ok(txid)
+}
+
+/// Environment variable read before prompting for the wallet password
+const PASSWORD_ENV: &str = "POLYTORUS_WALLET_PASSWORD";
+
+fn read_password(prompt: &str) -> Result<String> {
+    if let Ok(password) = std::env::var(PASSWORD_ENV) {
+        return Ok(password);
+    }
+    Ok(rpassword::prompt_password(prompt)?)
+}
+
+/// signer_secret reads the secret shared with the remote signer
+fn signer_secret() -> Result<Vec<u8>> {
+    match std::env::var(SIGNER_SECRET_ENV) {
+        Ok(secret) if !secret.is_empty() => Ok(secret.into_bytes()),
+        _ => Err(format_err!(
+            "set {} to the secret shared with the signer",
+            SIGNER_SECRET_ENV
+        )),
+    }
+}
+
+/// open_wallets loads the wallets and asks for the password if they are encrypted
+fn open_wallets() -> Result<Wallets> {
     let mut ws = Wallets::new()?;
+    if ws.is_locked() {
+        ws.unlock(&read_password("Wallet password: ")?)?;
+    }
+    Ok(ws)
+}
+
+fn cmd_encrypt_wallet() -> Result<()> {
+    let mut ws = open_wallets()?;
+    let password = read_password("New wallet password: ")?;
+    if std::env::var(PASSWORD_ENV).is_err()
+        && rpassword::prompt_password("Repeat password: ")? != password
+    {
+        return Err(format_err!("passwords do not match"));
+    }
+    if password.is_empty() {
+        return Err(format_err!("empty wallet password"));
+    }
+    ws.encrypt(&password)?;
+    ws.save_all()
+}
+
+fn cmd_create_wallet() -> Result<String> {
+    let mut ws = open_wallets()?;
     let address = ws.create_wallet();
     ws.save_all()?;
     Ok(address)
 }
 
+/// cmd_create_hd_wallet derives the next HD wallet, returning the mnemonic too when it created the seed
+fn cmd_create_hd_wallet(passphrase: &str) -> Result<(String, Option<String>)> {
+    let mut ws = open_wallets()?;
+    let mnemonic = if ws.has_hd_seed() {
+        None
+    } else {
+        Some(ws.init_hd(passphrase)?)
+    };
+    let address = ws.create_hd_wallet()?;
+    ws.save_all()?;
+    Ok((address, mnemonic))
+}
+
+fn cmd_restore_wallet(mnemonic: &str, passphrase: &str, force: bool) -> Result<String> {
+    let mut ws = open_wallets()?;
+    let address = ws.restore_hd(mnemonic, passphrase, force)?;
+    ws.save_all()?;
+    Ok(address)
+}
+
+fn cmd_derive_wallet(path: &str) -> Result<String> {
+    let mut ws = open_wallets()?;
+    let address = ws.derive_wallet(&path.parse()?)?;
+    ws.save_all()?;
+    Ok(address)
+}
+
 fn cmd_reindex() -> Result<i32> {
     let bc = Blockchain::new()?;
     let utxo_set = UTXOSet { blockchain: bc };
@@ -180,18 +1281,29 @@ fn cmd_reindex() -> Result<i32> {
     utxo_set.count_transactions()
 }
 
-fn cmd_create_blockchain(address: &str) -> Result<()> {
+/// cmd_create_blockchain creates the chain and returns its chain id and genesis block hash
+fn cmd_create_blockchain(address: &str) -> Result<(String, String)> {
     let address = String::from(address);
     let bc = Blockchain::create_blockchain(address)?;
+    let created = (bc.chain_id()?, bc.tip.clone());
 
     let utxo_set = UTXOSet { blockchain: bc };
     utxo_set.reindex()?;
-    println!("create blockchain");
-    Ok(())
+    Ok(created)
+}
+
+fn cmd_create_blockchain_from_genesis(file: &str) -> Result<(String, String)> {
+    let config = GenesisConfig::load(file)?;
+    let bc = Blockchain::create_from_genesis(&config)?;
+    let genesis = bc.tip.clone();
+
+    let utxo_set = UTXOSet { blockchain: bc };
+    utxo_set.reindex()?;
+    Ok((config.chain_id, genesis))
 }
 
 fn cmd_get_balance(address: &str) -> Result<i32> {
-    let pub_key_hash = Address::decode(address).unwrap().body;
+    let pub_key_hash = address::pub_key_hash(address)?;
     let bc = Blockchain::new()?;
     let utxo_set = UTXOSet { blockchain: bc };
     let utxos = utxo_set.find_UTXO(&pub_key_hash)?;
@@ -203,22 +1315,30 @@ fn cmd_get_balance(address: &str) -> Result<i32> {
     Ok(balance)
 }
 
-fn cmd_print_chain() -> Result<()> {
+fn cmd_history(address: &str) -> Result<Vec<TxLocation>> {
+    let pub_key_hash = address::pub_key_hash(address)?;
     let bc = Blockchain::new()?;
-    for b in bc.iter() {
-        println!("{:#?}", b);
-    }
-    Ok(())
+    let index = TxIndex::open(&bc)?;
+    index.sync(&bc)?;
+    index.address_transactions(&pub_key_hash)
 }
 
-fn cmd_list_address() -> Result<()> {
+fn cmd_print_chain() -> Result<Vec<Block>> {
+    let bc = Blockchain::new()?;
+    Ok(bc.iter().collect())
+}
+
+/// cmd_list_address returns the addresses with the address each deprecated one was rotated to
+fn cmd_list_address() -> Result<Vec<(String, Option<String>)>> {
     let ws = Wallets::new()?;
     let addresses = ws.get_all_addresses();
-    println!("addresses: ");
-    for ad in addresses {
-        println!("{}", ad);
-    }
-    Ok(())
+    Ok(addresses
+        .into_iter()
+        .map(|ad| {
+            let rotated_to = ws.rotation(&ad).map(|rotation| rotation.to.clone());
+            (ad, rotated_to)
+        })
+        .collect())
 }
 
 #[cfg(test)]
@@ -227,6 +1347,7 @@ mod test {
 
     #[test]
     fn test_locally() {
+        let _db = TEST_DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         let addr1 = cmd_create_wallet().unwrap();
         let addr2 = cmd_create_wallet().unwrap();
         cmd_create_blockchain(&addr1).unwrap();
@@ -236,17 +1357,93 @@ mod test {
         assert_eq!(b1, 10);
         assert_eq!(b2, 0);
 
-        cmd_send(&addr1, &addr2, 5, true).unwrap();
+        cmd_send(&addr1, &addr2, 5, true, &SendOptions::default()).unwrap();
 
         let b1 = cmd_get_balance(&addr1).unwrap();
         let b2 = cmd_get_balance(&addr2).unwrap();
         assert_eq!(b1, 15);
         assert_eq!(b2, 5);
 
-        cmd_send(&addr2, &addr1, 15, true).unwrap_err();
+        cmd_send(&addr2, &addr1, 15, true, &SendOptions::default()).unwrap_err();
         let b1 = cmd_get_balance(&addr1).unwrap();
         let b2 = cmd_get_balance(&addr2).unwrap();
         assert_eq!(b1, 15);
         assert_eq!(b2, 5);
+
+        // addr2 mines the block and collects the fee in its coinbase, the
+        // exact match leaves no change
+        let options = SendOptions {
+            coin_selection: CoinSelection::BranchAndBound,
+            fee: 1,
+            ..SendOptions::default()
+        };
+        cmd_send(&addr2, &addr1, 4, true, &options).unwrap();
+        let b1 = cmd_get_balance(&addr1).unwrap();
+        let b2 = cmd_get_balance(&addr2).unwrap();
+        assert_eq!(b1, 19);
+        assert_eq!(b2, 11);
+    }
+
+    #[test]
+    fn test_command_line() {
+        let matches = app()
+            .get_matches_from_safe(vec![
+                "polytorus",
+                "node",
+                "start",
+                "7000",
+                "--mine",
+                "addr",
+                "--json",
+            ])
+            .unwrap();
+        let node = matches.subcommand_matches("node").unwrap();
+        let start = node.subcommand_matches("start").unwrap();
+        assert_eq!(start.value_of("port"), Some("7000"));
+        assert_eq!(start.value_of("mine"), Some("addr"));
+        assert!(Output::new(start).json);
+
+        // --json is accepted before the subcommand too
+        let matches = app()
+            .get_matches_from_safe(vec!["polytorus", "--json", "wallet", "list"])
+            .unwrap();
+        let list = matches
+            .subcommand_matches("wallet")
+            .unwrap()
+            .subcommand_matches("list")
+            .unwrap();
+        assert!(Output::new(list).json);
+
+        // block assembly settings only apply to a mining node
+        let err = app()
+            .get_matches_from_safe(vec![
+                "polytorus",
+                "node",
+                "start",
+                "7000",
+                "--tx-order",
+                "fee",
+            ])
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::MissingRequiredArgument);
+        let err = app()
+            .get_matches_from_safe(vec!["polytorus", "wallet"])
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::MissingArgumentOrSubcommand);
+
+        assert_eq!(
+            renamed_command("startminer"),
+            Some("node start --mine <address>")
+        );
+        assert_eq!(renamed_command("createblockchain"), Some("chain create"));
+        assert_eq!(renamed_command("wallet"), None);
+        // every replacement is a command of the new command line
+        for (_, new) in RENAMED_COMMANDS.iter() {
+            let args: Vec<&str> = new.split(' ').take(2).collect();
+            let err = app()
+                .get_matches_from_safe([&["polytorus"], &args[..], &["--help"]].concat())
+                .unwrap_err();
+            assert_eq!(err.kind, ErrorKind::HelpDisplayed, "{}", new);
+        }
     }
 }
@@ -0,0 +1,84 @@
+//! Locked wallet outputs
+//!
+//! Outputs earmarked for something else, e.g. a pending settlement, can be
+//! locked so a send does not spend them by accident. Coin selection skips
+//! locked outputs until they are unlocked. Locks are kept under
+//! data/coinlocks and survive restarts.
+
+use super::*;
+use failure::format_err;
+use std::collections::HashSet;
+
+const COIN_LOCKS_PATH: &str = "data/coinlocks";
+
+/// CoinLocks stores the locked outputs as `txid:vout`
+pub struct CoinLocks {
+    locked: sled::Tree,
+}
+
+impl CoinLocks {
+    pub fn open() -> Result<CoinLocks> {
+        CoinLocks::with_db(&sled::open(COIN_LOCKS_PATH)?)
+    }
+
+    /// WithDb keeps the locks in a tree of an open database
+    pub fn with_db(db: &sled::Db) -> Result<CoinLocks> {
+        Ok(CoinLocks {
+            locked: db.open_tree("locked")?,
+        })
+    }
+
+    /// Lock locks an output and reports whether it was unlocked before
+    pub fn lock(&self, txid: &str, vout: i32) -> Result<bool> {
+        let was_locked = self.locked.insert(outpoint(txid, vout)?, &[])?.is_some();
+        self.locked.flush()?;
+        Ok(!was_locked)
+    }
+
+    /// Unlock unlocks an output and reports whether it was locked
+    pub fn unlock(&self, txid: &str, vout: i32) -> Result<bool> {
+        let was_locked = self.locked.remove(outpoint(txid, vout)?)?.is_some();
+        self.locked.flush()?;
+        Ok(was_locked)
+    }
+
+    /// Locked returns the locked outputs
+    pub fn locked(&self) -> Result<HashSet<(String, i32)>> {
+        let mut locked = HashSet::new();
+        for key in self.locked.iter().keys() {
+            let key = String::from_utf8(key?.to_vec())?;
+            match key.rsplit_once(':') {
+                Some((txid, vout)) => locked.insert((txid.to_string(), vout.parse()?)),
+                None => return Err(format_err!("corrupted coin lock {}", key)),
+            };
+        }
+        Ok(locked)
+    }
+}
+
+fn outpoint(txid: &str, vout: i32) -> Result<String> {
+    if txid.is_empty() || txid.contains(':') || vout < 0 {
+        return Err(format_err!("invalid output {}:{}", txid, vout));
+    }
+    Ok(format!("{}:{}", txid, vout))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_coin_locks() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let locks = CoinLocks::with_db(&db).unwrap();
+        assert!(locks.lock("aa", 0).unwrap());
+        assert!(!locks.lock("aa", 0).unwrap());
+        assert!(locks.lock("aa", 1).unwrap());
+        assert!(locks.lock("bb", -1).is_err());
+        assert!(locks.unlock("aa", 0).unwrap());
+        assert!(!locks.unlock("aa", 0).unwrap());
+        let locked = locks.locked().unwrap();
+        assert_eq!(locked.len(), 1);
+        assert!(locked.contains(&(String::from("aa"), 1)));
+    }
+}
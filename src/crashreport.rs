@@ -0,0 +1,273 @@
+//! Crash report bundles
+//!
+//! On a panic, or a fatal error of a long running command, a bundle with the
+//! version, the redacted command line and environment, a state summary, the
+//! recent log lines and a backtrace is written to data/crash. It is uploaded
+//! only when the operator opted in by setting both `POLYTORUS_CRASH_UPLOAD_URL`
+//! and `POLYTORUS_CRASH_UPLOAD_CONSENT=yes`.
+
+use super::*;
+use crate::logging;
+use failure::format_err;
+use std::backtrace::Backtrace;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::panic;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+const CRASH_DIR: &str = "data/crash";
+const UPLOAD_URL_ENV: &str = "POLYTORUS_CRASH_UPLOAD_URL";
+const UPLOAD_CONSENT_ENV: &str = "POLYTORUS_CRASH_UPLOAD_CONSENT";
+/// Arguments whose following value is a secret
+const SECRET_ARGS: [&str; 1] = ["--restore"];
+/// Environment variable names containing these words are redacted
+const SECRET_ENV_WORDS: [&str; 5] = ["PASSWORD", "SECRET", "KEY", "TOKEN", "MNEMONIC"];
+
+type StateSummary = Box<dyn Fn() -> String + Send>;
+
+static STATE_SUMMARY: Mutex<Option<StateSummary>> = Mutex::new(None);
+
+/// SetStateSummary registers the function describing the node state in bundles
+///
+/// The function runs inside the panic hook, so it must not block on locks
+/// that the panicking thread may hold.
+pub fn set_state_summary(summary: StateSummary) {
+    if let Ok(mut s) = STATE_SUMMARY.lock() {
+        *s = Some(summary);
+    }
+}
+
+/// CrashBundle is the content of one crash report
+pub struct CrashBundle {
+    pub reason: String,
+    pub version: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub state: String,
+    pub logs: Vec<String>,
+    pub backtrace: String,
+}
+
+impl CrashBundle {
+    /// Collect gathers a bundle for the current process
+    pub fn collect(reason: &str) -> CrashBundle {
+        let state = match STATE_SUMMARY.try_lock() {
+            Ok(s) => match s.as_ref() {
+                Some(f) => f(),
+                None => String::from("no state registered"),
+            },
+            Err(_) => String::from("state unavailable"),
+        };
+        CrashBundle {
+            reason: reason.to_string(),
+            version: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            args: redact_args(std::env::args().collect()),
+            env: redact_env(
+                std::env::vars()
+                    .filter(|(k, _)| k.starts_with("POLYTORUS_") || k == "RUST_LOG")
+                    .collect(),
+            ),
+            state,
+            logs: logging::recent_lines(),
+            backtrace: Backtrace::force_capture().to_string(),
+        }
+    }
+
+    /// Render formats the bundle as a plain text report
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("reason: {}\n", self.reason));
+        out.push_str(&format!("version: {}\n", self.version));
+        out.push_str(&format!("args: {}\n", self.args.join(" ")));
+        out.push_str("\n== environment ==\n");
+        for (k, v) in &self.env {
+            out.push_str(&format!("{}={}\n", k, v));
+        }
+        out.push_str("\n== state ==\n");
+        out.push_str(&self.state);
+        out.push_str("\n\n== recent logs ==\n");
+        for line in &self.logs {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push_str("\n== backtrace ==\n");
+        out.push_str(&self.backtrace);
+        out
+    }
+
+    /// Write stores the bundle under data/crash and returns its path
+    pub fn write(&self) -> Result<String> {
+        std::fs::create_dir_all(CRASH_DIR)?;
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis();
+        let path = format!("{}/crash-{}.txt", CRASH_DIR, millis);
+        std::fs::write(&path, self.render())?;
+        Ok(path)
+    }
+}
+
+fn redact_args(args: Vec<String>) -> Vec<String> {
+    let mut redact_next = false;
+    args.into_iter()
+        .map(|arg| {
+            let redacted = if redact_next {
+                String::from("<redacted>")
+            } else if let Some(name) = SECRET_ARGS
+                .iter()
+                .find(|a| arg.starts_with(&format!("{}=", a)))
+            {
+                format!("{}=<redacted>", name)
+            } else {
+                arg.clone()
+            };
+            redact_next = SECRET_ARGS.contains(&arg.as_str());
+            redacted
+        })
+        .collect()
+}
+
+fn redact_env(vars: Vec<(String, String)>) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = vars
+        .into_iter()
+        .map(|(k, v)| {
+            if SECRET_ENV_WORDS
+                .iter()
+                .any(|w| k.to_uppercase().contains(w))
+            {
+                (k, String::from("<redacted>"))
+            } else {
+                (k, v)
+            }
+        })
+        .collect();
+    vars.sort();
+    vars
+}
+
+/// Report writes a crash bundle and uploads it if the operator consented
+pub fn report(reason: &str) {
+    let bundle = CrashBundle::collect(reason);
+    match bundle.write() {
+        Ok(path) => eprintln!("crash report written to {}", path),
+        Err(e) => eprintln!("failed to write crash report: {}", e),
+    }
+
+    let url = match std::env::var(UPLOAD_URL_ENV) {
+        Ok(url) => url,
+        Err(_) => return,
+    };
+    if std::env::var(UPLOAD_CONSENT_ENV).as_deref() != Ok("yes") {
+        eprintln!(
+            "crash report not uploaded, set {}=yes to allow uploads",
+            UPLOAD_CONSENT_ENV
+        );
+        return;
+    }
+    if let Err(e) = upload(&url, &bundle.render()) {
+        eprintln!("failed to upload crash report: {}", e);
+    }
+}
+
+/// InstallPanicHook makes every panic produce a crash report
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        report(&format!("panic: {}", info));
+    }));
+}
+
+/// upload POSTs the report to a plain http:// endpoint
+fn upload(url: &str, body: &str) -> Result<()> {
+    let rest = match url.strip_prefix("http://") {
+        Some(r) => r,
+        None => return Err(format_err!("only http:// upload endpoints are supported")),
+    };
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    )?;
+
+    let mut status = [0u8; 12];
+    stream.read_exact(&mut status)?;
+    let status = String::from_utf8_lossy(&status);
+    if !status.starts_with("HTTP/1.1 2") && !status.starts_with("HTTP/1.0 2") {
+        return Err(format_err!("upload rejected: {}", status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redaction() {
+        let args = vec![
+            "polytorus",
+            "wallet",
+            "create",
+            "--restore",
+            "word1 word2",
+            "--hd",
+        ];
+        let args = redact_args(args.into_iter().map(String::from).collect());
+        assert_eq!(
+            args,
+            vec![
+                "polytorus",
+                "wallet",
+                "create",
+                "--restore",
+                "<redacted>",
+                "--hd"
+            ]
+        );
+        let args = redact_args(vec![String::from("--restore=word1 word2")]);
+        assert_eq!(args, vec!["--restore=<redacted>"]);
+
+        let env = redact_env(vec![
+            (
+                String::from("POLYTORUS_WALLET_PASSWORD"),
+                String::from("pw"),
+            ),
+            (String::from("RUST_LOG"), String::from("info")),
+        ]);
+        assert_eq!(env[0].1, "<redacted>");
+        assert_eq!(env[1].1, "info");
+
+        let bundle = CrashBundle {
+            reason: String::from("panic: boom"),
+            version: String::from("polytorus 0.1.0"),
+            args,
+            env,
+            state: String::from("best height: 3"),
+            logs: vec![String::from("1 INFO polytorus: mine a new block")],
+            backtrace: String::from("0: main"),
+        };
+        let report = bundle.render();
+        assert!(report.contains("reason: panic: boom"));
+        assert!(report.contains("best height: 3"));
+        assert!(report.contains("mine a new block"));
+        assert!(!report.contains("pw\n"));
+    }
+}
@@ -0,0 +1,159 @@
+//! Fee estimation from recent blocks
+//!
+//! Estimates are percentiles of the fees paid by the transactions of the
+//! last blocks. When more transactions wait in the mempool than a recent
+//! block carries on average, every priority moves up one step.
+//!
+//! The fees waiting in the mempool are summarized as a histogram with
+//! power of two buckets for monitoring.
+
+use super::*;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Number of recent blocks the estimate is based on
+pub const FEE_ESTIMATE_BLOCKS: usize = 20;
+
+/// Priority selects one of the estimated fees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+    Low,
+    Medium,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Priority> {
+        match s {
+            "low" => Ok(Priority::Low),
+            "medium" => Ok(Priority::Medium),
+            "high" => Ok(Priority::High),
+            _ => Err(format_err!(
+                "unknown priority {}, expected low, medium or high",
+                s
+            )),
+        }
+    }
+}
+
+/// FeeEstimate holds the suggested fee of each priority
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct FeeEstimate {
+    pub low: i32,
+    pub medium: i32,
+    pub high: i32,
+}
+
+impl FeeEstimate {
+    /// Estimate computes the fees from the fees paid in recent blocks
+    ///
+    /// `block_txs` is the number of non-coinbase transactions in each recent
+    /// block and `mempool_size` the number of transactions waiting.
+    pub fn estimate(fees: &[i32], block_txs: &[usize], mempool_size: usize) -> FeeEstimate {
+        if fees.is_empty() {
+            return FeeEstimate::default();
+        }
+        let mut fees = fees.to_vec();
+        fees.sort_unstable();
+        let mut tiers = [
+            percentile(&fees, 25),
+            percentile(&fees, 50),
+            percentile(&fees, 90),
+            fees[fees.len() - 1],
+        ];
+        let average = block_txs.iter().sum::<usize>() as f64 / block_txs.len().max(1) as f64;
+        if mempool_size as f64 > average {
+            tiers.rotate_left(1);
+        }
+        FeeEstimate {
+            low: tiers[0],
+            medium: tiers[1],
+            high: tiers[2],
+        }
+    }
+
+    pub fn get(&self, priority: Priority) -> i32 {
+        match priority {
+            Priority::Low => self.low,
+            Priority::Medium => self.medium,
+            Priority::High => self.high,
+        }
+    }
+}
+
+/// FeeBucket counts the fees from `min_fee` up to twice `min_fee`, exclusive
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FeeBucket {
+    pub min_fee: i32,
+    pub count: usize,
+}
+
+/// FeeHistogram sorts fees into the buckets 0, 1, 2-3, 4-7 and so on
+///
+/// Buckets up to the highest fee are returned, empty ones included.
+pub fn fee_histogram(fees: &[i32]) -> Vec<FeeBucket> {
+    let mut buckets: Vec<FeeBucket> = Vec::new();
+    for &fee in fees {
+        let index = match fee {
+            f if f <= 0 => 0,
+            f => 32 - f.leading_zeros() as usize,
+        };
+        while buckets.len() <= index {
+            let min_fee = match buckets.len() {
+                0 => 0,
+                i => 1 << (i - 1),
+            };
+            buckets.push(FeeBucket { min_fee, count: 0 });
+        }
+        buckets[index].count += 1;
+    }
+    buckets
+}
+
+/// percentile returns the nearest-rank percentile of sorted values
+fn percentile(sorted: &[i32], p: usize) -> i32 {
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fee_estimate() {
+        assert_eq!(FeeEstimate::estimate(&[], &[], 10), FeeEstimate::default());
+
+        let fees: Vec<i32> = (1..=10).rev().collect();
+        let estimate = FeeEstimate::estimate(&fees, &[5, 5], 5);
+        assert_eq!(
+            estimate,
+            FeeEstimate {
+                low: 3,
+                medium: 5,
+                high: 9
+            }
+        );
+        assert_eq!(estimate.get("high".parse().unwrap()), 9);
+
+        // a backlog bigger than a block raises every priority
+        let estimate = FeeEstimate::estimate(&fees, &[5, 5], 6);
+        assert_eq!(
+            estimate,
+            FeeEstimate {
+                low: 5,
+                medium: 9,
+                high: 10
+            }
+        );
+        assert!("urgent".parse::<Priority>().is_err());
+
+        let histogram = fee_histogram(&[0, 1, 3, 2, 5]);
+        let counts: Vec<(i32, usize)> = histogram.iter().map(|b| (b.min_fee, b.count)).collect();
+        assert_eq!(counts, vec![(0, 1), (1, 1), (2, 2), (4, 1)]);
+        assert!(fee_histogram(&[]).is_empty());
+    }
+}
@@ -0,0 +1,213 @@
+//! Genesis configuration of a network
+//!
+//! A genesis file is JSON naming the chain, the consensus parameters and
+//! the initial allocations. Every node creating its chain from the same
+//! file mines the same genesis block: the block is timestamped by the
+//! file and its coinbase pays the allocations and commits to the hash of
+//! the configuration.
+
+use super::*;
+use crate::address;
+use crate::block::{Block, TARGET_HEXS};
+use crate::transaction::*;
+use bincode::serialize;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+const COINBASE_PREFIX: &str = "genesis of ";
+
+/// Allocation pays `amount` to `address` in the genesis block
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Allocation {
+    pub address: String,
+    pub amount: i32,
+}
+
+/// GenesisConfig defines a network
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GenesisConfig {
+    pub chain_id: String,
+    /// milliseconds since the UNIX epoch
+    pub timestamp: u128,
+    /// leading zero hex digits of the genesis block hash, the floor of later
+    /// targets, fixed by this build
+    #[serde(default = "default_target_hexs")]
+    pub target_hexs: usize,
+    pub allocations: Vec<Allocation>,
+}
+
+fn default_target_hexs() -> usize {
+    TARGET_HEXS
+}
+
+impl GenesisConfig {
+    /// NewGenesisConfig creates a configuration timestamped now
+    pub fn new(chain_id: &str, allocations: Vec<Allocation>) -> Result<GenesisConfig> {
+        Ok(GenesisConfig {
+            chain_id: chain_id.to_string(),
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)?
+                .as_millis(),
+            target_hexs: TARGET_HEXS,
+            allocations,
+        })
+    }
+
+    /// Load reads and validates a genesis file
+    pub fn load(path: &str) -> Result<GenesisConfig> {
+        let config: GenesisConfig = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Validate checks the chain id, the consensus parameters and the allocations
+    pub fn validate(&self) -> Result<()> {
+        if self.chain_id.is_empty() || !self.chain_id.bytes().all(|c| c.is_ascii_graphic()) {
+            return Err(format_err!("chain id must be non-empty printable ASCII"));
+        }
+        if self.target_hexs != TARGET_HEXS {
+            return Err(format_err!(
+                "target of {} zero digits is not supported, this build mines {}",
+                self.target_hexs,
+                TARGET_HEXS
+            ));
+        }
+        if self.allocations.is_empty() {
+            return Err(format_err!("genesis has no allocations"));
+        }
+        let mut seen = HashSet::new();
+        let mut total: i64 = 0;
+        for a in &self.allocations {
+            let hash = address::pub_key_hash(&a.address)?;
+            if !seen.insert(hash) {
+                return Err(format_err!("{} is allocated twice", a.address));
+            }
+            if a.amount <= 0 {
+                return Err(format_err!("allocation to {} must be positive", a.address));
+            }
+            total += a.amount as i64;
+        }
+        if total > i32::MAX as i64 {
+            return Err(format_err!("allocations exceed {}", i32::MAX));
+        }
+        Ok(())
+    }
+
+    /// Hash returns the hex SHA-256 of the configuration
+    pub fn hash(&self) -> Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.input(&serialize(self)?);
+        Ok(hasher.result_str())
+    }
+
+    /// Coinbase returns the genesis transaction paying the allocations
+    pub fn coinbase(&self) -> Result<Transaction> {
+        let mut vout = Vec::new();
+        for a in &self.allocations {
+            vout.push(TXOutput::new(a.amount, a.address.clone())?);
+        }
+        let data = format!("{}{} {}", COINBASE_PREFIX, self.chain_id, self.hash()?);
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: String::new(),
+                vout: -1,
+                signature: Vec::new(),
+                pub_key: data.into_bytes(),
+            }],
+            vout,
+        };
+        tx.id = tx.hash()?;
+        Ok(tx)
+    }
+}
+
+/// ChainIdOf returns the chain id a genesis block was created for, None
+/// if it was not created from a genesis file
+pub fn chain_id_of(genesis: &Block) -> Option<String> {
+    let coinbase = genesis.get_transaction().first()?;
+    if !coinbase.is_coinbase() {
+        return None;
+    }
+    let data = std::str::from_utf8(&coinbase.vin[0].pub_key).ok()?;
+    let id = data.strip_prefix(COINBASE_PREFIX)?.split(' ').next()?;
+    Some(id.to_string())
+}
+
+/// ParseAllocation parses `address:amount`
+pub fn parse_allocation(s: &str) -> Result<Allocation> {
+    match s.rsplit_once(':') {
+        Some((address, amount)) => Ok(Allocation {
+            address: address.to_string(),
+            amount: amount.parse()?,
+        }),
+        None => Err(format_err!("allocation {} is not address:amount", s)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::MIN_TARGET_BITS;
+
+    const ALICE: &str = "3K2B53BeZoaSPBGxihwPkbjNxrLkapfYrn";
+    const BOB: &str = "pt1p0m5ct5900cdp88gln0qysfztex09hwvcvu9ngu";
+
+    #[test]
+    fn test_genesis_config() {
+        let config = GenesisConfig {
+            chain_id: String::from("polytorus-test"),
+            timestamp: 1_700_000_000_000,
+            target_hexs: TARGET_HEXS,
+            allocations: vec![
+                parse_allocation(&format!("{}:50", ALICE)).unwrap(),
+                Allocation {
+                    address: BOB.to_string(),
+                    amount: 25,
+                },
+            ],
+        };
+        config.validate().unwrap();
+        let json = serde_json::to_string(&config).unwrap();
+        let parsed: GenesisConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.hash().unwrap(), config.hash().unwrap());
+
+        let coinbase = config.coinbase().unwrap();
+        assert!(coinbase.is_coinbase());
+        assert_eq!(
+            coinbase.vout.iter().map(|o| o.value).collect::<Vec<_>>(),
+            vec![50, 25]
+        );
+        assert_eq!(coinbase.id, parsed.coinbase().unwrap().id);
+        let genesis = Block::new_block_at(
+            vec![coinbase.clone()],
+            String::new(),
+            "",
+            0,
+            config.timestamp,
+            MIN_TARGET_BITS,
+        )
+        .unwrap();
+        assert_eq!(chain_id_of(&genesis).unwrap(), "polytorus-test");
+
+        let mut other = config.clone();
+        other.chain_id = String::from("polytorus-main");
+        assert_ne!(other.coinbase().unwrap().id, coinbase.id);
+
+        let mut twice = config.clone();
+        twice.allocations.push(twice.allocations[0].clone());
+        assert!(twice.validate().is_err());
+        let mut harder = config.clone();
+        harder.target_hexs += 1;
+        assert!(harder.validate().is_err());
+        assert!(parse_allocation(ALICE).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Hierarchical deterministic wallets
+//!
+//! A BIP-39 mnemonic is turned into a 64 byte seed, from which child keys are
+//! derived along a path like `m/44'/7391'/0'/0'` with SLIP-10 style
+//! HMAC-SHA512 derivation. FN-DSA has no public key derivation, so only
+//! hardened indices are accepted. Each derived child key seeds the FN-DSA key
+//! generator, which makes the key pair fully reproducible from the mnemonic.
+
+use super::*;
+use crate::wallets::Wallet;
+use bip39::Mnemonic;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha512;
+use failure::format_err;
+use rand::RngCore;
+use rand_chacha::rand_core::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use rand_core::OsRng;
+use std::fmt;
+use std::str::FromStr;
+
+const HARDENED: u32 = 0x8000_0000;
+const MASTER_KEY: &[u8] = b"PolyTorus seed";
+/// Registered coin type used in the default derivation path
+const COIN_TYPE: u32 = 7391;
+
+/// GenerateMnemonic creates a new 24 word mnemonic phrase
+pub fn generate_mnemonic() -> Result<Mnemonic> {
+    let mut entropy = [0u8; 32];
+    OsRng.fill_bytes(&mut entropy);
+    Ok(Mnemonic::from_entropy(&entropy)?)
+}
+
+/// MnemonicToSeed validates the phrase and returns its BIP-39 seed
+pub fn mnemonic_to_seed(phrase: &str, passphrase: &str) -> Result<Vec<u8>> {
+    let mnemonic = Mnemonic::parse(phrase)?;
+    Ok(mnemonic.to_seed(passphrase).to_vec())
+}
+
+/// DerivationPath is a list of hardened child indices
+#[derive(Debug, Clone, PartialEq)]
+pub struct DerivationPath(Vec<u32>);
+
+impl DerivationPath {
+    /// Default returns the path of the `index`th address of the first account
+    pub fn default_for(index: u32) -> DerivationPath {
+        DerivationPath(vec![
+            44 | HARDENED,
+            COIN_TYPE | HARDENED,
+            HARDENED,
+            index | HARDENED,
+        ])
+    }
+}
+
+impl FromStr for DerivationPath {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<DerivationPath> {
+        let mut parts = s.split('/');
+        if parts.next() != Some("m") {
+            return Err(format_err!("derivation path must start with m: {}", s));
+        }
+        let mut indices = Vec::new();
+        for part in parts {
+            let index = match part.strip_suffix('\'').or_else(|| part.strip_suffix('h')) {
+                Some(i) => i.parse::<u32>()?,
+                None => {
+                    return Err(format_err!(
+                        "only hardened derivation is supported: {}",
+                        part
+                    ))
+                }
+            };
+            if index >= HARDENED {
+                return Err(format_err!("derivation index out of range: {}", part));
+            }
+            indices.push(index | HARDENED);
+        }
+        Ok(DerivationPath(indices))
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m")?;
+        for index in &self.0 {
+            write!(f, "/{}'", index & !HARDENED)?;
+        }
+        Ok(())
+    }
+}
+
+/// DeriveWallet derives the FN-DSA wallet at `path` from the seed
+pub fn derive_wallet(seed: &[u8], path: &DerivationPath) -> Wallet {
+    let (mut key, mut chain_code) = hmac_split(MASTER_KEY, &[seed]);
+    for index in &path.0 {
+        let (k, c) = hmac_split(&chain_code, &[&[0u8], &key, &index.to_be_bytes()]);
+        key = k;
+        chain_code = c;
+    }
+    Wallet::from_rng(&mut ChaCha20Rng::from_seed(key))
+}
+
+fn hmac_split(key: &[u8], data: &[&[u8]]) -> ([u8; 32], [u8; 32]) {
+    let mut mac = Hmac::new(Sha512::new(), key);
+    for d in data {
+        mac.input(d);
+    }
+    let result = mac.result();
+    let code = result.code();
+    let mut left = [0u8; 32];
+    let mut right = [0u8; 32];
+    left.copy_from_slice(&code[..32]);
+    right.copy_from_slice(&code[32..]);
+    (left, right)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_derivation() {
+        let mnemonic = generate_mnemonic().unwrap();
+        assert_eq!(mnemonic.word_count(), 24);
+        let phrase = mnemonic.to_string();
+        let seed = mnemonic_to_seed(&phrase, "").unwrap();
+
+        let path: DerivationPath = "m/44'/7391'/0'/0'".parse().unwrap();
+        assert_eq!(path, DerivationPath::default_for(0));
+        assert_eq!(path.to_string(), "m/44'/7391'/0'/0'");
+
+        let w1 = derive_wallet(&seed, &path);
+        let w2 = derive_wallet(&mnemonic_to_seed(&phrase, "").unwrap(), &path);
+        assert_eq!(w1, w2);
+        assert_ne!(w1, derive_wallet(&seed, &DerivationPath::default_for(1)));
+        assert_ne!(
+            w1,
+            derive_wallet(&mnemonic_to_seed(&phrase, "x").unwrap(), &path)
+        );
+
+        assert!("m/44'/0".parse::<DerivationPath>().is_err());
+        assert!("44'/0'".parse::<DerivationPath>().is_err());
+        assert!(mnemonic_to_seed("not a mnemonic", "").is_err());
+    }
+
+    #[test]
+    fn test_restore_over_seed() {
+        let mut ws = crate::wallets::Wallets::new().unwrap();
+        let phrase = ws.init_hd("").unwrap();
+        let first = ws.create_hd_wallet().unwrap();
+        let second = ws.create_hd_wallet().unwrap();
+
+        // the seed in use restores without resetting the derivation
+        assert_eq!(ws.restore_hd(&phrase, "", false).unwrap(), first);
+        assert_ne!(ws.create_hd_wallet().unwrap(), second);
+
+        let other = generate_mnemonic().unwrap().to_string();
+        assert!(ws.restore_hd(&other, "", false).is_err());
+        assert!(ws.restore_hd(&phrase, "passphrase", false).is_err());
+        let restored = ws.restore_hd(&other, "", true).unwrap();
+        assert_eq!(
+            restored,
+            derive_wallet(
+                &mnemonic_to_seed(&other, "").unwrap(),
+                &DerivationPath::default_for(0)
+            )
+            .get_address()
+        );
+    }
+}
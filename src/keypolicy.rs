@@ -0,0 +1,275 @@
+//! Key usage policies
+//!
+//! A wallet can carry a local policy limiting what its key signs: a cap on
+//! the amount paid to other addresses per UTC day, the only destinations it
+//! may pay, and an amount above which a spend needs an explicit
+//! confirmation. A transaction is checked against the policy before its
+//! inputs are signed, by the CLI for local wallets and by the signer server
+//! for remote signing requests. Spends count towards the daily cap once
+//! they are authorized.
+
+use super::*;
+use crate::signer::*;
+use crate::transaction::*;
+use bincode::{deserialize, serialize};
+use bitcoincash_addr::{Address, HashType, Scheme};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+const KEY_POLICY_PATH: &str = "data/keypolicy";
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// KeyPolicy restricts the transactions a wallet key signs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct KeyPolicy {
+    /// Most the key pays to other addresses per UTC day
+    pub daily_limit: Option<i32>,
+    /// Addresses the key may pay, any address if empty
+    pub allowed_destinations: Vec<String>,
+    /// Spends above this amount need an explicit confirmation
+    pub confirm_above: Option<i32>,
+}
+
+/// Spend is what a transaction pays to addresses other than the signer's
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spend {
+    pub destinations: Vec<String>,
+    pub amount: i32,
+}
+
+impl Spend {
+    /// Of returns the spend of a transaction whose change goes back to `pub_key_hash`
+    pub fn of(tx: &Transaction, pub_key_hash: &[u8]) -> Spend {
+        let mut destinations = Vec::new();
+        let mut amount = 0;
+        for out in tx
+            .vout
+            .iter()
+            .filter(|out| out.pub_key_hash != pub_key_hash)
+        {
+            let address = Address {
+                body: out.pub_key_hash.clone(),
+                scheme: Scheme::Base58,
+                hash_type: HashType::Script,
+                ..Default::default()
+            };
+            destinations.push(address.encode().unwrap_or_default());
+            amount += out.value;
+        }
+        Spend {
+            destinations,
+            amount,
+        }
+    }
+}
+
+impl KeyPolicy {
+    /// Check returns why the policy refuses a spend, given what was spent today
+    pub fn check(&self, spend: &Spend, spent_today: i32, confirmed: bool) -> Result<()> {
+        if !self.allowed_destinations.is_empty() {
+            if let Some(d) = spend
+                .destinations
+                .iter()
+                .find(|d| !self.allowed_destinations.contains(d))
+            {
+                return Err(format_err!(
+                    "key policy: {} is not an allowed destination",
+                    d
+                ));
+            }
+        }
+        if let Some(limit) = self.daily_limit {
+            if spent_today + spend.amount > limit {
+                return Err(format_err!(
+                    "key policy: spending {} exceeds the daily limit of {} ({} spent today)",
+                    spend.amount,
+                    limit,
+                    spent_today
+                ));
+            }
+        }
+        match self.confirm_above {
+            Some(threshold) if spend.amount > threshold && !confirmed => Err(format_err!(
+                "key policy: spending {} above {} needs confirmation",
+                spend.amount,
+                threshold
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// KeyPolicies stores the policy and the daily spending of each wallet
+pub struct KeyPolicies {
+    policies: sled::Tree,
+    spent: sled::Tree,
+}
+
+impl KeyPolicies {
+    pub fn open() -> Result<KeyPolicies> {
+        KeyPolicies::with_db(&sled::open(KEY_POLICY_PATH)?)
+    }
+
+    /// WithDb keeps the policies in the trees of an open database
+    pub fn with_db(db: &sled::Db) -> Result<KeyPolicies> {
+        Ok(KeyPolicies {
+            policies: db.open_tree("policies")?,
+            spent: db.open_tree("spent")?,
+        })
+    }
+
+    pub fn get(&self, address: &str) -> Result<Option<KeyPolicy>> {
+        match self.policies.get(address)? {
+            Some(p) => Ok(Some(deserialize(&p)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Set replaces the policy of a wallet, None removes it
+    pub fn set(&self, address: &str, policy: Option<&KeyPolicy>) -> Result<()> {
+        match policy {
+            Some(p) => self.policies.insert(address, serialize(p)?)?,
+            None => self.policies.remove(address)?,
+        };
+        self.policies.flush()?;
+        Ok(())
+    }
+
+    /// Authorize checks a transaction of `address` against its policy and counts the spend
+    pub fn authorize(&self, address: &str, tx: &Transaction, confirmed: bool) -> Result<()> {
+        let day = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs()
+            / SECONDS_PER_DAY;
+        self.authorize_on(address, tx, confirmed, day)
+    }
+
+    fn authorize_on(
+        &self,
+        address: &str,
+        tx: &Transaction,
+        confirmed: bool,
+        day: u64,
+    ) -> Result<()> {
+        let policy = match self.get(address)? {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let spend = Spend::of(tx, &crate::address::pub_key_hash(address)?);
+        let spent_today = match self.spent.get(address)? {
+            Some(s) => match deserialize::<(u64, i32)>(&s)? {
+                (d, amount) if d == day => amount,
+                _ => 0,
+            },
+            None => 0,
+        };
+        policy.check(&spend, spent_today, confirmed)?;
+        info!(
+            "key policy: authorized spending {} from {}",
+            spend.amount, address
+        );
+        self.spent
+            .insert(address, serialize(&(day, spent_today + spend.amount))?)?;
+        self.spent.flush()?;
+        Ok(())
+    }
+}
+
+/// PolicySigner is a local signer that authorizes transactions with the key policies
+pub struct PolicySigner<'a> {
+    signer: &'a dyn Signer,
+    address: String,
+    policies: KeyPolicies,
+}
+
+impl<'a> PolicySigner<'a> {
+    /// NewPolicySigner guards `signer` with the policy of `address`
+    pub fn new(signer: &'a dyn Signer, address: &str) -> Result<PolicySigner<'a>> {
+        Ok(PolicySigner {
+            signer,
+            address: address.to_string(),
+            policies: KeyPolicies::open()?,
+        })
+    }
+}
+
+impl Signer for PolicySigner<'_> {
+    fn public_key(&self) -> Result<Vec<u8>> {
+        self.signer.public_key()
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        self.signer.sign(message)
+    }
+
+    fn authorize(&self, tx: &Transaction, _chain_id: &str, confirmed: bool) -> Result<()> {
+        self.policies.authorize(&self.address, tx, confirmed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FROM: &str = "3K2B53BeZoaSPBGxihwPkbjNxrLkapfYrn";
+    const SHOP: &str = "373WyDU3SwgZYPoeEtRjpNPWffwnNFTeCA";
+    const OTHER: &str = "3NFC5xp8eNx3fBFZX2YhQny9H2Xzntadba";
+
+    fn payment(to: &str, amount: i32) -> Transaction {
+        Transaction {
+            id: String::new(),
+            vin: Vec::new(),
+            vout: vec![
+                TXOutput::new(amount, to.to_string()).unwrap(),
+                TXOutput::new(3, FROM.to_string()).unwrap(),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_key_policies() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let policies = KeyPolicies::with_db(&db).unwrap();
+        assert_eq!(
+            Spend::of(&payment(SHOP, 10), &Address::decode(FROM).unwrap().body).amount,
+            10
+        );
+        policies
+            .authorize_on(FROM, &payment(OTHER, 1000), false, 1)
+            .unwrap();
+
+        let policy = KeyPolicy {
+            daily_limit: Some(20),
+            allowed_destinations: vec![SHOP.to_string()],
+            confirm_above: Some(8),
+        };
+        policies.set(FROM, Some(&policy)).unwrap();
+        assert_eq!(policies.get(FROM).unwrap(), Some(policy));
+
+        assert!(policies
+            .authorize_on(FROM, &payment(OTHER, 5), false, 1)
+            .is_err());
+        assert!(policies
+            .authorize_on(FROM, &payment(SHOP, 10), false, 1)
+            .is_err());
+        policies
+            .authorize_on(FROM, &payment(SHOP, 10), true, 1)
+            .unwrap();
+        policies
+            .authorize_on(FROM, &payment(SHOP, 8), false, 1)
+            .unwrap();
+        // 18 spent today, the cap is 20
+        assert!(policies
+            .authorize_on(FROM, &payment(SHOP, 3), false, 1)
+            .is_err());
+        policies
+            .authorize_on(FROM, &payment(SHOP, 3), false, 2)
+            .unwrap();
+
+        policies.set(FROM, None).unwrap();
+        policies
+            .authorize_on(FROM, &payment(OTHER, 1000), false, 2)
+            .unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+//! PolyTorus, a quantum-resistant blockchain
+//!
+//! The `polytorus` binary is a thin CLI over this library; see `examples/`
+//! for driving the chain, wallets and node programmatically.
+
+#![allow(non_snake_case)]
+
+pub mod address;
+pub mod assembly;
+pub mod bandwidth;
+pub mod block;
+pub mod blockchain;
+pub mod census;
+pub mod cli;
+pub mod coinlocks;
+pub mod crashreport;
+pub mod fees;
+pub mod genesis;
+pub mod hdwallet;
+pub mod keypolicy;
+pub mod logging;
+pub mod metrics;
+pub mod orphans;
+pub mod peers;
+pub mod policy;
+pub mod rpc;
+pub mod server;
+pub mod signer;
+pub mod statesync;
+pub mod sync;
+pub mod systemtx;
+pub mod telemetry;
+pub mod transaction;
+pub mod transport;
+pub mod txbuilder;
+pub mod txindex;
+pub mod utxoset;
+pub mod walletcrypt;
+pub mod wallets;
+pub mod watchdog;
+
+#[macro_use]
+extern crate log;
+
+pub type Result<T> = std::result::Result<T, failure::Error>;
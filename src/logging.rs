@@ -0,0 +1,311 @@
+//! Logger setup
+//!
+//! Records are printed to the console by env_logger, filtered by RUST_LOG,
+//! and the most recent lines are kept in memory so a crash report can
+//! include what led up to it. Further outputs are configured with
+//! environment variables:
+//!
+//! - `POLYTORUS_LOG_FILE`: also append to this file, rotated at
+//!   `POLYTORUS_LOG_MAX_BYTES` (default 10 MiB) keeping
+//!   `POLYTORUS_LOG_KEEP` old files (default 5)
+//! - `POLYTORUS_LOG_FORMAT=json`: write JSON lines instead of text
+//! - `POLYTORUS_LOG_SYSLOG=1`: also send records to the local syslog
+//!
+//! The filter can be changed at runtime with `set_filter`.
+
+use env_logger::Env;
+use log::{Log, Metadata, Record};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::SystemTime;
+
+/// Number of log lines kept for crash reports
+const RECENT_LINES: usize = 200;
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEEP: usize = 5;
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+struct Logger {
+    console: RwLock<env_logger::Logger>,
+    json: bool,
+    file: Option<Mutex<RotatingFile>>,
+    #[cfg(unix)]
+    syslog: Option<std::os::unix::net::UnixDatagram>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.console.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let console = self.console.read().unwrap();
+        if !console.matches(record) {
+            return;
+        }
+        let line = format_line(record);
+        let json_line = if self.json {
+            format_json(record)
+        } else {
+            String::new()
+        };
+        if self.json {
+            eprintln!("{}", json_line);
+        } else {
+            console.log(record);
+        }
+        if let Some(file) = &self.file {
+            let out = if self.json { &json_line } else { &line };
+            if let Ok(mut file) = file.lock() {
+                // a failing log file must not take the node down
+                let _ = file.write_line(out);
+            }
+        }
+        #[cfg(unix)]
+        if let Some(syslog) = &self.syslog {
+            let _ = syslog.send(format_syslog(record).as_bytes());
+        }
+        remember(line);
+    }
+
+    fn flush(&self) {
+        self.console.read().unwrap().flush();
+    }
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
+
+fn format_line(record: &Record) -> String {
+    format!(
+        "{} {} {}: {}",
+        now_millis(),
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+fn format_json(record: &Record) -> String {
+    json!({
+        "ts": now_millis() as u64,
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "msg": record.args().to_string(),
+    })
+    .to_string()
+}
+
+/// format_syslog builds an RFC 3164 message with the user facility
+#[cfg(unix)]
+fn format_syslog(record: &Record) -> String {
+    use log::Level;
+    let severity = match record.level() {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    format!(
+        "<{}>polytorus[{}]: {}: {}",
+        8 + severity,
+        std::process::id(),
+        record.target(),
+        record.args()
+    )
+}
+
+fn remember(line: String) {
+    if let Ok(mut recent) = RECENT.lock() {
+        if recent.len() >= RECENT_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(line);
+    }
+}
+
+/// RotatingFile appends lines to a file, rotating it when it gets too big
+struct RotatingFile {
+    path: String,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_bytes: u64, keep: usize) -> std::io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_string(),
+            max_bytes,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// rotate moves path to path.1, path.1 to path.2, ... dropping the oldest
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.keep).rev() {
+                let from = format!("{}.{}", self.path, i);
+                if std::path::Path::new(&from).exists() {
+                    std::fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Init installs the global logger, filtered by RUST_LOG
+pub fn init() {
+    let console =
+        env_logger::Builder::from_env(Env::default().default_filter_or("warning")).build();
+    let max_level = console.filter();
+
+    let file = match std::env::var("POLYTORUS_LOG_FILE") {
+        Ok(path) => {
+            let max_bytes = env_or("POLYTORUS_LOG_MAX_BYTES", DEFAULT_MAX_BYTES);
+            let keep = env_or("POLYTORUS_LOG_KEEP", DEFAULT_KEEP);
+            match RotatingFile::open(&path, max_bytes, keep) {
+                Ok(f) => Some(Mutex::new(f)),
+                Err(e) => {
+                    eprintln!("failed to open log file {}: {}", path, e);
+                    None
+                }
+            }
+        }
+        Err(_) => None,
+    };
+    #[cfg(unix)]
+    let syslog = if std::env::var("POLYTORUS_LOG_SYSLOG").as_deref() == Ok("1") {
+        std::os::unix::net::UnixDatagram::unbound()
+            .and_then(|s| s.connect(SYSLOG_SOCKET).map(|_| s))
+            .map_err(|e| eprintln!("failed to connect to syslog: {}", e))
+            .ok()
+    } else {
+        None
+    };
+
+    let logger = LOGGER.get_or_init(|| Logger {
+        console: RwLock::new(console),
+        json: std::env::var("POLYTORUS_LOG_FORMAT").as_deref() == Ok("json"),
+        file,
+        #[cfg(unix)]
+        syslog,
+    });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+/// SetFilter replaces the log filter, using the RUST_LOG syntax
+pub fn set_filter(filters: &str) {
+    let console = env_logger::Builder::new().parse_filters(filters).build();
+    log::set_max_level(console.filter());
+    if let Some(logger) = LOGGER.get() {
+        *logger.console.write().unwrap() = console;
+    }
+}
+
+/// RecentLines returns the last logged lines, oldest first
+pub fn recent_lines() -> Vec<String> {
+    match RECENT.try_lock() {
+        Ok(recent) => recent.iter().cloned().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn test_recent_lines() {
+        for i in 0..RECENT_LINES + 5 {
+            remember(format!("test line {}", i));
+        }
+        let lines = recent_lines();
+        assert!(lines.len() <= RECENT_LINES);
+        assert_eq!(
+            lines.last().unwrap(),
+            &format!("test line {}", RECENT_LINES + 4)
+        );
+        assert!(!lines.contains(&String::from("test line 4")));
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("polytorus-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("node.log");
+        let path = path.to_str().unwrap();
+
+        let mut file = RotatingFile::open(path, 20, 2).unwrap();
+        for i in 0..4 {
+            file.write_line(&format!("line number {}", i)).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "line number 3\n");
+        assert_eq!(
+            std::fs::read_to_string(format!("{}.1", path)).unwrap(),
+            "line number 2\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(format!("{}.2", path)).unwrap(),
+            "line number 1\n"
+        );
+        assert!(!std::path::Path::new(&format!("{}.3", path)).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target("polytorus::server")
+            .args(format_args!("peer \"a\" misbehaved"))
+            .build();
+        let json: serde_json::Value = serde_json::from_str(&format_json(&record)).unwrap();
+        assert_eq!(json["level"], "WARN");
+        assert_eq!(json["msg"], "peer \"a\" misbehaved");
+        #[cfg(unix)]
+        assert!(format_syslog(&record).starts_with("<12>polytorus["));
+    }
+}
@@ -1,26 +1,16 @@
-#![allow(non_snake_case)]
-
-mod block;
-mod blockchain;
-mod cli;
-mod server;
-mod transaction;
-mod utxoset;
-mod wallets;
-
-#[macro_use]
-extern crate log;
-
-pub type Result<T> = std::result::Result<T, failure::Error>;
-
-use crate::cli::Cli;
-use env_logger::Env;
+use polytorus::cli::{Cli, EXIT_FAILURE};
+use polytorus::{crashreport, logging};
 
 fn main() {
-    env_logger::from_env(Env::default().default_filter_or("warning")).init();
+    logging::init();
+    crashreport::install_panic_hook();
 
     let mut cli = Cli::new();
     if let Err(e) = cli.run() {
-        println!("Error: {}", e);
+        eprintln!("Error: {}", e);
+        if cli.is_long_running() {
+            crashreport::report(&format!("fatal error: {}", e));
+        }
+        std::process::exit(EXIT_FAILURE);
     }
 }
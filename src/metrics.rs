@@ -0,0 +1,186 @@
+//! Prometheus metrics
+//!
+//! The node keeps counters of the messages it sends and receives and
+//! renders them, together with gauges read at scrape time, in the
+//! Prometheus text format on `GET /metrics` of a separate port.
+
+use super::*;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Labels of one sample, as name and value pairs
+pub type Labels<'a> = &'a [(&'a str, &'a str)];
+
+/// Counters accumulates monotonically increasing values by name and labels
+#[derive(Debug, Default)]
+pub struct Counters {
+    families: BTreeMap<&'static str, (&'static str, BTreeMap<String, u64>)>,
+}
+
+impl Counters {
+    pub fn new() -> Counters {
+        Counters::default()
+    }
+
+    /// Add increases the counter `name` with the given labels by `value`
+    pub fn add(&mut self, name: &'static str, help: &'static str, labels: Labels, value: u64) {
+        let (_, samples) = self
+            .families
+            .entry(name)
+            .or_insert_with(|| (help, BTreeMap::new()));
+        *samples.entry(format_labels(labels)).or_insert(0) += value;
+    }
+
+    pub fn get(&self, name: &str, labels: Labels) -> u64 {
+        self.families
+            .get(name)
+            .and_then(|(_, samples)| samples.get(&format_labels(labels)))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// Exposition builds a scrape in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    pub fn new() -> Exposition {
+        Exposition::default()
+    }
+
+    /// Gauge adds a metric family with one sample per label set
+    pub fn gauge(&mut self, name: &str, help: &str, samples: &[(Labels, f64)]) {
+        self.header(name, "gauge", help);
+        for (labels, value) in samples {
+            let _ = writeln!(self.out, "{}{} {}", name, format_labels(labels), value);
+        }
+    }
+
+    /// Counters adds every counter family
+    pub fn counters(&mut self, counters: &Counters) {
+        for (name, (help, samples)) in &counters.families {
+            self.header(name, "counter", help);
+            for (labels, value) in samples {
+                let _ = writeln!(self.out, "{}{} {}", name, labels, value);
+            }
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+}
+
+fn format_labels(labels: Labels) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Serve answers `GET /metrics` with the scrape built by `render`
+pub fn serve(listener: TcpListener, render: Arc<dyn Fn() -> String + Send + Sync>) -> Result<()> {
+    info!("metrics server listen on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let render = Arc::clone(&render);
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, render.as_ref()) {
+                warn!("metrics connection failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, render: &dyn Fn() -> String) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+    let (status, body) = match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exposition() {
+        let mut counters = Counters::new();
+        counters.add("msgs_total", "messages", &[("command", "tx")], 2);
+        counters.add("msgs_total", "messages", &[("command", "tx")], 1);
+        counters.add("msgs_total", "messages", &[("command", "inv")], 1);
+        assert_eq!(counters.get("msgs_total", &[("command", "tx")]), 3);
+
+        let mut scrape = Exposition::new();
+        scrape.gauge("height", "chain height", &[(&[], 7.0)]);
+        scrape.gauge("rtt", "round trip", &[(&[("peer", "a\"b")], 0.5)]);
+        scrape.counters(&counters);
+        assert_eq!(
+            scrape.finish(),
+            "# HELP height chain height\n# TYPE height gauge\nheight 7\n\
+             # HELP rtt round trip\n# TYPE rtt gauge\nrtt{peer=\"a\\\"b\"} 0.5\n\
+             # HELP msgs_total messages\n# TYPE msgs_total counter\n\
+             msgs_total{command=\"inv\"} 1\nmsgs_total{command=\"tx\"} 3\n"
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, Arc::new(|| String::from("up 1\n"))));
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\nup 1\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,133 @@
+//! Orphan blocks
+//!
+//! A relayed block whose parent is not stored yet is an orphan. Orphans
+//! are kept until their parent arrives and are then connected in height
+//! order. The pool is bounded; when it is full the oldest orphan is
+//! dropped, it is requested again if a descendant needs it.
+
+use crate::block::Block;
+use std::collections::{HashMap, VecDeque};
+
+/// Most orphan blocks kept at once
+pub const MAX_ORPHANS: usize = 100;
+
+/// OrphanPool holds blocks waiting for their parent
+#[derive(Debug)]
+pub struct OrphanPool {
+    capacity: usize,
+    blocks: HashMap<String, Block>,
+    order: VecDeque<String>,
+}
+
+impl OrphanPool {
+    pub fn new() -> OrphanPool {
+        OrphanPool::with_capacity(MAX_ORPHANS)
+    }
+
+    /// WithCapacity creates a pool keeping at most `capacity` orphans
+    pub fn with_capacity(capacity: usize) -> OrphanPool {
+        OrphanPool {
+            capacity,
+            blocks: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    /// Insert keeps an orphan and reports whether it was new
+    pub fn insert(&mut self, block: Block) -> bool {
+        let hash = block.get_hash();
+        if self.blocks.contains_key(&hash) {
+            return false;
+        }
+        if self.blocks.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.blocks.remove(&oldest);
+            }
+        }
+        self.order.push_back(hash.clone());
+        self.blocks.insert(hash, block);
+        true
+    }
+
+    /// TakeDescendants removes the orphans that connect to `parent`, parents first
+    pub fn take_descendants(&mut self, parent: &str) -> Vec<Block> {
+        let mut connected = Vec::new();
+        let mut parents = VecDeque::from([parent.to_string()]);
+        while let Some(parent) = parents.pop_front() {
+            let children: Vec<String> = self
+                .blocks
+                .values()
+                .filter(|b| b.get_prev_hash() == parent)
+                .map(|b| b.get_hash())
+                .collect();
+            for hash in children {
+                self.order.retain(|h| *h != hash);
+                if let Some(block) = self.blocks.remove(&hash) {
+                    parents.push_back(hash);
+                    connected.push(block);
+                }
+            }
+        }
+        connected
+    }
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        OrphanPool::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::MIN_TARGET_BITS;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn test_orphan_pool() {
+        let mut blocks: Vec<Block> = Vec::new();
+        for i in 0..4 {
+            let cbtx = Transaction::new_coinbase(
+                String::from("3K2B53BeZoaSPBGxihwPkbjNxrLkapfYrn"),
+                format!("block {}", i),
+            )
+            .unwrap();
+            let prev = blocks.last().map(|b| b.get_hash()).unwrap_or_default();
+            blocks.push(Block::new_block(vec![cbtx], prev, "", i, MIN_TARGET_BITS).unwrap());
+        }
+
+        let mut pool = OrphanPool::new();
+        assert!(pool.insert(blocks[3].clone()));
+        assert!(pool.insert(blocks[2].clone()));
+        assert!(!pool.insert(blocks[2].clone()));
+        assert!(pool.take_descendants(&blocks[0].get_hash()).is_empty());
+        let heights: Vec<i32> = pool
+            .take_descendants(&blocks[1].get_hash())
+            .iter()
+            .map(|b| b.get_height())
+            .collect();
+        assert_eq!(heights, vec![2, 3]);
+        assert!(pool.is_empty());
+
+        let mut small = OrphanPool::with_capacity(2);
+        for block in &blocks[1..] {
+            small.insert(block.clone());
+        }
+        assert_eq!(small.len(), 2);
+        assert!(!small.contains(&blocks[1].get_hash()));
+        assert!(small.contains(&blocks[3].get_hash()));
+    }
+}
@@ -0,0 +1,599 @@
+//! Per-peer bookkeeping of the node server
+//!
+//! The server measures round trip times with ping/pong messages and counts
+//! which peers announce new blocks first. Both feed the choice of the "fast
+//! relay" peers that receive block announcements before everyone else.
+//!
+//! Every peer also has a bounded cache of the transaction hashes
+//! it is known to have, so tx announcements are never sent back to a peer that
+//! already announced or received the same item.
+//!
+//! Misbehaving peers collect a ban score. Peers above `DEMOTE_SCORE` are no
+//! longer picked for fast relay, and peers reaching `BAN_SCORE` are banned
+//! for `BAN_DURATION`. Pinned peers, such as the operator's own nodes,
+//! are never banned.
+//!
+//! Messages and bytes exchanged with each peer are counted by command, and
+//! the last `RTT_SAMPLES` round trip times are kept for percentiles, so
+//! the protocol behavior of a single peer can be inspected over RPC.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Weight of a new RTT sample in the moving average
+const RTT_ALPHA: f64 = 0.25;
+/// Number of RTT samples kept per peer for percentiles
+const RTT_SAMPLES: usize = 100;
+/// Number of inventory hashes remembered per peer
+pub const MAX_KNOWN_INVENTORY: usize = 4096;
+/// Ban score from which a peer is no longer a fast relay peer
+const DEMOTE_SCORE: u32 = 50;
+/// Ban score at which a peer is banned
+const BAN_SCORE: u32 = 100;
+/// How long a ban lasts
+pub const BAN_DURATION: Duration = Duration::from_secs(3600);
+/// Most handshaked addresses remembered
+const MAX_IDENTITIES: usize = 4096;
+/// Most handshaked addresses remembered per sender identity
+const MAX_ADDRS_PER_IDENTITY: usize = 8;
+
+/// Misbehavior is a reason to lower the reputation of a peer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Misbehavior {
+    /// Sent a block or header that failed validation
+    InvalidBlock,
+    /// Sent bytes that do not decode as a message
+    MalformedMessage,
+    /// Did not answer a block request in time
+    SlowResponse,
+    /// Announced a height far behind the network
+    StaleHeight,
+    /// Sent a message type faster than its rate limit
+    Flooding,
+    /// Handshake names another chain
+    WrongChain,
+}
+
+impl Misbehavior {
+    fn penalty(&self) -> u32 {
+        match self {
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::MalformedMessage => 20,
+            Misbehavior::SlowResponse => 10,
+            Misbehavior::StaleHeight => 5,
+            Misbehavior::Flooding => 10,
+            Misbehavior::WrongChain => 100,
+        }
+    }
+}
+
+/// InventoryCache is a set of hashes that forgets the oldest entries first
+#[derive(Debug, Clone)]
+pub struct InventoryCache {
+    capacity: usize,
+    items: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl InventoryCache {
+    pub fn new(capacity: usize) -> InventoryCache {
+        InventoryCache {
+            capacity,
+            items: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Insert adds the hash, returns false if it was already present
+    pub fn insert(&mut self, id: &str) -> bool {
+        if self.items.contains(id) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.items.remove(&old);
+            }
+        }
+        self.items.insert(id.to_string());
+        self.order.push_back(id.to_string());
+        true
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.items.contains(id)
+    }
+}
+
+impl Default for InventoryCache {
+    fn default() -> InventoryCache {
+        InventoryCache::new(MAX_KNOWN_INVENTORY)
+    }
+}
+
+/// Handshake binds an address to the sender identity it completed its handshake from
+#[derive(Debug, Clone)]
+struct Handshake {
+    identity: String,
+    /// time of the last handshake, the oldest bindings are evicted first
+    at: Instant,
+}
+
+/// PeerInfo holds what the node observed about one peer
+#[derive(Debug, Clone, Default)]
+pub struct PeerInfo {
+    /// Smoothed round trip time in milliseconds
+    pub rtt_ms: Option<f64>,
+    /// Number of blocks this peer announced before any other peer
+    pub first_announcements: u64,
+    /// Accumulated penalties for misbehavior
+    pub ban_score: u32,
+    ping: Option<(u64, Instant)>,
+    known_inventory: InventoryCache,
+    rtt_samples: VecDeque<f64>,
+    counts: MessageCounts,
+    misbehaviors: BTreeMap<String, u64>,
+}
+
+/// MessageCounts counts the messages and bytes of one peer by command
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MessageCounts {
+    pub received: BTreeMap<String, u64>,
+    pub sent: BTreeMap<String, u64>,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+/// PeerStats is the exported protocol statistics of one peer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerStats {
+    pub addr: String,
+    pub messages: MessageCounts,
+    /// Misbehaviors by kind, e.g. invalid blocks or malformed messages
+    pub misbehaviors: BTreeMap<String, u64>,
+    pub ban_score: u32,
+    pub rtt_p50_ms: Option<f64>,
+    pub rtt_p90_ms: Option<f64>,
+    pub rtt_p99_ms: Option<f64>,
+}
+
+/// PeerSummary is the exported view of a peer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerSummary {
+    pub addr: String,
+    pub rtt_ms: Option<f64>,
+    pub first_announcements: u64,
+    pub ban_score: u32,
+    pub fast_relay: bool,
+}
+
+/// PeerTable tracks every peer the node talked to
+#[derive(Debug, Default)]
+pub struct PeerTable {
+    peers: HashMap<String, PeerInfo>,
+    /// Banned peers and the end of their ban, kept when a peer is removed
+    bans: HashMap<String, Instant>,
+    pinned: HashSet<String>,
+    /// Sender identity each address completed its handshake from
+    identities: HashMap<String, Handshake>,
+}
+
+impl PeerTable {
+    pub fn new() -> PeerTable {
+        PeerTable::default()
+    }
+
+    /// Remove forgets the statistics of `addr`, its handshake is kept
+    pub fn remove(&mut self, addr: &str) {
+        self.peers.remove(addr);
+    }
+
+    /// SetIdentity records the sender identity `addr` completed its handshake from
+    ///
+    /// Scores and bans are kept per identity, the socket IP or Noise static
+    /// key of the sender, because a message names its own `addr_from`.
+    /// An address bound to another identity is not rebound and false is
+    /// returned, so a sender cannot take over the address of another peer.
+    /// An identity keeps at most `MAX_ADDRS_PER_IDENTITY` addresses and the
+    /// table at most `MAX_IDENTITIES`, the oldest handshakes are evicted first.
+    pub fn set_identity(&mut self, addr: &str, identity: &str, now: Instant) -> bool {
+        if let Some(handshake) = self.identities.get_mut(addr) {
+            if handshake.identity != identity {
+                return false;
+            }
+            handshake.at = now;
+            return true;
+        }
+        // an evicted peer handshakes again on its next version
+        if self.addrs_of(identity).len() >= MAX_ADDRS_PER_IDENTITY {
+            self.evict_oldest(|id| id == identity);
+        }
+        if self.identities.len() >= MAX_IDENTITIES {
+            self.evict_oldest(|_| true);
+        }
+        self.identities.insert(
+            addr.to_string(),
+            Handshake {
+                identity: identity.to_string(),
+                at: now,
+            },
+        );
+        true
+    }
+
+    /// evict_oldest forgets the oldest handshake whose identity matches `filter`
+    fn evict_oldest(&mut self, filter: impl Fn(&str) -> bool) {
+        let oldest = self
+            .identities
+            .iter()
+            .filter(|(_, h)| filter(&h.identity))
+            .min_by_key(|(_, h)| h.at)
+            .map(|(addr, _)| addr.clone());
+        if let Some(addr) = oldest {
+            self.identities.remove(&addr);
+        }
+    }
+
+    /// Identity returns the sender identity of `addr`
+    pub fn identity(&self, addr: &str) -> Option<&str> {
+        self.identities.get(addr).map(|h| h.identity.as_str())
+    }
+
+    /// AddrsOf returns the addresses that completed their handshake from `identity`
+    pub fn addrs_of(&self, identity: &str) -> Vec<String> {
+        let mut addrs: Vec<String> = self
+            .identities
+            .iter()
+            .filter(|(_, h)| h.identity == identity)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        addrs.sort();
+        addrs
+    }
+
+    /// ban_score returns the score of the identity behind `addr`
+    fn ban_score(&self, addr: &str) -> u32 {
+        let identity = self.identity(addr).unwrap_or(addr);
+        self.peers.get(identity).map_or(0, |p| p.ban_score)
+    }
+
+    /// RecordPing remembers an outstanding ping to `addr`
+    pub fn record_ping(&mut self, addr: &str, nonce: u64, now: Instant) {
+        self.peers.entry(addr.to_string()).or_default().ping = Some((nonce, now));
+    }
+
+    /// RecordPong matches a pong with its ping and updates the peer RTT
+    pub fn record_pong(&mut self, addr: &str, nonce: u64, now: Instant) -> Option<Duration> {
+        let peer = self.peers.get_mut(addr)?;
+        match peer.ping {
+            Some((n, sent)) if n == nonce => {
+                peer.ping = None;
+                let rtt = now.saturating_duration_since(sent);
+                let sample = rtt.as_secs_f64() * 1000.0;
+                if peer.rtt_samples.len() == RTT_SAMPLES {
+                    peer.rtt_samples.pop_front();
+                }
+                peer.rtt_samples.push_back(sample);
+                peer.rtt_ms = Some(match peer.rtt_ms {
+                    Some(avg) => avg + RTT_ALPHA * (sample - avg),
+                    None => sample,
+                });
+                Some(rtt)
+            }
+            _ => None,
+        }
+    }
+
+    /// MarkKnown records that `addr` has the tx or block `id`
+    pub fn mark_known(&mut self, addr: &str, id: &str) {
+        self.peers
+            .entry(addr.to_string())
+            .or_default()
+            .known_inventory
+            .insert(id);
+    }
+
+    /// Knows reports whether `addr` is known to have the tx or block `id`
+    pub fn knows(&self, addr: &str, id: &str) -> bool {
+        match self.peers.get(addr) {
+            Some(peer) => peer.known_inventory.contains(id),
+            None => false,
+        }
+    }
+
+    /// Pin protects `addr` from bans and eviction
+    pub fn pin(&mut self, addr: &str) {
+        self.pinned.insert(addr.to_string());
+        self.bans.remove(addr);
+        if let Some(handshake) = self.identities.get(addr) {
+            self.bans.remove(&handshake.identity);
+        }
+    }
+
+    pub fn is_pinned(&self, addr: &str) -> bool {
+        self.pinned.contains(addr)
+    }
+
+    pub fn pinned(&self) -> Vec<String> {
+        let mut pinned: Vec<String> = self.pinned.iter().cloned().collect();
+        pinned.sort();
+        pinned
+    }
+
+    /// Misbehave penalizes the sender `identity` and returns true if it got banned
+    ///
+    /// Identities a pinned address handshaked from are never banned.
+    pub fn misbehave(&mut self, identity: &str, misbehavior: Misbehavior, now: Instant) -> bool {
+        let pinned = self.pinned.contains(identity)
+            || self
+                .addrs_of(identity)
+                .iter()
+                .any(|addr| self.pinned.contains(addr));
+        let peer = self.peers.entry(identity.to_string()).or_default();
+        peer.ban_score += misbehavior.penalty();
+        *peer
+            .misbehaviors
+            .entry(format!("{:?}", misbehavior))
+            .or_default() += 1;
+        if peer.ban_score < BAN_SCORE || pinned {
+            return false;
+        }
+        peer.ban_score = 0;
+        self.bans.insert(identity.to_string(), now + BAN_DURATION);
+        true
+    }
+
+    /// IsBanned reports whether the sender `identity` is banned, expired bans are lifted
+    pub fn is_banned(&mut self, identity: &str, now: Instant) -> bool {
+        match self.bans.get(identity) {
+            Some(until) if *until > now => true,
+            Some(_) => {
+                self.bans.remove(identity);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// RecordFirstAnnouncement credits `addr` for announcing a new block first
+    pub fn record_first_announcement(&mut self, addr: &str) {
+        self.peers
+            .entry(addr.to_string())
+            .or_default()
+            .first_announcements += 1;
+    }
+
+    /// RecordMessage counts a message of `bytes` bytes sent to or received from `addr`
+    pub fn record_message(&mut self, addr: &str, command: &str, bytes: usize, sent: bool) {
+        let counts = &mut self.peers.entry(addr.to_string()).or_default().counts;
+        if sent {
+            *counts.sent.entry(command.to_string()).or_default() += 1;
+            counts.bytes_sent += bytes as u64;
+        } else {
+            *counts.received.entry(command.to_string()).or_default() += 1;
+            counts.bytes_received += bytes as u64;
+        }
+    }
+
+    /// Stats exports the protocol statistics of `addr`
+    pub fn stats(&self, addr: &str) -> Option<PeerStats> {
+        let peer = self.peers.get(addr)?;
+        let mut samples: Vec<f64> = peer.rtt_samples.iter().copied().collect();
+        samples.sort_by(f64::total_cmp);
+        Some(PeerStats {
+            addr: addr.to_string(),
+            messages: peer.counts.clone(),
+            misbehaviors: peer.misbehaviors.clone(),
+            ban_score: peer.ban_score,
+            rtt_p50_ms: percentile(&samples, 50),
+            rtt_p90_ms: percentile(&samples, 90),
+            rtt_p99_ms: percentile(&samples, 99),
+        })
+    }
+
+    /// FastRelayPeers picks up to `count` candidates with the lowest RTT
+    ///
+    /// Peers without an RTT sample or with a high ban score are never picked. Ties are broken by the
+    /// number of first announcements, then by address for a stable order.
+    pub fn fast_relay_peers(&self, candidates: &[String], count: usize) -> Vec<String> {
+        let mut measured: Vec<(&String, f64, u64)> = candidates
+            .iter()
+            .filter_map(|addr| {
+                let peer = self.peers.get(addr)?;
+                if self.ban_score(addr) >= DEMOTE_SCORE {
+                    return None;
+                }
+                Some((addr, peer.rtt_ms?, peer.first_announcements))
+            })
+            .collect();
+        measured.sort_by(|a, b| a.1.total_cmp(&b.1).then(b.2.cmp(&a.2)).then(a.0.cmp(b.0)));
+        measured
+            .into_iter()
+            .take(count)
+            .map(|(addr, _, _)| addr.clone())
+            .collect()
+    }
+
+    /// RelayOrder returns the candidates with the fast relay peers first
+    pub fn relay_order(&self, candidates: &[String], fast_count: usize) -> Vec<String> {
+        let mut order = self.fast_relay_peers(candidates, fast_count);
+        for addr in candidates {
+            if !order.contains(addr) {
+                order.push(addr.clone());
+            }
+        }
+        order
+    }
+
+    /// Summaries exports the table for logging and diagnostics
+    pub fn summaries(&self, fast_count: usize) -> Vec<PeerSummary> {
+        let mut addrs: Vec<String> = self.peers.keys().cloned().collect();
+        addrs.sort();
+        let fast = self.fast_relay_peers(&addrs, fast_count);
+        addrs
+            .into_iter()
+            .map(|addr| {
+                let peer = &self.peers[&addr];
+                PeerSummary {
+                    fast_relay: fast.contains(&addr),
+                    rtt_ms: peer.rtt_ms,
+                    first_announcements: peer.first_announcements,
+                    ban_score: peer.ban_score,
+                    addr,
+                }
+            })
+            .collect()
+    }
+}
+
+/// percentile returns the nearest rank percentile of sorted samples
+fn percentile(sorted: &[f64], p: usize) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    Some(sorted[rank - 1])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fast_relay_peers() {
+        let mut table = PeerTable::new();
+        let start = Instant::now();
+        let peers: Vec<String> = ["a:1", "b:1", "c:1", "d:1"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        for (i, (addr, rtt)) in peers.iter().zip([30u64, 10, 20]).enumerate() {
+            table.record_ping(addr, i as u64, start);
+            assert!(table.record_pong(addr, i as u64 + 100, start).is_none());
+            let measured = table.record_pong(addr, i as u64, start + Duration::from_millis(rtt));
+            assert_eq!(measured, Some(Duration::from_millis(rtt)));
+        }
+
+        assert_eq!(table.fast_relay_peers(&peers, 2), vec!["b:1", "c:1"]);
+        assert_eq!(
+            table.relay_order(&peers, 2),
+            vec!["b:1", "c:1", "a:1", "d:1"]
+        );
+
+        // a slow sample only moves the average part of the way
+        table.record_ping("b:1", 7, start);
+        table.record_pong("b:1", 7, start + Duration::from_millis(50));
+        let rtt = table.peers["b:1"].rtt_ms.unwrap();
+        assert!((rtt - 20.0).abs() < 1e-6);
+        table.record_first_announcement("b:1");
+        assert_eq!(table.fast_relay_peers(&peers, 1), vec!["b:1"]);
+
+        let summaries = table.summaries(1);
+        assert_eq!(summaries.len(), 3);
+        assert!(summaries.iter().any(|s| s.addr == "b:1" && s.fast_relay));
+        assert_eq!(summaries.iter().filter(|s| s.fast_relay).count(), 1);
+
+        table.record_message("b:1", "block", 300, false);
+        table.record_message("b:1", "block", 200, false);
+        table.record_message("b:1", "inv", 50, true);
+        let stats = table.stats("b:1").unwrap();
+        assert_eq!(stats.messages.received["block"], 2);
+        assert_eq!(stats.messages.bytes_received, 500);
+        assert_eq!(stats.messages.sent["inv"], 1);
+        assert_eq!(stats.rtt_p50_ms, Some(10.0));
+        assert_eq!(stats.rtt_p99_ms, Some(50.0));
+        assert!(table.stats("x:1").is_none());
+    }
+
+    #[test]
+    fn test_known_inventory() {
+        let mut cache = InventoryCache::new(2);
+        assert!(cache.insert("tx1"));
+        assert!(!cache.insert("tx1"));
+        assert!(cache.insert("tx2"));
+        assert!(cache.insert("tx3"));
+        assert!(!cache.contains("tx1"));
+        assert!(cache.contains("tx2") && cache.contains("tx3"));
+
+        let mut table = PeerTable::new();
+        table.mark_known("a:1", "tx1");
+        assert!(table.knows("a:1", "tx1"));
+        assert!(!table.knows("a:1", "tx2"));
+        assert!(!table.knows("b:1", "tx1"));
+    }
+
+    #[test]
+    fn test_ban_policy() {
+        let mut table = PeerTable::new();
+        let now = Instant::now();
+        let peers = vec![String::from("a:1"), String::from("b:1")];
+        for addr in &peers {
+            table.record_ping(addr, 1, now);
+            table.record_pong(addr, 1, now + Duration::from_millis(10));
+        }
+
+        for _ in 0..5 {
+            assert!(!table.misbehave("a:1", Misbehavior::SlowResponse, now));
+        }
+        assert_eq!(table.fast_relay_peers(&peers, 2), vec!["b:1"]);
+        assert!(!table.is_banned("a:1", now));
+
+        let stats = table.stats("a:1").unwrap();
+        assert_eq!(stats.misbehaviors["SlowResponse"], 5);
+        assert_eq!(stats.ban_score, 50);
+
+        assert!(table.misbehave("b:1", Misbehavior::InvalidBlock, now));
+        table.remove("b:1");
+        assert!(table.is_banned("b:1", now));
+        assert!(!table.is_banned("b:1", now + BAN_DURATION));
+
+        table.pin("c:1");
+        assert!(!table.misbehave("c:1", Misbehavior::InvalidBlock, now));
+        assert!(!table.is_banned("c:1", now));
+        assert_eq!(table.pinned(), vec!["c:1"]);
+
+        // scores stick to the identity behind the addresses
+        assert!(table.set_identity("d:1", "10.0.0.4", now));
+        assert!(table.set_identity("d:2", "10.0.0.4", now));
+        assert!(table.set_identity("c:1", "10.0.0.3", now));
+        assert_eq!(table.addrs_of("10.0.0.4"), vec!["d:1", "d:2"]);
+        assert!(!table.misbehave("10.0.0.3", Misbehavior::InvalidBlock, now));
+        assert!(table.misbehave("10.0.0.4", Misbehavior::InvalidBlock, now));
+        assert!(table.is_banned("10.0.0.4", now));
+        assert!(!table.is_banned("d:1", now));
+    }
+
+    #[test]
+    fn test_identity_binding() {
+        let mut table = PeerTable::new();
+        let now = Instant::now();
+        assert!(table.set_identity("a:1", "10.0.0.1", now));
+        // another sender cannot take over a handshaked address
+        assert!(!table.set_identity("a:1", "10.0.0.2", now));
+        assert_eq!(table.identity("a:1"), Some("10.0.0.1"));
+        assert!(table.set_identity("a:1", "10.0.0.1", now + Duration::from_secs(1)));
+
+        // one sender keeps its most recent addresses only
+        for i in 0..MAX_ADDRS_PER_IDENTITY {
+            let at = now + Duration::from_secs(2 + i as u64);
+            assert!(table.set_identity(&format!("b:{}", i), "10.0.0.2", at));
+        }
+        assert!(table.set_identity("b:new", "10.0.0.2", now + Duration::from_secs(100)));
+        let addrs = table.addrs_of("10.0.0.2");
+        assert_eq!(addrs.len(), MAX_ADDRS_PER_IDENTITY);
+        assert!(!addrs.contains(&String::from("b:0")) && addrs.contains(&String::from("b:new")));
+        assert_eq!(table.identity("a:1"), Some("10.0.0.1"));
+
+        // a full table evicts the oldest handshake, not an arbitrary one
+        let mut table = PeerTable::new();
+        for i in 0..MAX_IDENTITIES {
+            let at = now + Duration::from_secs(i as u64);
+            assert!(table.set_identity(&format!("c:{}", i), &format!("10.1.{}", i), at));
+        }
+        assert!(table.set_identity("c:0", "10.1.0", now + Duration::from_secs(10_000)));
+        assert!(table.set_identity("d:1", "10.2.0", now + Duration::from_secs(10_001)));
+        assert_eq!(table.identity("c:0"), Some("10.1.0"));
+        assert_eq!(table.identity("c:1"), None);
+        assert_eq!(table.identity("d:1"), Some("10.2.0"));
+    }
+}
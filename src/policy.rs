@@ -0,0 +1,200 @@
+//! Opt-in compliance policy for mempool admission
+//!
+//! An operator may load a list of addresses signed by a policy authority.
+//! Transactions paying to or spending from a listed address are then not
+//! admitted to the mempool of this node, and every rejection is appended to
+//! an audit log. Blocks are never checked against the policy, so it has no
+//! effect on consensus: a listed transaction mined elsewhere is accepted.
+
+use super::*;
+use crate::signer::*;
+use crate::transaction::Transaction;
+use crate::wallets::*;
+use bincode::serialize;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::time::SystemTime;
+
+const AUDIT_LOG_PATH: &str = "data/policy-audit.log";
+
+/// PolicyList is the signed list of blocked addresses, stored as JSON
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PolicyList {
+    pub version: u64,
+    pub addresses: Vec<String>,
+    /// Hex encoded verifying key of the authority
+    pub public_key: String,
+    /// Hex encoded signature over the version and addresses
+    pub signature: String,
+}
+
+impl PolicyList {
+    /// Sign creates a list signed by `signer`
+    pub fn sign(version: u64, addresses: Vec<String>, signer: &dyn Signer) -> Result<PolicyList> {
+        let message = serialize(&(version, &addresses))?;
+        Ok(PolicyList {
+            version,
+            public_key: hex::encode(signer.public_key()?),
+            signature: hex::encode(signer.sign(&message)?),
+            addresses,
+        })
+    }
+
+    /// Verify checks that the list is signed by the authority `authority`
+    pub fn verify(&self, authority: &str) -> Result<()> {
+        let public_key = hex::decode(&self.public_key)?;
+        if address_from_pub_key(&public_key) != authority {
+            return Err(format_err!("policy list is not signed by {}", authority));
+        }
+        let message = serialize(&(self.version, &self.addresses))?;
+        if !verify_signature(&public_key, &message, &hex::decode(&self.signature)?) {
+            return Err(format_err!("policy list signature is invalid"));
+        }
+        Ok(())
+    }
+}
+
+/// CompliancePolicy rejects transactions touching the listed addresses
+pub struct CompliancePolicy {
+    version: u64,
+    /// Blocked public key hashes and their addresses
+    blocked: HashMap<Vec<u8>, String>,
+    audit_log: String,
+}
+
+impl CompliancePolicy {
+    /// Load reads a policy list file and checks its signature
+    pub fn load(path: &str, authority: &str) -> Result<CompliancePolicy> {
+        let list: PolicyList = serde_json::from_slice(&std::fs::read(path)?)?;
+        CompliancePolicy::new(list, authority, AUDIT_LOG_PATH)
+    }
+
+    pub fn new(list: PolicyList, authority: &str, audit_log: &str) -> Result<CompliancePolicy> {
+        list.verify(authority)?;
+        let mut blocked = HashMap::new();
+        for address in list.addresses {
+            let pub_key_hash = match crate::address::pub_key_hash(&address) {
+                Ok(h) => h,
+                Err(_) => return Err(format_err!("invalid address {} in policy list", address)),
+            };
+            blocked.insert(pub_key_hash, address);
+        }
+        info!(
+            "loaded compliance policy version {} with {} addresses",
+            list.version,
+            blocked.len()
+        );
+        Ok(CompliancePolicy {
+            version: list.version,
+            blocked,
+            audit_log: audit_log.to_string(),
+        })
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Addresses returns the listed addresses, sorted
+    pub fn addresses(&self) -> Vec<&str> {
+        let mut addresses: Vec<&str> = self.blocked.values().map(|a| a.as_str()).collect();
+        addresses.sort_unstable();
+        addresses
+    }
+
+    /// BlockedAddress returns the first listed address the transaction touches
+    pub fn blocked_address(&self, tx: &Transaction) -> Option<&str> {
+        if tx.is_coinbase() || tx.is_system() {
+            return None;
+        }
+        let outputs = tx.vout.iter().map(|out| out.pub_key_hash.clone());
+        let inputs = tx.vin.iter().map(|vin| {
+            let mut pub_key_hash = vin.pub_key.clone();
+            hash_pub_key(&mut pub_key_hash);
+            pub_key_hash
+        });
+        outputs
+            .chain(inputs)
+            .find_map(|h| self.blocked.get(&h).map(|a| a.as_str()))
+    }
+
+    /// Admit reports whether the transaction may enter the mempool, rejections are audited
+    pub fn admit(&self, tx: &Transaction, peer: &str) -> bool {
+        let address = match self.blocked_address(tx) {
+            Some(a) => a,
+            None => return true,
+        };
+        warn!("policy rejects tx {} touching {}", tx.id, address);
+        if let Err(e) = self.audit(tx, address, peer) {
+            error!("failed to write policy audit log: {}", e);
+        }
+        false
+    }
+
+    fn audit(&self, tx: &Transaction, address: &str, peer: &str) -> Result<()> {
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis();
+        if let Some(dir) = std::path::Path::new(&self.audit_log).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.audit_log)?;
+        writeln!(
+            file,
+            "{} policy={} tx={} address={} peer={}",
+            millis, self.version, tx.id, address, peer
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transaction::{TXInput, TXOutput};
+    use rand_core::OsRng;
+
+    #[test]
+    fn test_policy() {
+        let authority = Wallet::from_rng(&mut OsRng);
+        let blocked = Wallet::from_rng(&mut OsRng);
+        let other = Wallet::from_rng(&mut OsRng);
+        let list = PolicyList::sign(1, vec![blocked.get_address()], &authority).unwrap();
+        assert!(list.verify(&other.get_address()).is_err());
+        let mut forged = list.clone();
+        forged.addresses.clear();
+        assert!(forged.verify(&authority.get_address()).is_err());
+
+        let log = std::env::temp_dir().join(format!("polytorus-audit-{}.log", std::process::id()));
+        let log = log.to_str().unwrap();
+        let policy = CompliancePolicy::new(list, &authority.get_address(), log).unwrap();
+
+        let pay_to = |w: &Wallet, from: &Wallet| {
+            let mut tx = Transaction {
+                id: String::from("tx"),
+                vin: vec![TXInput {
+                    txid: String::from("prev"),
+                    vout: 0,
+                    signature: Vec::new(),
+                    pub_key: from.public_key.clone(),
+                }],
+                vout: vec![TXOutput::new(1, w.get_address()).unwrap()],
+            };
+            tx.id = tx.hash().unwrap();
+            tx
+        };
+        assert!(policy.admit(&pay_to(&other, &other), "peer"));
+        assert!(!policy.admit(&pay_to(&blocked, &other), "peer"));
+        assert!(!policy.admit(&pay_to(&other, &blocked), "peer"));
+
+        let audit = std::fs::read_to_string(log).unwrap();
+        assert_eq!(audit.lines().count(), 2);
+        assert!(audit.contains(&blocked.get_address()));
+        std::fs::remove_file(log).unwrap();
+    }
+}
@@ -0,0 +1,417 @@
+//! JSON-RPC 2.0 over HTTP
+//!
+//! The node answers HTTP POST requests carrying JSON-RPC 2.0 requests or
+//! batches on a separate port, so standard blockchain tooling can query it.
+//! The methods themselves are implemented by an `RpcHandler`.
+
+use super::*;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use failure::format_err;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+/// Largest request body accepted
+const MAX_BODY: usize = 4 * 1024 * 1024;
+/// Most request ids the idempotency cache remembers
+const MAX_IDEMPOTENCY_ENTRIES: usize = 10_000;
+
+/// RpcError is the error object of a JSON-RPC response
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: &str) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn invalid_params(message: &str) -> RpcError {
+        RpcError::new(INVALID_PARAMS, message)
+    }
+}
+
+impl From<failure::Error> for RpcError {
+    fn from(e: failure::Error) -> RpcError {
+        RpcError::new(INTERNAL_ERROR, &e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for RpcError {
+    fn from(e: serde_json::Error) -> RpcError {
+        RpcError::new(INTERNAL_ERROR, &e.to_string())
+    }
+}
+
+/// Outcome is the result of an RPC method
+pub type Outcome = std::result::Result<Value, RpcError>;
+
+/// Submission is what the idempotency cache knows about a request id
+#[derive(Debug, Clone, PartialEq)]
+pub enum Submission {
+    /// The id is new and the request should run, `finish` records its outcome
+    New,
+    /// A request with the same id is still running
+    InProgress,
+    /// The request already ran with this outcome
+    Done(Outcome),
+    /// The id was used for a request with other parameters
+    Conflict,
+}
+
+struct CachedSubmission {
+    /// SHA-256 of the request parameters
+    fingerprint: String,
+    at: Instant,
+    outcome: Option<Outcome>,
+}
+
+/// IdempotencyCache remembers the outcome of requests carrying a client
+/// request id, so a retried request is answered without running again
+///
+/// At most `MAX_IDEMPOTENCY_ENTRIES` ids are kept, the oldest is forgotten first.
+pub struct IdempotencyCache {
+    window: Duration,
+    entries: HashMap<String, CachedSubmission>,
+}
+
+impl IdempotencyCache {
+    pub fn new(window: Duration) -> IdempotencyCache {
+        IdempotencyCache {
+            window,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    /// Begin looks up a request id, `params` are the request parameters, e.g. a raw tx
+    pub fn begin(&mut self, id: &str, params: &str, now: Instant) -> Submission {
+        let window = self.window;
+        self.entries
+            .retain(|_, e| now.duration_since(e.at) < window);
+        let mut hasher = Sha256::new();
+        hasher.input_str(params);
+        let fingerprint = hasher.result_str();
+        match self.entries.get(id) {
+            Some(e) if e.fingerprint != fingerprint => Submission::Conflict,
+            Some(CachedSubmission { outcome: None, .. }) => Submission::InProgress,
+            Some(CachedSubmission {
+                outcome: Some(o), ..
+            }) => Submission::Done(o.clone()),
+            None => {
+                if self.entries.len() >= MAX_IDEMPOTENCY_ENTRIES {
+                    let oldest = self
+                        .entries
+                        .iter()
+                        .min_by_key(|(_, e)| e.at)
+                        .map(|(id, _)| id.clone());
+                    if let Some(oldest) = oldest {
+                        self.entries.remove(&oldest);
+                    }
+                }
+                self.entries.insert(
+                    id.to_string(),
+                    CachedSubmission {
+                        fingerprint,
+                        at: now,
+                        outcome: None,
+                    },
+                );
+                Submission::New
+            }
+        }
+    }
+
+    /// Finish records the outcome of a new request, internal errors are
+    /// forgotten so the request can be retried
+    pub fn finish(&mut self, id: &str, outcome: &Outcome) {
+        if matches!(outcome, Err(e) if e.code == INTERNAL_ERROR) {
+            self.entries.remove(id);
+        } else if let Some(e) = self.entries.get_mut(id) {
+            e.outcome = Some(outcome.clone());
+        }
+    }
+}
+
+/// RpcHandler runs the methods of the RPC server
+pub trait RpcHandler: Send + Sync {
+    fn call(&self, method: &str, params: &[Value]) -> std::result::Result<Value, RpcError>;
+}
+
+/// Serve answers RPC requests on the listener until it fails
+pub fn serve(listener: TcpListener, handler: Arc<dyn RpcHandler>) -> Result<()> {
+    info!("RPC server listen on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let handler = Arc::clone(&handler);
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, handler.as_ref()) {
+                warn!("rpc connection failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Call runs one method on the RPC server at `addr`
+pub fn call(addr: &str, method: &str, params: Value) -> Result<Value> {
+    let body = json!({"jsonrpc": "2.0", "method": method, "params": params, "id": 1}).to_string();
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        addr,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let body = match response.split_once("\r\n\r\n") {
+        Some((_, body)) => body,
+        None => return Err(format_err!("malformed rpc response")),
+    };
+    let mut response: Value = serde_json::from_str(body)?;
+    if let Some(error) = response.get("error") {
+        return Err(format_err!(
+            "rpc error {}: {}",
+            error["code"],
+            error["message"]
+        ));
+    }
+    Ok(response["result"].take())
+}
+
+fn handle_connection(stream: TcpStream, handler: &dyn RpcHandler) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()?;
+            }
+        }
+    }
+    if !request_line.starts_with("POST ") {
+        return write_response(&mut stream, "405 Method Not Allowed", "");
+    }
+    if content_length > MAX_BODY {
+        return write_response(&mut stream, "413 Payload Too Large", "");
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+
+    match handle_body(&body, handler) {
+        Some(response) => write_response(&mut stream, "200 OK", &response.to_string()),
+        None => write_response(&mut stream, "204 No Content", ""),
+    }
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: &str) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+/// handle_body runs a request or batch, returns None if only notifications were sent
+fn handle_body(body: &[u8], handler: &dyn RpcHandler) -> Option<Value> {
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(v) => v,
+        Err(_) => {
+            return Some(error_response(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, "parse error"),
+            ))
+        }
+    };
+    match request {
+        Value::Array(batch) if batch.is_empty() => Some(error_response(
+            Value::Null,
+            RpcError::new(INVALID_REQUEST, "empty batch"),
+        )),
+        Value::Array(batch) => {
+            let responses: Vec<Value> = batch
+                .iter()
+                .filter_map(|r| handle_request(r, handler))
+                .collect();
+            if responses.is_empty() {
+                None
+            } else {
+                Some(Value::Array(responses))
+            }
+        }
+        request => handle_request(&request, handler),
+    }
+}
+
+fn handle_request(request: &Value, handler: &dyn RpcHandler) -> Option<Value> {
+    let id = request.get("id").cloned();
+    let method = match (request.get("jsonrpc"), request.get("method")) {
+        (Some(v), Some(Value::String(m))) if v == "2.0" => m,
+        _ => {
+            return Some(error_response(
+                id.unwrap_or(Value::Null),
+                RpcError::new(INVALID_REQUEST, "invalid request"),
+            ))
+        }
+    };
+    let params = match request.get("params") {
+        None => Vec::new(),
+        Some(Value::Array(p)) => p.clone(),
+        Some(_) => {
+            return Some(error_response(
+                id.unwrap_or(Value::Null),
+                RpcError::invalid_params("params must be an array"),
+            ))
+        }
+    };
+
+    debug!("rpc call {} {:?}", method, params);
+    let result = handler.call(method, &params);
+    // requests without an id are notifications and get no response
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "result": result, "id": id}),
+        Err(e) => error_response(id, e),
+    })
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": {"code": error.code, "message": error.message},
+        "id": id,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct EchoHandler;
+
+    impl RpcHandler for EchoHandler {
+        fn call(&self, method: &str, params: &[Value]) -> std::result::Result<Value, RpcError> {
+            match method {
+                "echo" => Ok(Value::Array(params.to_vec())),
+                _ => Err(RpcError::new(METHOD_NOT_FOUND, "method not found")),
+            }
+        }
+    }
+
+    fn post(addr: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_idempotency_cache() {
+        let start = Instant::now();
+        let mut cache = IdempotencyCache::new(Duration::from_secs(60));
+        assert_eq!(cache.begin("r1", "tx-a", start), Submission::New);
+        assert_eq!(cache.begin("r1", "tx-a", start), Submission::InProgress);
+        cache.finish("r1", &Ok(json!("txid")));
+        assert_eq!(
+            cache.begin("r1", "tx-a", start),
+            Submission::Done(Ok(json!("txid")))
+        );
+        assert_eq!(cache.begin("r1", "tx-b", start), Submission::Conflict);
+
+        assert_eq!(cache.begin("r2", "tx-b", start), Submission::New);
+        cache.finish("r2", &Err(RpcError::new(INTERNAL_ERROR, "db failed")));
+        assert_eq!(cache.begin("r2", "tx-b", start), Submission::New);
+
+        let later = start + Duration::from_secs(60);
+        assert_eq!(cache.begin("r1", "tx-b", later), Submission::New);
+
+        // a full cache forgets its oldest request id
+        for i in 1..MAX_IDEMPOTENCY_ENTRIES {
+            cache.begin(
+                &format!("f{}", i),
+                "tx",
+                later + Duration::from_millis(i as u64),
+            );
+        }
+        assert_eq!(cache.entries.len(), MAX_IDEMPOTENCY_ENTRIES);
+        assert_eq!(
+            cache.begin("f0", "tx", later + Duration::from_secs(1)),
+            Submission::New
+        );
+        assert_eq!(cache.entries.len(), MAX_IDEMPOTENCY_ENTRIES);
+        assert!(!cache.entries.contains_key("r1"));
+        assert!(cache.entries.values().all(|e| e.fingerprint.len() == 64));
+    }
+
+    #[test]
+    fn test_json_rpc() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve(listener, Arc::new(EchoHandler)));
+
+        let response = post(
+            &addr,
+            r#"{"jsonrpc":"2.0","method":"echo","params":[1,"a"],"id":7}"#,
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with(r#"{"id":7,"jsonrpc":"2.0","result":[1,"a"]}"#));
+
+        let body = r#"[{"jsonrpc":"2.0","method":"nope","id":1},{"jsonrpc":"2.0","method":"echo"},{"method":"echo","id":2}]"#;
+        let batch: Value =
+            serde_json::from_str(post(&addr, body).split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(batch.as_array().unwrap().len(), 2);
+        assert_eq!(batch[0]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(batch[1]["error"]["code"], INVALID_REQUEST);
+
+        let response = post(&addr, "{not json");
+        assert!(response.contains(&PARSE_ERROR.to_string()));
+
+        assert_eq!(call(&addr, "echo", json!(["x"])).unwrap(), json!(["x"]));
+        assert!(call(&addr, "nope", json!([])).is_err());
+    }
+}
@@ -1,18 +1,41 @@
 //! server of Blockchain
 
 use super::*;
+use crate::address;
+use crate::assembly::*;
+use crate::bandwidth::*;
 use crate::block::*;
+use crate::census::*;
+use crate::crashreport;
+use crate::fees::fee_histogram;
+use crate::logging;
+use crate::metrics::{self, Counters, Exposition};
+use crate::orphans::OrphanPool;
+use crate::peers::*;
+use crate::policy::*;
+use crate::rpc::*;
+use crate::statesync::*;
+use crate::sync::*;
+use crate::telemetry::*;
 use crate::transaction::*;
+use crate::transport::*;
+use crate::txindex::*;
 use crate::utxoset::*;
-use bincode::{deserialize, serialize};
+use crate::watchdog::*;
+use bincode::{deserialize, serialize, Options};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 use failure::format_err;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use serde_reflection::{Tracer, TracerConfig};
 use std::collections::{HashMap, HashSet};
 use std::io::prelude::*;
 use std::net::{TcpListener, TcpStream};
 use std::sync::*;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum Message {
@@ -23,6 +46,52 @@ enum Message {
     GetBlock(GetBlocksmsg),
     Inv(Invmsg),
     Block(Blockmsg),
+    GetState(GetStatemsg),
+    StateInfo(StateInfomsg),
+    GetStateChunk(GetStateChunkmsg),
+    StateChunk(StateChunkmsg),
+    Ping(Pingmsg),
+    Pong(Pingmsg),
+    GetHeaders(GetHeadersmsg),
+    Headers(Headersmsg),
+}
+
+impl Message {
+    /// addr_from returns the node address the message claims to come from
+    fn addr_from(&self) -> Option<&str> {
+        match self {
+            Message::Addr(_) => None,
+            Message::Version(m) => Some(&m.addr_from),
+            Message::Tx(m) => Some(&m.addr_from),
+            Message::GetData(m) => Some(&m.addr_from),
+            Message::GetBlock(m) => Some(&m.addr_from),
+            Message::Inv(m) => Some(&m.addr_from),
+            Message::Block(m) => Some(&m.addr_from),
+            Message::GetState(m) => Some(&m.addr_from),
+            Message::StateInfo(m) => Some(&m.addr_from),
+            Message::GetStateChunk(m) => Some(&m.addr_from),
+            Message::StateChunk(m) => Some(&m.addr_from),
+            Message::Ping(m) | Message::Pong(m) => Some(&m.addr_from),
+            Message::GetHeaders(m) => Some(&m.addr_from),
+            Message::Headers(m) => Some(&m.addr_from),
+        }
+    }
+
+    /// well_formed checks the structure of a decoded message before it is handled
+    fn well_formed(&self) -> bool {
+        match self {
+            Message::Addr(addrs) => addrs.len() <= MAX_ADDR_ENTRIES,
+            Message::Inv(m) => {
+                (m.kind == "block" || m.kind == "tx")
+                    && !m.items.is_empty()
+                    && m.items.len() <= MAX_INV_ITEMS
+            }
+            Message::GetData(m) => m.kind == "block" || m.kind == "tx",
+            Message::GetHeaders(m) => m.locator.len() <= MAX_LOCATOR,
+            Message::Headers(m) => m.headers.len() <= MAX_HEADERS,
+            _ => true,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -61,6 +130,131 @@ struct Versionmsg {
     addr_from: String,
     version: i32,
     best_height: i32,
+    /// Sender clock in unix milliseconds, since version 2
+    timestamp: u64,
+    /// SERVICE_* bits, since version 2
+    services: u64,
+    /// Since version 2
+    user_agent: String,
+    /// Chain id of the sender's genesis, see `Blockchain::chain_id`, since version 3
+    chain_id: String,
+}
+
+/// VersionmsgV2 is the version message of version 2 nodes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct VersionmsgV2 {
+    addr_from: String,
+    version: i32,
+    best_height: i32,
+    timestamp: u64,
+    services: u64,
+    user_agent: String,
+}
+
+impl From<VersionmsgV2> for Versionmsg {
+    fn from(msg: VersionmsgV2) -> Versionmsg {
+        Versionmsg {
+            addr_from: msg.addr_from,
+            version: msg.version,
+            best_height: msg.best_height,
+            timestamp: msg.timestamp,
+            services: msg.services,
+            user_agent: msg.user_agent,
+            chain_id: String::new(),
+        }
+    }
+}
+
+/// VersionmsgV1 is the version message of version 1 nodes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct VersionmsgV1 {
+    addr_from: String,
+    version: i32,
+    best_height: i32,
+}
+
+impl From<VersionmsgV1> for Versionmsg {
+    fn from(msg: VersionmsgV1) -> Versionmsg {
+        Versionmsg {
+            addr_from: msg.addr_from,
+            version: msg.version,
+            best_height: msg.best_height,
+            timestamp: 0,
+            services: 0,
+            user_agent: String::new(),
+            chain_id: String::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetStatemsg {
+    addr_from: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StateInfomsg {
+    addr_from: String,
+    target: StateTarget,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetStateChunkmsg {
+    addr_from: String,
+    root: Vec<u8>,
+    index: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StateChunkmsg {
+    addr_from: String,
+    root: Vec<u8>,
+    chunk: StateChunk,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Pingmsg {
+    addr_from: String,
+    nonce: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetHeadersmsg {
+    addr_from: String,
+    locator: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Headersmsg {
+    addr_from: String,
+    headers: Vec<BlockHeader>,
+}
+
+/// Capability is a subsystem of the node as reported by getcapabilities
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Capability {
+    pub name: &'static str,
+    pub enabled: bool,
+    pub version: Option<u64>,
+    /// Hash of the settings of the subsystem, changes whenever they do
+    pub config_digest: Option<String>,
+}
+
+impl Capability {
+    fn new(name: &'static str, enabled: bool, settings: Value) -> Capability {
+        let mut hasher = Sha256::new();
+        hasher.input_str(&settings.to_string());
+        Capability {
+            name,
+            enabled,
+            version: None,
+            config_digest: if enabled {
+                Some(hasher.result_str())
+            } else {
+                None
+            },
+        }
+    }
 }
 
 pub struct Server {
@@ -73,14 +267,116 @@ struct ServerInner {
     known_nodes: HashSet<String>,
     utxo: UTXOSet,
     blocks_in_transit: Vec<String>,
-    mempool: HashMap<String, Transaction>,
+    mempool: HashMap<String, MempoolEntry>,
+    next_arrival: u64,
+    tx_ordering: OrderingStrategy,
+    max_block_txs: usize,
+    state_sync: Option<StateSync>,
+    state_peers: HashSet<String>,
+    /// UTXO set served to syncing peers, rebuilt when the tip moves
+    state_snapshot: Option<Arc<StateSnapshot>>,
+    peers: PeerTable,
+    fast_relay_count: usize,
+    announced_blocks: InventoryCache,
+    seen_txs: InventoryCache,
+    requested_txs: HashMap<String, Instant>,
+    /// announced blocks requested from a peer and not received yet
+    requested_blocks: HashMap<String, Instant>,
+    /// relayed blocks waiting for their parent
+    orphans: OrphanPool,
+    /// blocks taken off the best chain by the last reorg
+    last_reorg_depth: usize,
+    sync: SyncManager,
+    node_key: Option<NodeKey>,
+    encrypt_outbound: bool,
+    require_encryption: bool,
+    policy: Option<Arc<CompliancePolicy>>,
+    telemetry: Option<ArrivalLog>,
+    counters: Counters,
+    census: Census,
+    /// outcomes of submissions by client request id
+    submissions: IdempotencyCache,
+    watchdog: Option<Watchdog>,
+    /// set by the watchdog while the block storage fails
+    mining_paused: bool,
+    bandwidth: BandwidthLimiter,
+    floods: FloodLimiter,
+    /// templates handed out to external miners by id
+    templates: HashMap<String, BlockTemplate>,
+    /// sender identities whose system transactions enter the mempool
+    system_tx_submitters: HashSet<String>,
+}
+
+#[derive(Clone)]
+struct MempoolEntry {
+    tx: Transaction,
+    arrival: u64,
 }
 
 const CMD_LEN: usize = 12;
-const VERSION: i32 = 1;
+const VERSION: i32 = 5;
+/// Oldest protocol version accepted, blocks carry their target since
+/// version 4 and their chain id since version 5
+const MIN_PEER_VERSION: i32 = 5;
+/// Fields added to a wire type after version 1, with the version adding them
+const FIELD_GATES: &[(&str, &str, i32)] = &[
+    ("Versionmsg", "timestamp", 2),
+    ("Versionmsg", "services", 2),
+    ("Versionmsg", "user_agent", 2),
+    ("Versionmsg", "chain_id", 3),
+    ("Block", "target", 4),
+    ("BlockHeader", "target", 4),
+    ("Block", "chain_id", 5),
+    ("BlockHeader", "chain_id", 5),
+];
+const USER_AGENT: &str = concat!("polytorus/", env!("CARGO_PKG_VERSION"));
+/// Default number of low latency peers that get block announcements first
+pub const DEFAULT_FAST_RELAY_COUNT: usize = 3;
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_ANNOUNCED_BLOCKS: usize = 1024;
+/// Most addresses in an addr message
+const MAX_ADDR_ENTRIES: usize = 1000;
+/// Most hashes in an inv message, a getblocks answer lists the whole chain
+const MAX_INV_ITEMS: usize = 100_000;
+/// Most hashes in a block locator
+const MAX_LOCATOR: usize = 64;
+/// Most block templates kept for external miners
+const MAX_TEMPLATES: usize = 16;
+/// Most system transactions waiting in the mempool, they pay no fee
+const MAX_MEMPOOL_SYSTEM_TXS: usize = 16;
+/// Senders always allowed to submit system transactions: RPC and the local CLI
+const LOCAL_SUBMITTERS: &[&str] = &["rpc", "127.0.0.1", "::1"];
+/// Time a connection may take to deliver its message
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Number of recent block intervals reported by getmempoolinfo
+const RECENT_BLOCK_TIMES: usize = 10;
+/// Time after which an unanswered tx or block request may be sent to another peer
+const TX_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// RPC error code of a missing block, as used by other chains
+const RPC_NOT_FOUND: i64 = -5;
+/// RPC error code of a rejected transaction
+const RPC_VERIFY_REJECTED: i64 = -26;
+/// RPC error code of a request whose id is still being processed
+const RPC_IN_PROGRESS: i64 = -32001;
+/// Default time the outcome of a request with a client request id is remembered
+pub const DEFAULT_IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(600);
+/// Number of blocks a peer may lag behind before its height counts as stale
+const STALE_HEIGHT_LAG: i32 = 100;
+/// Node the CLI sends its transactions to
+const CLIENT_NODE: &str = "0.0.0.0:7000";
+/// Time the CLI waits for the node to answer its version
+const CLIENT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time between two checks for the answer of the node
+const CLIENT_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 impl Server {
-    pub fn new(host: &str, port: &str, miner_address: &str, bootstap: Option<&str>, utxo: UTXOSet) -> Result<Server> {
+    pub fn new(
+        host: &str,
+        port: &str,
+        miner_address: &str,
+        bootstap: Option<&str>,
+        utxo: UTXOSet,
+    ) -> Result<Server> {
         let mut node_set = HashSet::new();
         // node_set.insert(String::from(KNOWN_NODE1));
         if let Some(bn) = bootstap {
@@ -94,11 +390,46 @@ impl Server {
                 utxo,
                 blocks_in_transit: Vec::new(),
                 mempool: HashMap::new(),
+                next_arrival: 0,
+                tx_ordering: OrderingStrategy::default(),
+                max_block_txs: 0,
+                state_sync: None,
+                state_peers: HashSet::new(),
+                state_snapshot: None,
+                peers: PeerTable::new(),
+                fast_relay_count: DEFAULT_FAST_RELAY_COUNT,
+                announced_blocks: InventoryCache::new(MAX_ANNOUNCED_BLOCKS),
+                seen_txs: InventoryCache::default(),
+                requested_txs: HashMap::new(),
+                requested_blocks: HashMap::new(),
+                orphans: OrphanPool::new(),
+                last_reorg_depth: 0,
+                sync: SyncManager::new(),
+                node_key: None,
+                encrypt_outbound: false,
+                require_encryption: false,
+                policy: None,
+                telemetry: None,
+                counters: Counters::new(),
+                census: Census::new(),
+                submissions: IdempotencyCache::new(DEFAULT_IDEMPOTENCY_WINDOW),
+                watchdog: None,
+                mining_paused: false,
+                bandwidth: BandwidthLimiter::default(),
+                floods: FloodLimiter::new(),
+                templates: HashMap::new(),
+                system_tx_submitters: LOCAL_SUBMITTERS.iter().map(|s| s.to_string()).collect(),
             })),
         })
     }
 
     pub fn start_server(&self) -> Result<()> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.node_key.is_none() {
+                inner.node_key = Some(NodeKey::load_or_create()?);
+            }
+        }
         let server1 = Server {
             node_address: self.node_address.clone(),
             mining_address: self.mining_address.clone(),
@@ -109,17 +440,46 @@ impl Server {
             &self.node_address, &self.mining_address
         );
 
-        thread::spawn(move || {
+        let summary_inner = Arc::clone(&self.inner);
+        let summary_address = self.node_address.clone();
+        crashreport::set_state_summary(Box::new(move || {
+            let inner = match summary_inner.try_lock() {
+                Ok(inner) => inner,
+                Err(_) => return String::from("server state is locked"),
+            };
+            format!(
+                "node: {}\ntip: {}\nknown nodes: {}\nmempool: {}\nblocks in transit: {}\nsync headers pending: {}\nstate sync: {}",
+                summary_address,
+                inner.utxo.blockchain.tip,
+                inner.known_nodes.len(),
+                inner.mempool.len(),
+                inner.blocks_in_transit.len(),
+                inner.sync.pending(),
+                inner.state_sync.is_some(),
+            )
+        }));
+
+        thread::spawn(move || -> Result<()> {
             thread::sleep(Duration::from_millis(1000));
-            if server1.get_best_height()? == -1 {
-                server1.request_blocks()
-            } else {
-                let nodes = server1.get_known_nodes();
-                Ok(if !nodes.is_empty() {
-                    let first = nodes.iter().next().unwrap();
-                    server1.send_version(first)?;
-                })
+            // peers only accept requests after a handshake, see handle_version
+            for node in server1.get_known_nodes() {
+                server1.send_version(&node)?;
+            }
+            Ok(())
+        });
+
+        let server2 = Server {
+            node_address: self.node_address.clone(),
+            mining_address: self.mining_address.clone(),
+            inner: Arc::clone(&self.inner),
+        };
+        thread::spawn(move || loop {
+            server2.ping_peers();
+            server2.watch();
+            if let Err(e) = server2.request_sync_blocks() {
+                warn!("request sync blocks failed: {}", e);
             }
+            thread::sleep(PING_INTERVAL);
         });
 
         let listener = TcpListener::bind(&self.node_address).unwrap();
@@ -139,23 +499,439 @@ impl Server {
     }
 
     pub fn send_transaction(tx: &Transaction, utxoset: UTXOSet) -> Result<()> {
-        let server = Server::new("0.0.0.0", "7000", "", None, utxoset)?;
-        server.send_tx("0.0.0.0:7000", tx)?;
+        Server::send_transaction_to(CLIENT_NODE, tx, utxoset)
+    }
+
+    /// send_transaction_to sends a tx to `node` once the node answered the client version
+    fn send_transaction_to(node: &str, tx: &Transaction, utxoset: UTXOSet) -> Result<()> {
+        // the node drops the tx of a client that did not send its version
+        // first, and answers the version at the address the client announces
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port().to_string();
+        let server = Server::new("127.0.0.1", &port, "", None, utxoset)?;
+        server.send_version(node)?;
+        await_version(&listener)?;
+        server.send_tx(node, tx)?;
+        Ok(())
+    }
+
+    /// EnableStateSync makes the node fetch the UTXO set from its peers on start
+    ///
+    /// Only a state with `trusted_root` is fetched, any announced state if
+    /// None. An unfinished sync from a previous run is resumed if peers
+    /// still serve it.
+    pub fn enable_state_sync(&self, trusted_root: Option<Vec<u8>>) -> Result<()> {
+        self.inner.lock().unwrap().state_sync = Some(StateSync::new(trusted_root)?);
+        Ok(())
+    }
+
+    /// StartRpc serves JSON-RPC requests on `addr` in the background
+    pub fn start_rpc(&self, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let server = Server {
+            node_address: self.node_address.clone(),
+            mining_address: self.mining_address.clone(),
+            inner: Arc::clone(&self.inner),
+        };
+        thread::spawn(move || rpc::serve(listener, Arc::new(server)));
+        Ok(())
+    }
+
+    /// StartMetrics serves Prometheus metrics on `GET /metrics` at addr
+    pub fn start_metrics(&self, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let server = Server {
+            node_address: self.node_address.clone(),
+            mining_address: self.mining_address.clone(),
+            inner: Arc::clone(&self.inner),
+        };
+        thread::spawn(move || metrics::serve(listener, Arc::new(move || server.render_metrics())));
+        Ok(())
+    }
+
+    fn render_metrics(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut scrape = Exposition::new();
+        let height = inner.utxo.blockchain.get_best_height().unwrap_or(-1);
+        scrape.gauge(
+            "polytorus_chain_height",
+            "Height of the best local block",
+            &[(&[], height as f64)],
+        );
+        scrape.gauge(
+            "polytorus_network_height",
+            "Best height announced by peers",
+            &[(&[], inner.sync.network_height() as f64)],
+        );
+        scrape.gauge(
+            "polytorus_sync_headers_pending",
+            "Headers waiting for their blocks",
+            &[(&[], inner.sync.pending() as f64)],
+        );
+        scrape.gauge(
+            "polytorus_mempool_transactions",
+            "Transactions in the mempool",
+            &[(&[], inner.mempool.len() as f64)],
+        );
+        scrape.gauge(
+            "polytorus_known_nodes",
+            "Addresses of known nodes",
+            &[(&[], inner.known_nodes.len() as f64)],
+        );
+        scrape.gauge(
+            "polytorus_requests_in_flight",
+            "Announced items requested from peers and not received yet",
+            &[
+                (&[("kind", "block")], inner.requested_blocks.len() as f64),
+                (&[("kind", "tx")], inner.requested_txs.len() as f64),
+            ],
+        );
+        scrape.gauge(
+            "polytorus_orphan_blocks",
+            "Blocks waiting for their parent",
+            &[(&[], inner.orphans.len() as f64)],
+        );
+        scrape.gauge(
+            "polytorus_last_reorg_depth",
+            "Blocks taken off the best chain by the last reorg",
+            &[(&[], inner.last_reorg_depth as f64)],
+        );
+        let size = inner.utxo.blockchain.db.size_on_disk().unwrap_or_default();
+        scrape.gauge(
+            "polytorus_storage_bytes",
+            "Size of a database on disk",
+            &[(&[("db", "blocks")], size as f64)],
+        );
+
+        let peers = inner.peers.summaries(inner.fast_relay_count);
+        let labels: Vec<[(&str, &str); 1]> =
+            peers.iter().map(|p| [("peer", p.addr.as_str())]).collect();
+        let rtt: Vec<(metrics::Labels, f64)> = peers
+            .iter()
+            .zip(&labels)
+            .filter_map(|(p, l)| p.rtt_ms.map(|ms| (&l[..], ms / 1000.0)))
+            .collect();
+        scrape.gauge(
+            "polytorus_peer_rtt_seconds",
+            "Smoothed ping round trip time",
+            &rtt,
+        );
+        let scores: Vec<(metrics::Labels, f64)> = peers
+            .iter()
+            .zip(&labels)
+            .map(|(p, l)| (&l[..], p.ban_score as f64))
+            .collect();
+        scrape.gauge(
+            "polytorus_peer_ban_score",
+            "Misbehavior score, banned at 100",
+            &scores,
+        );
+        scrape.counters(&inner.counters);
+        scrape.finish()
+    }
+
+    fn count_message(&self, direction: &str, peer: Option<&str>, data: &[u8]) {
+        let command = command_name(data);
+        let labels = [("command", command.as_str())];
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(peer) = peer {
+            inner
+                .peers
+                .record_message(peer, &command, data.len(), direction == "sent");
+        }
+        if direction == "sent" {
+            inner.counters.add(
+                "polytorus_messages_sent_total",
+                "Messages sent to peers",
+                &labels,
+                1,
+            );
+            inner.counters.add(
+                "polytorus_bytes_sent_total",
+                "Message bytes sent to peers",
+                &labels,
+                data.len() as u64,
+            );
+        } else {
+            inner.counters.add(
+                "polytorus_messages_received_total",
+                "Messages received from peers",
+                &labels,
+                1,
+            );
+            inner.counters.add(
+                "polytorus_bytes_received_total",
+                "Message bytes received from peers",
+                &labels,
+                data.len() as u64,
+            );
+        }
+    }
+
+    /// EnableEncryption sends every message over an encrypted Noise connection
+    ///
+    /// With `require` set, plaintext messages from other nodes are refused.
+    pub fn enable_encryption(&self, require: bool) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.node_key.is_none() {
+            inner.node_key = Some(NodeKey::load_or_create()?);
+        }
+        inner.encrypt_outbound = true;
+        inner.require_encryption = require;
         Ok(())
     }
 
+    /// SetPolicy makes the node refuse mempool admission to transactions
+    /// touching the addresses of a compliance policy
+    pub fn set_policy(&self, policy: CompliancePolicy) {
+        self.inner.lock().unwrap().policy = Some(Arc::new(policy));
+    }
+
+    /// EnableTelemetry records when each peer first delivered each tx and block
+    pub fn enable_telemetry(&self) -> Result<()> {
+        self.inner.lock().unwrap().telemetry = Some(ArrivalLog::open(ARRIVAL_LOG_PATH)?);
+        Ok(())
+    }
+
+    /// SetBlockAssembly sets the order in which mined blocks take mempool
+    /// transactions and how many they take, 0 for all
+    pub fn set_block_assembly(&self, ordering: OrderingStrategy, max_block_txs: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tx_ordering = ordering;
+        inner.max_block_txs = max_block_txs;
+    }
+
+    /// SetIdempotencyWindow sets how long the outcome of a submission with a request id is kept
+    pub fn set_idempotency_window(&self, window: Duration) {
+        self.inner.lock().unwrap().submissions.set_window(window);
+    }
+
+    /// EnableWatchdog checks the health of the node on every ping round and heals it
+    pub fn enable_watchdog(&self, min_peers: usize, page_command: Option<&str>) {
+        self.inner.lock().unwrap().watchdog =
+            Some(Watchdog::new(min_peers, page_command, AUDIT_LOG_PATH));
+    }
+
+    /// PinPeers adds peers that are never evicted or banned
+    pub fn pin_peers(&self, addrs: &[String]) {
+        let mut inner = self.inner.lock().unwrap();
+        for addr in addrs {
+            inner.peers.pin(addr);
+            inner.known_nodes.insert(addr.clone());
+        }
+    }
+
+    /// AuthorizeSystemTxs lets the sender identities, IPs or Noise keys,
+    /// submit system transactions besides RPC and the local host
+    pub fn authorize_system_txs(&self, identities: &[String]) {
+        let mut inner = self.inner.lock().unwrap();
+        for identity in identities {
+            inner.system_tx_submitters.insert(identity.clone());
+        }
+    }
+
+    /// SetFastRelayCount sets how many low latency peers get block announcements first
+    pub fn set_fast_relay_count(&self, count: usize) {
+        self.inner.lock().unwrap().fast_relay_count = count;
+    }
+
+    /// SetBandwidthLimits limits the message bytes per second of each peer and of all peers
+    ///
+    /// Limits apply to both directions separately, 0 disables a limit.
+    pub fn set_bandwidth_limits(&self, peer_rate: u64, global_rate: u64) {
+        self.inner.lock().unwrap().bandwidth = BandwidthLimiter::new(peer_rate, global_rate);
+    }
+
+    /// Capabilities describes the protocol and the subsystems enabled on this node
+    fn capabilities(&self) -> Value {
+        let inner = self.inner.lock().unwrap();
+        let mut services = SERVICE_STATE | SERVICE_HEADERS;
+        if inner.node_key.is_some() {
+            services |= SERVICE_NOISE;
+        }
+        let services: Vec<&str> = SERVICES
+            .iter()
+            .filter(|(bit, _)| services & bit != 0)
+            .map(|(_, name)| *name)
+            .collect();
+        let mut policy = Capability::new(
+            "compliance-policy",
+            inner.policy.is_some(),
+            json!(inner.policy.as_ref().map(|p| (p.version(), p.addresses()))),
+        );
+        policy.version = inner.policy.as_ref().map(|p| p.version());
+        let features = vec![
+            Capability::new(
+                "mining",
+                !self.mining_address.is_empty(),
+                json!({"tx_order": inner.tx_ordering.to_string(), "max_block_txs": inner.max_block_txs}),
+            ),
+            Capability::new("state-sync", inner.state_sync.is_some(), json!(null)),
+            Capability::new(
+                "encryption",
+                inner.encrypt_outbound || inner.require_encryption,
+                json!({"require": inner.require_encryption}),
+            ),
+            policy,
+            Capability::new("telemetry", inner.telemetry.is_some(), json!(null)),
+            Capability::new(
+                "watchdog",
+                inner.watchdog.is_some(),
+                json!(inner
+                    .watchdog
+                    .as_ref()
+                    .map(|w| (w.min_peers(), w.page_command()))),
+            ),
+            Capability::new("tx-index", true, json!(null)),
+            Capability::new(
+                "bandwidth-limit",
+                inner.bandwidth.peer_rate() > 0 || inner.bandwidth.global_rate() > 0,
+                json!({"peer": inner.bandwidth.peer_rate(), "global": inner.bandwidth.global_rate()}),
+            ),
+            Capability::new(
+                "idempotent-submission",
+                true,
+                json!({"window_secs": inner.submissions.window().as_secs()}),
+            ),
+        ];
+        json!({
+            "user_agent": USER_AGENT,
+            "protocol_version": VERSION,
+            "services": services,
+            "features": features,
+        })
+    }
+
     /* ------------------- inner halp functions ----------------------------------*/
 
+    /// watch runs a watchdog check and carries out its actions
+    fn watch(&self) {
+        let (actions, page_command) = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.watchdog.is_none() {
+                return;
+            }
+            let db = &inner.utxo.blockchain.db;
+            let storage_writable = db
+                .insert("WATCHDOG", &now_millis().to_be_bytes())
+                .and_then(|_| db.flush())
+                .is_ok();
+            let health = Health {
+                tip: inner.utxo.blockchain.tip.clone(),
+                peers: inner.known_nodes.len(),
+                storage_writable,
+                mempool: inner.mempool.len(),
+            };
+            let watchdog = inner.watchdog.as_mut().unwrap();
+            let actions = watchdog.check(&health, Instant::now());
+            (actions, watchdog.page_command().map(String::from))
+        };
+        for action in actions {
+            match action {
+                Action::ReconnectPeers => {
+                    for node in self.get_known_nodes() {
+                        if let Err(e) = self.send_version(&node) {
+                            warn!("reconnect to {} failed: {}", node, e);
+                        }
+                    }
+                }
+                Action::PauseMining => self.inner.lock().unwrap().mining_paused = true,
+                Action::ResumeMining => self.inner.lock().unwrap().mining_paused = false,
+                Action::Page(message) => {
+                    if let Err(e) = page(page_command.as_deref(), &message) {
+                        error!("page failed: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
     fn remove_node(&self, addr: &str) {
-        self.inner.lock().unwrap().known_nodes.remove(addr);
+        let mut inner = self.inner.lock().unwrap();
+        if inner.peers.is_pinned(addr) {
+            info!("keep unreachable pinned peer {}", addr);
+            return;
+        }
+        inner.known_nodes.remove(addr);
+        inner.peers.remove(addr);
+        inner.sync.remove_peer(addr);
+        inner.census.remove(addr);
+        inner.bandwidth.remove_peer(addr);
+        inner.floods.remove_peer(addr);
+    }
+
+    /// throttle holds a message back while it exceeds the bandwidth limits
+    fn throttle(&self, peer: &str, direction: Direction, bytes: usize) {
+        let delay = {
+            let mut inner = self.inner.lock().unwrap();
+            let delay = inner
+                .bandwidth
+                .delay(peer, direction, bytes, Instant::now());
+            if delay > Duration::ZERO {
+                let direction = match direction {
+                    Direction::Ingress => "received",
+                    Direction::Egress => "sent",
+                };
+                inner.counters.add(
+                    "polytorus_bandwidth_throttled_milliseconds_total",
+                    "Time messages were held back by the bandwidth limits",
+                    &[("direction", direction)],
+                    delay.as_millis() as u64,
+                );
+            }
+            delay
+        };
+        if delay > Duration::ZERO {
+            debug!("throttle {} bytes of {} for {:?}", bytes, peer, delay);
+            thread::sleep(delay);
+        }
     }
 
     fn add_nodes(&self, addr: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let identity = inner.peers.identity(addr).map(|id| id.to_string());
+        if !identity.is_some_and(|id| inner.peers.is_banned(&id, Instant::now())) {
+            inner.known_nodes.insert(String::from(addr));
+        }
+    }
+
+    fn is_banned(&self, peer: &str) -> bool {
         self.inner
             .lock()
             .unwrap()
-            .known_nodes
-            .insert(String::from(addr));
+            .peers
+            .is_banned(peer, Instant::now())
+    }
+
+    /// handshaked reports whether `addr` completed a handshake from the sender
+    /// `peer`, any address of the sender counts for messages without one
+    fn handshaked(&self, peer: &str, addr: Option<&str>) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match addr {
+            Some(addr) => inner.peers.identity(addr) == Some(peer),
+            None => !inner.peers.addrs_of(peer).is_empty(),
+        }
+    }
+
+    /// misbehave penalizes the sender `peer`, see `peer_identity`, and
+    /// disconnects the addresses it handshaked from once it is banned
+    fn misbehave(&self, peer: &str, misbehavior: Misbehavior) {
+        warn!("peer {} misbehaved: {:?}", peer, misbehavior);
+        let mut inner = self.inner.lock().unwrap();
+        inner.counters.add(
+            "polytorus_peer_misbehavior_total",
+            "Misbehavior penalties given to peers",
+            &[("kind", &format!("{:?}", misbehavior))],
+            1,
+        );
+        if inner.peers.misbehave(peer, misbehavior, Instant::now()) {
+            warn!("ban peer {} for {:?}", peer, BAN_DURATION);
+            for addr in inner.peers.addrs_of(peer) {
+                inner.known_nodes.remove(&addr);
+                inner.sync.remove_peer(&addr);
+                inner.census.remove(&addr);
+            }
+        }
     }
 
     fn get_known_nodes(&self) -> HashSet<String> {
@@ -163,7 +939,7 @@ impl Server {
     }
 
     fn node_is_known(&self, addr: &str) -> bool {
-        self.inner.lock().unwrap().known_nodes.get(addr).is_some()
+        self.inner.lock().unwrap().known_nodes.contains(addr)
     }
 
     fn replace_in_transit(&self, hashs: Vec<String>) {
@@ -176,18 +952,48 @@ impl Server {
     }
 
     fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
-        match self.inner.lock().unwrap().mempool.get(addr) {
-            Some(tx) => Some(tx.clone()),
-            None => None,
-        }
+        self.inner
+            .lock()
+            .unwrap()
+            .mempool
+            .get(addr)
+            .map(|e| e.tx.clone())
     }
 
-    fn get_mempool(&self) -> HashMap<String, Transaction> {
+    fn get_mempool(&self) -> HashMap<String, MempoolEntry> {
         self.inner.lock().unwrap().mempool.clone()
     }
 
-    fn insert_mempool(&self, tx: Transaction) {
-        self.inner.lock().unwrap().mempool.insert(tx.id.clone(), tx);
+    fn admit_tx(&self, tx: &Transaction, peer: &str) -> bool {
+        let policy = self.inner.lock().unwrap().policy.clone();
+        match policy {
+            Some(policy) => policy.admit(tx, peer),
+            None => true,
+        }
+    }
+
+    fn record_arrival(&self, kind: ArrivalKind, hash: &str, peer: &str) {
+        if let Some(log) = self.inner.lock().unwrap().telemetry.as_mut() {
+            if let Err(e) = log.record(kind, hash, peer) {
+                warn!("failed to record arrival: {}", e);
+            }
+        }
+    }
+
+    /// insert_mempool adds a tx to the mempool, false if the system tx cap is reached
+    fn insert_mempool(&self, tx: Transaction) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if tx.is_system()
+            && inner.mempool.values().filter(|e| e.tx.is_system()).count() >= MAX_MEMPOOL_SYSTEM_TXS
+        {
+            return false;
+        }
+        let arrival = inner.next_arrival;
+        inner.next_arrival += 1;
+        inner
+            .mempool
+            .insert(tx.id.clone(), MempoolEntry { tx, arrival });
+        true
     }
 
     fn clear_mempool(&self) {
@@ -198,6 +1004,10 @@ impl Server {
         self.inner.lock().unwrap().utxo.blockchain.get_best_height()
     }
 
+    fn chain_id(&self) -> Result<String> {
+        self.inner.lock().unwrap().utxo.blockchain.chain_id()
+    }
+
     fn get_block_hashs(&self) -> Vec<String> {
         self.inner.lock().unwrap().utxo.blockchain.get_block_hashs()
     }
@@ -220,24 +1030,146 @@ impl Server {
             .verify_transacton(tx)
     }
 
+    fn get_fee(&self, tx: &Transaction) -> Result<i32> {
+        self.inner.lock().unwrap().utxo.blockchain.get_fee(tx)
+    }
+
+    fn total_fees(&self, txs: &[Transaction]) -> Result<i32> {
+        self.inner.lock().unwrap().utxo.blockchain.total_fees(txs)
+    }
+
+    /// add_block stores a block and counts a reorg when the tip moves to another branch
     fn add_block(&self, block: Block) -> Result<()> {
-        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let old_tip = inner.utxo.blockchain.tip.clone();
+        let extends_tip = block.get_prev_hash() == old_tip;
+        inner.utxo.blockchain.add_block(block)?;
+        let new_tip = &inner.utxo.blockchain.tip;
+        if extends_tip || *new_tip == old_tip {
+            return Ok(());
+        }
+        if let Some(depth) = inner.utxo.blockchain.reorg_depth(&old_tip, new_tip)? {
+            if depth > 0 {
+                warn!("reorg of {} blocks to {}", depth, new_tip);
+                inner.last_reorg_depth = depth;
+                inner.counters.add(
+                    "polytorus_reorgs_total",
+                    "Tip changes to another branch",
+                    &[],
+                    1,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// connect_orphans adds the orphans descending from a stored block
+    fn connect_orphans(&self, parent: &str) -> Result<()> {
+        let blocks = self.inner.lock().unwrap().orphans.take_descendants(parent);
+        for block in blocks {
+            info!(
+                "connect orphan block {} at height {}",
+                block.get_hash(),
+                block.get_height()
+            );
+            self.add_block(block)?;
+        }
+        Ok(())
+    }
+
+    /// block_transactions picks the valid mempool transactions for a block with `coinbase`
+    fn block_transactions(
+        &self,
+        mempool: &HashMap<String, MempoolEntry>,
+        coinbase: &Transaction,
+    ) -> Result<Vec<Transaction>> {
+        let mut candidates = Vec::new();
+        for entry in mempool.values() {
+            if self.verify_tx(&entry.tx)? {
+                candidates.push(Candidate {
+                    tx: entry.tx.clone(),
+                    arrival: entry.arrival,
+                    fee: self.get_fee(&entry.tx)?,
+                    weight: entry.tx.weight()?,
+                });
+            }
+        }
+        let (ordering, max_txs) = {
+            let inner = self.inner.lock().unwrap();
+            (inner.tx_ordering, inner.max_block_txs)
+        };
+        let max_weight = MAX_BLOCK_WEIGHT - coinbase.weight()?;
+        Ok(select(ordering, candidates, max_txs, max_weight))
     }
 
     fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
         self.inner.lock().unwrap().utxo.blockchain.mine_block(txs)
     }
 
+    fn has_block(&self, block_hash: &str) -> Result<bool> {
+        self.inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .has_block(block_hash)
+    }
+
+    /// relay_nodes returns the known nodes, fast relay peers first
+    fn relay_nodes(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        let mut nodes: Vec<String> = inner
+            .known_nodes
+            .iter()
+            .filter(|n| **n != self.node_address)
+            .cloned()
+            .collect();
+        nodes.sort();
+        inner.peers.relay_order(&nodes, inner.fast_relay_count)
+    }
+
     fn utxo_reindex(&self) -> Result<()> {
-        self.inner.lock().unwrap().utxo.reindex()
+        let mut inner = self.inner.lock().unwrap();
+        inner.state_snapshot = None;
+        inner.utxo.reindex()
+    }
+
+    fn state_sync_enabled(&self) -> bool {
+        self.inner.lock().unwrap().state_sync.is_some()
+    }
+
+    /// state_snapshot returns the UTXO set served to syncing peers
+    ///
+    /// The set is hashed once per tip, not for every request.
+    fn state_snapshot(&self) -> Result<Arc<StateSnapshot>> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(snapshot) = &inner.state_snapshot {
+            if snapshot.target.tip == inner.utxo.blockchain.tip {
+                return Ok(Arc::clone(snapshot));
+            }
+        }
+        let height = inner.utxo.blockchain.get_best_height()?;
+        let snapshot = Arc::new(StateSnapshot::new(
+            height,
+            &inner.utxo.blockchain.tip,
+            inner.utxo.entries()?,
+        )?);
+        inner.state_snapshot = Some(Arc::clone(&snapshot));
+        Ok(snapshot)
+    }
+
+    fn get_state_target(&self) -> Result<StateTarget> {
+        Ok(self.state_snapshot()?.target.clone())
     }
 
     /* -----------------------------------------------------*/
 
     fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
-        if addr == &self.node_address {
+        if addr == self.node_address {
             return Ok(());
         }
+        self.throttle(addr, Direction::Egress, data.len());
         let mut stream = match TcpStream::connect(addr) {
             Ok(s) => s,
             Err(_) => {
@@ -246,19 +1178,26 @@ impl Server {
             }
         };
 
-        stream.write(data)?;
+        let key = {
+            let inner = self.inner.lock().unwrap();
+            if inner.encrypt_outbound {
+                inner.node_key.clone()
+            } else {
+                None
+            }
+        };
+        match key {
+            Some(key) => {
+                write_encrypted(&mut stream, &key, data)?;
+            }
+            None => stream.write_all(data)?,
+        }
+        self.count_message("sent", Some(addr), data);
 
         info!("data send successfully");
         Ok(())
     }
 
-    fn request_blocks(&self) -> Result<()> {
-        for node in self.get_known_nodes() {
-            self.send_get_blocks(&node)?
-        }
-        Ok(())
-    }
-
     fn send_block(&self, addr: &str, b: &Block) -> Result<()> {
         info!("send block data to: {} block hash: {}", addr, b.get_hash());
         let data = Blockmsg {
@@ -277,6 +1216,23 @@ impl Server {
     }
 
     fn send_inv(&self, addr: &str, kind: &str, items: Vec<String>) -> Result<()> {
+        // block invs also answer getblocks, so only tx announcements are filtered
+        let items: Vec<String> = if kind != "tx" {
+            items
+        } else {
+            let mut inner = self.inner.lock().unwrap();
+            let items: Vec<String> = items
+                .into_iter()
+                .filter(|id| !inner.peers.knows(addr, id))
+                .collect();
+            for id in &items {
+                inner.peers.mark_known(addr, id);
+            }
+            items
+        };
+        if items.is_empty() {
+            return Ok(());
+        }
         info!(
             "send inv message to: {} kind: {} data: {:?}",
             addr, kind, items
@@ -290,12 +1246,29 @@ impl Server {
         self.send_data(addr, &data)
     }
 
-    fn send_get_blocks(&self, addr: &str) -> Result<()> {
-        info!("send get blocks message to: {}", addr);
-        let data = GetBlocksmsg {
+    /// send_get_headers asks for the headers following our chain
+    ///
+    /// `extra` is put in front of the locator of the local chain, so the
+    /// download continues after headers that are accepted but not applied.
+    fn send_get_headers(&self, addr: &str, extra: Vec<String>) -> Result<()> {
+        info!("send get headers message to: {}", addr);
+        let mut locator = extra;
+        locator.extend(self.inner.lock().unwrap().utxo.blockchain.locator());
+        let data = GetHeadersmsg {
+            addr_from: self.node_address.clone(),
+            locator,
+        };
+        let data = serialize(&(cmd_to_bytes("getheaders"), data))?;
+        self.send_data(addr, &data)
+    }
+
+    fn send_headers(&self, addr: &str, headers: Vec<BlockHeader>) -> Result<()> {
+        info!("send {} headers to: {}", headers.len(), addr);
+        let data = Headersmsg {
             addr_from: self.node_address.clone(),
+            headers,
         };
-        let data = serialize(&(cmd_to_bytes("getblocks"), data))?;
+        let data = serialize(&(cmd_to_bytes("headers"), data))?;
         self.send_data(addr, &data)
     }
 
@@ -325,27 +1298,95 @@ impl Server {
 
     fn send_version(&self, addr: &str) -> Result<()> {
         info!("send version info to: {}", addr);
+        let mut services = SERVICE_STATE | SERVICE_HEADERS;
+        if self.inner.lock().unwrap().node_key.is_some() {
+            services |= SERVICE_NOISE;
+        }
         let data = Versionmsg {
             addr_from: self.node_address.clone(),
             best_height: self.get_best_height()?,
             version: VERSION,
+            timestamp: now_millis(),
+            services,
+            user_agent: USER_AGENT.to_string(),
+            chain_id: self.chain_id()?,
         };
         let data = serialize(&(cmd_to_bytes("version"), data))?;
         self.send_data(addr, &data)
     }
 
-    fn handle_version(&self, msg: Versionmsg) -> Result<()> {
+    fn handle_version(&self, msg: Versionmsg, peer: &str) -> Result<()> {
         info!("receive version msg: {:#?}", msg);
         let my_best_height = self.get_best_height()?;
-        if my_best_height < msg.best_height {
-            self.send_get_blocks(&msg.addr_from)?;
-        } else if my_best_height > msg.best_height {
-            self.send_version(&msg.addr_from)?;
+        if msg.version < MIN_PEER_VERSION {
+            info!(
+                "drop version {} peer {}, it needs at least {}",
+                msg.version, msg.addr_from, MIN_PEER_VERSION
+            );
+            return Ok(());
         }
-
-        self.send_addr(&msg.addr_from)?;
-
-        if !self.node_is_known(&msg.addr_from) {
+        let chain_id = self.chain_id()?;
+        if msg.chain_id != chain_id {
+            warn!(
+                "peer {} is on chain '{}', not '{}'",
+                msg.addr_from, msg.chain_id, chain_id
+            );
+            self.misbehave(peer, Misbehavior::WrongChain);
+            return Ok(());
+        }
+        let new_handshake = {
+            let mut inner = self.inner.lock().unwrap();
+            let new_handshake = inner.peers.identity(&msg.addr_from) != Some(peer);
+            if !inner
+                .peers
+                .set_identity(&msg.addr_from, peer, Instant::now())
+            {
+                warn!(
+                    "drop version of {}, {} completed its handshake from another peer",
+                    peer, msg.addr_from
+                );
+                return Ok(());
+            }
+            new_handshake
+        };
+        if msg.best_height.saturating_add(STALE_HEIGHT_LAG) < my_best_height {
+            self.misbehave(peer, Misbehavior::StaleHeight);
+        }
+        let clock_offset_ms = Some(msg.timestamp as i64 - now_millis() as i64);
+        let syncing = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.census.observe(
+                &msg.addr_from,
+                Observation {
+                    version: msg.version,
+                    services: msg.services,
+                    user_agent: msg.user_agent.clone(),
+                    clock_offset_ms,
+                },
+            );
+            inner.sync.set_peer_height(&msg.addr_from, msg.best_height);
+            inner.sync.is_syncing()
+        };
+        // the peer drops our requests until it has our version, they wait for its answer
+        if new_handshake {
+            return self.send_version(&msg.addr_from);
+        }
+        if self.state_sync_enabled() {
+            self.send_get_state(&msg.addr_from)?;
+        }
+        if my_best_height < msg.best_height {
+            if syncing {
+                self.request_sync_blocks()?;
+            } else {
+                self.send_get_headers(&msg.addr_from, Vec::new())?;
+            }
+        } else if my_best_height > msg.best_height {
+            self.send_version(&msg.addr_from)?;
+        }
+
+        self.send_addr(&msg.addr_from)?;
+
+        if !self.node_is_known(&msg.addr_from) {
             self.add_nodes(&msg.addr_from);
         }
         Ok(())
@@ -360,16 +1401,83 @@ impl Server {
         Ok(())
     }
 
-    fn handle_block(&self, msg: Blockmsg) -> Result<()> {
+    fn handle_block(&self, msg: Blockmsg, peer: &str) -> Result<()> {
         info!(
             "receive block msg: {}, {}",
             msg.addr_from,
             msg.block.get_hash()
         );
+        self.record_arrival(
+            ArrivalKind::BlockReceived,
+            &msg.block.get_hash(),
+            &msg.addr_from,
+        );
+        if !msg.block.verify()? {
+            warn!(
+                "drop block from {}: invalid hash or transactions",
+                msg.addr_from
+            );
+            self.misbehave(peer, Misbehavior::InvalidBlock);
+            return Ok(());
+        }
+        let synced = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.requested_blocks.remove(&msg.block.get_hash());
+            inner.sync.receive_block(msg.block.clone())
+        };
+        match synced {
+            Ok(true) => return self.apply_sync_blocks(),
+            Ok(false) => {}
+            Err(e) => {
+                warn!("drop block from {}: {}", msg.addr_from, e);
+                self.misbehave(peer, Misbehavior::InvalidBlock);
+                return self.request_sync_blocks();
+            }
+        }
+        let parent = msg.block.get_prev_hash();
+        if !parent.is_empty() && !self.has_block(&parent)? {
+            info!(
+                "keep orphan block {} until {} arrives",
+                msg.block.get_hash(),
+                parent
+            );
+            let new = {
+                let mut inner = self.inner.lock().unwrap();
+                let new = inner.orphans.insert(msg.block);
+                if new {
+                    inner.counters.add(
+                        "polytorus_orphan_blocks_total",
+                        "Blocks received before their parent",
+                        &[],
+                        1,
+                    );
+                }
+                new
+            };
+            if new {
+                self.send_get_data(&msg.addr_from, "block", &parent)?;
+            }
+            return Ok(());
+        }
+        let checked = self
+            .inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .check_header(&msg.block);
+        if let Err(e) = checked {
+            warn!("drop block from {}: {}", msg.addr_from, e);
+            self.misbehave(peer, Misbehavior::InvalidBlock);
+            return Ok(());
+        }
+        let hash = msg.block.get_hash();
         self.add_block(msg.block)?;
+        self.connect_orphans(&hash)?;
+        self.install_state()?;
 
         let mut in_transit = self.get_in_transit();
-        if in_transit.len() > 0 {
+        if !in_transit.is_empty() {
             let block_hash = &in_transit[0];
             self.send_get_data(&msg.addr_from, "block", block_hash)?;
             in_transit.remove(0);
@@ -384,30 +1492,210 @@ impl Server {
     fn handle_inv(&self, msg: Invmsg) -> Result<()> {
         info!("receive inv msg: {:#?}", msg);
         if msg.kind == "block" {
-            let block_hash = &msg.items[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
+            // a getblocks answer lists the tip first, it is downloaded oldest
+            // first so every block arrives after its parent
+            let block_hash = &msg.items[msg.items.len() - 1];
+            if msg.items.len() == 1 {
+                self.record_arrival(ArrivalKind::BlockAnnounced, block_hash, &msg.addr_from);
+            }
+            if msg.items.len() == 1 && !self.has_block(block_hash)? {
+                let mut inner = self.inner.lock().unwrap();
+                if inner.announced_blocks.insert(block_hash) {
+                    inner.peers.record_first_announcement(&msg.addr_from);
+                }
+            }
+            if msg.items.len() > 1 || self.wants_block(block_hash)? {
+                self.send_get_data(&msg.addr_from, "block", block_hash)?;
+            }
 
             let mut new_in_transit = Vec::new();
-            for b in &msg.items {
+            for b in msg.items.iter().rev() {
                 if b != block_hash {
                     new_in_transit.push(b.clone());
                 }
             }
             self.replace_in_transit(new_in_transit);
         } else if msg.kind == "tx" {
-            let txid = &msg.items[0];
-            match self.get_mempool_tx(txid) {
-                Some(tx) => {
-                    if tx.id.is_empty() {
-                        self.send_get_data(&msg.addr_from, "tx", txid)?
-                    }
-                }
-                None => self.send_get_data(&msg.addr_from, "tx", txid)?,
+            for txid in &msg.items {
+                self.record_arrival(ArrivalKind::TxAnnounced, txid, &msg.addr_from);
+            }
+            for txid in self.unknown_txs(&msg.addr_from, &msg.items) {
+                self.send_get_data(&msg.addr_from, "tx", &txid)?;
             }
         }
         Ok(())
     }
 
+    /// wants_block reports whether an announced block should be requested
+    ///
+    /// Blocks already stored, or requested from another peer recently, are skipped.
+    fn wants_block(&self, hash: &str) -> Result<bool> {
+        if self.has_block(hash)? {
+            return Ok(false);
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner
+            .requested_blocks
+            .retain(|_, at| now.duration_since(*at) < TX_REQUEST_TIMEOUT);
+        if inner.requested_blocks.contains_key(hash) {
+            count_duplicate_request(&mut inner.counters, "block");
+            return Ok(false);
+        }
+        inner.requested_blocks.insert(hash.to_string(), now);
+        Ok(true)
+    }
+
+    /// unknown_txs filters announced txs down to the ones worth requesting
+    ///
+    /// Txs already seen, or requested from another peer recently, are skipped.
+    fn unknown_txs(&self, addr: &str, txids: &[String]) -> Vec<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner
+            .requested_txs
+            .retain(|_, at| now.duration_since(*at) < TX_REQUEST_TIMEOUT);
+
+        let mut wanted = Vec::new();
+        for txid in txids {
+            inner.peers.mark_known(addr, txid);
+            if inner.seen_txs.contains(txid) || inner.mempool.contains_key(txid) {
+                continue;
+            }
+            if inner.requested_txs.contains_key(txid) {
+                count_duplicate_request(&mut inner.counters, "tx");
+                continue;
+            }
+            inner.requested_txs.insert(txid.clone(), now);
+            wanted.push(txid.clone());
+        }
+        wanted
+    }
+
+    fn handle_get_headers(&self, msg: GetHeadersmsg) -> Result<()> {
+        info!("receive get headers msg: {}", msg.addr_from);
+        let headers = self
+            .inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .headers_after(&msg.locator, MAX_HEADERS)?;
+        self.send_headers(&msg.addr_from, headers)
+    }
+
+    fn handle_headers(&self, msg: Headersmsg, peer: &str) -> Result<()> {
+        info!(
+            "receive headers msg: {} count: {}",
+            msg.addr_from,
+            msg.headers.len()
+        );
+        let full = msg.headers.len() >= MAX_HEADERS;
+        let chain_id = self.chain_id()?;
+        if msg
+            .headers
+            .iter()
+            .any(|h| !h.prev_block_hash.is_empty() && h.chain_id != chain_id)
+        {
+            warn!(
+                "drop headers from {}: not on chain '{}'",
+                msg.addr_from, chain_id
+            );
+            self.misbehave(peer, Misbehavior::WrongChain);
+            return Ok(());
+        }
+        let added = {
+            let mut inner = self.inner.lock().unwrap();
+            let inner = &mut *inner;
+            if let Some(h) = msg.headers.last() {
+                inner.sync.set_peer_height(&msg.addr_from, h.height);
+            }
+            let bc = &inner.utxo.blockchain;
+            let stored_height = |hash: &str| match bc.has_block(hash) {
+                Ok(true) => bc.get_block(hash).ok().map(|b| b.get_height()),
+                _ => None,
+            };
+            inner
+                .sync
+                .add_headers(msg.headers, stored_height)
+                .map(|added| {
+                    let last = inner.sync.last_header().map(|h| h.hash.clone());
+                    (added, last, inner.sync.has_room())
+                })
+        };
+        let (added, last, has_room) = match added {
+            Ok(added) => added,
+            Err(e) => {
+                warn!("drop headers from {}: {}", msg.addr_from, e);
+                self.misbehave(peer, Misbehavior::InvalidBlock);
+                return Ok(());
+            }
+        };
+        // a batch that did not connect is not followed, it would only be sent again
+        if full && added > 0 && has_room {
+            if let Some(last) = last {
+                self.send_get_headers(&msg.addr_from, vec![last])?;
+            }
+        }
+        self.request_sync_blocks()
+    }
+
+    /// request_sync_blocks asks the peers for the block bodies the sync is missing
+    fn request_sync_blocks(&self) -> Result<()> {
+        let (requests, slow_peers) = {
+            let mut inner = self.inner.lock().unwrap();
+            let requests = inner.sync.next_requests(Instant::now());
+            let slow_peers: Vec<String> = inner
+                .sync
+                .take_slow_peers()
+                .iter()
+                .filter_map(|addr| inner.peers.identity(addr).map(|id| id.to_string()))
+                .collect();
+            (requests, slow_peers)
+        };
+        for peer in slow_peers {
+            self.misbehave(&peer, Misbehavior::SlowResponse);
+        }
+        for (peer, hash) in requests {
+            self.send_get_data(&peer, "block", &hash)?;
+        }
+        Ok(())
+    }
+
+    /// apply_sync_blocks adds the downloaded blocks to the chain in height order
+    fn apply_sync_blocks(&self) -> Result<()> {
+        let (done, behind_peer) = {
+            let mut inner = self.inner.lock().unwrap();
+            let inner = &mut *inner;
+            for block in inner.sync.take_ready() {
+                info!(
+                    "sync block {} at height {}",
+                    block.get_hash(),
+                    block.get_height()
+                );
+                inner.utxo.blockchain.add_block(block)?;
+            }
+            let done = !inner.sync.is_syncing();
+            let behind = inner.utxo.blockchain.get_best_height()? < inner.sync.network_height();
+            (
+                done,
+                if done && behind {
+                    inner.sync.best_peer()
+                } else {
+                    None
+                },
+            )
+        };
+        if !done {
+            return self.request_sync_blocks();
+        }
+        self.utxo_reindex()?;
+        if let Some(peer) = behind_peer {
+            self.send_get_headers(&peer, Vec::new())?;
+        }
+        Ok(())
+    }
+
     fn handle_get_blocks(&self, msg: GetBlocksmsg) -> Result<()> {
         info!("receive get blocks msg: {:#?}", msg);
         let block_hashs = self.get_block_hashs();
@@ -421,45 +1709,81 @@ impl Server {
             let block = self.get_block(&msg.id)?;
             self.send_block(&msg.addr_from, &block)?;
         } else if msg.kind == "tx" {
-            let tx = self.get_mempool_tx(&msg.id).unwrap();
-            self.send_tx(&msg.addr_from, &tx)?;
+            match self.get_mempool_tx(&msg.id) {
+                Some(tx) => self.send_tx(&msg.addr_from, &tx)?,
+                None => info!("tx {} is no longer in the mempool", msg.id),
+            }
         }
         Ok(())
     }
 
-    fn handle_tx(&self, msg: Txmsg) -> Result<()> {
+    fn handle_tx(&self, msg: Txmsg, peer: &str) -> Result<()> {
         info!("receive tx msg: {} {}", msg.addr_from, &msg.transaction.id);
-        self.insert_mempool(msg.transaction.clone());
+        let txid = msg.transaction.id.clone();
+        self.record_arrival(ArrivalKind::TxReceived, &txid, &msg.addr_from);
+        let is_new = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.requested_txs.remove(&txid);
+            inner.peers.mark_known(&msg.addr_from, &txid);
+            inner.seen_txs.insert(&txid)
+        };
+        if !is_new {
+            info!("tx {} was already seen, not relaying", txid);
+            return Ok(());
+        }
+        if !self.admit_tx(&msg.transaction, &msg.addr_from) {
+            return Ok(());
+        }
+        if msg.transaction.is_system()
+            && !self
+                .inner
+                .lock()
+                .unwrap()
+                .system_tx_submitters
+                .contains(peer)
+        {
+            warn!(
+                "drop system tx {} from {}, it may not submit system transactions",
+                txid, peer
+            );
+            return Ok(());
+        }
+        if !self.insert_mempool(msg.transaction.clone()) {
+            warn!(
+                "drop system tx {}, the mempool holds {} already",
+                txid, MAX_MEMPOOL_SYSTEM_TXS
+            );
+            return Ok(());
+        }
 
         let known_nodes = self.get_known_nodes();
 
         for node in known_nodes {
             if node != self.node_address && node != msg.addr_from {
-                self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
+                self.send_inv(&node, "tx", vec![txid.clone()])?;
             }
         }
 
-        if !self.mining_address.is_empty() {
-            let mut mempool  = self.get_mempool();
-            debug!("Current mempool: {:#?}", &mempool);
+        if self.inner.lock().unwrap().mining_paused {
+            warn!("mining is paused, tx {} waits in the mempool", txid);
+        } else if !self.mining_address.is_empty() {
+            let mut mempool = self.get_mempool();
+            debug!("Current mempool: {:#?}", mempool.keys());
 
-            if mempool.len() >= 1 {
+            if !mempool.is_empty() {
                 loop {
-                    let mut txs = Vec::new();
-
-                    for (_, tx) in &mempool {
-                        if self.verify_tx(tx)? {
-                            txs.push(tx.clone());
-                        }
-                    }
-
+                    let cbtx =
+                        Transaction::new_coinbase(self.mining_address.clone(), String::new())?;
+                    let mut txs = self.block_transactions(&mempool, &cbtx)?;
                     if txs.is_empty() {
                         return Ok(());
                     }
-
-                    let cbtx =
-                        Transaction::new_coinbase(self.mining_address.clone(), String::new())?;
-                    txs.push(cbtx);
+                    let fees = self.total_fees(&txs)?;
+                    txs.push(Transaction::new_coinbase_with_fees(
+                        self.mining_address.clone(),
+                        String::new(),
+                        fees,
+                    )?);
 
                     for tx in &txs {
                         mempool.remove(&tx.id);
@@ -468,44 +1792,765 @@ impl Server {
                     let new_block = self.mine_block(txs)?;
                     self.utxo_reindex()?;
 
-                    for node in self.get_known_nodes() {
-                        if node != self.node_address {
-                            self.send_inv(&node, "block", vec![new_block.get_hash()])?;
-                        }
+                    for node in self.relay_nodes() {
+                        self.send_inv(&node, "block", vec![new_block.get_hash()])?;
                     }
 
-                    if mempool.len() == 0 {
+                    if mempool.is_empty() {
                         break;
                     }
                 }
                 self.clear_mempool();
             }
         }
-        
+
+        Ok(())
+    }
+
+    fn ping_peers(&self) {
+        for node in self.get_known_nodes() {
+            // nodes learned from addr messages are greeted first
+            let handshaked = self.inner.lock().unwrap().peers.identity(&node).is_some();
+            let sent = if handshaked {
+                self.send_ping(&node)
+            } else {
+                self.send_version(&node)
+            };
+            if let Err(e) = sent {
+                warn!("ping {} failed: {}", node, e);
+            }
+        }
+        let inner = self.inner.lock().unwrap();
+        for peer in inner.peers.summaries(inner.fast_relay_count) {
+            debug!("peer: {:?}", peer);
+        }
+    }
+
+    fn send_ping(&self, addr: &str) -> Result<()> {
+        let nonce = rand::random::<u64>();
+        self.inner
+            .lock()
+            .unwrap()
+            .peers
+            .record_ping(addr, nonce, Instant::now());
+        let data = Pingmsg {
+            addr_from: self.node_address.clone(),
+            nonce,
+        };
+        let data = serialize(&(cmd_to_bytes("ping"), data))?;
+        self.send_data(addr, &data)
+    }
+
+    fn handle_ping(&self, msg: Pingmsg) -> Result<()> {
+        let data = Pingmsg {
+            addr_from: self.node_address.clone(),
+            nonce: msg.nonce,
+        };
+        let data = serialize(&(cmd_to_bytes("pong"), data))?;
+        self.send_data(&msg.addr_from, &data)
+    }
+
+    fn handle_pong(&self, msg: Pingmsg) -> Result<()> {
+        let rtt =
+            self.inner
+                .lock()
+                .unwrap()
+                .peers
+                .record_pong(&msg.addr_from, msg.nonce, Instant::now());
+        if let Some(rtt) = rtt {
+            info!("rtt to {}: {:?}", msg.addr_from, rtt);
+        }
+        Ok(())
+    }
+
+    fn send_get_state(&self, addr: &str) -> Result<()> {
+        info!("send get state message to: {}", addr);
+        let data = GetStatemsg {
+            addr_from: self.node_address.clone(),
+        };
+        let data = serialize(&(cmd_to_bytes("getstate"), data))?;
+        self.send_data(addr, &data)
+    }
+
+    fn send_state_info(&self, addr: &str) -> Result<()> {
+        info!("send state info to: {}", addr);
+        let data = StateInfomsg {
+            addr_from: self.node_address.clone(),
+            target: self.get_state_target()?,
+        };
+        let data = serialize(&(cmd_to_bytes("stateinfo"), data))?;
+        self.send_data(addr, &data)
+    }
+
+    fn send_get_state_chunk(&self, addr: &str, root: &[u8], index: u32) -> Result<()> {
+        info!("send get state chunk message to: {} index: {}", addr, index);
+        let data = GetStateChunkmsg {
+            addr_from: self.node_address.clone(),
+            root: root.to_vec(),
+            index,
+        };
+        let data = serialize(&(cmd_to_bytes("getchunk"), data))?;
+        self.send_data(addr, &data)
+    }
+
+    fn send_state_chunk(&self, addr: &str, root: &[u8], chunk: StateChunk) -> Result<()> {
+        info!("send state chunk to: {} index: {}", addr, chunk.index);
+        let data = StateChunkmsg {
+            addr_from: self.node_address.clone(),
+            root: root.to_vec(),
+            chunk,
+        };
+        let data = serialize(&(cmd_to_bytes("statechunk"), data))?;
+        self.send_data(addr, &data)
+    }
+
+    /// request_state_chunks asks the peers serving the sync target for missing chunks
+    fn request_state_chunks(&self) -> Result<()> {
+        let (root, requests) = {
+            let mut inner = self.inner.lock().unwrap();
+            let peers: Vec<String> = inner.state_peers.iter().cloned().collect();
+            let sync = match inner.state_sync.as_mut() {
+                Some(s) => s,
+                None => return Ok(()),
+            };
+            let root = match sync.get_target() {
+                Some(t) => t.root.clone(),
+                None => return Ok(()),
+            };
+            (root, sync.next_requests(&peers)?)
+        };
+        for (peer, index) in requests {
+            self.send_get_state_chunk(&peer, &root, index)?;
+        }
+        Ok(())
+    }
+
+    fn handle_get_state(&self, msg: GetStatemsg) -> Result<()> {
+        info!("receive get state msg: {:#?}", msg);
+        self.send_state_info(&msg.addr_from)
+    }
+
+    fn handle_state_info(&self, msg: StateInfomsg) -> Result<()> {
+        info!(
+            "receive state info msg: {} height: {}",
+            msg.addr_from, msg.target.height
+        );
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let sync = match inner.state_sync.as_mut() {
+                Some(s) => s,
+                None => return Ok(()),
+            };
+            let serves_target = match sync.get_target() {
+                Some(t) => *t == msg.target,
+                None => sync.accepts(&msg.target),
+            };
+            if sync.get_target().is_none() && serves_target {
+                sync.set_target(msg.target.clone())?;
+            }
+            if !serves_target {
+                return Ok(());
+            }
+            inner.state_peers.insert(msg.addr_from.clone());
+        }
+        self.request_state_chunks()
+    }
+
+    fn handle_get_state_chunk(&self, msg: GetStateChunkmsg) -> Result<()> {
+        info!(
+            "receive get state chunk msg: {} index: {}",
+            msg.addr_from, msg.index
+        );
+        let snapshot = self.state_snapshot()?;
+        if snapshot.target.root != msg.root {
+            info!("state root moved on, drop chunk request");
+            return Ok(());
+        }
+        let chunk = match snapshot.chunk(msg.index) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                warn!("drop chunk request from {}: {}", msg.addr_from, e);
+                return Ok(());
+            }
+        };
+        self.send_state_chunk(&msg.addr_from, &msg.root, chunk)
+    }
+
+    fn handle_state_chunk(&self, msg: StateChunkmsg) -> Result<()> {
+        info!(
+            "receive state chunk msg: {} index: {}",
+            msg.addr_from, msg.chunk.index
+        );
+        let complete = {
+            let mut inner = self.inner.lock().unwrap();
+            let inner = &mut *inner;
+            let sync = match inner.state_sync.as_mut() {
+                Some(s) => s,
+                None => return Ok(()),
+            };
+            if sync.get_target().map(|t| &t.root) != Some(&msg.root) {
+                return Ok(());
+            }
+            match sync.apply_chunk(msg.chunk) {
+                Ok(true) => {
+                    inner.state_peers.clear();
+                    true
+                }
+                Ok(false) => false,
+                Err(e) => {
+                    warn!("drop state chunk from {}: {}", msg.addr_from, e);
+                    sync.release_peer(&msg.addr_from);
+                    inner.state_peers.remove(&msg.addr_from);
+                    false
+                }
+            }
+        };
+        if complete {
+            return self.install_state();
+        }
+        self.request_state_chunks()
+    }
+
+    /// install_state activates a completed state sync once the local chain is at its tip
+    fn install_state(&self) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let sync = match inner.state_sync.as_mut() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        if !sync.is_complete()? {
+            return Ok(());
+        }
+        if sync.get_target().map(|t| &t.tip) != Some(&inner.utxo.blockchain.tip) {
+            info!("state synced, waiting for the chain to reach the state tip");
+            return Ok(());
+        }
+        sync.install(&inner.utxo)?;
+        inner.state_sync = None;
+        inner.state_snapshot = None;
         Ok(())
     }
 
     fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
-        let mut buffer = Vec::new();
-        let count = stream.read_to_end(&mut buffer)?;
-        info!("Accept request: length {}", count);
+        let ip = stream.peer_addr()?.ip().to_string();
+        if self.is_banned(&ip) {
+            return Ok(());
+        }
+        let (key, require_encryption) = {
+            let inner = self.inner.lock().unwrap();
+            (inner.node_key.clone(), inner.require_encryption)
+        };
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let (buffer, remote_key) = read_message(&mut stream, key.as_ref())?;
+        info!(
+            "Accept request: length {} encrypted: {}",
+            buffer.len(),
+            remote_key.is_some()
+        );
+        if require_encryption && remote_key.is_none() {
+            info!("drop plaintext message from {}", ip);
+            return Ok(());
+        }
+        let peer = peer_identity(&ip, remote_key.as_deref());
+        if self.is_banned(&peer) {
+            info!("drop message from banned peer {}", peer);
+            return Ok(());
+        }
 
-        let cmd = bytes_to_cmd(&buffer)?;
+        let cmd = match bytes_to_cmd(&buffer) {
+            Ok(cmd) => {
+                self.count_message("received", cmd.addr_from(), &buffer);
+                cmd
+            }
+            Err(e) => {
+                self.misbehave(&peer, Misbehavior::MalformedMessage);
+                return Err(e);
+            }
+        };
+        let command = command_name(&buffer);
+        if !cmd.well_formed() {
+            warn!("drop malformed {} message from {}", command, peer);
+            self.misbehave(&peer, Misbehavior::MalformedMessage);
+            return Ok(());
+        }
+        if !matches!(cmd, Message::Version(_)) && !self.handshaked(&peer, cmd.addr_from()) {
+            info!(
+                "drop {} message from {} before its handshake",
+                command, peer
+            );
+            return Ok(());
+        }
+        if !self
+            .inner
+            .lock()
+            .unwrap()
+            .floods
+            .allow(&peer, &command, Instant::now())
+        {
+            info!("drop {} message flooded by {}", command, peer);
+            self.misbehave(&peer, Misbehavior::Flooding);
+            return Ok(());
+        }
+        self.throttle(&peer, Direction::Ingress, buffer.len());
 
         match cmd {
             Message::Addr(data) => self.handle_addr(data)?,
-            Message::Block(data) => self.handle_block(data)?,
+            Message::Block(data) => self.handle_block(data, &peer)?,
             Message::Inv(data) => self.handle_inv(data)?,
             Message::GetBlock(data) => self.handle_get_blocks(data)?,
             Message::GetData(data) => self.handle_get_data(data)?,
-            Message::Tx(data) => self.handle_tx(data)?,
-            Message::Version(data) => self.handle_version(data)?,
+            Message::Tx(data) => self.handle_tx(data, &peer)?,
+            Message::Version(data) => self.handle_version(data, &peer)?,
+            Message::GetState(data) => self.handle_get_state(data)?,
+            Message::StateInfo(data) => self.handle_state_info(data)?,
+            Message::GetStateChunk(data) => self.handle_get_state_chunk(data)?,
+            Message::StateChunk(data) => self.handle_state_chunk(data)?,
+            Message::Ping(data) => self.handle_ping(data)?,
+            Message::Pong(data) => self.handle_pong(data)?,
+            Message::GetHeaders(data) => self.handle_get_headers(data)?,
+            Message::Headers(data) => self.handle_headers(data, &peer)?,
         }
 
         Ok(())
     }
 }
 
+/// RPC methods of the node
+///
+/// Raw transactions are hex encoded bincode `Transaction`s.
+impl RpcHandler for Server {
+    fn call(&self, method: &str, params: &[Value]) -> std::result::Result<Value, RpcError> {
+        match method {
+            "getblockcount" => Ok(json!(self.get_best_height()?)),
+            "getbestblockhash" => Ok(json!(self.inner.lock().unwrap().utxo.blockchain.tip)),
+            "getblock" => {
+                let hash = string_param(params, 0)?;
+                if !self.has_block(hash)? {
+                    return Err(RpcError::new(RPC_NOT_FOUND, "block not found"));
+                }
+                Ok(serde_json::to_value(self.get_block(hash)?)?)
+            }
+            "getbalance" => {
+                let address = string_param(params, 0)?;
+                let pub_key_hash = match address::pub_key_hash(address) {
+                    Ok(h) => h,
+                    Err(_) => return Err(RpcError::invalid_params("invalid address")),
+                };
+                let utxos = self.inner.lock().unwrap().utxo.find_UTXO(&pub_key_hash)?;
+                Ok(json!(utxos
+                    .outputs
+                    .iter()
+                    .map(|out| out.value)
+                    .sum::<i32>()))
+            }
+            "gettransaction" => {
+                let txid = string_param(params, 0)?;
+                let inner = self.inner.lock().unwrap();
+                let bc = &inner.utxo.blockchain;
+                let index = TxIndex::open(bc)?;
+                index.sync(bc)?;
+                let location = match index.find_transaction(bc, txid)? {
+                    Some(l) => l,
+                    None => return Err(RpcError::new(RPC_NOT_FOUND, "transaction not found")),
+                };
+                let block = bc.get_block(&location.block)?;
+                let tx = block.get_transaction().iter().find(|tx| tx.id == txid);
+                Ok(json!({"transaction": tx, "block": location.block, "height": location.height}))
+            }
+            "getorderingproof" => {
+                let txid = string_param(params, 0)?;
+                let inner = self.inner.lock().unwrap();
+                let bc = &inner.utxo.blockchain;
+                let index = TxIndex::open(bc)?;
+                index.sync(bc)?;
+                let location = match index.find_transaction(bc, txid)? {
+                    Some(l) => l,
+                    None => return Err(RpcError::new(RPC_NOT_FOUND, "transaction not found")),
+                };
+                let block = bc.get_block(&location.block)?;
+                Ok(json!({"header": block.header()?, "proof": block.ordering_proof(txid)?}))
+            }
+            "getaddresstxs" => {
+                let pub_key_hash = match address::pub_key_hash(string_param(params, 0)?) {
+                    Ok(h) => h,
+                    Err(_) => return Err(RpcError::invalid_params("invalid address")),
+                };
+                let inner = self.inner.lock().unwrap();
+                let index = TxIndex::open(&inner.utxo.blockchain)?;
+                index.sync(&inner.utxo.blockchain)?;
+                Ok(serde_json::to_value(
+                    index.address_transactions(&pub_key_hash)?,
+                )?)
+            }
+            "getcapabilities" => Ok(self.capabilities()),
+            "getstateinfo" => {
+                let target = self.get_state_target()?;
+                Ok(json!({
+                    "height": target.height,
+                    "tip": target.tip,
+                    "root": hex::encode(&target.root),
+                    "leaves": target.leaves,
+                }))
+            }
+            "getnetworkcensus" => {
+                let report = self.inner.lock().unwrap().census.report(VERSION);
+                Ok(serde_json::to_value(report)?)
+            }
+            "estimatefee" => {
+                let inner = self.inner.lock().unwrap();
+                let estimate = inner.utxo.blockchain.estimate_fee(inner.mempool.len())?;
+                Ok(serde_json::to_value(estimate)?)
+            }
+            "getmempoolinfo" => {
+                let inner = self.inner.lock().unwrap();
+                let bc = &inner.utxo.blockchain;
+                // fees of txs spending unconfirmed outputs are not known yet
+                let fees: Vec<i32> = inner
+                    .mempool
+                    .values()
+                    .filter_map(|e| bc.get_fee(&e.tx).ok())
+                    .collect();
+                let mut timestamps = Vec::new();
+                for block in bc.iter().take(RECENT_BLOCK_TIMES + 1) {
+                    timestamps.push(block.header()?.timestamp);
+                }
+                let block_times: Vec<u128> = timestamps
+                    .windows(2)
+                    .map(|w| w[0].saturating_sub(w[1]))
+                    .collect();
+                Ok(json!({
+                    "size": inner.mempool.len(),
+                    "fee_histogram": fee_histogram(&fees),
+                    "mining": !self.mining_address.is_empty() && !inner.mining_paused,
+                    "recent_block_times_ms": block_times,
+                }))
+            }
+            "getblocktemplate" => {
+                let address = match params.first() {
+                    None | Some(Value::Null) => self.mining_address.as_str(),
+                    Some(_) => string_param(params, 0)?,
+                };
+                if address::pub_key_hash(address).is_err() {
+                    return Err(RpcError::invalid_params("a coinbase address is required"));
+                }
+                self.block_template(address)
+            }
+            "submitblock" => {
+                let id = string_param(params, 0)?;
+                let nonce = match params.get(1).and_then(Value::as_i64) {
+                    Some(n) if i32::try_from(n).is_ok() => n as i32,
+                    _ => {
+                        return Err(RpcError::invalid_params(
+                            "parameter 1 must be a 32 bit nonce",
+                        ))
+                    }
+                };
+                self.submit_block(id, nonce)
+            }
+            "sendrawtransaction" => {
+                let raw = string_param(params, 0)?;
+                let request_id = match params.get(1) {
+                    None | Some(Value::Null) => return self.send_raw_transaction(raw),
+                    Some(Value::String(id)) => id,
+                    Some(_) => return Err(RpcError::invalid_params("request id must be a string")),
+                };
+                let submission =
+                    self.inner
+                        .lock()
+                        .unwrap()
+                        .submissions
+                        .begin(request_id, raw, Instant::now());
+                match submission {
+                    Submission::New => {
+                        let outcome = self.send_raw_transaction(raw);
+                        self.inner
+                            .lock()
+                            .unwrap()
+                            .submissions
+                            .finish(request_id, &outcome);
+                        outcome
+                    }
+                    Submission::Done(outcome) => {
+                        info!("answer repeated request {} from the cache", request_id);
+                        outcome
+                    }
+                    Submission::InProgress => Err(RpcError::new(
+                        RPC_IN_PROGRESS,
+                        "a request with this id is in progress",
+                    )),
+                    Submission::Conflict => Err(RpcError::invalid_params(
+                        "request id was used for another transaction",
+                    )),
+                }
+            }
+            "setloglevel" => {
+                logging::set_filter(string_param(params, 0)?);
+                Ok(Value::Null)
+            }
+            "exportpeers" => {
+                let inner = self.inner.lock().unwrap();
+                let mut peers: Vec<&String> = inner.known_nodes.iter().collect();
+                peers.sort();
+                Ok(json!({"peers": peers, "pinned": inner.peers.pinned()}))
+            }
+            "importpeers" => {
+                let list = |key: &str| -> std::result::Result<Vec<String>, RpcError> {
+                    match params.first().and_then(|p| p.get(key)) {
+                        None => Ok(Vec::new()),
+                        Some(v) => serde_json::from_value(v.clone()).map_err(|_| {
+                            RpcError::invalid_params(&format!(
+                                "{} must be a list of addresses",
+                                key
+                            ))
+                        }),
+                    }
+                };
+                let peers = list("peers")?;
+                let pinned = list("pinned")?;
+                for addr in &peers {
+                    self.add_nodes(addr);
+                }
+                self.pin_peers(&pinned);
+                Ok(json!(peers.len() + pinned.len()))
+            }
+            "getpeerinfo" => {
+                let inner = self.inner.lock().unwrap();
+                Ok(serde_json::to_value(
+                    inner.peers.summaries(inner.fast_relay_count),
+                )?)
+            }
+            "getpeerstats" => {
+                let addr = string_param(params, 0)?;
+                match self.inner.lock().unwrap().peers.stats(addr) {
+                    Some(stats) => Ok(serde_json::to_value(stats)?),
+                    None => Err(RpcError::new(RPC_NOT_FOUND, "peer not found")),
+                }
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, "method not found")),
+        }
+    }
+}
+
+impl Server {
+    /// send_raw_transaction verifies a hex encoded transaction and relays it
+    fn send_raw_transaction(&self, raw: &str) -> std::result::Result<Value, RpcError> {
+        let tx: Transaction = match hex::decode(raw) {
+            Ok(raw) => match deserialize(&raw) {
+                Ok(tx) => tx,
+                Err(_) => return Err(RpcError::invalid_params("invalid transaction")),
+            },
+            Err(_) => return Err(RpcError::invalid_params("invalid hex")),
+        };
+        if tx.is_coinbase() || tx.hash()? != tx.id || !self.verify_tx(&tx)? {
+            return Err(RpcError::new(RPC_VERIFY_REJECTED, "transaction rejected"));
+        }
+        if !self.admit_tx(&tx, "rpc") {
+            return Err(RpcError::new(
+                RPC_VERIFY_REJECTED,
+                "transaction rejected by policy",
+            ));
+        }
+        let txid = tx.id.clone();
+        self.handle_tx(
+            Txmsg {
+                addr_from: self.node_address.clone(),
+                transaction: tx,
+            },
+            "rpc",
+        )?;
+        Ok(json!(txid))
+    }
+
+    /// block_template builds a block of the mempool for an external miner
+    fn block_template(&self, address: &str) -> std::result::Result<Value, RpcError> {
+        let coinbase = Transaction::new_coinbase(address.to_string(), String::new())?;
+        let mut txs = self.block_transactions(&self.get_mempool(), &coinbase)?;
+        let fees = self.total_fees(&txs)?;
+        txs.push(Transaction::new_coinbase_with_fees(
+            address.to_string(),
+            String::new(),
+            fees,
+        )?);
+        let (tip, chain_id, height, target) = {
+            let inner = self.inner.lock().unwrap();
+            let bc = &inner.utxo.blockchain;
+            (
+                bc.tip.clone(),
+                bc.chain_id()?,
+                bc.get_best_height()?,
+                bc.next_target(&bc.tip)?,
+            )
+        };
+        let template = BlockTemplate::new(txs, tip, &chain_id, height + 1, target)?;
+        let prefix = template.pow_prefix()?;
+        let mut hasher = Sha256::new();
+        hasher.input(&prefix);
+        let id = hasher.result_str();
+        let result = json!({
+            "id": id,
+            "height": template.height,
+            "prev_block_hash": template.prev_block_hash,
+            "timestamp": template.timestamp,
+            "merkle_root": hex::encode(template.merkle_root()?),
+            "target_bits": template.target,
+            "pow_prefix": hex::encode(&prefix),
+            "transactions": template.transactions.iter().map(|tx| &tx.id).collect::<Vec<_>>(),
+        });
+
+        let mut inner = self.inner.lock().unwrap();
+        let tip = inner.utxo.blockchain.tip.clone();
+        inner.templates.retain(|_, t| t.prev_block_hash == tip);
+        if inner.templates.len() >= MAX_TEMPLATES {
+            inner.templates.clear();
+        }
+        inner.templates.insert(id, template);
+        Ok(result)
+    }
+
+    /// submit_block completes a template with the nonce found by an external miner
+    fn submit_block(&self, id: &str, nonce: i32) -> std::result::Result<Value, RpcError> {
+        let template = match self.inner.lock().unwrap().templates.get(id) {
+            Some(t) => t.clone(),
+            None => return Err(RpcError::new(RPC_NOT_FOUND, "unknown or expired template")),
+        };
+        if template.prev_block_hash != self.inner.lock().unwrap().utxo.blockchain.tip {
+            return Err(RpcError::new(RPC_VERIFY_REJECTED, "template is stale"));
+        }
+        let block = match template.solve(nonce) {
+            Ok(b) => b,
+            Err(e) => return Err(RpcError::new(RPC_VERIFY_REJECTED, &e.to_string())),
+        };
+        for tx in block.get_transaction() {
+            if !self.verify_tx(tx)? {
+                return Err(RpcError::new(
+                    RPC_VERIFY_REJECTED,
+                    "template transaction is no longer valid",
+                ));
+            }
+        }
+        if !block.verify()? {
+            return Err(RpcError::new(RPC_VERIFY_REJECTED, "block rejected"));
+        }
+        let hash = block.get_hash();
+        info!("external miner solved block {}", hash);
+        {
+            let mut inner = self.inner.lock().unwrap();
+            for tx in block.get_transaction() {
+                inner.mempool.remove(&tx.id);
+            }
+            inner.templates.clear();
+        }
+        self.add_block(block)?;
+        self.utxo_reindex()?;
+        for node in self.relay_nodes() {
+            self.send_inv(&node, "block", vec![hash.clone()])?;
+        }
+        Ok(json!(hash))
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// peer_identity names the sender of a connection for scores and bans
+///
+/// It is the Noise static key of an encrypted connection, the IP address
+/// otherwise, never the `addr_from` a message claims.
+fn peer_identity(ip: &str, remote_key: Option<&[u8]>) -> String {
+    match remote_key {
+        Some(key) => hex::encode(key),
+        None => ip.to_string(),
+    }
+}
+
+fn string_param(params: &[Value], index: usize) -> std::result::Result<&str, RpcError> {
+    match params.get(index) {
+        Some(Value::String(s)) => Ok(s),
+        _ => Err(RpcError::invalid_params(&format!(
+            "parameter {} must be a string",
+            index
+        ))),
+    }
+}
+
+/// ProtocolDescription describes the wire protocol as JSON
+///
+/// Each message travels over its own connection as the bincode encoding of
+/// a zero padded `CMD_LEN` byte command followed by the payload. Payload
+/// formats are traced from the message types, so the description follows
+/// the code.
+pub fn protocol_description() -> Result<Value> {
+    let mut tracer = Tracer::new(TracerConfig::default());
+    let mut messages = Vec::new();
+    macro_rules! trace {
+        ($payload:ty) => {
+            tracer
+                .trace_simple_type::<$payload>()
+                .map_err(|e| format_err!("{}", e))?
+                .0
+        };
+    }
+    macro_rules! message {
+        ($command:expr, $since:expr, $payload:ty) => {
+            let format = trace!($payload);
+            messages.push(json!({"command": $command, "since_version": $since, "payload": format}));
+        };
+    }
+    message!("addr", 1, Vec<String>);
+    message!("block", 1, Blockmsg);
+    message!("inv", 1, Invmsg);
+    message!("getblocks", 1, GetBlocksmsg);
+    message!("getdata", 1, GetDatamsg);
+    message!("tx", 1, Txmsg);
+    message!("version", 1, Versionmsg);
+    // older nodes send a shorter version message, still decoded by bytes_to_cmd
+    let layouts = json!([
+        {"since_version": 1, "payload": trace!(VersionmsgV1)},
+        {"since_version": 2, "payload": trace!(VersionmsgV2)},
+        {"since_version": 3, "payload": trace!(Versionmsg)},
+    ]);
+    if let Some(version) = messages.last_mut() {
+        version["layouts"] = layouts;
+    }
+    message!("getstate", 1, GetStatemsg);
+    message!("stateinfo", 1, StateInfomsg);
+    message!("getchunk", 1, GetStateChunkmsg);
+    message!("statechunk", 1, StateChunkmsg);
+    message!("ping", 1, Pingmsg);
+    message!("pong", 1, Pingmsg);
+    message!("getheaders", 1, GetHeadersmsg);
+    message!("headers", 1, Headersmsg);
+    let types = tracer.registry().map_err(|e| format_err!("{}", e))?;
+    let field_gates: Vec<Value> = FIELD_GATES
+        .iter()
+        .map(|(ty, field, since)| json!({"type": ty, "field": field, "since_version": since}))
+        .collect();
+    Ok(json!({
+        "version": VERSION,
+        "min_peer_version": MIN_PEER_VERSION,
+        "cmd_len": CMD_LEN,
+        "encoding": "bincode 1.3, little endian fixed width integers",
+        "messages": messages,
+        "field_gates": field_gates,
+        "types": types,
+    }))
+}
+
+/// count_duplicate_request counts an announcement not requested again
+fn count_duplicate_request(counters: &mut Counters, kind: &str) {
+    counters.add(
+        "polytorus_duplicate_requests_skipped_total",
+        "Announced items not requested because a request is in flight",
+        &[("kind", kind)],
+        1,
+    );
+}
+
 fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
     let mut data = [0; CMD_LEN];
     for (i, d) in cmd.as_bytes().iter().enumerate() {
@@ -514,38 +2559,117 @@ fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
     data
 }
 
+/// command_name returns the command a message starts with
+fn command_name(bytes: &[u8]) -> String {
+    let cmd: Vec<u8> = bytes
+        .iter()
+        .take(CMD_LEN)
+        .copied()
+        .filter(|b| *b != 0)
+        .collect();
+    String::from_utf8_lossy(&cmd).into_owned()
+}
+
+/// decode deserializes a message body without allocating more than a message may carry
+fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    Ok(bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_MESSAGE_SIZE as u64)
+        .deserialize(data)?)
+}
+
+/// await_version waits for a version message on `listener`, at most `CLIENT_HANDSHAKE_TIMEOUT`
+fn await_version(listener: &TcpListener) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    // a node with encryption enabled answers over Noise
+    let key = NodeKey::generate()?;
+    let deadline = Instant::now() + CLIENT_HANDSHAKE_TIMEOUT;
+    while Instant::now() < deadline {
+        match listener.accept() {
+            Ok((mut stream, _)) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(READ_TIMEOUT))?;
+                if let Ok((buffer, _)) = read_message(&mut stream, Some(&key)) {
+                    if let Ok(Message::Version(_)) = bytes_to_cmd(&buffer) {
+                        return Ok(());
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(CLIENT_POLL_INTERVAL)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(format_err!("the node did not answer the version in time"))
+}
+
 fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
+    if bytes.len() < CMD_LEN {
+        return Err(format_err!("message is too short"));
+    }
     let mut cmd = Vec::new();
     let cmd_bytes = &bytes[..CMD_LEN];
     let data = &bytes[CMD_LEN..];
     for b in cmd_bytes {
-        if 0 as u8 != *b {
+        if 0 != *b {
             cmd.push(*b);
         }
     }
     info!("cmd: {}", String::from_utf8(cmd.clone())?);
 
     if cmd == "addr".as_bytes() {
-        let data: Vec<String> = deserialize(data)?;
+        let data: Vec<String> = decode(data)?;
         Ok(Message::Addr(data))
     } else if cmd == "block".as_bytes() {
-        let data: Blockmsg = deserialize(data)?;
+        let data: Blockmsg = decode(data)?;
         Ok(Message::Block(data))
     } else if cmd == "inv".as_bytes() {
-        let data: Invmsg = deserialize(data)?;
+        let data: Invmsg = decode(data)?;
         Ok(Message::Inv(data))
     } else if cmd == "getblocks".as_bytes() {
-        let data: GetBlocksmsg = deserialize(data)?;
+        let data: GetBlocksmsg = decode(data)?;
         Ok(Message::GetBlock(data))
     } else if cmd == "getdata".as_bytes() {
-        let data: GetDatamsg = deserialize(data)?;
+        let data: GetDatamsg = decode(data)?;
         Ok(Message::GetData(data))
     } else if cmd == "tx".as_bytes() {
-        let data: Txmsg = deserialize(data)?;
+        let data: Txmsg = decode(data)?;
         Ok(Message::Tx(data))
     } else if cmd == "version".as_bytes() {
-        let data: Versionmsg = deserialize(data)?;
+        let data: Versionmsg = match decode(data) {
+            Ok(data) => data,
+            Err(_) => match decode::<VersionmsgV2>(data) {
+                Ok(data) => data.into(),
+                Err(_) => decode::<VersionmsgV1>(data)?.into(),
+            },
+        };
         Ok(Message::Version(data))
+    } else if cmd == "getstate".as_bytes() {
+        let data: GetStatemsg = decode(data)?;
+        Ok(Message::GetState(data))
+    } else if cmd == "stateinfo".as_bytes() {
+        let data: StateInfomsg = decode(data)?;
+        Ok(Message::StateInfo(data))
+    } else if cmd == "getchunk".as_bytes() {
+        let data: GetStateChunkmsg = decode(data)?;
+        Ok(Message::GetStateChunk(data))
+    } else if cmd == "statechunk".as_bytes() {
+        let data: StateChunkmsg = decode(data)?;
+        Ok(Message::StateChunk(data))
+    } else if cmd == "ping".as_bytes() {
+        let data: Pingmsg = decode(data)?;
+        Ok(Message::Ping(data))
+    } else if cmd == "pong".as_bytes() {
+        let data: Pingmsg = decode(data)?;
+        Ok(Message::Pong(data))
+    } else if cmd == "getheaders".as_bytes() {
+        let data: GetHeadersmsg = decode(data)?;
+        Ok(Message::GetHeaders(data))
+    } else if cmd == "headers".as_bytes() {
+        let data: Headersmsg = decode(data)?;
+        Ok(Message::Headers(data))
     } else {
         Err(format_err!("Unknown command in the server"))
     }
@@ -557,6 +2681,19 @@ mod test {
     use crate::blockchain::*;
     use crate::wallets::*;
 
+    /// memory_server returns a server on a chain of only the genesis block, kept in memory
+    fn memory_server(address: &str) -> Server {
+        let cbtx = Transaction::new_coinbase(address.to_string(), String::from("genesis")).unwrap();
+        let bc = Blockchain::temporary(&Block::new_genesis_block(cbtx)).unwrap();
+        Server::new("localhost", "7879", "", None, UTXOSet { blockchain: bc }).unwrap()
+    }
+
+    #[test]
+    fn test_peer_identity() {
+        assert_eq!(peer_identity("10.0.0.1", None), "10.0.0.1");
+        assert_eq!(peer_identity("10.0.0.1", Some(&[0xab, 0x01])), "ab01");
+    }
+
     #[test]
     fn test_cmd() {
         let mut ws = Wallets::new().unwrap();
@@ -569,6 +2706,10 @@ mod test {
             addr_from: server.node_address.clone(),
             best_height: server.get_best_height().unwrap(),
             version: VERSION,
+            timestamp: now_millis(),
+            services: SERVICE_HEADERS,
+            user_agent: USER_AGENT.to_string(),
+            chain_id: String::from("polytorus-test"),
         };
         let data = serialize(&(cmd_to_bytes("version"), vmsg.clone())).unwrap();
         if let Message::Version(v) = bytes_to_cmd(&data).unwrap() {
@@ -576,5 +2717,413 @@ mod test {
         } else {
             panic!("wrong!");
         }
+
+        let inv = |kind: &str, items: usize| {
+            Message::Inv(Invmsg {
+                addr_from: server.node_address.clone(),
+                kind: kind.to_string(),
+                items: vec![String::from("h"); items],
+            })
+        };
+        assert!(inv("block", 1).well_formed());
+        assert!(!inv("block", 0).well_formed());
+        assert!(!inv("utxo", 1).well_formed());
+        assert!(!Message::Addr(vec![String::new(); MAX_ADDR_ENTRIES + 1]).well_formed());
+
+        // a version on the local chain completes the handshake of its sender only
+        let peer_addr = "127.0.0.1:1";
+        assert_eq!(
+            server.chain_id().unwrap(),
+            server.get_block_hashs().last().unwrap().clone()
+        );
+        let version = Versionmsg {
+            addr_from: peer_addr.to_string(),
+            chain_id: String::new(),
+            ..vmsg
+        };
+        server.handle_version(version.clone(), "10.0.0.7").unwrap();
+        assert!(!server.handshaked("10.0.0.7", Some(peer_addr)));
+        let version = Versionmsg {
+            chain_id: server.chain_id().unwrap(),
+            ..version
+        };
+        server.handle_version(version, "10.0.0.9").unwrap();
+        assert!(server.handshaked("10.0.0.9", Some(peer_addr)));
+        assert!(server.handshaked("10.0.0.9", None));
+        assert!(!server.handshaked("10.0.0.8", Some(peer_addr)));
+    }
+
+    #[test]
+    fn test_system_tx_admission() {
+        use crate::systemtx::{Checkpoint, SystemTxEnvelope, KIND_CHECKPOINT};
+
+        let server = memory_server("3K2B53BeZoaSPBGxihwPkbjNxrLkapfYrn");
+        let checkpoint = |height: i32| {
+            let checkpoint = Checkpoint {
+                height,
+                block_hash: String::from("00ab"),
+            };
+            Txmsg {
+                addr_from: String::from("10.0.0.9:7000"),
+                transaction: SystemTxEnvelope::new(KIND_CHECKPOINT, &checkpoint)
+                    .unwrap()
+                    .into_transaction()
+                    .unwrap(),
+            }
+        };
+        let system_txs = |server: &Server| {
+            server
+                .get_mempool()
+                .values()
+                .filter(|e| e.tx.is_system())
+                .count()
+        };
+
+        server.handle_tx(checkpoint(0), "10.0.0.9").unwrap();
+        assert_eq!(system_txs(&server), 0);
+        server.authorize_system_txs(&[String::from("10.0.0.9")]);
+        server.handle_tx(checkpoint(1), "10.0.0.9").unwrap();
+        server.handle_tx(checkpoint(2), "127.0.0.1").unwrap();
+        assert_eq!(system_txs(&server), 2);
+
+        // they pay no fee, so only a few wait in the mempool
+        for height in 3..MAX_MEMPOOL_SYSTEM_TXS as i32 + 10 {
+            server.handle_tx(checkpoint(height), "rpc").unwrap();
+        }
+        assert_eq!(system_txs(&server), MAX_MEMPOOL_SYSTEM_TXS);
+    }
+
+    #[test]
+    fn test_send_malformed_raw_transaction() {
+        let wallet = Wallet::from_rng(&mut rand_core::OsRng);
+        let server = memory_server(&wallet.get_address());
+        let genesis = server.get_block(&server.get_block_hashs()[0]).unwrap();
+        let spend = |vout: i32, pub_key: Vec<u8>| {
+            let mut tx = Transaction {
+                id: String::new(),
+                vin: vec![TXInput {
+                    txid: genesis.get_transaction()[0].id.clone(),
+                    vout,
+                    signature: vec![0; 10],
+                    pub_key,
+                }],
+                vout: Vec::new(),
+            };
+            tx.id = tx.hash().unwrap();
+            hex::encode(serialize(&tx).unwrap())
+        };
+
+        for raw in [
+            spend(1, wallet.public_key.clone()),
+            spend(-2, wallet.public_key.clone()),
+            spend(0, vec![7; 10]),
+        ] {
+            let err = server
+                .call("sendrawtransaction", &[json!(raw)])
+                .unwrap_err();
+            assert_eq!(err.code, RPC_VERIFY_REJECTED);
+        }
+        // the server lock survived the rejected transactions
+        assert_eq!(server.call("getblockcount", &[]).unwrap(), json!(0));
+    }
+
+    #[test]
+    fn test_send_transaction() {
+        let wallet = Wallet::from_rng(&mut rand_core::OsRng);
+        let node = memory_server(&wallet.get_address());
+        let genesis = node.get_block(&node.get_block_hashs()[0]).unwrap();
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: genesis.get_transaction()[0].id.clone(),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: wallet.public_key.clone(),
+            }],
+            vout: vec![TXOutput::new(9, wallet.get_address()).unwrap()],
+        };
+        tx.id = tx.hash().unwrap();
+        node.inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .sign_transacton(&mut tx, &wallet)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node_address = listener.local_addr().unwrap().to_string();
+        let handler = Server {
+            node_address: node.node_address.clone(),
+            mining_address: node.mining_address.clone(),
+            inner: Arc::clone(&node.inner),
+        };
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = handler.handle_connection(stream);
+            }
+        });
+
+        let client = UTXOSet {
+            blockchain: Blockchain::temporary(&genesis).unwrap(),
+        };
+        Server::send_transaction_to(&node_address, &tx, client).unwrap();
+
+        // the tx went out right after the version answer, without a fixed delay
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while node.get_mempool_tx(&tx.id).is_none() {
+            assert!(
+                Instant::now() < deadline,
+                "the node never admitted the client tx"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_protocol_description() {
+        let description = protocol_description().unwrap();
+        let golden: Value = serde_json::from_str(include_str!("../docs/protocol.json")).unwrap();
+        assert_eq!(
+            description, golden,
+            "docs/protocol.json is stale, regenerate it with `polytorus protocol`"
+        );
+
+        let messages = description["messages"].as_array().unwrap();
+        let commands: HashSet<&str> = messages
+            .iter()
+            .map(|m| m["command"].as_str().unwrap())
+            .collect();
+        assert_eq!(commands.len(), messages.len());
+        for command in commands {
+            assert!(command.len() <= CMD_LEN, "command {} is too long", command);
+        }
+
+        // every gated field exists, and only in the layouts of its version and later
+        let fields = |ty: &Value| -> Vec<String> {
+            ty["STRUCT"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .flat_map(|f| f.as_object().unwrap().keys().cloned())
+                .collect()
+        };
+        let types = &description["types"];
+        let version = messages.iter().find(|m| m["command"] == "version").unwrap();
+        for gate in description["field_gates"].as_array().unwrap() {
+            let (ty, field, since) = (
+                gate["type"].as_str().unwrap(),
+                gate["field"].as_str().unwrap(),
+                gate["since_version"].as_i64().unwrap(),
+            );
+            assert!(since <= VERSION as i64);
+            assert!(
+                fields(&types[ty]).iter().any(|f| f == field),
+                "{} has no field {}",
+                ty,
+                field
+            );
+            if ty != "Versionmsg" {
+                continue;
+            }
+            for layout in version["layouts"].as_array().unwrap() {
+                let name = layout["payload"]["TYPENAME"].as_str().unwrap();
+                let has_field = fields(&types[name]).iter().any(|f| f == field);
+                assert_eq!(
+                    has_field,
+                    layout["since_version"].as_i64().unwrap() >= since,
+                    "{} in {}",
+                    field,
+                    name
+                );
+            }
+        }
+    }
+
+    /// wire_block returns a block with fixed contents for the wire vectors
+    fn wire_block() -> Block {
+        let cbtx = Transaction::new_coinbase(
+            String::from("3K2B53BeZoaSPBGxihwPkbjNxrLkapfYrn"),
+            String::from("vector"),
+        )
+        .unwrap();
+        Block::new_block_at(
+            vec![cbtx],
+            String::from("00ab"),
+            "main",
+            1,
+            1_700_000_000_000,
+            MIN_TARGET_BITS,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_wire_vectors() {
+        // byte for byte encodings other implementations must produce
+        let vectors: Vec<(Vec<u8>, &str)> = vec![
+            (
+                serialize(&(
+                    cmd_to_bytes("version"),
+                    VersionmsgV1 {
+                        addr_from: String::from("127.0.0.1:7000"),
+                        version: 1,
+                        best_height: 3,
+                    },
+                ))
+                .unwrap(),
+                "76657273696f6e00000000000e000000000000003132372e302e302e313a373030300100000003000000",
+            ),
+            (
+                serialize(&(
+                    cmd_to_bytes("version"),
+                    VersionmsgV2 {
+                        addr_from: String::from("127.0.0.1:7000"),
+                        version: 2,
+                        best_height: 3,
+                        timestamp: 1_700_000_000_000,
+                        services: SERVICE_STATE | SERVICE_HEADERS,
+                        user_agent: String::from("polytorus/0.1.0"),
+                    },
+                ))
+                .unwrap(),
+                "76657273696f6e00000000000e000000000000003132372e302e302e313a3730303002000000030000000068e5cf8b01000003000000000000000f00000000000000706f6c79746f7275732f302e312e30",
+            ),
+            (
+                serialize(&(
+                    cmd_to_bytes("version"),
+                    Versionmsg {
+                        addr_from: String::from("127.0.0.1:7000"),
+                        version: 3,
+                        best_height: 3,
+                        timestamp: 1_700_000_000_000,
+                        services: SERVICE_STATE | SERVICE_HEADERS,
+                        user_agent: String::from("polytorus/0.1.0"),
+                        chain_id: String::from("main"),
+                    },
+                ))
+                .unwrap(),
+                "76657273696f6e00000000000e000000000000003132372e302e302e313a3730303003000000030000000068e5cf8b01000003000000000000000f00000000000000706f6c79746f7275732f302e312e3004000000000000006d61696e",
+            ),
+            (
+                serialize(&(
+                    cmd_to_bytes("ping"),
+                    Pingmsg {
+                        addr_from: String::from("127.0.0.1:7000"),
+                        nonce: 0x0102030405060708,
+                    },
+                ))
+                .unwrap(),
+                "70696e6700000000000000000e000000000000003132372e302e302e313a373030300807060504030201",
+            ),
+            (
+                serialize(&(
+                    cmd_to_bytes("inv"),
+                    Invmsg {
+                        addr_from: String::from("127.0.0.1:7000"),
+                        kind: String::from("tx"),
+                        items: vec![String::from("ab")],
+                    },
+                ))
+                .unwrap(),
+                "696e760000000000000000000e000000000000003132372e302e302e313a3730303002000000000000007478010000000000000002000000000000006162",
+            ),
+            (
+                serialize(&(
+                    cmd_to_bytes("block"),
+                    Blockmsg {
+                        addr_from: String::from("127.0.0.1:7000"),
+                        block: wire_block(),
+                    },
+                ))
+                .unwrap(),
+                "626c6f636b000000000000000e000000000000003132372e302e302e313a373030300068e5cf8b0100000000000000000000010000000000000040000000000000003239616437623133383365363236646361306337313564313164613863663962623661306136316534326530366261666331383861326163383464623236613401000000000000000000000000000000ffffffff00000000000000002600000000000000766563746f72000000000000000000000000000000000000000000000000000000000000000001000000000000000a0000001400000000000000be19ad3ad531aaab9b79217b452aa04d9c59680a04000000000000003030616240000000000000003030303061613562623032666563633233616235333238636562303162353339303931376230613539343737633666313737343662313165666536353137363999870800010000001000000004000000000000006d61696e",
+            ),
+        ];
+        for (data, golden) in &vectors {
+            assert_eq!(&hex::encode(data), golden);
+            bytes_to_cmd(data).unwrap();
+        }
+        // blocks decode to the same header, target and chain id included
+        if let Message::Block(b) = bytes_to_cmd(&vectors[5].0).unwrap() {
+            assert_eq!(b.block.header().unwrap(), wire_block().header().unwrap());
+            assert_eq!(
+                (b.block.get_target(), b.block.get_chain_id()),
+                (MIN_TARGET_BITS, "main")
+            );
+        } else {
+            panic!("wrong!");
+        }
+        // version 1 handshakes still decode, without the version 2 fields
+        if let Message::Version(v) = bytes_to_cmd(&vectors[0].0).unwrap() {
+            assert_eq!((v.version, v.best_height, v.services), (1, 3, 0));
+        } else {
+            panic!("wrong!");
+        }
+        // and version 1 nodes read version 2 handshakes, ignoring the new fields
+        let (_, v1): ([u8; CMD_LEN], VersionmsgV1) = deserialize(&vectors[1].0).unwrap();
+        assert_eq!(v1.best_height, 3);
+        // version 2 handshakes decode without a chain id, and version 2 nodes read version 3 ones
+        if let Message::Version(v) = bytes_to_cmd(&vectors[1].0).unwrap() {
+            assert_eq!(
+                (v.version, v.user_agent.as_str(), v.chain_id.as_str()),
+                (2, "polytorus/0.1.0", "")
+            );
+        } else {
+            panic!("wrong!");
+        }
+        let (_, v2): ([u8; CMD_LEN], VersionmsgV2) = deserialize(&vectors[2].0).unwrap();
+        assert_eq!(v2.version, 3);
+        assert!(bytes_to_cmd(&serialize(&(cmd_to_bytes("nope"), 0u8)).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_mutated_messages() {
+        use rand::Rng;
+        use rand_chacha::rand_core::SeedableRng;
+
+        let corpus = [
+            serialize(&(cmd_to_bytes("addr"), vec![String::from("127.0.0.1:7000")])).unwrap(),
+            serialize(&(
+                cmd_to_bytes("inv"),
+                Invmsg {
+                    addr_from: String::from("127.0.0.1:7000"),
+                    kind: String::from("block"),
+                    items: vec![String::from("00ab"), String::from("00cd")],
+                },
+            ))
+            .unwrap(),
+            serialize(&(
+                cmd_to_bytes("getheaders"),
+                GetHeadersmsg {
+                    addr_from: String::from("127.0.0.1:7000"),
+                    locator: vec![String::from("00ab")],
+                },
+            ))
+            .unwrap(),
+        ];
+        // a length prefix far beyond the message is refused, not allocated
+        let mut huge = corpus[0].clone();
+        huge[CMD_LEN..CMD_LEN + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(bytes_to_cmd(&huge).is_err());
+
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(7);
+        for _ in 0..5000 {
+            let mut message = corpus[rng.gen_range(0..corpus.len())].clone();
+            match rng.gen_range(0..3) {
+                0 => message.truncate(rng.gen_range(0..message.len())),
+                1 => {
+                    let at = rng.gen_range(CMD_LEN..message.len());
+                    message[at] = rng.gen();
+                }
+                _ => {
+                    let at = rng.gen_range(CMD_LEN..message.len() - 8);
+                    message[at..at + 8].copy_from_slice(&rng.gen_range(0..u64::MAX).to_le_bytes());
+                }
+            }
+            if let Ok(cmd) = bytes_to_cmd(&message) {
+                cmd.well_formed();
+            }
+        }
     }
 }
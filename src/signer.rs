@@ -0,0 +1,612 @@
+//! Signer abstraction for transaction signing
+//!
+//! A `Signer` owns a key pair somewhere (in-process, on a hardware device,
+//! behind a remote signing service) and only ever hands out public keys and
+//! signatures, so the node itself never needs access to the secret key.
+//!
+//! Remote signer requests and responses are length prefixed and carry an
+//! HMAC-SHA256 tag over a secret shared by the signer and its clients, so
+//! only clients holding the secret get signatures out of the signer.
+
+use super::*;
+use crate::keypolicy::*;
+use crate::transaction::*;
+use crate::wallets::*;
+use bincode::{deserialize, serialize};
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::sha2::Sha256;
+use failure::format_err;
+use fn_dsa::{
+    signature_size, SigningKey, SigningKeyStandard, VerifyingKey, VerifyingKeyStandard,
+    DOMAIN_NONE, HASH_ID_RAW,
+};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Environment variable holding the secret shared by a signer and its clients
+pub const SIGNER_SECRET_ENV: &str = "POLYTORUS_SIGNER_SECRET";
+/// Largest request or response the signer protocol carries
+const MAX_SIGNER_MESSAGE: usize = 1 << 20;
+/// How far the clock of a request may be from the clock of the signer
+const MAX_REQUEST_SKEW: Duration = Duration::from_secs(30);
+/// Time a connection may take to deliver its request
+const SIGNER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time the messages of an authorized transaction may be signed in
+const AUTHORIZATION_TTL: Duration = Duration::from_secs(60);
+/// Most messages waiting for their signature per address
+const MAX_AUTHORIZED_MESSAGES: usize = 256;
+
+/// Signer produces FN-DSA signatures for a single key pair
+pub trait Signer {
+    /// PublicKey returns the encoded verifying key of the signer
+    fn public_key(&self) -> Result<Vec<u8>>;
+
+    /// Sign signs the raw message and returns the encoded signature
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>>;
+
+    /// Authorize lets the key holder refuse a transaction of chain `chain_id`
+    /// before its inputs are signed, `confirmed` approves spends that need a
+    /// confirmation
+    fn authorize(&self, _tx: &Transaction, _chain_id: &str, _confirmed: bool) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// VerifySignature checks an FN-DSA signature made by a `Signer`
+pub fn verify_signature(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    match VerifyingKeyStandard::decode(public_key) {
+        Some(vk) => vk.verify(signature, &DOMAIN_NONE, &HASH_ID_RAW, message),
+        None => false,
+    }
+}
+
+impl Signer for Wallet {
+    fn public_key(&self) -> Result<Vec<u8>> {
+        Ok(self.public_key.clone())
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let mut sk = match SigningKeyStandard::decode(&self.secret_key) {
+            Some(sk) => sk,
+            None => return Err(format_err!("ERROR: Invalid signing key")),
+        };
+        let mut signature = vec![0u8; signature_size(sk.get_logn())];
+        sk.sign(
+            &mut OsRng,
+            &DOMAIN_NONE,
+            &HASH_ID_RAW,
+            message,
+            &mut signature,
+        );
+        Ok(signature)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum SignerRequest {
+    PublicKey {
+        address: String,
+    },
+    Sign {
+        address: String,
+        message: Vec<u8>,
+    },
+    Authorize {
+        address: String,
+        tx: Transaction,
+        chain_id: String,
+        confirmed: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+enum SignerResponse {
+    PublicKey(Vec<u8>),
+    Signature(Vec<u8>),
+    Error(String),
+    Authorized,
+}
+
+/// SignedRequest is a request tagged with the shared secret
+///
+/// The timestamp and nonce keep a captured request from being replayed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SignedRequest {
+    /// client clock in unix milliseconds
+    timestamp: u64,
+    nonce: u64,
+    request: SignerRequest,
+    mac: Vec<u8>,
+}
+
+/// SignedResponse is a response tagged with the shared secret and bound to
+/// the tag of its request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SignedResponse {
+    response: SignerResponse,
+    mac: Vec<u8>,
+}
+
+fn request_mac(
+    secret: &[u8],
+    timestamp: u64,
+    nonce: u64,
+    request: &SignerRequest,
+) -> Result<MacResult> {
+    let mut mac = Hmac::new(Sha256::new(), secret);
+    mac.input(&serialize(&(timestamp, nonce, request))?);
+    Ok(mac.result())
+}
+
+fn response_mac(secret: &[u8], request_mac: &[u8], response: &SignerResponse) -> Result<MacResult> {
+    let mut mac = Hmac::new(Sha256::new(), secret);
+    mac.input(request_mac);
+    mac.input(&serialize(response)?);
+    Ok(mac.result())
+}
+
+fn write_signer_message<T: Serialize>(stream: &mut TcpStream, message: &T) -> Result<()> {
+    let data = serialize(message)?;
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(&data)?;
+    Ok(())
+}
+
+fn read_signer_message(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_SIGNER_MESSAGE {
+        return Err(format_err!(
+            "signer message exceeds {} bytes",
+            MAX_SIGNER_MESSAGE
+        ));
+    }
+    let mut data = vec![0u8; len];
+    stream.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// RemoteSigner delegates signing to an external signing device or service
+///
+/// Every request opens a new connection to `endpoint`, writes a length
+/// prefixed bincode encoded request tagged with `secret` and reads the
+/// tagged response.
+pub struct RemoteSigner {
+    endpoint: String,
+    address: String,
+    secret: Vec<u8>,
+}
+
+impl RemoteSigner {
+    /// NewRemoteSigner creates a signer for `address` served at `endpoint`
+    pub fn new(endpoint: &str, address: &str, secret: &[u8]) -> RemoteSigner {
+        RemoteSigner {
+            endpoint: endpoint.to_string(),
+            address: address.to_string(),
+            secret: secret.to_vec(),
+        }
+    }
+
+    fn request(&self, req: &SignerRequest) -> Result<SignerResponse> {
+        let timestamp = now_millis();
+        let nonce = rand::random::<u64>();
+        let mac = request_mac(&self.secret, timestamp, nonce, req)?;
+        let mac = mac.code().to_vec();
+        let mut stream = TcpStream::connect(&self.endpoint)?;
+        stream.set_read_timeout(Some(SIGNER_READ_TIMEOUT))?;
+        write_signer_message(
+            &mut stream,
+            &SignedRequest {
+                timestamp,
+                nonce,
+                request: req.clone(),
+                mac: mac.clone(),
+            },
+        )?;
+
+        let signed: SignedResponse = deserialize(&read_signer_message(&mut stream)?)?;
+        if response_mac(&self.secret, &mac, &signed.response)? != MacResult::new(&signed.mac) {
+            return Err(format_err!("remote signer: response is not authenticated"));
+        }
+        match signed.response {
+            SignerResponse::Error(e) => Err(format_err!("remote signer: {}", e)),
+            resp => Ok(resp),
+        }
+    }
+}
+
+impl Signer for RemoteSigner {
+    fn public_key(&self) -> Result<Vec<u8>> {
+        let req = SignerRequest::PublicKey {
+            address: self.address.clone(),
+        };
+        match self.request(&req)? {
+            SignerResponse::PublicKey(pk) => Ok(pk),
+            resp => Err(format_err!("remote signer: unexpected response {:?}", resp)),
+        }
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let req = SignerRequest::Sign {
+            address: self.address.clone(),
+            message: message.to_vec(),
+        };
+        match self.request(&req)? {
+            SignerResponse::Signature(sig) => Ok(sig),
+            resp => Err(format_err!("remote signer: unexpected response {:?}", resp)),
+        }
+    }
+
+    fn authorize(&self, tx: &Transaction, chain_id: &str, confirmed: bool) -> Result<()> {
+        let req = SignerRequest::Authorize {
+            address: self.address.clone(),
+            tx: tx.clone(),
+            chain_id: chain_id.to_string(),
+            confirmed,
+        };
+        match self.request(&req)? {
+            SignerResponse::Authorized => Ok(()),
+            resp => Err(format_err!("remote signer: unexpected response {:?}", resp)),
+        }
+    }
+}
+
+/// SignerServer answers RemoteSigner requests with the keys of a wallet set
+///
+/// It is meant to run on the machine holding the keys, e.g. an air-gapped
+/// host or the bridge process talking to a hardware device. A wallet with a
+/// key policy only signs the messages of transactions authorized first,
+/// within `AUTHORIZATION_TTL`.
+/// Requests without a valid tag of `secret` are refused.
+pub struct SignerServer {
+    wallets: Wallets,
+    policies: KeyPolicies,
+    secret: Vec<u8>,
+    /// messages each address may sign, from authorized transactions, and
+    /// when they were authorized
+    authorized: Mutex<HashMap<String, HashMap<Vec<u8>, u64>>>,
+    /// nonces of recent requests and their timestamps
+    seen: Mutex<HashMap<u64, u64>>,
+}
+
+impl SignerServer {
+    pub fn new(wallets: Wallets, policies: KeyPolicies, secret: &[u8]) -> Result<SignerServer> {
+        if secret.is_empty() {
+            return Err(format_err!("the signer needs a shared secret"));
+        }
+        Ok(SignerServer {
+            wallets,
+            policies,
+            secret: secret.to_vec(),
+            authorized: Mutex::new(HashMap::new()),
+            seen: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Serve handles signing requests on the listener until it fails
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        info!("Signer listen...");
+        for stream in listener.incoming() {
+            if let Err(e) = self.handle_connection(stream?) {
+                error!("signer request failed: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(SIGNER_READ_TIMEOUT))?;
+        let buffer = read_signer_message(&mut stream)?;
+        let signed: SignedRequest = match deserialize(&buffer) {
+            Ok(signed) => signed,
+            Err(e) => return Err(format_err!("malformed request: {}", e)),
+        };
+        let mac = request_mac(
+            &self.secret,
+            signed.timestamp,
+            signed.nonce,
+            &signed.request,
+        )?;
+        if mac != MacResult::new(&signed.mac) {
+            return Err(format_err!("request is not authenticated"));
+        }
+        let resp = match self.check_fresh(signed.timestamp, signed.nonce) {
+            Ok(()) => self.handle_request(signed.request),
+            Err(e) => SignerResponse::Error(e.to_string()),
+        };
+        let mac = response_mac(&self.secret, &signed.mac, &resp)?;
+        write_signer_message(
+            &mut stream,
+            &SignedResponse {
+                response: resp,
+                mac: mac.code().to_vec(),
+            },
+        )
+    }
+
+    /// check_fresh refuses requests outside the clock skew and replayed nonces
+    fn check_fresh(&self, timestamp: u64, nonce: u64) -> Result<()> {
+        let now = now_millis();
+        let skew = MAX_REQUEST_SKEW.as_millis() as u64;
+        if timestamp.abs_diff(now) > skew {
+            return Err(format_err!(
+                "request timestamp is too far from the signer clock"
+            ));
+        }
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, at| at.abs_diff(now) <= skew);
+        if seen.insert(nonce, timestamp).is_some() {
+            return Err(format_err!("request was replayed"));
+        }
+        Ok(())
+    }
+
+    fn handle_request(&self, req: SignerRequest) -> SignerResponse {
+        let address = match &req {
+            SignerRequest::PublicKey { address }
+            | SignerRequest::Sign { address, .. }
+            | SignerRequest::Authorize { address, .. } => address.clone(),
+        };
+        let wallet = match self.wallets.get_wallet(&address) {
+            Some(w) => w,
+            None => return SignerResponse::Error(format!("unknown address {}", address)),
+        };
+        let resp = match req {
+            SignerRequest::PublicKey { .. } => {
+                Ok(SignerResponse::PublicKey(wallet.public_key.clone()))
+            }
+            SignerRequest::Sign { message, .. } => {
+                info!("sign request for: {}", address);
+                self.sign(wallet, &address, &message, now_millis())
+            }
+            SignerRequest::Authorize {
+                tx,
+                chain_id,
+                confirmed,
+                ..
+            } => {
+                info!("authorize request for: {}", address);
+                self.authorize(wallet, &address, &tx, &chain_id, confirmed, now_millis())
+            }
+        };
+        resp.unwrap_or_else(|e| SignerResponse::Error(e.to_string()))
+    }
+
+    fn sign(
+        &self,
+        wallet: &Wallet,
+        address: &str,
+        message: &[u8],
+        now: u64,
+    ) -> Result<SignerResponse> {
+        if self.policies.get(address)?.is_some() {
+            let mut authorized = self.authorized.lock().unwrap();
+            expire_authorized(&mut authorized, now);
+            let allowed = authorized.get_mut(address).and_then(|m| m.remove(message));
+            if allowed.is_none() {
+                return Err(format_err!(
+                    "key policy: message of an unauthorized transaction"
+                ));
+            }
+        }
+        Ok(SignerResponse::Signature(wallet.sign(message)?))
+    }
+
+    fn authorize(
+        &self,
+        wallet: &Wallet,
+        address: &str,
+        tx: &Transaction,
+        chain_id: &str,
+        confirmed: bool,
+        now: u64,
+    ) -> Result<SignerResponse> {
+        self.policies.authorize(address, tx, confirmed)?;
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let mut authorized = self.authorized.lock().unwrap();
+        expire_authorized(&mut authorized, now);
+        let messages = authorized.entry(address.to_string()).or_default();
+        if messages.len() + tx.vin.len() > MAX_AUTHORIZED_MESSAGES {
+            return Err(format_err!(
+                "{} has too many authorized messages waiting for their signature",
+                address
+            ));
+        }
+        for in_id in 0..tx.vin.len() {
+            messages.insert(
+                tx.signature_hash(in_id, &pub_key_hash, chain_id)?
+                    .into_bytes(),
+                now,
+            );
+        }
+        Ok(SignerResponse::Authorized)
+    }
+}
+
+/// expire_authorized forgets the messages authorized longer than `AUTHORIZATION_TTL` ago
+fn expire_authorized(authorized: &mut HashMap<String, HashMap<Vec<u8>, u64>>, now: u64) {
+    let ttl = AUTHORIZATION_TTL.as_millis() as u64;
+    for messages in authorized.values_mut() {
+        messages.retain(|_, at| now.saturating_sub(*at) <= ttl);
+    }
+    authorized.retain(|_, messages| !messages.is_empty());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fn_dsa::{VerifyingKey, VerifyingKeyStandard};
+    use std::thread;
+
+    fn verify(public_key: &[u8], signature: &[u8], message: &[u8]) -> bool {
+        VerifyingKeyStandard::decode(public_key).unwrap().verify(
+            signature,
+            &DOMAIN_NONE,
+            &HASH_ID_RAW,
+            message,
+        )
+    }
+
+    #[test]
+    fn test_remote_signer() {
+        let mut ws = Wallets::new().unwrap();
+        let address = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let guarded = ws.create_wallet();
+        let guarded_key = ws.get_wallet(&guarded).unwrap().public_key.clone();
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let policies = KeyPolicies::with_db(&db).unwrap();
+        let policy = KeyPolicy {
+            allowed_destinations: vec![address.clone()],
+            ..KeyPolicy::default()
+        };
+        policies.set(&guarded, Some(&policy)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        let secret = b"shared secret";
+        let server = SignerServer::new(ws, policies, secret).unwrap();
+        thread::spawn(move || server.serve(listener));
+
+        // clients without the secret get nothing signed
+        assert!(RemoteSigner::new(&endpoint, &address, b"wrong")
+            .public_key()
+            .is_err());
+        let mut stream = TcpStream::connect(&endpoint).unwrap();
+        stream.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        let mut answer = Vec::new();
+        assert!(stream
+            .read_to_end(&mut answer)
+            .map(|_| answer.is_empty())
+            .unwrap_or(true));
+
+        let signer = RemoteSigner::new(&endpoint, &address, secret);
+        assert_eq!(signer.public_key().unwrap(), wallet.public_key);
+        assert_eq!(address_from_pub_key(&signer.public_key().unwrap()), address);
+
+        let sig = signer.sign(b"message").unwrap();
+        assert!(verify(&wallet.public_key, &sig, b"message"));
+        assert!(!verify(&wallet.public_key, &sig, b"other message"));
+
+        let unknown = RemoteSigner::new(&endpoint, "unknown", secret);
+        assert!(unknown.sign(b"message").is_err());
+
+        // a key with a policy only signs authorized transactions
+        let signer = RemoteSigner::new(&endpoint, &guarded, secret);
+        assert!(signer.sign(b"message").is_err());
+        let payment = |to: &str| Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: String::from("prev"),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: guarded_key.clone(),
+            }],
+            vout: vec![TXOutput::new(5, to.to_string()).unwrap()],
+        };
+        assert!(signer
+            .authorize(
+                &payment("3NFC5xp8eNx3fBFZX2YhQny9H2Xzntadba"),
+                "test",
+                false
+            )
+            .is_err());
+        let tx = payment(&address);
+        signer.authorize(&tx, "test", false).unwrap();
+        let mut pub_key_hash = guarded_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let message = tx.signature_hash(0, &pub_key_hash, "test").unwrap();
+        let sig = signer.sign(message.as_bytes()).unwrap();
+        assert!(verify(&guarded_key, &sig, message.as_bytes()));
+        assert!(signer.sign(message.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_authorization_expiry() {
+        let mut ws = Wallets::new().unwrap();
+        let guarded = ws.create_wallet();
+        let wallet = ws.get_wallet(&guarded).unwrap().clone();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let policies = KeyPolicies::with_db(&db).unwrap();
+        let policy = KeyPolicy {
+            allowed_destinations: vec![guarded.clone()],
+            ..KeyPolicy::default()
+        };
+        policies.set(&guarded, Some(&policy)).unwrap();
+        let server = SignerServer::new(ws, policies, b"secret").unwrap();
+
+        let payment = |inputs: usize| Transaction {
+            id: String::new(),
+            vin: (0..inputs)
+                .map(|i| TXInput {
+                    txid: format!("prev{}", i),
+                    vout: 0,
+                    signature: Vec::new(),
+                    pub_key: wallet.public_key.clone(),
+                })
+                .collect(),
+            vout: vec![TXOutput::new(5, guarded.clone()).unwrap()],
+        };
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let message = payment(1)
+            .signature_hash(0, &pub_key_hash, "test")
+            .unwrap()
+            .into_bytes();
+
+        // authorizations expire unused
+        let now = now_millis();
+        server
+            .authorize(&wallet, &guarded, &payment(1), "test", false, now)
+            .unwrap();
+        let late = now + AUTHORIZATION_TTL.as_millis() as u64 + 1;
+        assert!(server.sign(&wallet, &guarded, &message, late).is_err());
+        assert!(server.authorized.lock().unwrap().is_empty());
+
+        // and only a bounded number of them waits at a time
+        assert!(server
+            .authorize(
+                &wallet,
+                &guarded,
+                &payment(MAX_AUTHORIZED_MESSAGES + 1),
+                "test",
+                false,
+                now
+            )
+            .is_err());
+        server
+            .authorize(
+                &wallet,
+                &guarded,
+                &payment(MAX_AUTHORIZED_MESSAGES),
+                "test",
+                false,
+                now,
+            )
+            .unwrap();
+        assert!(server
+            .authorize(&wallet, &guarded, &payment(1), "test", false, now)
+            .is_err());
+        let message = payment(MAX_AUTHORIZED_MESSAGES)
+            .signature_hash(0, &pub_key_hash, "test")
+            .unwrap()
+            .into_bytes();
+        assert!(server.sign(&wallet, &guarded, &message, now).is_ok());
+    }
+}
@@ -0,0 +1,556 @@
+//! State sync of the UTXO set over P2P
+//!
+//! The UTXO set is ordered by txid and split into fixed size chunks. Every
+//! leaf is the hash of one (txid, outputs) entry and the state root is the
+//! merkle root over all leaves, so a chunk can be verified on its own with a
+//! merkle multi-proof. Received chunks are persisted under data/statesync,
+//! which lets an interrupted sync resume after a restart.
+//!
+//! Block headers do not commit to the UTXO set, so the root announced by a
+//! peer is only trusted if it matches a root the operator got out of band,
+//! e.g. from `getstateinfo` on a node they run. Before the synced state is
+//! activated its root is recomputed entry by entry from the stored chunks,
+//! and it waits until block sync brought the local chain to the state tip.
+
+use super::*;
+use crate::block::MergeVu8;
+use crate::transaction::TXOutputs;
+use crate::utxoset::UTXOSet;
+use bincode::{deserialize, serialize};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use failure::format_err;
+use merkle_cbt::merkle_tree::{MerkleProof, CBMT};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Number of UTXO entries carried by one state chunk
+pub const STATE_CHUNK_SIZE: u32 = 64;
+/// Number of chunk requests a single peer may have outstanding
+const MAX_IN_FLIGHT_PER_PEER: usize = 4;
+
+/// StateTarget describes the UTXO state a node is syncing to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StateTarget {
+    pub height: i32,
+    pub tip: String,
+    pub root: Vec<u8>,
+    pub leaves: u32,
+}
+
+impl StateTarget {
+    /// ChunkCount returns the number of chunks the target state is split into
+    pub fn chunk_count(&self) -> u32 {
+        self.leaves.div_ceil(STATE_CHUNK_SIZE)
+    }
+}
+
+/// StateChunk is a contiguous range of UTXO entries with its merkle proof
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StateChunk {
+    pub index: u32,
+    pub entries: Vec<(String, TXOutputs)>,
+    proof_indices: Vec<u32>,
+    proof_lemmas: Vec<Vec<u8>>,
+}
+
+impl StateChunk {
+    /// Build cuts chunk `index` out of the ordered UTXO entries
+    pub fn build(entries: &[(String, TXOutputs)], index: u32) -> Result<StateChunk> {
+        StateChunk::from_leaves(entries, &state_leaves(entries)?, index)
+    }
+
+    fn from_leaves(
+        entries: &[(String, TXOutputs)],
+        leaves: &[Vec<u8>],
+        index: u32,
+    ) -> Result<StateChunk> {
+        let start = match index.checked_mul(STATE_CHUNK_SIZE) {
+            Some(start) if (start as usize) < leaves.len() => start as usize,
+            _ => return Err(format_err!("state chunk {} is out of range", index)),
+        };
+        let end = std::cmp::min(start + STATE_CHUNK_SIZE as usize, leaves.len());
+        let positions: Vec<u32> = (start as u32..end as u32).collect();
+        let proof = match CBMT::<Vec<u8>, MergeVu8>::build_merkle_proof(leaves, &positions) {
+            Some(p) => p,
+            None => return Err(format_err!("failed to build proof for chunk {}", index)),
+        };
+        Ok(StateChunk {
+            index,
+            entries: entries[start..end].to_vec(),
+            proof_indices: proof.indices().to_vec(),
+            proof_lemmas: proof.lemmas().to_vec(),
+        })
+    }
+
+    /// Verify checks that the chunk is complete and belongs to the target state
+    pub fn verify(&self, target: &StateTarget) -> bool {
+        if self.index >= target.chunk_count() {
+            return false;
+        }
+        let start = match self.index.checked_mul(STATE_CHUNK_SIZE) {
+            Some(start) if start < target.leaves => start,
+            _ => return false,
+        };
+        let expected = std::cmp::min(STATE_CHUNK_SIZE, target.leaves - start);
+        if self.entries.len() != expected as usize {
+            return false;
+        }
+        if self.entries.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return false;
+        }
+
+        // the proof has to cover exactly the leaf positions of this chunk
+        let mut node_indices = self.proof_indices.clone();
+        node_indices.sort_unstable();
+        let first = match (target.leaves - 1).checked_add(start) {
+            Some(first) if first.checked_add(expected).is_some() => first,
+            _ => return false,
+        };
+        if node_indices != (first..first + expected).collect::<Vec<u32>>() {
+            return false;
+        }
+
+        let leaves = match state_leaves(&self.entries) {
+            Ok(l) => l,
+            Err(_) => return false,
+        };
+        let proof = MerkleProof::<Vec<u8>, MergeVu8>::new(
+            self.proof_indices.clone(),
+            self.proof_lemmas.clone(),
+        );
+        proof.verify(&target.root, &leaves)
+    }
+}
+
+/// StateSnapshot is the UTXO set at one tip with its leaves and root
+///
+/// Nodes serving state sync build it once per tip instead of hashing the
+/// whole set for every request.
+pub struct StateSnapshot {
+    pub target: StateTarget,
+    entries: Vec<(String, TXOutputs)>,
+    leaves: Vec<Vec<u8>>,
+}
+
+impl StateSnapshot {
+    pub fn new(height: i32, tip: &str, entries: Vec<(String, TXOutputs)>) -> Result<StateSnapshot> {
+        let leaves = state_leaves(&entries)?;
+        Ok(StateSnapshot {
+            target: StateTarget {
+                height,
+                tip: tip.to_string(),
+                root: CBMT::<Vec<u8>, MergeVu8>::build_merkle_root(&leaves),
+                leaves: leaves.len() as u32,
+            },
+            entries,
+            leaves,
+        })
+    }
+
+    /// Chunk cuts chunk `index` out of the snapshot
+    pub fn chunk(&self, index: u32) -> Result<StateChunk> {
+        StateChunk::from_leaves(&self.entries, &self.leaves, index)
+    }
+}
+
+/// StateRoot computes the merkle root over the ordered UTXO entries
+pub fn state_root(entries: &[(String, TXOutputs)]) -> Result<Vec<u8>> {
+    let leaves = state_leaves(entries)?;
+    Ok(CBMT::<Vec<u8>, MergeVu8>::build_merkle_root(&leaves))
+}
+
+fn state_leaves(entries: &[(String, TXOutputs)]) -> Result<Vec<Vec<u8>>> {
+    let mut leaves = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let mut hasher = Sha256::new();
+        hasher.input(&serialize(entry)?);
+        let mut leaf = vec![0u8; 32];
+        hasher.result(&mut leaf);
+        leaves.push(leaf);
+    }
+    Ok(leaves)
+}
+
+/// StateSync tracks the progress of downloading a StateTarget from peers
+pub struct StateSync {
+    db: sled::Db,
+    target: Option<StateTarget>,
+    in_flight: HashMap<u32, String>,
+    trusted_root: Option<Vec<u8>>,
+}
+
+impl StateSync {
+    /// NewStateSync opens the sync progress, resuming a previous target if any
+    ///
+    /// Only targets with `trusted_root` are synced. Without a trusted root
+    /// the first root announced by a peer is accepted unverified.
+    pub fn new(trusted_root: Option<Vec<u8>>) -> Result<StateSync> {
+        StateSync::with_db(sled::open("data/statesync")?, trusted_root)
+    }
+
+    fn with_db(db: sled::Db, trusted_root: Option<Vec<u8>>) -> Result<StateSync> {
+        let mut target: Option<StateTarget> = match db.get("TARGET")? {
+            Some(t) => Some(deserialize(&t)?),
+            None => None,
+        };
+        match &target {
+            Some(t) if trusted_root.is_some() && trusted_root.as_ref() != Some(&t.root) => {
+                warn!(
+                    "discard state sync to height {} with an untrusted root",
+                    t.height
+                );
+                target = None;
+            }
+            Some(t) => info!("resume state sync to height {}", t.height),
+            None => {}
+        }
+        Ok(StateSync {
+            db,
+            target,
+            in_flight: HashMap::new(),
+            trusted_root,
+        })
+    }
+
+    /// Accepts reports whether the sync may switch to `target`
+    pub fn accepts(&self, target: &StateTarget) -> bool {
+        target.leaves > 0 && self.trusted_root.as_ref().is_none_or(|r| *r == target.root)
+    }
+
+    pub fn get_target(&self) -> Option<&StateTarget> {
+        self.target.as_ref()
+    }
+
+    /// SetTarget starts syncing to `target`, discarding progress of any other target
+    pub fn set_target(&mut self, target: StateTarget) -> Result<()> {
+        if self.target.as_ref() == Some(&target) {
+            return Ok(());
+        }
+        if !self.accepts(&target) {
+            return Err(format_err!(
+                "state root of height {} is not trusted",
+                target.height
+            ));
+        }
+        info!(
+            "state sync target height: {} leaves: {}",
+            target.height, target.leaves
+        );
+        self.db.open_tree("chunks")?.clear()?;
+        self.db.open_tree("entries")?.clear()?;
+        self.db.insert("TARGET", serialize(&target)?)?;
+        self.db.flush()?;
+        self.target = Some(target);
+        self.in_flight.clear();
+        Ok(())
+    }
+
+    /// MissingChunks returns the chunk indices not received yet
+    pub fn missing_chunks(&self) -> Result<Vec<u32>> {
+        let target = match &self.target {
+            Some(t) => t,
+            None => return Ok(Vec::new()),
+        };
+        let chunks = self.db.open_tree("chunks")?;
+        let mut missing = Vec::new();
+        for index in 0..target.chunk_count() {
+            if !chunks.contains_key(index.to_be_bytes())? {
+                missing.push(index);
+            }
+        }
+        Ok(missing)
+    }
+
+    /// NextRequests spreads the missing chunks over the peers serving the target
+    pub fn next_requests(&mut self, peers: &[String]) -> Result<Vec<(String, u32)>> {
+        let mut requests = Vec::new();
+        if peers.is_empty() {
+            return Ok(requests);
+        }
+        let mut load: HashMap<&String, usize> = peers.iter().map(|p| (p, 0)).collect();
+        for peer in self.in_flight.values() {
+            if let Some(l) = load.get_mut(peer) {
+                *l += 1;
+            }
+        }
+
+        for index in self.missing_chunks()? {
+            if self.in_flight.contains_key(&index) {
+                continue;
+            }
+            let (peer, l) = match load.iter_mut().min_by_key(|(_, l)| **l) {
+                Some(p) => p,
+                None => break,
+            };
+            if *l >= MAX_IN_FLIGHT_PER_PEER {
+                break;
+            }
+            *l += 1;
+            requests.push(((*peer).clone(), index));
+        }
+
+        for (peer, index) in &requests {
+            self.in_flight.insert(*index, peer.clone());
+        }
+        Ok(requests)
+    }
+
+    /// ReleasePeer forgets the requests in flight to `peer` so they get reassigned
+    pub fn release_peer(&mut self, peer: &str) {
+        self.in_flight.retain(|_, p| p != peer);
+    }
+
+    /// ApplyChunk verifies and stores a chunk, returns whether the sync is complete
+    pub fn apply_chunk(&mut self, chunk: StateChunk) -> Result<bool> {
+        self.in_flight.remove(&chunk.index);
+        let target = match &self.target {
+            Some(t) => t,
+            None => return Err(format_err!("no state sync in progress")),
+        };
+        if !chunk.verify(target) {
+            return Err(format_err!(
+                "state chunk {} failed verification",
+                chunk.index
+            ));
+        }
+
+        let entries = self.db.open_tree("entries")?;
+        for (txid, outs) in &chunk.entries {
+            entries.insert(txid.as_bytes(), serialize(outs)?)?;
+        }
+        self.db
+            .open_tree("chunks")?
+            .insert(chunk.index.to_be_bytes(), vec![])?;
+        self.db.flush()?;
+
+        Ok(self.missing_chunks()?.is_empty())
+    }
+
+    /// StoredRoot recomputes the state root from the stored entries
+    ///
+    /// Only the leaf hashes are kept in memory, not the entries.
+    pub fn stored_root(&self) -> Result<(Vec<u8>, u32)> {
+        let mut leaves = Vec::new();
+        for kv in self.db.open_tree("entries")?.iter() {
+            let (k, v) = kv?;
+            let entry: (String, TXOutputs) = (String::from_utf8(k.to_vec())?, deserialize(&v)?);
+            leaves.append(&mut state_leaves(&[entry])?);
+        }
+        let count = leaves.len() as u32;
+        Ok((CBMT::<Vec<u8>, MergeVu8>::build_merkle_root(&leaves), count))
+    }
+
+    /// IsComplete reports whether every chunk of the target has been received
+    pub fn is_complete(&self) -> Result<bool> {
+        Ok(self.target.is_some() && self.missing_chunks()?.is_empty())
+    }
+
+    /// Install recomputes the state root from the stored chunks and loads them into the UTXO set
+    ///
+    /// The state is refused if the recomputed root or size differs from the
+    /// target, or while the local chain is not at the target tip: the UTXO
+    /// set must match the blocks stored, or block sync applies them twice.
+    pub fn install(&mut self, utxo: &UTXOSet) -> Result<()> {
+        let target = match &self.target {
+            Some(t) => t.clone(),
+            None => return Err(format_err!("no state sync in progress")),
+        };
+        if !self.accepts(&target) {
+            return Err(format_err!(
+                "state root of height {} is not trusted",
+                target.height
+            ));
+        }
+        if utxo.blockchain.tip != target.tip {
+            return Err(format_err!(
+                "local chain is not at the state tip {}",
+                target.tip
+            ));
+        }
+        if self.stored_root()? != (target.root.clone(), target.leaves) {
+            return Err(format_err!("synced state does not match the target root"));
+        }
+        let mut entries = Vec::new();
+        for kv in self.db.open_tree("entries")?.iter() {
+            let (k, v) = kv?;
+            entries.push((String::from_utf8(k.to_vec())?, deserialize(&v)?));
+        }
+        utxo.load_entries(entries)?;
+        info!("state root {} verified", hex::encode(&target.root));
+
+        info!("state sync to height {} complete", target.height);
+        self.db.open_tree("chunks")?.clear()?;
+        self.db.open_tree("entries")?.clear()?;
+        self.db.remove("TARGET")?;
+        self.db.flush()?;
+        self.target = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::{Block, MIN_TARGET_BITS};
+    use crate::blockchain::Blockchain;
+    use crate::transaction::{TXOutput, Transaction};
+    use crate::utxoset::TEST_DB_LOCK;
+
+    fn entries(n: usize) -> Vec<(String, TXOutputs)> {
+        (0..n)
+            .map(|i| {
+                let outs = TXOutputs {
+                    outputs: vec![TXOutput {
+                        value: i as i32,
+                        pub_key_hash: vec![i as u8; 20],
+                    }],
+                };
+                (format!("{:08}", i), outs)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_state_chunks() {
+        let entries = entries(150);
+        let target = StateTarget {
+            height: 1,
+            tip: String::new(),
+            root: state_root(&entries).unwrap(),
+            leaves: entries.len() as u32,
+        };
+        assert_eq!(target.chunk_count(), 3);
+
+        for index in 0..target.chunk_count() {
+            let chunk = StateChunk::build(&entries, index).unwrap();
+            assert!(chunk.verify(&target));
+        }
+        assert!(StateChunk::build(&entries, 3).is_err());
+
+        let mut tampered = StateChunk::build(&entries, 1).unwrap();
+        tampered.entries[0].1.outputs[0].value += 1;
+        assert!(!tampered.verify(&target));
+
+        let mut truncated = StateChunk::build(&entries, 2).unwrap();
+        truncated.entries.pop();
+        assert!(!truncated.verify(&target));
+
+        let mut moved = StateChunk::build(&entries, 0).unwrap();
+        moved.index = 1;
+        assert!(!moved.verify(&target));
+
+        // indices and sizes of peers near the integer limits are refused, not wrapped
+        assert!(StateChunk::build(&entries, 1 << 26).is_err());
+        moved.index = u32::MAX;
+        assert!(!moved.verify(&target));
+        let huge = StateTarget {
+            leaves: u32::MAX,
+            ..target.clone()
+        };
+        let mut last = StateChunk::build(&entries, 0).unwrap();
+        last.index = huge.chunk_count() - 1;
+        assert!(!last.verify(&huge));
+
+        let snapshot = StateSnapshot::new(1, "", entries.clone()).unwrap();
+        assert_eq!(snapshot.target, target);
+        assert!(snapshot.chunk(2).unwrap().verify(&target));
+        assert!(snapshot.chunk(3).is_err());
+    }
+
+    const ADDRESS: &str = "3K2B53BeZoaSPBGxihwPkbjNxrLkapfYrn";
+
+    fn block(bc: &Blockchain, prev: &Block, data: &str) -> Block {
+        let cbtx = Transaction::new_coinbase(String::from(ADDRESS), data.to_string()).unwrap();
+        let chain_id = bc.chain_id().unwrap();
+        Block::new_block(
+            vec![cbtx],
+            prev.get_hash(),
+            &chain_id,
+            prev.get_height() + 1,
+            MIN_TARGET_BITS,
+        )
+        .unwrap()
+    }
+
+    fn chain_entries(bc: &Blockchain) -> Vec<(String, TXOutputs)> {
+        let mut entries: Vec<_> = bc.find_UTXO().into_iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    #[test]
+    fn test_install_at_tip() {
+        let _db = TEST_DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let cbtx =
+            Transaction::new_coinbase(String::from(ADDRESS), String::from("genesis")).unwrap();
+        let genesis = Block::new_genesis_block(cbtx);
+        let mut remote = Blockchain::temporary(&genesis).unwrap();
+        let b1 = block(&remote, &genesis, "b1");
+        remote.add_block(b1.clone()).unwrap();
+        let snapshot = StateSnapshot::new(1, &b1.get_hash(), chain_entries(&remote)).unwrap();
+
+        let utxo = UTXOSet {
+            blockchain: Blockchain::temporary(&genesis).unwrap(),
+        };
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut sync = StateSync::with_db(db, Some(snapshot.target.root.clone())).unwrap();
+        sync.set_target(snapshot.target.clone()).unwrap();
+        assert!(sync.apply_chunk(snapshot.chunk(0).unwrap()).unwrap());
+        assert!(sync.is_complete().unwrap());
+        // the state of b1 does not fit a chain still at genesis
+        assert!(sync.install(&utxo).is_err());
+
+        let mut utxo = utxo;
+        utxo.blockchain.add_block(b1.clone()).unwrap();
+        sync.install(&utxo).unwrap();
+        assert_eq!(
+            state_root(&utxo.entries().unwrap()).unwrap(),
+            state_root(&chain_entries(&utxo.blockchain)).unwrap()
+        );
+
+        // blocks synced after the state apply on top of it once
+        let b2 = block(&utxo.blockchain, &b1, "b2");
+        utxo.blockchain.add_block(b2.clone()).unwrap();
+        utxo.update(&b2).unwrap();
+        assert_eq!(utxo.count_transactions().unwrap(), 3);
+        assert_eq!(
+            state_root(&utxo.entries().unwrap()).unwrap(),
+            state_root(&chain_entries(&utxo.blockchain)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_trusted_root() {
+        let entries = entries(70);
+        let target = StateTarget {
+            height: 1,
+            tip: String::new(),
+            root: state_root(&entries).unwrap(),
+            leaves: entries.len() as u32,
+        };
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut sync = StateSync::with_db(db.clone(), Some(vec![0; 32])).unwrap();
+        assert!(!sync.accepts(&target));
+        assert!(sync.set_target(target.clone()).is_err());
+
+        let mut sync = StateSync::with_db(db.clone(), Some(target.root.clone())).unwrap();
+        sync.set_target(target.clone()).unwrap();
+        sync.apply_chunk(StateChunk::build(&entries, 0).unwrap())
+            .unwrap();
+        assert!(sync
+            .apply_chunk(StateChunk::build(&entries, 1).unwrap())
+            .unwrap());
+        assert_eq!(sync.stored_root().unwrap(), (target.root.clone(), 70));
+
+        // a resumed sync is dropped once its root is no longer trusted
+        assert!(StateSync::with_db(db.clone(), Some(target.root.clone()))
+            .unwrap()
+            .get_target()
+            .is_some());
+        assert!(StateSync::with_db(db, Some(vec![0; 32]))
+            .unwrap()
+            .get_target()
+            .is_none());
+    }
+}
@@ -0,0 +1,367 @@
+//! Headers-first block synchronization
+//!
+//! A node behind its peers first downloads the chain of block headers and
+//! checks linkage and proof of work, then fetches the block bodies in
+//! parallel from every peer known to have them. Bodies may arrive in any
+//! order; they are handed to the blockchain strictly in height order.
+//!
+//! Each peer has a window of requests in flight that grows with every
+//! delivered body and halves when a request times out, so fast peers end
+//! up serving most of the chain. Only bodies close to the next block to
+//! apply are requested, which bounds the blocks held in memory.
+
+use super::*;
+use crate::block::*;
+use failure::format_err;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Maximum number of headers sent in one headers message
+pub const MAX_HEADERS: usize = 500;
+/// Number of block bodies a new peer may have in flight
+const INITIAL_BLOCKS_IN_FLIGHT: usize = 4;
+/// Maximum number of block bodies requested from one peer at a time
+const MAX_BLOCKS_IN_FLIGHT: usize = 32;
+/// Bodies are only requested this many blocks ahead of the next block to apply
+const DOWNLOAD_WINDOW: usize = 256;
+/// Most accepted headers waiting for their blocks
+const MAX_PENDING_HEADERS: usize = DOWNLOAD_WINDOW + 4 * MAX_HEADERS;
+/// Time after which a body request is sent to another peer
+const BLOCK_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// SyncManager drives headers-first sync towards the best announced height
+#[derive(Debug, Default)]
+pub struct SyncManager {
+    /// Accepted headers whose blocks are not applied yet, oldest first
+    headers: VecDeque<BlockHeader>,
+    /// Hashes of `headers`
+    queued: HashSet<String>,
+    peer_heights: HashMap<String, i32>,
+    in_flight: HashMap<String, (String, Instant)>,
+    received: HashMap<String, Block>,
+    /// Peers whose requests timed out since the last `take_slow_peers`
+    slow_peers: Vec<String>,
+    /// Bodies each peer may have in flight
+    windows: HashMap<String, usize>,
+}
+
+impl SyncManager {
+    pub fn new() -> SyncManager {
+        SyncManager::default()
+    }
+
+    /// SetPeerHeight records the best height announced by `addr`
+    pub fn set_peer_height(&mut self, addr: &str, height: i32) {
+        let h = self.peer_heights.entry(addr.to_string()).or_insert(height);
+        *h = (*h).max(height);
+    }
+
+    /// RemovePeer forgets a peer and frees the bodies requested from it
+    pub fn remove_peer(&mut self, addr: &str) {
+        self.peer_heights.remove(addr);
+        self.windows.remove(addr);
+        self.in_flight.retain(|_, (peer, _)| peer != addr);
+    }
+
+    /// Window returns how many bodies a peer may have in flight
+    pub fn window(&self, addr: &str) -> usize {
+        self.windows
+            .get(addr)
+            .copied()
+            .unwrap_or(INITIAL_BLOCKS_IN_FLIGHT)
+    }
+
+    /// NetworkHeight is the best height announced by any peer, -1 if none
+    pub fn network_height(&self) -> i32 {
+        self.peer_heights.values().copied().max().unwrap_or(-1)
+    }
+
+    /// BestPeer returns the peer announcing the best height
+    pub fn best_peer(&self) -> Option<String> {
+        let mut peers: Vec<(&String, &i32)> = self.peer_heights.iter().collect();
+        peers.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        peers.first().map(|(addr, _)| addr.to_string())
+    }
+
+    /// LastHeader returns the newest accepted header
+    pub fn last_header(&self) -> Option<&BlockHeader> {
+        self.headers.back()
+    }
+
+    /// IsSyncing reports whether headers are waiting for their blocks
+    pub fn is_syncing(&self) -> bool {
+        !self.headers.is_empty()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.headers.len()
+    }
+
+    /// HasRoom reports whether more headers are accepted
+    pub fn has_room(&self) -> bool {
+        self.headers.len() < MAX_PENDING_HEADERS
+    }
+
+    /// AddHeaders accepts a batch of headers and returns how many were new
+    ///
+    /// `stored_height` returns the height of a block already in the local
+    /// chain. Headers continue either the accepted headers or a stored
+    /// block; the rest of a batch that does not connect, e.g. a competing
+    /// fork or the answer to a stale locator, is ignored, as are headers
+    /// beyond `MAX_PENDING_HEADERS`. A wrong height or an invalid proof of
+    /// work is an error.
+    pub fn add_headers<F>(&mut self, headers: Vec<BlockHeader>, stored_height: F) -> Result<usize>
+    where
+        F: Fn(&str) -> Option<i32>,
+    {
+        let mut added = 0;
+        for header in headers {
+            if stored_height(&header.hash).is_some() || self.queued.contains(&header.hash) {
+                continue;
+            }
+            if !self.has_room() {
+                break;
+            }
+            let parent_height = match self.headers.back() {
+                Some(last) if last.hash == header.prev_block_hash => Some(last.height),
+                Some(_) => None,
+                None if header.prev_block_hash.is_empty() => Some(-1),
+                None => stored_height(&header.prev_block_hash),
+            };
+            match parent_height {
+                Some(h) if h.checked_add(1) == Some(header.height) => {}
+                Some(_) => return Err(format_err!("header {} has a wrong height", header.hash)),
+                None => {
+                    debug!(
+                        "header {} does not connect, ignore the rest of the batch",
+                        header.hash
+                    );
+                    break;
+                }
+            }
+            if !header.validate()? {
+                return Err(format_err!(
+                    "header {} has an invalid proof of work",
+                    header.hash
+                ));
+            }
+            self.queued.insert(header.hash.clone());
+            self.headers.push_back(header);
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// NextRequests assigns missing block bodies to peers
+    ///
+    /// Each peer gets at most its window of requests, spread over the peers
+    /// that announced a high enough chain, with the most free slots first.
+    /// Requests that timed out halve the window of their peer and are
+    /// handed out again.
+    pub fn next_requests(&mut self, now: Instant) -> Vec<(String, String)> {
+        let mut timed_out = Vec::new();
+        self.in_flight.retain(|_, (peer, at)| {
+            let waiting = now.duration_since(*at) < BLOCK_REQUEST_TIMEOUT;
+            if !waiting {
+                timed_out.push(peer.clone());
+            }
+            waiting
+        });
+        for peer in timed_out {
+            let window = (self.window(&peer) / 2).max(1);
+            self.windows.insert(peer.clone(), window);
+            self.slow_peers.push(peer);
+        }
+
+        let mut load: HashMap<String, usize> = self
+            .peer_heights
+            .keys()
+            .map(|addr| (addr.clone(), 0))
+            .collect();
+        for (peer, _) in self.in_flight.values() {
+            if let Some(n) = load.get_mut(peer) {
+                *n += 1;
+            }
+        }
+
+        let mut requests = Vec::new();
+        for header in self.headers.iter().take(DOWNLOAD_WINDOW) {
+            if self.received.contains_key(&header.hash) || self.in_flight.contains_key(&header.hash)
+            {
+                continue;
+            }
+            let peer = load
+                .iter()
+                .map(|(addr, n)| (addr, self.window(addr).saturating_sub(*n)))
+                .filter(|(addr, free)| *free > 0 && self.peer_heights[*addr] >= header.height)
+                .min_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)))
+                .map(|(addr, _)| addr.clone());
+            let peer = match peer {
+                Some(p) => p,
+                None => continue,
+            };
+            *load.get_mut(&peer).unwrap() += 1;
+            self.in_flight
+                .insert(header.hash.clone(), (peer.clone(), now));
+            requests.push((peer, header.hash.clone()));
+        }
+        requests
+    }
+
+    /// TakeSlowPeers returns the peers that let block requests time out
+    pub fn take_slow_peers(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.slow_peers)
+    }
+
+    /// ReceiveBlock stores a body after checking it against its header
+    ///
+    /// Returns false if the block was not requested by the sync.
+    pub fn receive_block(&mut self, block: Block) -> Result<bool> {
+        let hash = block.get_hash();
+        let header = match self.headers.iter().find(|h| h.hash == hash) {
+            Some(h) => h,
+            None => return Ok(false),
+        };
+        if block.header()? != *header {
+            self.in_flight.remove(&hash);
+            return Err(format_err!("block {} does not match its header", hash));
+        }
+        if let Some((peer, _)) = self.in_flight.remove(&hash) {
+            let window = (self.window(&peer) + 1).min(MAX_BLOCKS_IN_FLIGHT);
+            self.windows.insert(peer, window);
+        }
+        self.received.insert(hash, block);
+        Ok(true)
+    }
+
+    /// TakeReady removes the received blocks that continue the chain, oldest first
+    pub fn take_ready(&mut self) -> Vec<Block> {
+        let mut ready = Vec::new();
+        while let Some(header) = self.headers.front() {
+            match self.received.remove(&header.hash) {
+                Some(block) => {
+                    ready.push(block);
+                    if let Some(header) = self.headers.pop_front() {
+                        self.queued.remove(&header.hash);
+                    }
+                }
+                None => break,
+            }
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transaction::Transaction;
+
+    fn chain(len: usize) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for i in 0..len {
+            let cbtx = Transaction::new_coinbase(
+                String::from("3K2B53BeZoaSPBGxihwPkbjNxrLkapfYrn"),
+                format!("block {}", i),
+            )
+            .unwrap();
+            let prev = blocks.last().map(|b| b.get_hash()).unwrap_or_default();
+            blocks.push(Block::new_block(vec![cbtx], prev, "", i as i32, MIN_TARGET_BITS).unwrap());
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_headers_first_sync() {
+        let blocks = chain(3);
+        let headers: Vec<BlockHeader> = blocks.iter().map(|b| b.header().unwrap()).collect();
+        let genesis = blocks[0].get_hash();
+        let stored = |hash: &str| if hash == genesis { Some(0) } else { None };
+
+        let mut sync = SyncManager::new();
+        // headers that do not connect are not useful, but not an offence
+        assert_eq!(
+            sync.add_headers(vec![headers[2].clone()], stored).unwrap(),
+            0
+        );
+        let mut forged = headers[1].clone();
+        forged.nonce += 1;
+        assert!(sync.add_headers(vec![forged], stored).is_err());
+        let mut misplaced = headers[1].clone();
+        misplaced.height = 5;
+        assert!(sync.add_headers(vec![misplaced], stored).is_err());
+        assert_eq!(sync.add_headers(headers.clone(), stored).unwrap(), 2);
+        assert_eq!(sync.add_headers(headers.clone(), stored).unwrap(), 0);
+        assert_eq!(sync.last_header().unwrap().height, 2);
+
+        let start = Instant::now();
+        sync.set_peer_height("a:1", 2);
+        sync.set_peer_height("b:1", 2);
+        assert_eq!(sync.network_height(), 2);
+        let requests = sync.next_requests(start);
+        assert_eq!(requests.len(), 2);
+        assert_ne!(requests[0].0, requests[1].0);
+        assert!(sync.next_requests(start).is_empty());
+        assert_eq!(sync.next_requests(start + BLOCK_REQUEST_TIMEOUT).len(), 2);
+        assert_eq!(sync.take_slow_peers().len(), 2);
+        assert_eq!(sync.window("a:1"), INITIAL_BLOCKS_IN_FLIGHT / 2);
+
+        // bodies are applied in height order whatever order they arrive in
+        assert!(sync.receive_block(blocks[2].clone()).unwrap());
+        assert!(sync.take_ready().is_empty());
+        assert!(!sync.receive_block(blocks[0].clone()).unwrap());
+        assert!(sync.receive_block(blocks[1].clone()).unwrap());
+        let ready: Vec<i32> = sync.take_ready().iter().map(|b| b.get_height()).collect();
+        assert_eq!(ready, vec![1, 2]);
+        assert!(!sync.is_syncing());
+        // every delivery widens the window of the peer
+        assert_eq!(
+            sync.window("a:1") + sync.window("b:1"),
+            INITIAL_BLOCKS_IN_FLIGHT + 2
+        );
+
+        // requests stay within the download window and favour wide peers
+        for height in 3..3 + DOWNLOAD_WINDOW as i32 * 2 {
+            sync.queued.insert(format!("h{}", height));
+            sync.headers.push_back(BlockHeader {
+                height,
+                hash: format!("h{}", height),
+                ..headers[0].clone()
+            });
+        }
+        sync.windows
+            .insert(String::from("a:1"), MAX_BLOCKS_IN_FLIGHT);
+        sync.windows.insert(String::from("b:1"), 1);
+        sync.set_peer_height("a:1", 1000);
+        sync.set_peer_height("b:1", 1000);
+        let requests = sync.next_requests(start);
+        assert_eq!(requests.len(), MAX_BLOCKS_IN_FLIGHT + 1);
+        assert_eq!(requests.iter().filter(|(peer, _)| peer == "b:1").count(), 1);
+        for i in 0..DOWNLOAD_WINDOW {
+            sync.set_peer_height(&format!("p{}:1", i), 1000);
+        }
+        sync.next_requests(start);
+        assert!(sync.in_flight.len() <= DOWNLOAD_WINDOW);
+        assert!(!sync
+            .in_flight
+            .contains_key(&format!("h{}", 3 + DOWNLOAD_WINDOW)));
+
+        // the pending headers are bounded
+        while sync.has_room() {
+            let height = sync.last_header().unwrap().height + 1;
+            sync.queued.insert(format!("h{}", height));
+            sync.headers.push_back(BlockHeader {
+                height,
+                hash: format!("h{}", height),
+                ..headers[0].clone()
+            });
+        }
+        let next = BlockHeader {
+            height: sync.last_header().unwrap().height + 1,
+            prev_block_hash: sync.last_header().unwrap().hash.clone(),
+            ..headers[0].clone()
+        };
+        assert_eq!(sync.add_headers(vec![next], stored).unwrap(), 0);
+        assert_eq!(sync.pending(), MAX_PENDING_HEADERS);
+    }
+}
@@ -0,0 +1,290 @@
+//! Reserved system transactions
+//!
+//! System transactions carry protocol operations (checkpoints, and later
+//! staking or governance actions) instead of value transfers. They have a
+//! single marker input with `vout == SYSTEM_TX_VOUT` whose `pub_key` holds a
+//! versioned `SystemTxEnvelope`, and no outputs. Every envelope kind has a
+//! dedicated validator in the `SystemTxRegistry` of the `Blockchain`; unknown
+//! kinds are rejected.
+//!
+//! System transactions carry no signature and pay no fee, so a block holds
+//! at most `MAX_SYSTEM_TXS_PER_BLOCK` of them. The node admits them to its
+//! mempool only from RPC, the local host and the senders it authorized, and
+//! keeps a few of them at a time.
+
+use super::*;
+use crate::blockchain::Blockchain;
+use crate::transaction::{TXInput, Transaction};
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Marker `vout` of the input of a system transaction
+pub const SYSTEM_TX_VOUT: i32 = -2;
+/// Latest envelope version understood by this node
+pub const SYSTEM_TX_VERSION: u16 = 1;
+/// Most system transactions a block may carry
+pub const MAX_SYSTEM_TXS_PER_BLOCK: usize = 1;
+
+/// Kind of the checkpoint system transaction
+pub const KIND_CHECKPOINT: u16 = 1;
+
+/// SystemTxEnvelope is the versioned payload of a system transaction
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SystemTxEnvelope {
+    pub version: u16,
+    pub kind: u16,
+    pub payload: Vec<u8>,
+}
+
+impl SystemTxEnvelope {
+    pub fn new<T: Serialize>(kind: u16, payload: &T) -> Result<SystemTxEnvelope> {
+        Ok(SystemTxEnvelope {
+            version: SYSTEM_TX_VERSION,
+            kind,
+            payload: serialize(payload)?,
+        })
+    }
+
+    /// IntoTransaction wraps the envelope into a system transaction
+    pub fn into_transaction(self) -> Result<Transaction> {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: String::new(),
+                vout: SYSTEM_TX_VOUT,
+                signature: Vec::new(),
+                pub_key: serialize(&self)?,
+            }],
+            vout: Vec::new(),
+        };
+        tx.id = tx.hash()?;
+        Ok(tx)
+    }
+
+    /// FromTransaction extracts the envelope of a system transaction
+    pub fn from_transaction(tx: &Transaction) -> Result<SystemTxEnvelope> {
+        if !tx.is_system() {
+            return Err(format_err!(
+                "transaction {} is not a system transaction",
+                tx.id
+            ));
+        }
+        if !tx.vout.is_empty() {
+            return Err(format_err!("system transaction {} has outputs", tx.id));
+        }
+        Ok(deserialize(&tx.vin[0].pub_key)?)
+    }
+}
+
+/// Checkpoint pins the hash of the block at a given height
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub height: i32,
+    pub block_hash: String,
+}
+
+/// SystemTxValidator checks the payload of one kind of system transaction
+pub trait SystemTxValidator: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn validate(&self, payload: &[u8], bc: &Blockchain) -> Result<()>;
+}
+
+struct CheckpointValidator;
+
+impl SystemTxValidator for CheckpointValidator {
+    fn name(&self) -> &'static str {
+        "checkpoint"
+    }
+
+    fn validate(&self, payload: &[u8], bc: &Blockchain) -> Result<()> {
+        let cp: Checkpoint = deserialize(payload)?;
+        match bc.block_hash_at(cp.height)? {
+            Some(hash) if hash == cp.block_hash => Ok(()),
+            Some(_) => Err(format_err!(
+                "checkpoint hash mismatch at height {}",
+                cp.height
+            )),
+            None => Err(format_err!(
+                "checkpoint height {} is not in the chain",
+                cp.height
+            )),
+        }
+    }
+}
+
+/// SystemTxRegistry maps system transaction kinds to their validators
+pub struct SystemTxRegistry {
+    validators: HashMap<u16, Box<dyn SystemTxValidator>>,
+}
+
+impl SystemTxRegistry {
+    /// NewSystemTxRegistry creates a registry with the built-in kinds
+    pub fn new() -> SystemTxRegistry {
+        let mut registry = SystemTxRegistry {
+            validators: HashMap::new(),
+        };
+        registry.register(KIND_CHECKPOINT, Box::new(CheckpointValidator));
+        registry
+    }
+
+    /// Register adds the validator of a new system transaction kind
+    pub fn register(&mut self, kind: u16, validator: Box<dyn SystemTxValidator>) {
+        self.validators.insert(kind, validator);
+    }
+
+    /// Decode extracts the envelope and checks its version and kind
+    pub fn decode(&self, tx: &Transaction) -> Result<(SystemTxEnvelope, &dyn SystemTxValidator)> {
+        let env = SystemTxEnvelope::from_transaction(tx)?;
+        if env.version == 0 || env.version > SYSTEM_TX_VERSION {
+            return Err(format_err!(
+                "unsupported system transaction version {}",
+                env.version
+            ));
+        }
+        match self.validators.get(&env.kind) {
+            Some(v) => Ok((env, v.as_ref())),
+            None => Err(format_err!("unknown system transaction kind {}", env.kind)),
+        }
+    }
+
+    /// Validate runs the protocol rules of the system transaction
+    pub fn validate(&self, tx: &Transaction, bc: &Blockchain) -> Result<()> {
+        let (env, validator) = self.decode(tx)?;
+        debug!("validate {} system transaction {}", validator.name(), tx.id);
+        validator.validate(&env.payload, bc)
+    }
+}
+
+impl fmt::Debug for SystemTxRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut kinds: Vec<(&u16, &'static str)> =
+            self.validators.iter().map(|(k, v)| (k, v.name())).collect();
+        kinds.sort();
+        f.debug_struct("SystemTxRegistry")
+            .field("kinds", &kinds)
+            .finish()
+    }
+}
+
+/// SystemTxCount returns the number of system transactions among `txs`
+pub fn system_tx_count(txs: &[Transaction]) -> usize {
+    txs.iter().filter(|tx| tx.is_system()).count()
+}
+
+impl Default for SystemTxRegistry {
+    fn default() -> SystemTxRegistry {
+        SystemTxRegistry::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::{Block, MIN_TARGET_BITS};
+
+    const ADDRESS: &str = "3K2B53BeZoaSPBGxihwPkbjNxrLkapfYrn";
+
+    struct AcceptAll;
+
+    impl SystemTxValidator for AcceptAll {
+        fn name(&self) -> &'static str {
+            "accept-all"
+        }
+
+        fn validate(&self, _payload: &[u8], _bc: &Blockchain) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_chain_registry() {
+        let cbtx =
+            Transaction::new_coinbase(String::from(ADDRESS), String::from("genesis")).unwrap();
+        let genesis = Block::new_genesis_block(cbtx);
+        let mut bc = Blockchain::temporary(&genesis).unwrap();
+        let cbtx = Transaction::new_coinbase(String::from(ADDRESS), String::from("b1")).unwrap();
+        let b1 = Block::new_block(
+            vec![cbtx],
+            genesis.get_hash(),
+            &bc.chain_id().unwrap(),
+            1,
+            MIN_TARGET_BITS,
+        )
+        .unwrap();
+        bc.add_block(b1.clone()).unwrap();
+        assert_eq!(bc.block_hash_at(0).unwrap(), Some(genesis.get_hash()));
+        assert_eq!(bc.block_hash_at(2).unwrap(), None);
+
+        let checkpoint = |height: i32, block_hash: String| {
+            SystemTxEnvelope::new(KIND_CHECKPOINT, &Checkpoint { height, block_hash })
+                .unwrap()
+                .into_transaction()
+                .unwrap()
+        };
+        assert!(bc.verify_transacton(&checkpoint(1, b1.get_hash())).unwrap());
+        assert!(!bc
+            .verify_transacton(&checkpoint(1, genesis.get_hash()))
+            .unwrap());
+        assert!(!bc.verify_transacton(&checkpoint(2, b1.get_hash())).unwrap());
+
+        // kinds registered on the chain are the ones validation accepts
+        let custom = SystemTxEnvelope::new(7, &())
+            .unwrap()
+            .into_transaction()
+            .unwrap();
+        assert!(!bc.verify_transacton(&custom).unwrap());
+        bc.system_txs.register(7, Box::new(AcceptAll));
+        assert!(bc.verify_transacton(&custom).unwrap());
+
+        let cbtx = Transaction::new_coinbase(String::from(ADDRESS), String::from("b2")).unwrap();
+        let crowded = Block::new_block(
+            vec![custom, checkpoint(1, b1.get_hash()), cbtx],
+            b1.get_hash(),
+            "",
+            2,
+            MIN_TARGET_BITS,
+        )
+        .unwrap();
+        assert!(!crowded.verify().unwrap());
+    }
+
+    #[test]
+    fn test_envelope() {
+        let registry = SystemTxRegistry::new();
+        let cp = Checkpoint {
+            height: 3,
+            block_hash: String::from("00ab"),
+        };
+        let env = SystemTxEnvelope::new(KIND_CHECKPOINT, &cp).unwrap();
+        let tx = env.clone().into_transaction().unwrap();
+        assert!(tx.is_system());
+        assert!(!tx.is_coinbase());
+
+        let (decoded, validator) = registry.decode(&tx).unwrap();
+        assert_eq!(decoded, env);
+        assert_eq!(validator.name(), "checkpoint");
+        assert_eq!(deserialize::<Checkpoint>(&decoded.payload).unwrap(), cp);
+
+        let mut future = env.clone();
+        future.version = SYSTEM_TX_VERSION + 1;
+        assert!(registry
+            .decode(&future.into_transaction().unwrap())
+            .is_err());
+
+        let mut unknown = env;
+        unknown.kind = 0xffff;
+        assert!(registry
+            .decode(&unknown.into_transaction().unwrap())
+            .is_err());
+
+        let coinbase = Transaction::new_coinbase(
+            String::from("3K2B53BeZoaSPBGxihwPkbjNxrLkapfYrn"),
+            String::from("data"),
+        )
+        .unwrap();
+        assert!(registry.decode(&coinbase).is_err());
+    }
+}
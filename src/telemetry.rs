@@ -0,0 +1,143 @@
+//! Arrival telemetry for propagation research
+//!
+//! When enabled, the node records the first time each peer announced or
+//! sent each transaction and block. Records are appended to a compact
+//! binary log under data/telemetry and can be exported to CSV. Peer
+//! addresses end up in the log, so recording is off unless the operator
+//! turns it on.
+
+use super::*;
+use crate::peers::InventoryCache;
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::time::SystemTime;
+
+pub const ARRIVAL_LOG_PATH: &str = "data/telemetry/arrivals.log";
+/// Number of (item, peer) pairs remembered to record only first arrivals
+const SEEN_CAPACITY: usize = 65536;
+
+/// ArrivalKind says what arrived and how
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ArrivalKind {
+    TxAnnounced,
+    TxReceived,
+    BlockAnnounced,
+    BlockReceived,
+}
+
+/// Arrival is one record of the arrival log
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Arrival {
+    pub millis: u64,
+    pub kind: ArrivalKind,
+    pub hash: String,
+    pub peer: String,
+}
+
+/// ArrivalLog appends first arrivals to the on-disk log
+pub struct ArrivalLog {
+    file: BufWriter<File>,
+    seen: InventoryCache,
+}
+
+impl ArrivalLog {
+    pub fn open(path: &str) -> Result<ArrivalLog> {
+        if let Some(dir) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(ArrivalLog {
+            file: BufWriter::new(file),
+            seen: InventoryCache::new(SEEN_CAPACITY),
+        })
+    }
+
+    /// Record appends the arrival unless the peer already delivered the item this way
+    pub fn record(&mut self, kind: ArrivalKind, hash: &str, peer: &str) -> Result<()> {
+        if !self.seen.insert(&format!("{:?}:{}:{}", kind, hash, peer)) {
+            return Ok(());
+        }
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis() as u64;
+        let record = serialize(&Arrival {
+            millis,
+            kind,
+            hash: hash.to_string(),
+            peer: peer.to_string(),
+        })?;
+        self.file.write_all(&(record.len() as u32).to_le_bytes())?;
+        self.file.write_all(&record)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// ReadArrivals reads every record of an arrival log
+pub fn read_arrivals(path: &str) -> Result<Vec<Arrival>> {
+    let data = std::fs::read(path)?;
+    let mut arrivals = Vec::new();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(format_err!("arrival log is truncated"));
+        }
+        let len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() < 4 + len {
+            return Err(format_err!("arrival log is truncated"));
+        }
+        arrivals.push(deserialize(&rest[4..4 + len])?);
+        rest = &rest[4 + len..];
+    }
+    Ok(arrivals)
+}
+
+/// ExportCsv writes the arrivals as CSV with a header line
+pub fn export_csv<W: Write>(arrivals: &[Arrival], out: &mut W) -> Result<()> {
+    writeln!(out, "millis,kind,hash,peer")?;
+    for a in arrivals {
+        writeln!(out, "{},{:?},{},{}", a.millis, a.kind, a.hash, a.peer)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_arrival_log() {
+        let path =
+            std::env::temp_dir().join(format!("polytorus-arrivals-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        {
+            let mut log = ArrivalLog::open(path).unwrap();
+            log.record(ArrivalKind::TxAnnounced, "tx1", "a:1").unwrap();
+            log.record(ArrivalKind::TxAnnounced, "tx1", "a:1").unwrap();
+            log.record(ArrivalKind::TxAnnounced, "tx1", "b:1").unwrap();
+            log.record(ArrivalKind::TxReceived, "tx1", "a:1").unwrap();
+        }
+        let arrivals = read_arrivals(path).unwrap();
+        assert_eq!(arrivals.len(), 3);
+        assert_eq!(arrivals[1].peer, "b:1");
+        assert_eq!(arrivals[2].kind, ArrivalKind::TxReceived);
+
+        let mut csv = Vec::new();
+        export_csv(&arrivals, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 4);
+        assert!(csv
+            .lines()
+            .nth(1)
+            .unwrap()
+            .ends_with(",TxAnnounced,tx1,a:1"));
+
+        std::fs::write(path, &std::fs::read(path).unwrap()[..10]).unwrap();
+        assert!(read_arrivals(path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,23 +1,23 @@
 use super::*;
+use crate::signer::*;
+use crate::systemtx::SYSTEM_TX_VOUT;
+use crate::txbuilder::TransactionBuilder;
 use crate::utxoset::*;
 use crate::wallets::*;
 use bincode::serialize;
-use bitcoincash_addr::Address;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use failure::format_err;
-use fn_dsa::{
-    signature_size,
-    SigningKey, SigningKeyStandard, VerifyingKey, VerifyingKeyStandard, DOMAIN_NONE,
-    HASH_ID_RAW,
-};
+use fn_dsa::{VerifyingKey, VerifyingKeyStandard, DOMAIN_NONE, HASH_ID_RAW};
 use rand::Rng;
-use rand_core::OsRng;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::vec;
 
-const SUBSIDY: i32 = 10;
+/// Reward of a block on top of the fees of its transactions
+pub const SUBSIDY: i32 = 10;
+/// Weight units of a non-witness byte, witness bytes weigh one unit
+pub const WITNESS_SCALE_FACTOR: usize = 4;
 
 /// TXInput represents a transaction input
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -51,57 +51,26 @@ pub struct Transaction {
 
 impl Transaction {
     /// NewUTXOTransaction creates a new transaction
-    pub fn new_UTXO(wallet: &Wallet, to: &str, amount: i32, utxo: &UTXOSet) -> Result<Transaction> {
-        info!(
-            "new UTXO Transaction from: {} to: {}",
-            wallet.get_address(),
-            to
-        );
-        let mut vin = Vec::new();
-
-        let mut pub_key_hash = wallet.public_key.clone();
-        hash_pub_key(&mut pub_key_hash);
-
-        let acc_v = utxo.find_spendable_outputs(&pub_key_hash, amount)?;
-
-        if acc_v.0 < amount {
-            error!("Not Enough balance");
-            return Err(format_err!(
-                "Not Enough balance: current balance {}",
-                acc_v.0
-            ));
-        }
-
-        for tx in acc_v.1 {
-            for out in tx.1 {
-                let input = TXInput {
-                    txid: tx.0.clone(),
-                    vout: out,
-                    signature: Vec::new(),
-                    pub_key: wallet.public_key.clone(),
-                };
-                vin.push(input);
-            }
-        }
-
-        let mut vout = vec![TXOutput::new(amount, to.to_string())?];
-        if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, wallet.get_address())?)
-        }
-
-        let mut tx = Transaction {
-            id: String::new(),
-            vin,
-            vout,
-        };
-        tx.id = tx.hash()?;
-        utxo.blockchain
-            .sign_transacton(&mut tx, &wallet.secret_key)?;
-        Ok(tx)
+    pub fn new_UTXO(
+        signer: &dyn Signer,
+        to: &str,
+        amount: i32,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
+        let from = address_from_pub_key(&signer.public_key()?);
+        info!("new UTXO Transaction from: {} to: {}", from, to);
+        TransactionBuilder::new(signer)
+            .pay_to(to, amount)
+            .build(utxo)
     }
 
     /// NewCoinbaseTX creates a new coinbase transaction
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
+    pub fn new_coinbase(to: String, data: String) -> Result<Transaction> {
+        Transaction::new_coinbase_with_fees(to, data, 0)
+    }
+
+    /// NewCoinbaseWithFees creates a coinbase paying the subsidy and the `fees` of its block
+    pub fn new_coinbase_with_fees(to: String, mut data: String, fees: i32) -> Result<Transaction> {
         info!("new coinbase Transaction to: {}", to);
         let mut key: [u8; 32] = [0; 32];
         if data.is_empty() {
@@ -120,7 +89,7 @@ impl Transaction {
                 signature: Vec::new(),
                 pub_key,
             }],
-            vout: vec![TXOutput::new(SUBSIDY, to)?],
+            vout: vec![TXOutput::new(SUBSIDY + fees, to)?],
         };
         tx.id = tx.hash()?;
         Ok(tx)
@@ -131,46 +100,49 @@ impl Transaction {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
 
-    /// Verify verifies signatures of Transaction inputs
-    pub fn verify(&self, prev_TXs: HashMap<String, Transaction>) -> Result<bool> {
-        if self.is_coinbase() {
-            return Ok(true);
-        }
+    /// IsSystem checks whether the transaction is a reserved system transaction
+    pub fn is_system(&self) -> bool {
+        self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == SYSTEM_TX_VOUT
+    }
 
-        for vin in &self.vin {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
-            }
+    /// Verify verifies signatures of Transaction inputs made on chain `chain_id`
+    ///
+    /// Each input must carry the public key its previous output is locked to.
+    pub fn verify(&self, prev_TXs: HashMap<String, Transaction>, chain_id: &str) -> Result<bool> {
+        if self.is_coinbase() || self.is_system() {
+            return Ok(true);
         }
 
-        let mut tx_copy = self.trim_copy();
-
         for in_id in 0..self.vin.len() {
-            let prev_Tx = prev_TXs.get(&self.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_Tx.vout[self.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key = Vec::new();
+            let prev_pub_key_hash = match prev_output(&prev_TXs, &self.vin[in_id])? {
+                Some(out) => &out.pub_key_hash,
+                None => return Ok(false),
+            };
+            let mut pub_key_hash = self.vin[in_id].pub_key.clone();
+            hash_pub_key(&mut pub_key_hash);
+            if pub_key_hash != *prev_pub_key_hash {
+                return Ok(false);
+            }
+            let message = self.signature_hash(in_id, prev_pub_key_hash, chain_id)?;
 
             // if !ed25519::verify(
-            //     &tx_copy.id.as_bytes(), // message
+            //     &message.as_bytes(), // message
             //     &self.vin[in_id].pub_key, // public key
             //     &self.vin[in_id].signature, // signature
             // ) {
             //     return Ok(false);
             // }
 
-            if !VerifyingKeyStandard::decode(&self.vin[in_id].pub_key)
-                .unwrap()
-                .verify(
-                    &self.vin[in_id].signature,
-                    &DOMAIN_NONE,
-                    &HASH_ID_RAW,
-                    &tx_copy.id.as_bytes(),
-                )
-            {
+            let key = match VerifyingKeyStandard::decode(&self.vin[in_id].pub_key) {
+                Some(key) => key,
+                None => return Ok(false),
+            };
+            if !key.verify(
+                &self.vin[in_id].signature,
+                &DOMAIN_NONE,
+                &HASH_ID_RAW,
+                message.as_bytes(),
+            ) {
                 return Ok(false);
             }
         }
@@ -178,46 +150,72 @@ impl Transaction {
         Ok(true)
     }
 
-    /// Sign signs each input of a Transaction
+    /// Sign signs each input of a Transaction for chain `chain_id`
     pub fn sign(
         &mut self,
-        private_key: &[u8],
+        signer: &dyn Signer,
         prev_TXs: HashMap<String, Transaction>,
+        chain_id: &str,
     ) -> Result<()> {
-        if self.is_coinbase() {
+        if self.is_coinbase() || self.is_system() {
             return Ok(());
         }
 
-        for vin in &self.vin {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
-            }
+        for in_id in 0..self.vin.len() {
+            let prev_pub_key_hash = match prev_output(&prev_TXs, &self.vin[in_id])? {
+                Some(out) => &out.pub_key_hash,
+                None => {
+                    return Err(format_err!(
+                        "ERROR: input {} spends a missing output",
+                        in_id
+                    ))
+                }
+            };
+            // let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
+            let message = self.signature_hash(in_id, prev_pub_key_hash, chain_id)?;
+            self.vin[in_id].signature = signer.sign(message.as_bytes())?;
         }
 
-        let mut tx_copy = self.trim_copy();
+        Ok(())
+    }
 
-        for in_id in 0..tx_copy.vin.len() {
-            let prev_Tx = prev_TXs.get(&tx_copy.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_Tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key = Vec::new();
-            // let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
-            let mut sk = SigningKeyStandard::decode(private_key).unwrap();
-            let mut signature = vec![0u8; signature_size(sk.get_logn())];
-            sk.sign(
-                &mut OsRng,
-                &DOMAIN_NONE,
-                &HASH_ID_RAW,
-                tx_copy.id.as_bytes(),
-                &mut signature,
-            );
-            self.vin[in_id].signature = signature.to_vec();
+    /// SignatureHash returns the message signed for input `in_id`, which spends
+    /// an output locked to `prev_pub_key_hash`
+    ///
+    /// The message commits to the chain id so a signature is only valid on
+    /// its own network, signing without a chain id is an error.
+    pub fn signature_hash(
+        &self,
+        in_id: usize,
+        prev_pub_key_hash: &[u8],
+        chain_id: &str,
+    ) -> Result<String> {
+        if chain_id.is_empty() {
+            return Err(format_err!("cannot sign a transaction without a chain id"));
         }
+        let mut tx_copy = self.trim_copy();
+        tx_copy.vin[in_id].pub_key = prev_pub_key_hash.to_vec();
+        let hash = tx_copy.hash()?;
+        let mut hasher = Sha256::new();
+        hasher.input(chain_id.as_bytes());
+        hasher.input(hash.as_bytes());
+        Ok(hasher.result_str())
+    }
 
-        Ok(())
+    /// Weight returns the serialized size with the signatures and public keys discounted
+    ///
+    /// FN-DSA keys and signatures make up most of a transaction, so they
+    /// count one weight unit per byte and every other byte
+    /// `WITNESS_SCALE_FACTOR` units.
+    pub fn weight(&self) -> Result<usize> {
+        let size = serialize(self)?.len();
+        let witness: usize = self
+            .vin
+            .iter()
+            .filter(|vin| !vin.txid.is_empty())
+            .map(|vin| vin.signature.len() + vin.pub_key.len())
+            .sum();
+        Ok((size - witness) * WITNESS_SCALE_FACTOR + witness)
     }
 
     /// Hash returns the hash of the Transaction
@@ -238,7 +236,7 @@ impl Transaction {
         for v in &self.vin {
             vin.push(TXInput {
                 txid: v.txid.clone(),
-                vout: v.vout.clone(),
+                vout: v.vout,
                 signature: Vec::new(),
                 pub_key: Vec::new(),
             })
@@ -259,6 +257,20 @@ impl Transaction {
     }
 }
 
+/// prev_output returns the output spent by `vin`, None if it has no such output
+fn prev_output<'a>(
+    prev_TXs: &'a HashMap<String, Transaction>,
+    vin: &TXInput,
+) -> Result<Option<&'a TXOutput>> {
+    let prev_Tx = match prev_TXs.get(&vin.txid) {
+        Some(tx) if !tx.id.is_empty() => tx,
+        _ => return Err(format_err!("ERROR: Previous transaction is not correct")),
+    };
+    Ok(usize::try_from(vin.vout)
+        .ok()
+        .and_then(|idx| prev_Tx.vout.get(idx)))
+}
+
 impl TXOutput {
     /// IsLockedWithKey checks if the output can be used by the owner of the pubkey
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
@@ -266,7 +278,7 @@ impl TXOutput {
     }
     /// Lock signs the output
     fn lock(&mut self, address: &str) -> Result<()> {
-        let pub_key_hash = Address::decode(address).unwrap().body;
+        let pub_key_hash = crate::address::pub_key_hash(address)?;
         debug!("lock: {}", address);
         self.pub_key_hash = pub_key_hash;
         Ok(())
@@ -285,6 +297,8 @@ impl TXOutput {
 #[cfg(test)]
 mod test {
     use super::*;
+    use fn_dsa::{signature_size, SigningKey, SigningKeyStandard};
+    use rand_core::OsRng;
 
     #[test]
     fn test_signature() {
@@ -316,4 +330,75 @@ mod test {
             tx.id.as_bytes()
         ));
     }
+
+    #[test]
+    fn test_verify_owner() {
+        let owner = Wallet::from_rng(&mut OsRng);
+        let thief = Wallet::from_rng(&mut OsRng);
+        let prev = Transaction::new_coinbase(owner.get_address(), String::from("prev")).unwrap();
+        let prev_TXs = HashMap::from([(prev.id.clone(), prev.clone())]);
+        let spend = |wallet: &Wallet| {
+            let mut tx = Transaction {
+                id: String::new(),
+                vin: vec![TXInput {
+                    txid: prev.id.clone(),
+                    vout: 0,
+                    signature: Vec::new(),
+                    pub_key: wallet.public_key.clone(),
+                }],
+                vout: vec![TXOutput::new(SUBSIDY, thief.get_address()).unwrap()],
+            };
+            tx.id = tx.hash().unwrap();
+            tx.sign(wallet, prev_TXs.clone(), "test").unwrap();
+            tx
+        };
+
+        assert!(spend(&owner).verify(prev_TXs.clone(), "test").unwrap());
+        // a valid signature of another key does not unlock the output
+        assert!(!spend(&thief).verify(prev_TXs.clone(), "test").unwrap());
+        // signatures always commit to a chain id
+        assert!(spend(&owner).sign(&owner, prev_TXs.clone(), "").is_err());
+        assert!(spend(&owner).verify(prev_TXs, "").is_err());
+    }
+
+    #[test]
+    fn test_verify_malformed() {
+        let owner = Wallet::from_rng(&mut OsRng);
+        let mut garbage_hash = vec![7; 10];
+        hash_pub_key(&mut garbage_hash);
+        let mut prev =
+            Transaction::new_coinbase(owner.get_address(), String::from("prev")).unwrap();
+        prev.vout.push(TXOutput {
+            value: SUBSIDY,
+            pub_key_hash: garbage_hash,
+        });
+        let prev_TXs = HashMap::from([(prev.id.clone(), prev.clone())]);
+        let spend = |vout: i32, pub_key: Vec<u8>| Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: prev.id.clone(),
+                vout,
+                signature: vec![0; 10],
+                pub_key,
+            }],
+            vout: Vec::new(),
+        };
+
+        assert!(!spend(2, owner.public_key.clone())
+            .verify(prev_TXs.clone(), "test")
+            .unwrap());
+        assert!(!spend(-2, owner.public_key.clone())
+            .verify(prev_TXs.clone(), "test")
+            .unwrap());
+        // a key that is no FN-DSA key fails verification
+        assert!(!spend(1, vec![7; 10])
+            .verify(prev_TXs.clone(), "test")
+            .unwrap());
+        assert!(spend(0, owner.public_key.clone())
+            .verify(HashMap::new(), "test")
+            .is_err());
+        assert!(spend(2, owner.public_key.clone())
+            .sign(&owner, prev_TXs, "test")
+            .is_err());
+    }
 }
@@ -0,0 +1,309 @@
+//! Encrypted transport for node connections
+//!
+//! Every message still travels over its own TCP connection. An encrypted
+//! connection starts with `NOISE_PREAMBLE`, runs a Noise XX handshake with
+//! the static keys of both nodes and then carries the message in
+//! length-prefixed transport frames. The first transport frame holds the
+//! length of the message, so a connection closed early is an error rather
+//! than a shorter message. Plaintext connections start directly
+//! with the command bytes, so the receiver tells the two apart from the
+//! first bytes and can refuse plaintext when encryption is required.
+
+use super::*;
+use failure::format_err;
+use snow::{Builder, HandshakeState, TransportState};
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const NOISE_PREAMBLE: &[u8; 4] = b"\0NXX";
+const NODE_KEY_PATH: &str = "data/nodekey";
+const KEY_LEN: usize = 32;
+const MAX_FRAME: usize = 65535;
+/// Most bytes of a message, enough for a block of the maximum weight
+pub const MAX_MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+const TAG_LEN: usize = 16;
+/// Permissions of the node key file, readable by its owner only
+#[cfg(unix)]
+const KEY_FILE_MODE: u32 = 0o600;
+
+/// NodeKey is the static Noise key pair of the node
+#[derive(Clone)]
+pub struct NodeKey {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl NodeKey {
+    pub fn generate() -> Result<NodeKey> {
+        let keypair = Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+        Ok(NodeKey {
+            private: keypair.private,
+            public: keypair.public,
+        })
+    }
+
+    /// LoadOrCreate reads the node key from data/nodekey, creating it on first use
+    pub fn load_or_create() -> Result<NodeKey> {
+        NodeKey::load_or_create_at(Path::new(NODE_KEY_PATH))
+    }
+
+    /// load_or_create_at reads the node key from `path`, creating it readable by the owner only
+    fn load_or_create_at(path: &Path) -> Result<NodeKey> {
+        if let Ok(data) = std::fs::read(path) {
+            if data.len() != 2 * KEY_LEN {
+                return Err(format_err!("{} is corrupted", path.display()));
+            }
+            // key files written by older versions were world readable
+            #[cfg(unix)]
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(KEY_FILE_MODE))?;
+            return Ok(NodeKey {
+                private: data[..KEY_LEN].to_vec(),
+                public: data[KEY_LEN..].to_vec(),
+            });
+        }
+        let key = NodeKey::generate()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut data = key.private.clone();
+        data.extend(&key.public);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(KEY_FILE_MODE);
+        options.open(path)?.write_all(&data)?;
+        Ok(key)
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+
+    fn builder(&self) -> Result<Builder<'_>> {
+        Ok(Builder::new(NOISE_PARAMS.parse()?).local_private_key(&self.private))
+    }
+}
+
+/// WriteEncrypted sends one message over a fresh connection and returns the
+/// static key of the remote node
+pub fn write_encrypted<S: Read + Write>(
+    stream: &mut S,
+    key: &NodeKey,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    let (mut transport, remote) = initiate(stream, key)?;
+    let mut buf = vec![0u8; MAX_FRAME];
+    let len = transport.write_message(&(data.len() as u64).to_be_bytes(), &mut buf)?;
+    write_frame(stream, &buf[..len])?;
+    for chunk in data.chunks(MAX_FRAME - TAG_LEN) {
+        let len = transport.write_message(chunk, &mut buf)?;
+        write_frame(stream, &buf[..len])?;
+    }
+    Ok(remote)
+}
+
+/// initiate runs the initiator side of the handshake and returns the
+/// transport and the static key of the remote node
+fn initiate<S: Read + Write>(stream: &mut S, key: &NodeKey) -> Result<(TransportState, Vec<u8>)> {
+    stream.write_all(NOISE_PREAMBLE)?;
+    let mut hs = key.builder()?.build_initiator()?;
+    let mut buf = vec![0u8; MAX_FRAME];
+
+    let len = hs.write_message(&[], &mut buf)?;
+    write_frame(stream, &buf[..len])?;
+    hs.read_message(&read_frame(stream)?, &mut buf)?;
+    let len = hs.write_message(&[], &mut buf)?;
+    write_frame(stream, &buf[..len])?;
+    let remote = remote_key(&hs);
+    Ok((hs.into_transport_mode()?, remote))
+}
+
+/// ReadMessage reads one message from a connection, encrypted or not
+///
+/// Returns the message and, for encrypted connections, the static key of
+/// the remote node. Encrypted connections are refused without a node key.
+pub fn read_message<S: Read + Write>(
+    stream: &mut S,
+    key: Option<&NodeKey>,
+) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+    let mut buffer = Vec::new();
+    (&mut *stream)
+        .take(NOISE_PREAMBLE.len() as u64)
+        .read_to_end(&mut buffer)?;
+    if buffer != NOISE_PREAMBLE {
+        (&mut *stream)
+            .take((MAX_MESSAGE_SIZE + 1 - buffer.len()) as u64)
+            .read_to_end(&mut buffer)?;
+        if buffer.len() > MAX_MESSAGE_SIZE {
+            return Err(format_err!("message exceeds {} bytes", MAX_MESSAGE_SIZE));
+        }
+        return Ok((buffer, None));
+    }
+    let key = match key {
+        Some(k) => k,
+        None => return Err(format_err!("encrypted connections are not accepted")),
+    };
+
+    let mut hs = key.builder()?.build_responder()?;
+    let mut buf = vec![0u8; MAX_FRAME];
+    hs.read_message(&read_frame(stream)?, &mut buf)?;
+    let len = hs.write_message(&[], &mut buf)?;
+    write_frame(stream, &buf[..len])?;
+    hs.read_message(&read_frame(stream)?, &mut buf)?;
+    let remote = remote_key(&hs);
+
+    let mut transport = hs.into_transport_mode()?;
+    let len = transport.read_message(&read_frame(stream)?, &mut buf)?;
+    let size = match <[u8; 8]>::try_from(&buf[..len]) {
+        Ok(size) => u64::from_be_bytes(size),
+        Err(_) => return Err(format_err!("malformed message length")),
+    };
+    if size > MAX_MESSAGE_SIZE as u64 {
+        return Err(format_err!("message exceeds {} bytes", MAX_MESSAGE_SIZE));
+    }
+    let mut data = Vec::with_capacity(size as usize);
+    while data.len() < size as usize {
+        let frame = match read_frame_opt(stream)? {
+            Some(frame) => frame,
+            None => {
+                return Err(format_err!(
+                    "connection closed after {} of {} bytes",
+                    data.len(),
+                    size
+                ))
+            }
+        };
+        let len = transport.read_message(&frame, &mut buf)?;
+        data.extend_from_slice(&buf[..len]);
+    }
+    if data.len() != size as usize {
+        return Err(format_err!("message is longer than its {} bytes", size));
+    }
+    Ok((data, Some(remote)))
+}
+
+fn remote_key(hs: &HandshakeState) -> Vec<u8> {
+    hs.get_remote_static()
+        .map(|k| k.to_vec())
+        .unwrap_or_default()
+}
+
+fn write_frame<W: Write>(stream: &mut W, frame: &[u8]) -> Result<()> {
+    stream.write_all(&(frame.len() as u16).to_be_bytes())?;
+    stream.write_all(frame)?;
+    Ok(())
+}
+
+fn read_frame<R: Read>(stream: &mut R) -> Result<Vec<u8>> {
+    match read_frame_opt(stream)? {
+        Some(frame) => Ok(frame),
+        None => Err(format_err!("connection closed before the message")),
+    }
+}
+
+fn read_frame_opt<R: Read>(stream: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 2];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut frame = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn test_encrypted_transport() {
+        let server_key = NodeKey::generate().unwrap();
+        let client_key = NodeKey::generate().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let key = server_key.clone();
+        let handle = thread::spawn(move || {
+            let mut received = Vec::new();
+            for stream in listener.incoming().take(4) {
+                let mut stream = stream.unwrap();
+                received.push(read_message(&mut stream, Some(&key)));
+            }
+            received
+        });
+
+        let message: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let remote = write_encrypted(&mut stream, &client_key, &message).unwrap();
+        assert_eq!(remote, server_key.public_key());
+        drop(stream);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"version\0\0\0\0\0plain").unwrap();
+        drop(stream);
+
+        // a peer that is not a Noise responder does not get the plaintext
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(NOISE_PREAMBLE).unwrap();
+        stream.write_all(&[0, 3, 1, 2, 3]).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        drop(stream);
+
+        // a message cut short is refused, not delivered shorter
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let (mut transport, _) = initiate(&mut stream, &client_key).unwrap();
+        let mut buf = vec![0u8; MAX_FRAME];
+        let len = transport
+            .write_message(&100u64.to_be_bytes(), &mut buf)
+            .unwrap();
+        write_frame(&mut stream, &buf[..len]).unwrap();
+        let len = transport.write_message(&message[..10], &mut buf).unwrap();
+        write_frame(&mut stream, &buf[..len]).unwrap();
+        drop(stream);
+
+        let received = handle.join().unwrap();
+        let (data, remote) = received[0].as_ref().unwrap();
+        assert_eq!(data, &message);
+        assert_eq!(remote.as_deref(), Some(client_key.public_key()));
+        let (data, remote) = received[1].as_ref().unwrap();
+        assert_eq!(data, b"version\0\0\0\0\0plain");
+        assert!(remote.is_none());
+        assert!(received[2].is_err());
+        assert!(received[3].is_err());
+    }
+
+    #[test]
+    fn test_node_key_file() {
+        let dir = std::env::temp_dir().join(format!("polytorus-nodekey-{}", std::process::id()));
+        let path = dir.join("nodekey");
+        std::fs::remove_dir_all(&dir).ok();
+        let key = NodeKey::load_or_create_at(&path).unwrap();
+        #[cfg(unix)]
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            KEY_FILE_MODE
+        );
+
+        // a world readable key of an older version is locked down on load
+        #[cfg(unix)]
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(
+            NodeKey::load_or_create_at(&path).unwrap().public_key(),
+            key.public_key()
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            KEY_FILE_MODE
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,344 @@
+//! Transaction builder with coin selection
+//!
+//! `TransactionBuilder` pays one or more addresses from the outputs of a
+//! single key. Which unspent outputs are spent is decided by a
+//! `CoinSelection` strategy, the fee is left unspent by the outputs and
+//! whatever exceeds outputs plus fee goes back to a change address.
+//! A sweep instead spends every unlocked coin to a single address.
+
+use super::*;
+use crate::signer::Signer;
+use crate::transaction::*;
+use crate::utxoset::UTXOSet;
+use crate::wallets::{address_from_pub_key, hash_pub_key};
+use failure::format_err;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// Maximum number of branches branch-and-bound explores before giving up
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// Coin is an unspent output that can be spent by the builder
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coin {
+    pub txid: String,
+    pub vout: i32,
+    pub value: i32,
+}
+
+/// CoinSelection decides which coins pay for a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CoinSelection {
+    /// Spend the biggest coins first, fewest inputs
+    #[default]
+    LargestFirst,
+    /// Look for coins adding up exactly to the target so no change is
+    /// needed, falling back to largest-first
+    BranchAndBound,
+}
+
+impl FromStr for CoinSelection {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<CoinSelection> {
+        match s {
+            "largest" => Ok(CoinSelection::LargestFirst),
+            "bnb" => Ok(CoinSelection::BranchAndBound),
+            _ => Err(format_err!(
+                "unknown coin selection {}, expected largest or bnb",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CoinSelection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CoinSelection::LargestFirst => write!(f, "largest"),
+            CoinSelection::BranchAndBound => write!(f, "bnb"),
+        }
+    }
+}
+
+/// SelectCoins picks coins worth at least `target`, None if they are not enough
+pub fn select_coins(strategy: CoinSelection, coins: &[Coin], target: i32) -> Option<Vec<Coin>> {
+    let mut sorted = coins.to_vec();
+    sorted.sort_by(|a, b| {
+        b.value
+            .cmp(&a.value)
+            .then(a.txid.cmp(&b.txid))
+            .then(a.vout.cmp(&b.vout))
+    });
+    if strategy == CoinSelection::BranchAndBound {
+        if let Some(exact) = branch_and_bound(&sorted, target) {
+            return Some(exact);
+        }
+    }
+    largest_first(&sorted, target)
+}
+
+fn largest_first(sorted: &[Coin], target: i32) -> Option<Vec<Coin>> {
+    let mut selected = Vec::new();
+    let mut total = 0;
+    for coin in sorted {
+        if total >= target {
+            break;
+        }
+        total += coin.value;
+        selected.push(coin.clone());
+    }
+    if total >= target {
+        Some(selected)
+    } else {
+        None
+    }
+}
+
+/// branch_and_bound searches the coins, biggest first, for a subset worth exactly `target`
+fn branch_and_bound(sorted: &[Coin], target: i32) -> Option<Vec<Coin>> {
+    // remaining[i] is the value of sorted[i..]
+    let mut remaining = vec![0; sorted.len() + 1];
+    for i in (0..sorted.len()).rev() {
+        remaining[i] = remaining[i + 1] + sorted[i].value;
+    }
+    let mut chosen = Vec::new();
+    let mut tries = 0;
+    if bnb_step(sorted, &remaining, 0, target, &mut chosen, &mut tries) {
+        Some(chosen.iter().map(|&i| sorted[i].clone()).collect())
+    } else {
+        None
+    }
+}
+
+fn bnb_step(
+    sorted: &[Coin],
+    remaining: &[i32],
+    index: usize,
+    left: i32,
+    chosen: &mut Vec<usize>,
+    tries: &mut usize,
+) -> bool {
+    if left == 0 {
+        return true;
+    }
+    *tries += 1;
+    if index == sorted.len() || left < 0 || remaining[index] < left || *tries > BNB_MAX_TRIES {
+        return false;
+    }
+    chosen.push(index);
+    if bnb_step(
+        sorted,
+        remaining,
+        index + 1,
+        left - sorted[index].value,
+        chosen,
+        tries,
+    ) {
+        return true;
+    }
+    chosen.pop();
+    bnb_step(sorted, remaining, index + 1, left, chosen, tries)
+}
+
+/// TransactionBuilder builds and signs a transaction spending the coins of one key
+pub struct TransactionBuilder<'a> {
+    signer: &'a dyn Signer,
+    outputs: Vec<(String, i32)>,
+    fee: i32,
+    coin_selection: CoinSelection,
+    change_address: Option<String>,
+    confirmed: bool,
+    locked: HashSet<(String, i32)>,
+    sweep: Option<String>,
+}
+
+impl<'a> TransactionBuilder<'a> {
+    pub fn new(signer: &'a dyn Signer) -> TransactionBuilder<'a> {
+        TransactionBuilder {
+            signer,
+            outputs: Vec::new(),
+            fee: 0,
+            coin_selection: CoinSelection::default(),
+            change_address: None,
+            confirmed: false,
+            locked: HashSet::new(),
+            sweep: None,
+        }
+    }
+
+    /// PayTo adds an output paying `amount` to `address`
+    pub fn pay_to(mut self, address: &str, amount: i32) -> Self {
+        self.outputs.push((address.to_string(), amount));
+        self
+    }
+
+    /// Fee sets how much the inputs exceed the outputs
+    pub fn fee(mut self, fee: i32) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    pub fn coin_selection(mut self, coin_selection: CoinSelection) -> Self {
+        self.coin_selection = coin_selection;
+        self
+    }
+
+    /// ChangeAddress sets where change goes, the sender by default
+    pub fn change_address(mut self, address: &str) -> Self {
+        self.change_address = Some(address.to_string());
+        self
+    }
+
+    /// Confirmed approves a spend the key policy wants confirmed
+    pub fn confirmed(mut self, confirmed: bool) -> Self {
+        self.confirmed = confirmed;
+        self
+    }
+
+    /// SkipLocked keeps the locked outputs, given as txid and vout, out of coin selection
+    pub fn skip_locked(mut self, locked: HashSet<(String, i32)>) -> Self {
+        self.locked = locked;
+        self
+    }
+
+    /// SweepTo spends every unlocked coin to `address`, less the fee
+    pub fn sweep_to(mut self, address: &str) -> Self {
+        self.sweep = Some(address.to_string());
+        self
+    }
+
+    /// Build selects the coins, adds change and signs the transaction
+    pub fn build(mut self, utxo: &UTXOSet) -> Result<Transaction> {
+        if self.sweep.is_some() && !self.outputs.is_empty() {
+            return Err(format_err!("a sweep pays a single address"));
+        }
+        if self.outputs.is_empty() && self.sweep.is_none() {
+            return Err(format_err!("transaction has no outputs"));
+        }
+        if self.outputs.iter().any(|(_, amount)| *amount <= 0) {
+            return Err(format_err!("output amounts must be positive"));
+        }
+        if self.fee < 0 {
+            return Err(format_err!("fee must not be negative"));
+        }
+
+        let public_key = self.signer.public_key()?;
+        let from = address_from_pub_key(&public_key);
+        let mut pub_key_hash = public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+
+        let (locked, coins): (Vec<Coin>, Vec<Coin>) = utxo
+            .find_coins(&pub_key_hash)?
+            .into_iter()
+            .partition(|c| self.locked.contains(&(c.txid.clone(), c.vout)));
+        if !locked.is_empty() {
+            info!("skip {} locked coins", locked.len());
+        }
+        if let Some(address) = self.sweep.take() {
+            let balance = coins.iter().map(|c| c.value).sum::<i32>();
+            if balance <= self.fee {
+                return Err(format_err!(
+                    "nothing to sweep: balance {} does not cover the fee {}",
+                    balance,
+                    self.fee
+                ));
+            }
+            self.outputs.push((address, balance - self.fee));
+        }
+        let target = self.outputs.iter().map(|(_, amount)| amount).sum::<i32>() + self.fee;
+        let selected = match select_coins(self.coin_selection, &coins, target) {
+            Some(s) => s,
+            None => {
+                error!("Not Enough balance");
+                return Err(format_err!(
+                    "Not Enough balance: current balance {}, locked {}",
+                    coins.iter().map(|c| c.value).sum::<i32>(),
+                    locked.iter().map(|c| c.value).sum::<i32>()
+                ));
+            }
+        };
+        debug!(
+            "selected {} coins with {}",
+            selected.len(),
+            self.coin_selection
+        );
+
+        let vin = selected
+            .iter()
+            .map(|coin| TXInput {
+                txid: coin.txid.clone(),
+                vout: coin.vout,
+                signature: Vec::new(),
+                pub_key: public_key.clone(),
+            })
+            .collect();
+        let mut vout = Vec::new();
+        for (address, amount) in self.outputs {
+            vout.push(TXOutput::new(amount, address)?);
+        }
+        let change = selected.iter().map(|c| c.value).sum::<i32>() - target;
+        if change > 0 {
+            vout.push(TXOutput::new(change, self.change_address.unwrap_or(from))?);
+        }
+
+        let mut tx = Transaction {
+            id: String::new(),
+            vin,
+            vout,
+        };
+        tx.id = tx.hash()?;
+        self.signer
+            .authorize(&tx, &utxo.blockchain.chain_id()?, self.confirmed)?;
+        utxo.blockchain.sign_transacton(&mut tx, self.signer)?;
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn coins(values: &[i32]) -> Vec<Coin> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| Coin {
+                txid: format!("tx{}", i),
+                vout: 0,
+                value,
+            })
+            .collect()
+    }
+
+    fn values(coins: Option<Vec<Coin>>) -> Vec<i32> {
+        coins.unwrap().iter().map(|c| c.value).collect()
+    }
+
+    #[test]
+    fn test_coin_selection() {
+        let available = coins(&[3, 10, 4, 6]);
+        assert_eq!(
+            values(select_coins(CoinSelection::LargestFirst, &available, 13)),
+            vec![10, 6]
+        );
+        assert_eq!(
+            values(select_coins(CoinSelection::BranchAndBound, &available, 13)),
+            vec![10, 3]
+        );
+        // no exact match, fall back to largest first
+        assert_eq!(
+            values(select_coins(CoinSelection::BranchAndBound, &available, 22)),
+            vec![10, 6, 4, 3]
+        );
+        assert!(select_coins(CoinSelection::BranchAndBound, &available, 24).is_none());
+        assert!(select_coins(CoinSelection::LargestFirst, &[], 1).is_none());
+
+        assert_eq!(
+            "bnb".parse::<CoinSelection>().unwrap(),
+            CoinSelection::BranchAndBound
+        );
+        assert!("smallest".parse::<CoinSelection>().is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! Transaction and address index
+//!
+//! Secondary sled trees next to the blocks map every transaction of the
+//! best chain to its block, and every address to the transactions paying
+//! or spending it, so history queries do not scan the chain. The index is
+//! brought up to the tip incrementally before each query: blocks of the
+//! new branch are added and blocks of an abandoned branch are removed.
+
+use super::*;
+use crate::block::*;
+use crate::blockchain::*;
+use crate::transaction::*;
+use crate::wallets::hash_pub_key;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use sled::Tree;
+
+const TIP_KEY: &str = "TIP";
+
+/// TxLocation is where a transaction is in the best chain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxLocation {
+    pub txid: String,
+    pub block: String,
+    pub height: i32,
+}
+
+/// TxIndex maps transactions to blocks and addresses to transactions
+pub struct TxIndex {
+    /// txid -> block hash
+    tx_block: Tree,
+    /// public key hash ++ height ++ txid -> block hash
+    addr_txs: Tree,
+    /// indexed best chain blocks: block hash -> height, and the indexed tip
+    blocks: Tree,
+}
+
+impl TxIndex {
+    pub fn open(bc: &Blockchain) -> Result<TxIndex> {
+        Ok(TxIndex {
+            tx_block: bc.db.open_tree("tx_block")?,
+            addr_txs: bc.db.open_tree("addr_txs")?,
+            blocks: bc.db.open_tree("indexed_blocks")?,
+        })
+    }
+
+    /// Sync brings the index to the tip of the chain and returns the number of blocks indexed
+    pub fn sync(&self, bc: &Blockchain) -> Result<usize> {
+        let mut branch = Vec::new();
+        let mut hash = bc.tip.clone();
+        while !hash.is_empty() && !self.blocks.contains_key(&hash)? {
+            let block = bc.get_block(&hash)?;
+            hash = block.get_prev_hash();
+            branch.push(block);
+        }
+        // hash is now the fork point, unindex what the index has above it
+        let mut old = self.indexed_tip()?;
+        while !old.is_empty() && old != hash {
+            let block = bc.get_block(&old)?;
+            self.apply(&block, false)?;
+            old = block.get_prev_hash();
+        }
+        let count = branch.len();
+        for block in branch.iter().rev() {
+            self.apply(block, true)?;
+        }
+        self.blocks.insert(TIP_KEY, bc.tip.as_bytes())?;
+        Ok(count)
+    }
+
+    /// FindTransaction returns the location of a transaction of the best chain
+    pub fn find_transaction(&self, bc: &Blockchain, txid: &str) -> Result<Option<TxLocation>> {
+        let block = match self.tx_block.get(txid)? {
+            Some(b) => String::from_utf8(b.to_vec())?,
+            None => return Ok(None),
+        };
+        let height = bc.get_block(&block)?.get_height();
+        Ok(Some(TxLocation {
+            txid: txid.to_string(),
+            block,
+            height,
+        }))
+    }
+
+    /// AddressTransactions returns the transactions paying or spending an address, oldest first
+    pub fn address_transactions(&self, pub_key_hash: &[u8]) -> Result<Vec<TxLocation>> {
+        let mut txs = Vec::new();
+        for kv in self.addr_txs.scan_prefix(pub_key_hash) {
+            let (key, block) = kv?;
+            if key.len() < pub_key_hash.len() + 4 {
+                return Err(format_err!("corrupted address index"));
+            }
+            let rest = &key[pub_key_hash.len()..];
+            let height = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as i32;
+            let txid = String::from_utf8(rest[4..].to_vec())?;
+            let block = String::from_utf8(block.to_vec())?;
+            txs.push(TxLocation {
+                txid,
+                block,
+                height,
+            });
+        }
+        Ok(txs)
+    }
+
+    fn indexed_tip(&self) -> Result<String> {
+        match self.blocks.get(TIP_KEY)? {
+            Some(tip) => Ok(String::from_utf8(tip.to_vec())?),
+            None => Ok(String::new()),
+        }
+    }
+
+    /// apply adds a block to the index or removes it
+    fn apply(&self, block: &Block, add: bool) -> Result<()> {
+        let hash = block.get_hash();
+        let height = (block.get_height() as u32).to_be_bytes();
+        for tx in block.get_transaction() {
+            for pub_key_hash in addresses(tx) {
+                let mut key = pub_key_hash;
+                key.extend_from_slice(&height);
+                key.extend_from_slice(tx.id.as_bytes());
+                if add {
+                    self.addr_txs.insert(key, hash.as_bytes())?;
+                } else {
+                    self.addr_txs.remove(key)?;
+                }
+            }
+            if add {
+                self.tx_block.insert(&tx.id, hash.as_bytes())?;
+            } else {
+                self.tx_block.remove(&tx.id)?;
+            }
+        }
+        if add {
+            self.blocks.insert(&hash, &height)?;
+        } else {
+            self.blocks.remove(&hash)?;
+        }
+        Ok(())
+    }
+}
+
+/// addresses returns the public key hashes a transaction pays or spends from
+fn addresses(tx: &Transaction) -> Vec<Vec<u8>> {
+    let mut hashes: Vec<Vec<u8>> = tx.vout.iter().map(|out| out.pub_key_hash.clone()).collect();
+    if !tx.is_coinbase() && !tx.is_system() {
+        for vin in &tx.vin {
+            let mut pub_key_hash = vin.pub_key.clone();
+            hash_pub_key(&mut pub_key_hash);
+            hashes.push(pub_key_hash);
+        }
+    }
+    hashes.sort();
+    hashes.dedup();
+    hashes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoincash_addr::Address;
+
+    const ADDRESS: &str = "3K2B53BeZoaSPBGxihwPkbjNxrLkapfYrn";
+
+    fn block(bc: &Blockchain, prev: &Block, data: &str) -> Block {
+        let cbtx = Transaction::new_coinbase(String::from(ADDRESS), data.to_string()).unwrap();
+        let chain_id = bc.chain_id().unwrap();
+        Block::new_block(
+            vec![cbtx],
+            prev.get_hash(),
+            &chain_id,
+            prev.get_height() + 1,
+            MIN_TARGET_BITS,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_tx_index() {
+        let cbtx =
+            Transaction::new_coinbase(String::from(ADDRESS), String::from("genesis")).unwrap();
+        let genesis = Block::new_genesis_block(cbtx);
+        let mut bc = Blockchain::temporary(&genesis).unwrap();
+        let b1 = block(&bc, &genesis, "b1");
+        let b2 = block(&bc, &b1, "b2");
+        bc.add_block(b1.clone()).unwrap();
+        bc.add_block(b2.clone()).unwrap();
+        // a block must carry the target its parent asks for
+        assert_eq!(bc.next_target(&b2.get_hash()).unwrap(), MIN_TARGET_BITS);
+        let cbtx = Transaction::new_coinbase(String::from(ADDRESS), String::from("hard")).unwrap();
+        let hard = Block::new_block(
+            vec![cbtx],
+            b2.get_hash(),
+            &bc.chain_id().unwrap(),
+            3,
+            MIN_TARGET_BITS + 1,
+        )
+        .unwrap();
+        assert!(bc.add_block(hard).is_err());
+
+        let index = TxIndex::open(&bc).unwrap();
+        assert_eq!(index.sync(&bc).unwrap(), 3);
+        assert_eq!(index.sync(&bc).unwrap(), 0);
+        let tx = &b2.get_transaction()[0].id;
+        let location = index.find_transaction(&bc, tx).unwrap().unwrap();
+        assert_eq!(
+            (location.block.as_str(), location.height),
+            (b2.get_hash().as_str(), 2)
+        );
+        let pub_key_hash = Address::decode(ADDRESS).unwrap().body;
+        let heights: Vec<i32> = index
+            .address_transactions(&pub_key_hash)
+            .unwrap()
+            .iter()
+            .map(|l| l.height)
+            .collect();
+        assert_eq!(heights, vec![0, 1, 2]);
+
+        // a longer branch from b1 replaces b2
+        let c2 = block(&bc, &b1, "c2");
+        let c3 = block(&bc, &c2, "c3");
+        bc.add_block(c2).unwrap();
+        bc.add_block(c3.clone()).unwrap();
+        assert_eq!(index.sync(&bc).unwrap(), 2);
+        assert!(index.find_transaction(&bc, tx).unwrap().is_none());
+        let history = index.address_transactions(&pub_key_hash).unwrap();
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[3].block, c3.get_hash());
+    }
+}
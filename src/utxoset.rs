@@ -2,10 +2,14 @@ use super::*;
 use crate::block::*;
 use crate::blockchain::*;
 use crate::transaction::*;
+use crate::txbuilder::Coin;
 use bincode::{deserialize, serialize};
-use sled;
 use std::collections::HashMap;
 
+/// Serializes the tests sharing the data/utxos database
+#[cfg(test)]
+pub static TEST_DB_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
 /// UTXOSet represents UTXO set
 pub struct UTXOSet {
     pub blockchain: Blockchain,
@@ -25,7 +29,7 @@ impl UTXOSet {
         for kv in db.iter() {
             let (k, v) = kv?;
             let txid = String::from_utf8(k.to_vec())?;
-            let outs: TXOutputs = deserialize(&v.to_vec())?;
+            let outs: TXOutputs = deserialize(&v)?;
 
             for out_idx in 0..outs.outputs.len() {
                 if outs.outputs[out_idx].is_locked_with_key(pub_key_hash) && accumulated < amount {
@@ -43,6 +47,28 @@ impl UTXOSet {
         Ok((accumulated, unspent_outputs))
     }
 
+    /// FindCoins returns every unspent output locked to a public key hash
+    pub fn find_coins(&self, pub_key_hash: &[u8]) -> Result<Vec<Coin>> {
+        let mut coins = Vec::new();
+        let db = sled::open("data/utxos")?;
+        for kv in db.iter() {
+            let (k, v) = kv?;
+            let txid = String::from_utf8(k.to_vec())?;
+            let outs: TXOutputs = deserialize(&v)?;
+
+            for (out_idx, out) in outs.outputs.iter().enumerate() {
+                if out.is_locked_with_key(pub_key_hash) {
+                    coins.push(Coin {
+                        txid: txid.clone(),
+                        vout: out_idx as i32,
+                        value: out.value,
+                    });
+                }
+            }
+        }
+        Ok(coins)
+    }
+
     /// FindUTXO finds UTXO for a public key hash
     pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
         let mut utxos = TXOutputs {
@@ -52,7 +78,7 @@ impl UTXOSet {
 
         for kv in db.iter() {
             let (_, v) = kv?;
-            let outs: TXOutputs = deserialize(&v.to_vec())?;
+            let outs: TXOutputs = deserialize(&v)?;
 
             for out in outs.outputs {
                 if out.is_locked_with_key(pub_key_hash) {
@@ -75,6 +101,30 @@ impl UTXOSet {
         Ok(counter)
     }
 
+    /// Entries returns every (txid, outputs) pair of the UTXO set ordered by txid
+    pub fn entries(&self) -> Result<Vec<(String, TXOutputs)>> {
+        let mut entries = Vec::new();
+        let db = sled::open("data/utxos")?;
+        for kv in db.iter() {
+            let (k, v) = kv?;
+            entries.push((String::from_utf8(k.to_vec())?, deserialize(&v)?));
+        }
+        Ok(entries)
+    }
+
+    /// LoadEntries replaces the UTXO set with the given entries
+    pub fn load_entries(&self, entries: Vec<(String, TXOutputs)>) -> Result<()> {
+        std::fs::remove_dir_all("data/utxos").ok();
+        let db = sled::open("data/utxos")?;
+
+        for (txid, outs) in entries {
+            db.insert(txid.as_bytes(), serialize(&outs)?)?;
+        }
+
+        db.flush()?;
+        Ok(())
+    }
+
     /// Reindex rebuilds the UTXO set
     pub fn reindex(&self) -> Result<()> {
         std::fs::remove_dir_all("data/utxos").ok();
@@ -96,12 +146,12 @@ impl UTXOSet {
         let db = sled::open("data/utxos")?;
 
         for tx in block.get_transaction() {
-            if !tx.is_coinbase() {
+            if !tx.is_coinbase() && !tx.is_system() {
                 for vin in &tx.vin {
                     let mut update_outputs = TXOutputs {
                         outputs: Vec::new(),
                     };
-                    let outs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
+                    let outs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap())?;
                     for out_idx in 0..outs.outputs.len() {
                         if out_idx != vin.vout as usize {
                             update_outputs.outputs.push(outs.outputs[out_idx].clone());
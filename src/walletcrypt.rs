@@ -0,0 +1,131 @@
+//! Password based encryption of wallet files
+//!
+//! The wallet key is derived from the password with Argon2id and every
+//! stored value is sealed with XChaCha20-Poly1305 under a fresh random nonce.
+//! The KDF parameters and a sealed check value are stored next to the
+//! wallets, so a wrong password is detected before any wallet is decrypted.
+
+use super::*;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use failure::format_err;
+use rand::RngCore;
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+
+const NONCE_LEN: usize = 24;
+const CHECK_VALUE: &[u8] = b"polytorus wallet";
+
+/// KdfParams are the Argon2id parameters the wallet key was derived with
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KdfParams {
+    pub salt: [u8; 16],
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub check: Vec<u8>,
+}
+
+/// WalletKey seals and opens wallet records
+pub struct WalletKey {
+    cipher: XChaCha20Poly1305,
+    params: KdfParams,
+}
+
+impl WalletKey {
+    /// NewWalletKey derives a key from the password with a fresh salt
+    pub fn new(password: &str) -> Result<WalletKey> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut params = KdfParams {
+            salt,
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+            check: Vec::new(),
+        };
+        let cipher = derive_cipher(password, &params)?;
+        let mut key = WalletKey {
+            cipher,
+            params: params.clone(),
+        };
+        params.check = key.seal(CHECK_VALUE)?;
+        key.params = params;
+        Ok(key)
+    }
+
+    /// Unlock derives the key for stored parameters and checks the password
+    pub fn unlock(password: &str, params: &KdfParams) -> Result<WalletKey> {
+        let key = WalletKey {
+            cipher: derive_cipher(password, params)?,
+            params: params.clone(),
+        };
+        match key.open(&params.check) {
+            Ok(v) if v == CHECK_VALUE => Ok(key),
+            _ => Err(format_err!("wrong wallet password")),
+        }
+    }
+
+    pub fn params(&self) -> &KdfParams {
+        &self.params
+    }
+
+    /// Seal encrypts the plaintext, the output is nonce || ciphertext
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = match self.cipher.encrypt(&nonce, plaintext) {
+            Ok(c) => c,
+            Err(_) => return Err(format_err!("failed to encrypt wallet data")),
+        };
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    /// Open decrypts and authenticates a sealed value
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(format_err!("sealed wallet data is truncated"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        match self.cipher.decrypt(XNonce::from_slice(nonce), ciphertext) {
+            Ok(p) => Ok(p),
+            Err(_) => Err(format_err!("failed to decrypt wallet data")),
+        }
+    }
+}
+
+fn derive_cipher(password: &str, params: &KdfParams) -> Result<XChaCha20Poly1305> {
+    let argon_params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))?;
+    let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params);
+    let mut key = [0u8; 32];
+    argon.hash_password_into(password.as_bytes(), &params.salt, &mut key)?;
+    match XChaCha20Poly1305::new_from_slice(&key) {
+        Ok(c) => Ok(c),
+        Err(_) => Err(format_err!("invalid wallet key length")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let key = WalletKey::new("correct horse").unwrap();
+        let sealed = key.seal(b"secret key").unwrap();
+        assert_ne!(&sealed[NONCE_LEN..], b"secret key");
+        assert_eq!(key.open(&sealed).unwrap(), b"secret key");
+        assert_ne!(key.seal(b"secret key").unwrap(), sealed);
+
+        let unlocked = WalletKey::unlock("correct horse", key.params()).unwrap();
+        assert_eq!(unlocked.open(&sealed).unwrap(), b"secret key");
+        assert!(WalletKey::unlock("wrong horse", key.params()).is_err());
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(key.open(&tampered).is_err());
+        assert!(key.open(&sealed[..10]).is_err());
+    }
+}
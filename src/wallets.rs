@@ -1,17 +1,19 @@
 use super::*;
+use crate::address::legacy_address;
+use crate::hdwallet::*;
+use crate::walletcrypt::*;
 use bincode::{deserialize, serialize};
-use bitcoincash_addr::*;
 use crypto::digest::Digest;
 use crypto::ripemd160::Ripemd160;
 use crypto::sha2::Sha256;
+use failure::format_err;
 use fn_dsa::{
-    sign_key_size, vrfy_key_size, KeyPairGenerator, KeyPairGeneratorStandard,
-    FN_DSA_LOGN_512, 
+    sign_key_size, vrfy_key_size, KeyPairGenerator, KeyPairGeneratorStandard, FN_DSA_LOGN_512,
 };
-use rand_core::OsRng;
+use rand_core::{CryptoRng, OsRng, RngCore};
 use serde::{Deserialize, Serialize};
-use sled;
 use std::collections::HashMap;
+use std::time::SystemTime;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Wallet {
@@ -22,10 +24,15 @@ pub struct Wallet {
 impl Wallet {
     /// NewWallet creates and returns a Wallet
     fn new() -> Self {
+        Wallet::from_rng(&mut OsRng)
+    }
+
+    /// FromRng creates a Wallet from the key pair generated with `rng`
+    pub fn from_rng<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
         let mut kg = KeyPairGeneratorStandard::default();
         let mut sign_key = [0u8; sign_key_size(FN_DSA_LOGN_512)];
         let mut vrfy_key = [0u8; vrfy_key_size(FN_DSA_LOGN_512)];
-        kg.keygen(FN_DSA_LOGN_512, &mut OsRng, &mut sign_key, &mut vrfy_key);
+        kg.keygen(FN_DSA_LOGN_512, rng, &mut sign_key, &mut vrfy_key);
 
         Wallet {
             secret_key: sign_key.to_vec(),
@@ -35,22 +42,25 @@ impl Wallet {
 
     /// GetAddress returns wallet address
     pub fn get_address(&self) -> String {
-        let mut pub_hash: Vec<u8> = self.public_key.clone();
-        hash_pub_key(&mut pub_hash);
-        let address = Address {
-            body: pub_hash,
-            scheme: Scheme::Base58,
-            hash_type: HashType::Script,
-            ..Default::default()
-        };
-        address.encode().unwrap()
+        address_from_pub_key(&self.public_key)
     }
 }
 
+/// AddressFromPubKey encodes the address owning the public key
+pub fn address_from_pub_key(pub_key: &[u8]) -> String {
+    let mut pub_hash: Vec<u8> = pub_key.to_vec();
+    hash_pub_key(&mut pub_hash);
+    legacy_address(&pub_hash)
+}
+
 /// HashPubKey hashes public key
 pub fn hash_pub_key(pubKey: &mut Vec<u8>) {
     let mut hasher1 = Sha256::new();
     hasher1.input(pubKey);
+    // the digest overwrites the start of the key, short keys are padded for it
+    if pubKey.len() < 32 {
+        pubKey.resize(32, 0);
+    }
     hasher1.result(pubKey);
     let mut hasher2 = Ripemd160::new();
     hasher2.input(pubKey);
@@ -58,8 +68,30 @@ pub fn hash_pub_key(pubKey: &mut Vec<u8>) {
     hasher2.result(pubKey);
 }
 
+/// Rotation records that the key of an address was replaced by a new one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rotation {
+    pub from: String,
+    pub to: String,
+    /// milliseconds since the UNIX epoch
+    pub timestamp: u128,
+}
+
+/// Wallets is the set of key pairs stored under data/wallets
+///
+/// Encrypted wallet files are loaded locked: addresses can be listed, but the
+/// key pairs stay sealed until `unlock` is called with the password.
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
+    hd_seed: Option<Vec<u8>>,
+    hd_next_index: u32,
+    sealed: HashMap<String, Vec<u8>>,
+    sealed_seed: Option<Vec<u8>>,
+    kdf: Option<KdfParams>,
+    key: Option<WalletKey>,
+    rewrite: bool,
+    /// rotations by the deprecated address
+    rotations: HashMap<String, Rotation>,
 }
 
 impl Wallets {
@@ -67,19 +99,89 @@ impl Wallets {
     pub fn new() -> Result<Wallets> {
         let mut wlt = Wallets {
             wallets: HashMap::<String, Wallet>::new(),
+            hd_seed: None,
+            hd_next_index: 0,
+            sealed: HashMap::new(),
+            sealed_seed: None,
+            kdf: None,
+            key: None,
+            rewrite: false,
+            rotations: HashMap::new(),
         };
         let db = sled::open("data/wallets")?;
 
+        if let Some(kdf) = db.open_tree("crypto")?.get("KDF")? {
+            wlt.kdf = Some(deserialize(&kdf)?);
+        }
+
+        let hd = db.open_tree("hd")?;
+        let seed = hd.get("SEED")?.map(|s| s.to_vec());
+        if let Some(next) = hd.get("NEXT")? {
+            wlt.hd_next_index = deserialize(&next)?;
+        }
+
+        for item in db.open_tree("rotations")?.iter() {
+            let rotation: Rotation = deserialize(&item?.1)?;
+            wlt.rotations.insert(rotation.from.clone(), rotation);
+        }
+
         for item in db.into_iter() {
             let i = item?;
             let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = deserialize(&i.1.to_vec())?;
-            wlt.wallets.insert(address, wallet);
+            if wlt.kdf.is_some() {
+                wlt.sealed.insert(address, i.1.to_vec());
+            } else {
+                let wallet = deserialize(&i.1)?;
+                wlt.wallets.insert(address, wallet);
+            }
+        }
+        if wlt.kdf.is_some() {
+            wlt.sealed_seed = seed;
+        } else {
+            wlt.hd_seed = seed;
         }
         drop(db);
         Ok(wlt)
     }
 
+    /// IsLocked reports whether the wallets are encrypted and not unlocked yet
+    pub fn is_locked(&self) -> bool {
+        self.kdf.is_some() && self.key.is_none()
+    }
+
+    /// Unlock decrypts the stored wallets with the password
+    pub fn unlock(&mut self, password: &str) -> Result<()> {
+        let kdf = match &self.kdf {
+            Some(kdf) => kdf,
+            None => return Ok(()),
+        };
+        let key = WalletKey::unlock(password, kdf)?;
+        for (address, sealed) in self.sealed.drain() {
+            let wallet = deserialize(&key.open(&sealed)?)?;
+            self.wallets.insert(address, wallet);
+        }
+        if let Some(seed) = self.sealed_seed.take() {
+            self.hd_seed = Some(key.open(&seed)?);
+        }
+        self.key = Some(key);
+        Ok(())
+    }
+
+    /// Encrypt protects the wallets with a new password on the next save
+    ///
+    /// Plaintext wallet files are migrated by rewriting them from scratch, so
+    /// no unencrypted key material is left behind in the old database files.
+    pub fn encrypt(&mut self, password: &str) -> Result<()> {
+        if self.is_locked() {
+            return Err(format_err!("wallets are locked, unlock them first"));
+        }
+        let key = WalletKey::new(password)?;
+        self.kdf = Some(key.params().clone());
+        self.key = Some(key);
+        self.rewrite = true;
+        Ok(())
+    }
+
     /// CreateWallet adds a Wallet to Wallets
     pub fn create_wallet(&mut self) -> String {
         let wallet = Wallet::new();
@@ -89,10 +191,115 @@ impl Wallets {
         address
     }
 
+    /// InitHD generates a new mnemonic and uses its seed for HD wallets
+    ///
+    /// The returned phrase is not stored, it has to be written down by the
+    /// user together with the optional BIP-39 `passphrase`.
+    pub fn init_hd(&mut self, passphrase: &str) -> Result<String> {
+        let mnemonic = generate_mnemonic()?;
+        self.hd_seed = Some(mnemonic.to_seed(passphrase).to_vec());
+        self.hd_next_index = 0;
+        Ok(mnemonic.to_string())
+    }
+
+    /// RestoreHD recovers the HD seed from a mnemonic and derives its first wallet
+    ///
+    /// An existing seed is only replaced with `force`, which starts the
+    /// derivation over. Wallets derived from the old seed keep their keys but
+    /// can no longer be derived again. Restoring the seed already in use
+    /// keeps the derivation state.
+    pub fn restore_hd(&mut self, phrase: &str, passphrase: &str, force: bool) -> Result<String> {
+        let seed = mnemonic_to_seed(phrase, passphrase)?;
+        match &self.hd_seed {
+            Some(old) if *old == seed => {
+                return self.derive_wallet(&DerivationPath::default_for(0))
+            }
+            Some(_) if !force => {
+                return Err(format_err!(
+                    "wallets already have an HD seed, replacing it needs --force"
+                ))
+            }
+            _ => {}
+        }
+        self.hd_seed = Some(seed);
+        self.hd_next_index = 0;
+        self.create_hd_wallet()
+    }
+
+    /// HasHDSeed reports whether HD wallets can be derived
+    pub fn has_hd_seed(&self) -> bool {
+        self.hd_seed.is_some()
+    }
+
+    /// CreateHDWallet derives the next wallet on the default path
+    pub fn create_hd_wallet(&mut self) -> Result<String> {
+        let path = DerivationPath::default_for(self.hd_next_index);
+        let address = self.derive_wallet(&path)?;
+        self.hd_next_index += 1;
+        Ok(address)
+    }
+
+    /// DeriveWallet adds the wallet derived at `path` to Wallets
+    pub fn derive_wallet(&mut self, path: &DerivationPath) -> Result<String> {
+        let seed = match &self.hd_seed {
+            Some(s) => s,
+            None => {
+                return Err(format_err!(
+                    "no HD seed, create or restore a mnemonic first"
+                ))
+            }
+        };
+        let wallet = derive_wallet(seed, path);
+        let address = wallet.get_address();
+        self.wallets.insert(address.clone(), wallet);
+        info!("derive wallet {}: {}", path, address);
+        Ok(address)
+    }
+
+    /// Rekey replaces the key of `address` with a new one and returns its address
+    ///
+    /// The new key is derived from the HD seed if there is one. The old key
+    /// is kept to sign the sweep of its outputs, its address is deprecated.
+    pub fn rekey(&mut self, address: &str) -> Result<String> {
+        if self.is_locked() {
+            return Err(format_err!("wallets are locked, unlock them first"));
+        }
+        if !self.wallets.contains_key(address) {
+            return Err(format_err!("no wallet for {}", address));
+        }
+        if let Some(rotation) = self.rotations.get(address) {
+            return Err(format_err!(
+                "{} was already rotated to {}",
+                address,
+                rotation.to
+            ));
+        }
+        let to = if self.has_hd_seed() {
+            self.create_hd_wallet()?
+        } else {
+            self.create_wallet()
+        };
+        let rotation = Rotation {
+            from: address.to_string(),
+            to: to.clone(),
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_millis(),
+        };
+        info!("rotate key of {} to {}", address, to);
+        self.rotations.insert(address.to_string(), rotation);
+        Ok(to)
+    }
+
+    /// Rotation returns the rotation that deprecated `address`, if any
+    pub fn rotation(&self, address: &str) -> Option<&Rotation> {
+        self.rotations.get(address)
+    }
+
     /// GetAddresses returns an array of addresses stored in the wallet file
     pub fn get_all_addresses(&self) -> Vec<String> {
         let mut addresses = Vec::<String>::new();
-        for (address, _) in &self.wallets {
+        for address in self.wallets.keys().chain(self.sealed.keys()) {
             addresses.push(address.clone());
         }
         addresses
@@ -105,13 +312,52 @@ impl Wallets {
 
     /// SaveToFile saves wallets to a file
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
+        if self.is_locked() {
+            return Err(format_err!("wallets are locked, unlock them first"));
+        }
+        if !self.rewrite {
+            return self.save_to("data/wallets");
+        }
+
+        std::fs::remove_dir_all("data/wallets.new").ok();
+        self.save_to("data/wallets.new")?;
+        std::fs::remove_dir_all("data/wallets.old").ok();
+        std::fs::rename("data/wallets", "data/wallets.old").ok();
+        std::fs::rename("data/wallets.new", "data/wallets")?;
+        std::fs::remove_dir_all("data/wallets.old").ok();
+        Ok(())
+    }
+
+    fn save_to(&self, path: &str) -> Result<()> {
+        let db = sled::open(path)?;
 
         for (address, wallet) in &self.wallets {
-            let data = serialize(wallet)?;
+            let mut data = serialize(wallet)?;
+            if let Some(key) = &self.key {
+                data = key.seal(&data)?;
+            }
             db.insert(address, data)?;
         }
 
+        if let Some(seed) = &self.hd_seed {
+            let seed = match &self.key {
+                Some(key) => key.seal(seed)?,
+                None => seed.clone(),
+            };
+            let hd = db.open_tree("hd")?;
+            hd.insert("SEED", seed)?;
+            hd.insert("NEXT", serialize(&self.hd_next_index)?)?;
+        }
+
+        let rotations = db.open_tree("rotations")?;
+        for (address, rotation) in &self.rotations {
+            rotations.insert(address, serialize(rotation)?)?;
+        }
+
+        if let Some(kdf) = &self.kdf {
+            db.open_tree("crypto")?.insert("KDF", serialize(kdf)?)?;
+        }
+
         db.flush()?;
         drop(db);
         Ok(())
@@ -121,10 +367,10 @@ impl Wallets {
 #[cfg(test)]
 mod test {
     use super::*;
+    use bitcoincash_addr::Address;
     use fn_dsa::{
-        signature_size,
-        SigningKey, SigningKeyStandard, VerifyingKey, VerifyingKeyStandard, DOMAIN_NONE,
-        HASH_ID_RAW,
+        signature_size, SigningKey, SigningKeyStandard, VerifyingKey, VerifyingKeyStandard,
+        DOMAIN_NONE, HASH_ID_RAW,
     };
     #[test]
     fn test_create_wallet_and_hash() {
@@ -152,6 +398,20 @@ mod test {
         assert_eq!(&w1, w2);
     }
 
+    #[test]
+    fn test_rekey() {
+        let mut ws = Wallets::new().unwrap();
+        let old = ws.create_wallet();
+        let new = ws.rekey(&old).unwrap();
+        assert_ne!(old, new);
+        assert!(ws.get_wallet(&old).is_some());
+        assert!(ws.get_wallet(&new).is_some());
+        assert_eq!(ws.rotation(&old).unwrap().to, new);
+        assert!(ws.rotation(&new).is_none());
+        assert!(ws.rekey(&old).is_err());
+        assert!(ws.rekey(&Wallet::new().get_address()).is_err());
+    }
+
     #[test]
     #[should_panic]
     fn test_wallets_not_exist() {
@@ -0,0 +1,254 @@
+//! Chain health watchdog
+//!
+//! On every maintenance tick the node hands the watchdog a snapshot of its
+//! health. The watchdog checks that the tip advances while transactions
+//! wait, that enough peers are known, that the block storage takes writes
+//! and that the mempool stays bounded. Problems are answered with a
+//! self-healing action first, reconnecting to the peers or pausing mining
+//! while the storage fails, and the operator is paged only when they
+//! persist. Every action is appended to the watchdog audit log.
+
+use super::*;
+use std::fmt;
+use std::io::prelude::*;
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime};
+
+pub const AUDIT_LOG_PATH: &str = "data/watchdog-audit.log";
+/// Default number of peers below which the node reconnects
+pub const DEFAULT_MIN_PEERS: usize = 1;
+/// Time the tip may stay unchanged while transactions wait
+const TIP_STALL: Duration = Duration::from_secs(600);
+/// Mempool size above which the mempool counts as growing unboundedly
+const MAX_MEMPOOL: usize = 10_000;
+/// Number of unhealthy checks in a row before the operator is paged
+const PAGE_AFTER_CHECKS: u32 = 10;
+
+/// Health is a snapshot of the node taken for a check
+#[derive(Debug, Clone, PartialEq)]
+pub struct Health {
+    pub tip: String,
+    pub peers: usize,
+    pub storage_writable: bool,
+    pub mempool: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    TipStalled(Duration),
+    FewPeers(usize),
+    StorageUnwritable,
+    MempoolFull(usize),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::TipStalled(d) => write!(
+                f,
+                "tip unchanged for {}s with transactions waiting",
+                d.as_secs()
+            ),
+            Problem::FewPeers(n) => write!(f, "only {} peers", n),
+            Problem::StorageUnwritable => write!(f, "block storage is not writable"),
+            Problem::MempoolFull(n) => write!(f, "{} transactions in the mempool", n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Send a version message to every known peer to resume syncing
+    ReconnectPeers,
+    PauseMining,
+    ResumeMining,
+    /// Run the page command with the message
+    Page(String),
+}
+
+/// Watchdog turns health snapshots into actions
+pub struct Watchdog {
+    min_peers: usize,
+    page_command: Option<String>,
+    audit_log: String,
+    tip: String,
+    tip_since: Instant,
+    mining_paused: bool,
+    unhealthy_checks: u32,
+    paged: bool,
+}
+
+impl Watchdog {
+    /// NewWatchdog creates a watchdog paging the operator with `page_command`,
+    /// a program called with the problem description as its argument
+    pub fn new(min_peers: usize, page_command: Option<&str>, audit_log: &str) -> Watchdog {
+        Watchdog {
+            min_peers,
+            page_command: page_command.map(String::from),
+            audit_log: audit_log.to_string(),
+            tip: String::new(),
+            tip_since: Instant::now(),
+            mining_paused: false,
+            unhealthy_checks: 0,
+            paged: false,
+        }
+    }
+
+    /// Problems returns what is wrong with the node
+    pub fn problems(&mut self, health: &Health, now: Instant) -> Vec<Problem> {
+        if health.tip != self.tip {
+            self.tip = health.tip.clone();
+            self.tip_since = now;
+        }
+        let mut problems = Vec::new();
+        let stalled = now.duration_since(self.tip_since);
+        if health.mempool > 0 && stalled >= TIP_STALL {
+            problems.push(Problem::TipStalled(stalled));
+        }
+        if health.peers < self.min_peers {
+            problems.push(Problem::FewPeers(health.peers));
+        }
+        if !health.storage_writable {
+            problems.push(Problem::StorageUnwritable);
+        }
+        if health.mempool > MAX_MEMPOOL {
+            problems.push(Problem::MempoolFull(health.mempool));
+        }
+        problems
+    }
+
+    /// Check returns the actions for a health snapshot and audits them
+    pub fn check(&mut self, health: &Health, now: Instant) -> Vec<Action> {
+        let problems = self.problems(health, now);
+        let mut actions = Vec::new();
+        if !health.storage_writable && !self.mining_paused {
+            actions.push(Action::PauseMining);
+        } else if health.storage_writable && self.mining_paused {
+            actions.push(Action::ResumeMining);
+        }
+        self.mining_paused = !health.storage_writable;
+        if problems
+            .iter()
+            .any(|p| matches!(p, Problem::TipStalled(_) | Problem::FewPeers(_)))
+        {
+            actions.push(Action::ReconnectPeers);
+        }
+
+        if problems.is_empty() {
+            self.unhealthy_checks = 0;
+            self.paged = false;
+        } else {
+            self.unhealthy_checks += 1;
+            if self.unhealthy_checks >= PAGE_AFTER_CHECKS && !self.paged {
+                let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+                actions.push(Action::Page(problems.join(", ")));
+                self.paged = true;
+            }
+        }
+        for action in &actions {
+            warn!("watchdog: {:?}", action);
+            if let Err(e) = self.audit(action, &problems) {
+                error!("failed to write watchdog audit log: {}", e);
+            }
+        }
+        actions
+    }
+
+    pub fn min_peers(&self) -> usize {
+        self.min_peers
+    }
+
+    pub fn page_command(&self) -> Option<&str> {
+        self.page_command.as_deref()
+    }
+
+    fn audit(&self, action: &Action, problems: &[Problem]) -> Result<()> {
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis();
+        if let Some(dir) = std::path::Path::new(&self.audit_log).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.audit_log)?;
+        let problems: Vec<String> = problems.iter().map(|p| format!("{:?}", p)).collect();
+        writeln!(
+            file,
+            "{} action={:?} problems={}",
+            millis,
+            action,
+            problems.join(";")
+        )?;
+        Ok(())
+    }
+}
+
+/// Page logs the message and runs the page command with it
+pub fn page(command: Option<&str>, message: &str) -> Result<()> {
+    error!("watchdog: {}", message);
+    if let Some(command) = command {
+        let status = Command::new(command).arg(message).status()?;
+        if !status.success() {
+            warn!("page command exited with {}", status);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_watchdog() {
+        let log =
+            std::env::temp_dir().join(format!("polytorus-watchdog-{}.log", std::process::id()));
+        let log = log.to_str().unwrap();
+        let mut watchdog = Watchdog::new(1, None, log);
+        let start = Instant::now();
+        let healthy = Health {
+            tip: String::from("a"),
+            peers: 2,
+            storage_writable: true,
+            mempool: 0,
+        };
+        assert!(watchdog.check(&healthy, start).is_empty());
+        // an idle chain is not stalled
+        assert!(watchdog.check(&healthy, start + TIP_STALL).is_empty());
+
+        let waiting = Health {
+            mempool: 3,
+            ..healthy.clone()
+        };
+        assert_eq!(
+            watchdog.check(&waiting, start + TIP_STALL),
+            vec![Action::ReconnectPeers]
+        );
+        let broken = Health {
+            storage_writable: false,
+            ..waiting.clone()
+        };
+        let now = start + TIP_STALL * 2;
+        assert_eq!(
+            watchdog.check(&broken, now),
+            vec![Action::PauseMining, Action::ReconnectPeers]
+        );
+        let mut actions = Vec::new();
+        for _ in 2..PAGE_AFTER_CHECKS {
+            actions = watchdog.check(&broken, now);
+        }
+        assert_eq!(actions.len(), 2);
+        assert!(matches!(&actions[1], Action::Page(m) if m.contains("not writable")));
+        assert_eq!(watchdog.check(&broken, now), vec![Action::ReconnectPeers]);
+
+        let advanced = Health {
+            tip: String::from("b"),
+            ..waiting
+        };
+        assert_eq!(watchdog.check(&advanced, now), vec![Action::ResumeMining]);
+        assert_eq!(std::fs::read_to_string(log).unwrap().lines().count(), 14);
+        std::fs::remove_file(log).unwrap();
+    }
+}